@@ -0,0 +1,473 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+#[cfg(feature = "opus")]
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate, coder, packet::Packet};
+
+use crate::{format::SampleFormat, lossless};
+
+// Sample rates Opus works at
+const OPUS_SAMPLE_RATES: [usize; 5] = [8000, 12000, 16000, 24000, 48000];
+
+// Frame durations Opus can encode, in microseconds
+const OPUS_FRAME_DURATIONS: [u128; 6] = [2500, 5000, 10000, 20000, 40000, 60000];
+
+// Longest frame an Opus packet may decode to, 120 ms at 48 kHz, in samples per channel
+#[cfg(feature = "opus")]
+const OPUS_MAX_FRAME: usize = 5760;
+
+// Lowest bitrate in kbit/s an adaptive encoder steps down to
+const MIN_ADAPTIVE_BITRATE: u32 = 16;
+
+// Loss in percent above which an adaptive encoder steps down, and below which it may recover
+const HIGH_LOSS: f64 = 5.0;
+const LOW_LOSS: f64 = 1.0;
+
+// Loss reports in a row below LOW_LOSS before an adaptive encoder steps back up
+const RECOVERY_REPORTS: u32 = 3;
+
+// How audio is carried in packet payloads, must match on both ends
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    Pcm,      // Samples in the wire sample format
+    Opus,     // One lossy Opus frame per packet
+    Lossless, // Compressed samples, decoding to exactly what was sent
+}
+
+impl Codec {
+    // Identifier used when exchanging stream settings
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::Pcm => 0,
+            Codec::Opus => 1,
+            Codec::Lossless => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::Pcm),
+            1 => Some(Codec::Opus),
+            2 => Some(Codec::Lossless),
+            _ => None,
+        }
+    }
+
+    // Whether packets decode to exactly the samples sent, a stream may switch between these
+    pub fn is_exact(self) -> bool {
+        matches!(self, Codec::Pcm | Codec::Lossless)
+    }
+
+    // Bytes a payload may take over the samples it carries
+    pub fn overhead(self) -> usize {
+        match self {
+            Codec::Lossless => lossless::OVERHEAD,
+            Codec::Pcm | Codec::Opus => 0,
+        }
+    }
+
+    // Creates the encoder a sender needs for this codec, if any
+    pub fn encoder(
+        self,
+        sample_rate: usize,
+        channels: usize,
+        bitrate: u32,
+    ) -> Result<Option<Encoder>, &'static str> {
+        match self {
+            Codec::Pcm => Ok(None),
+            Codec::Opus => OpusEncoder::new(sample_rate, channels, bitrate)
+                .map(|encoder| Some(Encoder::Opus(encoder))),
+            Codec::Lossless => Ok(Some(Encoder::Lossless(lossless::Coder::new(channels)))),
+        }
+    }
+
+    // Creates the decoder a receiver needs for each stream of this codec, if any
+    pub fn decoder(
+        self,
+        sample_rate: usize,
+        channels: usize,
+    ) -> Result<Option<Decoder>, &'static str> {
+        match self {
+            Codec::Pcm => Ok(None),
+            Codec::Opus => {
+                OpusDecoder::new(sample_rate, channels).map(|decoder| Some(Decoder::Opus(decoder)))
+            }
+            Codec::Lossless => Ok(Some(Decoder::Lossless(lossless::Coder::new(channels)))),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pcm" => Ok(Codec::Pcm),
+            "opus" => Ok(Codec::Opus),
+            "lossless" => Ok(Codec::Lossless),
+            _ => Err("unknown codec, expected pcm, opus or lossless"),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Pcm => "pcm",
+            Codec::Opus => "opus",
+            Codec::Lossless => "lossless",
+        })
+    }
+}
+
+// Bytes of samples in the wire format making up one Opus frame lasting `frame`. This takes
+// the place of the packet size, both ends gather and play out audio in these units.
+pub fn opus_packet_size(
+    sample_rate: usize,
+    channels: usize,
+    frame: Duration,
+    format: SampleFormat,
+) -> Result<usize, &'static str> {
+    if !OPUS_SAMPLE_RATES.contains(&sample_rate) {
+        return Err("sample rate not supported by opus, use 8, 12, 16, 24 or 48 kHz");
+    }
+    if !OPUS_FRAME_DURATIONS.contains(&frame.as_micros()) {
+        return Err("frame duration not supported by opus, use 2.5, 5, 10, 20, 40 or 60 ms");
+    }
+    // Every supported rate is a multiple of 400 Hz, so frames are whole
+    let frames = sample_rate * frame.as_micros() as usize / 1_000_000;
+    Ok(frames * channels * format.sample_size())
+}
+
+// Opus channel layout for a channel count, beyond stereo it would take multistream coding
+#[cfg(feature = "opus")]
+fn opus_channels(channels: usize) -> Result<Channels, &'static str> {
+    match channels {
+        1 => Ok(Channels::Mono),
+        2 => Ok(Channels::Stereo),
+        _ => Err("opus supports one or two channels"),
+    }
+}
+
+// Compresses the audio of a sender's packets
+pub enum Encoder {
+    Opus(OpusEncoder),
+    Lossless(lossless::Coder),
+}
+
+impl Encoder {
+    // Compresses a packet of interleaved samples in `format`, returns the number of bytes
+    // written to `out`, which needs room for the samples plus the codec's overhead
+    pub fn encode(
+        &mut self,
+        format: SampleFormat,
+        pcm: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        match self {
+            Encoder::Opus(encoder) => encoder.encode(format, pcm, out),
+            Encoder::Lossless(coder) => Ok(coder.encode(format, pcm, out)),
+        }
+    }
+
+    // Changes the bitrate of codecs that have one
+    pub fn set_bitrate(&mut self, kbps: u32) -> Result<(), &'static str> {
+        match self {
+            Encoder::Opus(encoder) => encoder.set_bitrate(kbps),
+            Encoder::Lossless(_) => Ok(()),
+        }
+    }
+}
+
+// Steers an encoder's bitrate by the loss its receiver reports. It steps down by a quarter
+// whenever loss is high, and back up by an eighth once a few reports in a row came in low,
+// never above the bitrate it started at.
+pub struct BitrateControl {
+    max: u32,     // Configured bitrate in kbit/s
+    current: u32, // Bitrate the encoder runs at
+    clear: u32,   // Reports in a row with low loss
+}
+
+impl BitrateControl {
+    pub fn new(kbps: u32) -> Self {
+        Self {
+            max: kbps,
+            current: kbps,
+            clear: 0,
+        }
+    }
+
+    // Takes the loss in percent of a report, returns the new bitrate if it changes
+    pub fn update(&mut self, loss: f64) -> Option<u32> {
+        let bitrate = if loss > HIGH_LOSS {
+            self.clear = 0;
+            (self.current * 3 / 4).max(MIN_ADAPTIVE_BITRATE.min(self.max))
+        } else if loss < LOW_LOSS {
+            self.clear += 1;
+            if self.clear < RECOVERY_REPORTS {
+                return None;
+            }
+            self.clear = 0;
+            (self.current + self.current.div_ceil(8)).min(self.max)
+        } else {
+            self.clear = 0;
+            return None;
+        };
+        (bitrate != self.current).then(|| {
+            self.current = bitrate;
+            bitrate
+        })
+    }
+}
+
+// Decompresses the packets of one stream. Opus keeps state from packet to packet, so every
+// stream needs its own.
+pub enum Decoder {
+    Opus(OpusDecoder),
+    Lossless(lossless::Coder),
+}
+
+impl Decoder {
+    // Decompresses a packet into interleaved samples in `format`, returns the number of bytes
+    // written to `out`
+    pub fn decode(
+        &mut self,
+        packet: &[u8],
+        format: SampleFormat,
+        out: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        match self {
+            Decoder::Opus(decoder) => decoder.decode(packet, format, out),
+            Decoder::Lossless(coder) => coder.decode(packet, format, out),
+        }
+    }
+
+    // Stands in for one lost packet with audio extrapolated from the ones before it, returns
+    // the number of bytes written to `out`. Codecs without concealment of their own write none.
+    pub fn conceal(&mut self, format: SampleFormat, out: &mut [u8]) -> Result<usize, &'static str> {
+        match self {
+            Decoder::Opus(decoder) => decoder.conceal(format, out),
+            Decoder::Lossless(_) => Ok(0),
+        }
+    }
+}
+
+#[cfg(feature = "opus")]
+pub struct OpusEncoder {
+    encoder: coder::Encoder,
+    samples: Vec<f32>, // Frame being encoded
+}
+
+#[cfg(feature = "opus")]
+impl OpusEncoder {
+    fn new(sample_rate: usize, channels: usize, bitrate: u32) -> Result<Self, &'static str> {
+        let sample_rate = SampleRate::try_from(sample_rate as i32)
+            .map_err(|_| "sample rate not supported by opus, use 8, 12, 16, 24 or 48 kHz")?;
+        let channels = opus_channels(channels)?;
+        let mut encoder = coder::Encoder::new(sample_rate, channels, Application::Audio)
+            .map_err(|_| "unable to create opus encoder")?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(bitrate as i32 * 1000))
+            .map_err(|_| "opus bitrate out of range")?;
+        Ok(Self {
+            encoder,
+            samples: Vec::new(),
+        })
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) -> Result<(), &'static str> {
+        self.encoder
+            .set_bitrate(Bitrate::BitsPerSecond(kbps as i32 * 1000))
+            .map_err(|_| "opus bitrate out of range")
+    }
+
+    // Compresses a whole frame, Opus lowers the quality rather than exceed the space in `out`
+    fn encode(
+        &mut self,
+        format: SampleFormat,
+        pcm: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        self.samples.resize(pcm.len() / format.sample_size(), 0.0);
+        format.decode(pcm, &mut self.samples);
+        self.encoder
+            .encode_float(&self.samples, out)
+            .map_err(|_| "unable to encode opus frame")
+    }
+}
+
+#[cfg(feature = "opus")]
+pub struct OpusDecoder {
+    decoder: coder::Decoder,
+    channels: usize,
+    samples: Box<[f32]>, // Decoded frame, large enough for any packet
+    frames: usize,       // Samples per channel of the last frame, the length of a concealed one
+}
+
+#[cfg(feature = "opus")]
+impl OpusDecoder {
+    fn new(sample_rate: usize, channels: usize) -> Result<Self, &'static str> {
+        let sample_rate = SampleRate::try_from(sample_rate as i32)
+            .map_err(|_| "sample rate not supported by opus, use 8, 12, 16, 24 or 48 kHz")?;
+        let decoder = coder::Decoder::new(sample_rate, opus_channels(channels)?)
+            .map_err(|_| "unable to create opus decoder")?;
+        Ok(Self {
+            decoder,
+            channels,
+            samples: vec![0.0; channels * OPUS_MAX_FRAME].into_boxed_slice(),
+            frames: 0,
+        })
+    }
+
+    fn decode(
+        &mut self,
+        packet: &[u8],
+        format: SampleFormat,
+        out: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        // Empty payloads, such as the end of a stream, carry no frame. Losses are concealed
+        // by conceal instead.
+        if packet.is_empty() {
+            return Ok(0);
+        }
+        let packet = Packet::try_from(packet).map_err(|_| "invalid opus packet")?;
+        let signals = MutSignals::try_from(&mut self.samples[..])
+            .map_err(|_| "unable to decode opus packet")?;
+        let frames = self
+            .decoder
+            .decode_float(Some(packet), signals, false)
+            .map_err(|_| "unable to decode opus packet")?;
+        self.frames = frames;
+        let samples = self.samples[..self.channels * frames].iter().copied();
+        Ok(format.encode(samples, None, out))
+    }
+
+    // Opus's packet loss concealment, one frame as long as the last one decoded. Nothing is
+    // concealed before the first frame.
+    fn conceal(&mut self, format: SampleFormat, out: &mut [u8]) -> Result<usize, &'static str> {
+        if self.frames == 0 {
+            return Ok(0);
+        }
+        let signals = MutSignals::try_from(&mut self.samples[..self.channels * self.frames])
+            .map_err(|_| "unable to conceal opus frame")?;
+        let frames = self
+            .decoder
+            .decode_float(None, signals, false)
+            .map_err(|_| "unable to conceal opus frame")?;
+        let samples = self.samples[..self.channels * frames].iter().copied();
+        Ok(format.encode(samples, None, out))
+    }
+}
+
+// Stand-ins when built without Opus, which can never be created
+#[cfg(not(feature = "opus"))]
+pub enum OpusEncoder {}
+
+#[cfg(not(feature = "opus"))]
+impl OpusEncoder {
+    fn new(_: usize, _: usize, _: u32) -> Result<Self, &'static str> {
+        Err("built without opus support, rebuild with --features opus")
+    }
+
+    fn set_bitrate(&mut self, _: u32) -> Result<(), &'static str> {
+        match *self {}
+    }
+
+    fn encode(&mut self, _: SampleFormat, _: &[u8], _: &mut [u8]) -> Result<usize, &'static str> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+pub enum OpusDecoder {}
+
+#[cfg(not(feature = "opus"))]
+impl OpusDecoder {
+    fn new(_: usize, _: usize) -> Result<Self, &'static str> {
+        Err("built without opus support, rebuild with --features opus")
+    }
+
+    fn decode(&mut self, _: &[u8], _: SampleFormat, _: &mut [u8]) -> Result<usize, &'static str> {
+        match *self {}
+    }
+
+    fn conceal(&mut self, _: SampleFormat, _: &mut [u8]) -> Result<usize, &'static str> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opus_frames_set_the_packet_size() {
+        let frame = Duration::from_micros(2500);
+        assert_eq!(
+            opus_packet_size(48000, 2, frame, SampleFormat::F32),
+            Ok(120 * 2 * 4)
+        );
+        assert_eq!(
+            opus_packet_size(16000, 1, Duration::from_millis(20), SampleFormat::S16),
+            Ok(320 * 2)
+        );
+        assert!(opus_packet_size(44100, 2, frame, SampleFormat::F32).is_err());
+        assert!(opus_packet_size(48000, 2, Duration::from_millis(3), SampleFormat::F32).is_err());
+
+        for codec in [Codec::Pcm, Codec::Opus, Codec::Lossless] {
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+            assert_eq!(codec.to_string().parse(), Ok(codec));
+        }
+    }
+
+    #[test]
+    fn bitrate_follows_reported_loss() {
+        let mut control = BitrateControl::new(128);
+        assert_eq!(control.update(10.0), Some(96));
+        assert_eq!(control.update(3.0), None);
+        assert_eq!(control.update(20.0), Some(72));
+        for _ in 0..20 {
+            control.update(50.0);
+        }
+        assert_eq!(control.update(50.0), None, "held at the floor");
+
+        // Recovery waits for a few clear reports, and a lossy one starts the wait over
+        assert_eq!(control.update(0.0), None);
+        assert_eq!(control.update(0.0), None);
+        assert_eq!(control.update(0.0), Some(18));
+        assert_eq!(control.update(0.0), None);
+        assert_eq!(control.update(2.0), None);
+        assert_eq!(control.update(0.0), None);
+        assert_eq!(control.update(0.0), None);
+        assert_eq!(control.update(0.0), Some(21));
+        for _ in 0..100 {
+            control.update(0.0);
+        }
+        assert_eq!(control.current, 128, "capped at the configured bitrate");
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn opus_conceals_lost_frames() {
+        let format = SampleFormat::F32;
+        let mut encoder = OpusEncoder::new(48000, 1, 64).unwrap();
+        let mut decoder = OpusDecoder::new(48000, 1).unwrap();
+        let mut pcm = vec![0; 480 * 4];
+        let tone = (0..480).map(|i| (i as f32 * 0.05).sin() * 0.5);
+        format.encode(tone, None, &mut pcm);
+        let mut packet = [0; 1500];
+        let mut out = vec![0; OPUS_MAX_FRAME * 4];
+
+        // Nothing to extrapolate from before the first frame
+        assert_eq!(decoder.conceal(format, &mut out), Ok(0));
+        for _ in 0..5 {
+            let length = encoder.encode(format, &pcm, &mut packet).unwrap();
+            assert_eq!(
+                decoder.decode(&packet[..length], format, &mut out),
+                Ok(pcm.len())
+            );
+        }
+        // A lost frame is as long as the last one and carries on the tone
+        assert_eq!(decoder.conceal(format, &mut out), Ok(pcm.len()));
+        assert!(out[..pcm.len()].iter().any(|&byte| byte != 0));
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    sync::{
+        Arc, Condvar, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use jack::{AudioIn, Client, Port, PortFlags, PortSpec, Unowned};
+
+// Bumped whenever a port appears, waking the threads keeping connections up
+static APPEARED: Mutex<u64> = Mutex::new(0);
+static WAKE: Condvar = Condvar::new();
+
+// Called by the notification handler when a port is registered, by any client
+pub fn port_appeared() {
+    *APPEARED.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    WAKE.notify_all();
+}
+
+// Connections from netaudio's ports to the ports of other clients matching --connect patterns.
+// The first port of a group is connected to the first port matched, the second to the second
+// and so on, patterns matching in the order they were given.
+pub struct Connections {
+    patterns: Vec<String>, // JACK port name regular expressions
+    outputs: bool,         // Whether our ports are outputs, connected to inputs
+    // One group of ports per stream
+    ports: Mutex<Vec<Vec<Port<Unowned>>>>,
+}
+
+impl Connections {
+    pub fn new(patterns: Vec<String>, outputs: bool) -> Arc<Self> {
+        Arc::new(Self {
+            patterns,
+            outputs,
+            ports: Mutex::default(),
+        })
+    }
+
+    // Connects `ports` from now on
+    pub fn add_ports(&self, ports: impl IntoIterator<Item = Port<Unowned>>) {
+        if self.patterns.is_empty() {
+            return;
+        }
+        let mut all = self.ports.lock().unwrap_or_else(PoisonError::into_inner);
+        all.push(ports.into_iter().collect());
+        drop(all);
+        port_appeared();
+    }
+
+    // Makes the connections that are missing, as far as there are ports to make them with
+    fn apply(&self, client: &Client) {
+        let flags = match self.outputs {
+            true => PortFlags::IS_INPUT,
+            false => PortFlags::IS_OUTPUT,
+        };
+        // Our own ports never count, so a pattern can be as loose as a client name
+        let own = format!("{}:", client.name());
+        let audio = AudioIn::default();
+        let mut matched: Vec<String> = Vec::new();
+        for pattern in &self.patterns {
+            for name in client.ports(Some(pattern), Some(audio.jack_port_type()), flags) {
+                if !name.starts_with(&own) && !matched.contains(&name) {
+                    matched.push(name);
+                }
+            }
+        }
+        let all = self.ports.lock().unwrap_or_else(PoisonError::into_inner);
+        for group in all.iter() {
+            for (port, other) in group.iter().zip(&matched) {
+                let Ok(name) = port.name() else {
+                    continue;
+                };
+                if port.is_connected_to(other).unwrap_or(true) {
+                    continue;
+                }
+                let (source, destination) = match self.outputs {
+                    true => (name.as_str(), other.as_str()),
+                    false => (other.as_str(), name.as_str()),
+                };
+                match client.connect_ports_by_name(source, destination) {
+                    Ok(()) => info!("connected {} to {}", source, destination),
+                    Err(_) => warning!("unable to connect {} to {}", source, destination),
+                }
+            }
+        }
+    }
+}
+
+// Runs `body` while another thread keeps the ports of `connections` connected, reconnecting
+// them whenever ports appear. Connections are only made on an active client.
+pub fn keep<T>(client: &Client, connections: &Connections, body: impl FnOnce() -> T) -> T {
+    if connections.patterns.is_empty() {
+        return body();
+    }
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut seen = None;
+            loop {
+                let appeared = APPEARED.lock().unwrap_or_else(PoisonError::into_inner);
+                let appeared = WAKE
+                    .wait_while(appeared, |appeared| {
+                        Some(*appeared) == seen && !done.load(Ordering::Relaxed)
+                    })
+                    .unwrap_or_else(PoisonError::into_inner);
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                seen = Some(*appeared);
+                drop(appeared);
+                connections.apply(client);
+            }
+        });
+        let result = body();
+        done.store(true, Ordering::Relaxed);
+        // Taking the lock makes sure the thread is either waiting or yet to check
+        drop(APPEARED.lock().unwrap_or_else(PoisonError::into_inner));
+        WAKE.notify_all();
+        result
+    })
+}
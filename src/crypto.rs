@@ -0,0 +1,657 @@
+use std::{collections::VecDeque, fmt, fs, net::SocketAddr, ops::Range, str::FromStr};
+
+#[cfg(feature = "encryption")]
+use std::sync::{
+    OnceLock,
+    atomic::{AtomicU64, Ordering},
+};
+
+#[cfg(feature = "encryption")]
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce, Tag,
+    aead::{AeadInPlace, OsRng, rand_core::RngCore},
+};
+#[cfg(feature = "encryption")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "encryption")]
+use sha2::Sha256;
+#[cfg(feature = "encryption")]
+use snow::{Builder, HandshakeState};
+
+use crate::packet::{self, FLAG_HANDSHAKE, HEADER_SIZE, Header};
+
+// Size of a pre-shared key, AES-256, and of the static keys of the key exchange
+const KEY_SIZE: usize = 32;
+
+// A nonce is a random salt drawn once per process followed by a counter
+const SALT_SIZE: usize = 4;
+const NONCE_SIZE: usize = SALT_SIZE + 8;
+
+// Size of the authentication tag closing every encrypted datagram
+const TAG_SIZE: usize = 16;
+
+// Bytes encryption adds to a datagram, the nonce in front and the tag behind
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+// Bytes of HMAC-SHA256 kept behind an authenticated datagram, truncated as in RFC 4868
+const MAC_SIZE: usize = 16;
+
+// Noise pattern of the key exchange. The sender knows the receiver's static key beforehand and
+// sends its own encrypted in the first message, so one round trip authenticates both ends.
+#[cfg(feature = "encryption")]
+const NOISE_PARAMS: &str = "Noise_IK_25519_AESGCM_SHA256";
+
+// Binds the key exchange to this protocol
+#[cfg(feature = "encryption")]
+const PROLOGUE: &[u8] = b"netaudio";
+
+// Largest key exchange message, the first one with an empty payload takes 96 bytes
+pub const HANDSHAKE_SIZE: usize = 128;
+
+// Senders a receiver keeps session keys for, the oldest make way beyond that
+const MAX_SESSIONS: usize = 64;
+
+// Salt and counter shared by every cipher of the process, so no two datagrams sealed here
+// get the same nonce. The counter starts at the current time in microseconds, which keeps a
+// restarted process clear of the nonces it used before.
+#[cfg(feature = "encryption")]
+static SALT: OnceLock<[u8; SALT_SIZE]> = OnceLock::new();
+#[cfg(feature = "encryption")]
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Pre-shared key both ends encrypt and authenticate packets with, or only authenticate them,
+// or a private or public key of the key exchange
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key([u8; KEY_SIZE]);
+
+impl FromStr for Key {
+    type Err = &'static str;
+
+    // 64 hex digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.as_bytes();
+        if digits.len() != 2 * KEY_SIZE {
+            return Err("key must be 64 hex digits");
+        }
+        let mut key = [0; KEY_SIZE];
+        for (byte, pair) in key.iter_mut().zip(digits.chunks_exact(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| "key must be 64 hex digits")?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| "key must be 64 hex digits")?;
+        }
+        Ok(Key(key))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl Key {
+    // Takes the key as given, or else from the file it names
+    pub fn read(value: &str) -> Result<Self, &'static str> {
+        if let Ok(key) = value.parse() {
+            return Ok(key);
+        }
+        fs::read_to_string(value)
+            .map_err(|_| "key must be 64 hex digits or a file holding them")?
+            .trim()
+            .parse()
+    }
+}
+
+#[cfg(feature = "encryption")]
+type Aead = Aes256Gcm;
+
+// Stand-in when built without encryption, which can never be created
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+enum Aead {}
+
+#[cfg(feature = "encryption")]
+type Authenticator = Hmac<Sha256>;
+
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+enum Authenticator {}
+
+// Encrypts and authenticates datagrams with AES-GCM as they leave, and checks and decrypts
+// them as they arrive. With an authentication key instead, datagrams stay readable and only
+// carry an HMAC. Without a key datagrams pass through unchanged.
+#[derive(Clone, Default)]
+pub struct Cipher {
+    // Expanded key schedules, too large to move around inline. The same for a pre-shared key,
+    // one per direction for keys exchanged.
+    sealing: Option<Box<Aead>>,
+    opening: Option<Box<Aead>>,
+    authenticator: Option<Box<Authenticator>>, // Keyed HMAC, for authentication alone
+    buffer: Vec<u8>,                           // Last datagram sealed
+}
+
+impl Cipher {
+    // Encrypts with `key`, or only authenticates with `auth_key`
+    pub fn new(key: Option<Key>, auth_key: Option<Key>) -> Result<Self, &'static str> {
+        let aead = key.map(create).transpose()?.map(Box::new);
+        let authenticator = auth_key.map(create_mac).transpose()?.map(Box::new);
+        Ok(Self {
+            sealing: aead.clone(),
+            opening: aead,
+            authenticator,
+            buffer: Vec::new(),
+        })
+    }
+
+    // Bytes sealing adds to a datagram
+    pub fn overhead(&self) -> usize {
+        if self.sealing.is_some() {
+            OVERHEAD
+        } else if self.authenticator.is_some() {
+            MAC_SIZE
+        } else {
+            0
+        }
+    }
+
+    // The datagram to send in place of `packet`
+    pub fn seal<'a>(&'a mut self, packet: &'a [u8]) -> &'a [u8] {
+        if let Some(aead) = &self.sealing {
+            seal(aead, packet, &mut self.buffer);
+        } else if let Some(authenticator) = &self.authenticator {
+            sign(authenticator, packet, &mut self.buffer);
+        } else {
+            return packet;
+        }
+        &self.buffer
+    }
+
+    // The packet a received datagram holds, decrypted in place, or nothing if it fails
+    // authentication
+    pub fn open<'a>(&self, datagram: &'a mut [u8]) -> Option<&'a [u8]> {
+        let packet = self.unseal(datagram)?;
+        Some(&datagram[packet])
+    }
+
+    // Decrypts a datagram in place, returning where its packet lies
+    fn unseal(&self, datagram: &mut [u8]) -> Option<Range<usize>> {
+        match (&self.opening, &self.authenticator) {
+            (Some(aead), _) => open(aead, datagram),
+            (None, Some(authenticator)) => verify(authenticator, datagram),
+            (None, None) => Some(0..datagram.len()),
+        }
+    }
+}
+
+// What a receiver made of a datagram
+pub enum Opened<'a> {
+    Packet(&'a [u8]), // Authentic, or taken as it is without keys
+    Handshake,        // Key exchange message, answered with Sessions::reply
+    Rejected,         // Failed authentication
+}
+
+// A sender a receiver exchanged keys with
+struct Session {
+    peer: SocketAddr,
+    cipher: Cipher,
+    message: Box<[u8]>, // The sender's key exchange message, answered again if repeated
+    reply: Box<[u8]>,
+}
+
+// Opens and seals a receiver's datagrams: with the pre-shared key, with the keys exchanged
+// with each sender, or not at all
+#[derive(Default)]
+pub struct Sessions {
+    shared: Cipher,
+    responder: Option<Responder>,
+    sessions: VecDeque<Session>, // Oldest first
+    reply: Vec<u8>,              // Answer to the last key exchange message
+}
+
+impl Sessions {
+    // Exchanges keys only with senders holding one of the `peers` public keys, if `identity`
+    // is set
+    pub fn new(
+        key: Option<Key>,
+        auth_key: Option<Key>,
+        identity: Option<Key>,
+        peers: Vec<Key>,
+    ) -> Result<Self, &'static str> {
+        Ok(Self {
+            shared: Cipher::new(key, auth_key)?,
+            responder: identity
+                .map(|identity| Responder::new(identity, peers))
+                .transpose()?,
+            sessions: VecDeque::new(),
+            reply: Vec::new(),
+        })
+    }
+
+    pub fn open<'a>(&mut self, source: SocketAddr, datagram: &'a mut [u8]) -> Opened<'a> {
+        let Some(responder) = &self.responder else {
+            return match self.shared.open(datagram) {
+                Some(packet) => Opened::Packet(packet),
+                None => Opened::Rejected,
+            };
+        };
+        // Datagrams failing authentication are left as they were
+        let session = self
+            .sessions
+            .iter()
+            .position(|session| session.peer == source);
+        if let Some(index) = session
+            && let Some(packet) = self.sessions[index].cipher.unseal(datagram)
+        {
+            return Opened::Packet(&datagram[packet]);
+        }
+
+        let Ok((header, message)) = packet::parse(datagram) else {
+            return Opened::Rejected;
+        };
+        if header.flags & FLAG_HANDSHAKE != FLAG_HANDSHAKE {
+            return Opened::Rejected;
+        }
+        // A repeated message gets the same answer, the sender only takes the first one back
+        if let Some(index) = session
+            && *self.sessions[index].message == *message
+        {
+            self.reply.clear();
+            self.reply.extend_from_slice(&self.sessions[index].reply);
+            return Opened::Handshake;
+        }
+        let Some(cipher) = responder.accept(header, message, &mut self.reply) else {
+            return Opened::Rejected;
+        };
+        if let Some(index) = session {
+            self.sessions.remove(index);
+        }
+        if self.sessions.len() == MAX_SESSIONS {
+            self.sessions.pop_front();
+        }
+        self.sessions.push_back(Session {
+            peer: source,
+            cipher,
+            message: message.into(),
+            reply: self.reply.as_slice().into(),
+        });
+        Opened::Handshake
+    }
+
+    // Answer to the key exchange message just opened
+    pub fn reply(&self) -> &[u8] {
+        &self.reply
+    }
+
+    // The datagram to send to `destination` in place of `packet`. Without keys exchanged with
+    // it yet, which only happens to subscriptions, it goes out in the clear.
+    pub fn seal<'a>(&'a mut self, destination: SocketAddr, packet: &'a [u8]) -> &'a [u8] {
+        if self.responder.is_none() {
+            return self.shared.seal(packet);
+        }
+        match self
+            .sessions
+            .iter_mut()
+            .find(|session| session.peer == destination)
+        {
+            Some(session) => session.cipher.seal(packet),
+            None => packet,
+        }
+    }
+}
+
+// Writes a key exchange message as the payload of a handshake packet
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+fn handshake_packet(stream: u8, message: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.resize(HEADER_SIZE, 0);
+    Header {
+        sequence: 0,
+        timestamp: packet::now_micros(),
+        flags: FLAG_HANDSHAKE,
+        stream,
+        length: message.len() as u16,
+    }
+    .write(out);
+    out.extend_from_slice(message);
+}
+
+// Sender side of the key exchange, waiting for the receiver's answer
+#[cfg(feature = "encryption")]
+pub struct Initiator(Box<HandshakeState>);
+
+#[cfg(feature = "encryption")]
+impl Initiator {
+    // Starts the exchange with the receiver holding the private key of `peer`, returns the
+    // datagram to send it
+    pub fn new(identity: Key, peer: Key, stream: u8) -> Result<(Self, Vec<u8>), &'static str> {
+        let mut state = builder()?
+            .local_private_key(&identity.0)
+            .remote_public_key(&peer.0)
+            .build_initiator()
+            .map_err(|_| "unable to start key exchange")?;
+        let mut message = [0; HANDSHAKE_SIZE];
+        let length = state
+            .write_message(&[], &mut message)
+            .map_err(|_| "unable to start key exchange")?;
+        let mut datagram = Vec::new();
+        handshake_packet(stream, &message[..length], &mut datagram);
+        Ok((Self(Box::new(state)), datagram))
+    }
+
+    // Completes the exchange with the receiver's answer, nothing if it is not one
+    pub fn finish(mut self, datagram: &[u8]) -> Option<Cipher> {
+        let (header, message) = packet::parse(datagram).ok()?;
+        if header.flags & FLAG_HANDSHAKE != FLAG_HANDSHAKE {
+            return None;
+        }
+        self.0.read_message(message, &mut []).ok()?;
+        let (sending, receiving) = self.0.dangerously_get_raw_split();
+        Some(Cipher::session(sending, receiving))
+    }
+}
+
+// Receiver side of the key exchange, answering senders that hold one of the known keys
+#[cfg(feature = "encryption")]
+struct Responder {
+    identity: Key,
+    peers: Vec<Key>,
+}
+
+#[cfg(feature = "encryption")]
+impl Responder {
+    fn new(identity: Key, peers: Vec<Key>) -> Result<Self, &'static str> {
+        builder()?;
+        Ok(Self { identity, peers })
+    }
+
+    // Answers a sender's message in `reply`, returns the keys agreed on if the sender holds
+    // one of the known keys
+    fn accept(&self, header: Header, message: &[u8], reply: &mut Vec<u8>) -> Option<Cipher> {
+        let mut state = builder()
+            .ok()?
+            .local_private_key(&self.identity.0)
+            .build_responder()
+            .ok()?;
+        state.read_message(message, &mut []).ok()?;
+        let known = state
+            .get_remote_static()
+            .is_some_and(|key| self.peers.iter().any(|peer| peer.0 == key));
+        if !known {
+            return None;
+        }
+        let mut answer = [0; HANDSHAKE_SIZE];
+        let length = state.write_message(&[], &mut answer).ok()?;
+        handshake_packet(header.stream, &answer[..length], reply);
+        let (receiving, sending) = state.dangerously_get_raw_split();
+        Some(Cipher::session(sending, receiving))
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn builder() -> Result<Builder<'static>, &'static str> {
+    let params = NOISE_PARAMS
+        .parse()
+        .map_err(|_| "unable to start key exchange")?;
+    Ok(Builder::new(params).prologue(PROLOGUE))
+}
+
+// Creates a key pair for the key exchange, private key first
+#[cfg(feature = "encryption")]
+pub fn generate_identity() -> Result<(Key, Key), &'static str> {
+    let pair = builder()?
+        .generate_keypair()
+        .map_err(|_| "unable to generate key pair")?;
+    let key = |bytes: Vec<u8>| bytes.try_into().map(Key);
+    match (key(pair.private), key(pair.public)) {
+        (Ok(private), Ok(public)) => Ok((private, public)),
+        _ => Err("unable to generate key pair"),
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    // Seals with one key and opens with the other, as agreed in a key exchange. The keys are
+    // taken from the handshake rather than left to Noise's transport, so datagrams keep the
+    // layout and explicit nonces of pre-shared keys, which lost packets do not upset.
+    fn session(sealing: [u8; KEY_SIZE], opening: [u8; KEY_SIZE]) -> Self {
+        let aead = |key: [u8; KEY_SIZE]| create(Key(key)).ok().map(Box::new);
+        Self {
+            sealing: aead(sealing),
+            opening: aead(opening),
+            authenticator: None,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn create(key: Key) -> Result<Aead, &'static str> {
+    SALT.get_or_init(|| {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    });
+    COUNTER.fetch_max(packet::now_micros(), Ordering::Relaxed);
+    Ok(Aes256Gcm::new(&key.0.into()))
+}
+
+// Writes the nonce, the encrypted packet and the tag to `out`
+#[cfg(feature = "encryption")]
+fn seal(aead: &Aead, packet: &[u8], out: &mut Vec<u8>) {
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    out.clear();
+    out.extend_from_slice(SALT.get().unwrap_or(&[0; SALT_SIZE]));
+    out.extend_from_slice(&counter.to_be_bytes());
+    out.extend_from_slice(packet);
+    let (nonce, packet) = out.split_at_mut(NONCE_SIZE);
+    // Only fails for packets far larger than a datagram
+    let tag = aead
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), &[], packet)
+        .unwrap_or_default();
+    out.extend_from_slice(&tag);
+}
+
+#[cfg(feature = "encryption")]
+fn open(aead: &Aead, datagram: &mut [u8]) -> Option<Range<usize>> {
+    let length = datagram.len().checked_sub(OVERHEAD)?;
+    let (nonce, rest) = datagram.split_at_mut(NONCE_SIZE);
+    let (packet, tag) = rest.split_at_mut(length);
+    aead.decrypt_in_place_detached(Nonce::from_slice(nonce), &[], packet, Tag::from_slice(tag))
+        .ok()?;
+    Some(NONCE_SIZE..NONCE_SIZE + length)
+}
+
+#[cfg(feature = "encryption")]
+fn create_mac(key: Key) -> Result<Authenticator, &'static str> {
+    <Authenticator as Mac>::new_from_slice(&key.0).map_err(|_| "invalid authentication key")
+}
+
+// Writes the packet followed by its truncated HMAC to `out`
+#[cfg(feature = "encryption")]
+fn sign(authenticator: &Authenticator, packet: &[u8], out: &mut Vec<u8>) {
+    let mut mac = authenticator.clone();
+    mac.update(packet);
+    out.clear();
+    out.extend_from_slice(packet);
+    out.extend_from_slice(&mac.finalize().into_bytes()[..MAC_SIZE]);
+}
+
+// Checks the HMAC behind a datagram in constant time
+#[cfg(feature = "encryption")]
+fn verify(authenticator: &Authenticator, datagram: &[u8]) -> Option<Range<usize>> {
+    let length = datagram.len().checked_sub(MAC_SIZE)?;
+    let (packet, tag) = datagram.split_at(length);
+    let mut mac = authenticator.clone();
+    mac.update(packet);
+    mac.verify_truncated_left(tag).ok()?;
+    Some(0..length)
+}
+
+// Stand-ins when built without encryption, which can never be created
+#[cfg(not(feature = "encryption"))]
+pub enum Initiator {}
+
+#[cfg(not(feature = "encryption"))]
+impl Initiator {
+    pub fn new(_: Key, _: Key, _: u8) -> Result<(Self, Vec<u8>), &'static str> {
+        Err("built without encryption support, rebuild with --features encryption")
+    }
+
+    pub fn finish(self, _: &[u8]) -> Option<Cipher> {
+        match self {}
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+enum Responder {}
+
+#[cfg(not(feature = "encryption"))]
+impl Responder {
+    fn new(_: Key, _: Vec<Key>) -> Result<Self, &'static str> {
+        Err("built without encryption support, rebuild with --features encryption")
+    }
+
+    fn accept(&self, _: Header, _: &[u8], _: &mut Vec<u8>) -> Option<Cipher> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn generate_identity() -> Result<(Key, Key), &'static str> {
+    Err("built without encryption support, rebuild with --features encryption")
+}
+
+#[cfg(not(feature = "encryption"))]
+fn create(_: Key) -> Result<Aead, &'static str> {
+    Err("built without encryption support, rebuild with --features encryption")
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal(aead: &Aead, _: &[u8], _: &mut Vec<u8>) {
+    match *aead {}
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open(aead: &Aead, _: &mut [u8]) -> Option<Range<usize>> {
+    match *aead {}
+}
+
+#[cfg(not(feature = "encryption"))]
+fn create_mac(_: Key) -> Result<Authenticator, &'static str> {
+    Err("built without encryption support, rebuild with --features encryption")
+}
+
+#[cfg(not(feature = "encryption"))]
+fn sign(authenticator: &Authenticator, _: &[u8], _: &mut Vec<u8>) {
+    match *authenticator {}
+}
+
+#[cfg(not(feature = "encryption"))]
+fn verify(authenticator: &Authenticator, _: &[u8]) -> Option<Range<usize>> {
+    match *authenticator {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn keys_are_hex_and_datagrams_pass_through_without_one() {
+        let key: Key = HEX.parse().unwrap();
+        assert_eq!(key.0[31], 0x1f);
+        assert_eq!(HEX.to_uppercase().parse::<Key>().unwrap().0, key.0);
+        assert!(HEX[1..].parse::<Key>().is_err());
+        assert!(HEX.replace('a', "g").parse::<Key>().is_err());
+        assert!(Key::read("/no/such/key").is_err());
+
+        let mut cipher = Cipher::default();
+        assert_eq!(cipher.seal(&[1, 2, 3]), [1, 2, 3]);
+        assert_eq!(cipher.open(&mut [1, 2, 3]), Some(&[1, 2, 3][..]));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn sealed_datagrams_open_only_untouched_and_with_the_key() {
+        let key = Some(HEX.parse().unwrap());
+        let mut sender = Cipher::new(key, None).unwrap();
+        let receiver = Cipher::new(key, None).unwrap();
+        let first = sender.seal(&[1, 2, 3]).to_vec();
+        let mut second = sender.seal(&[1, 2, 3]).to_vec();
+        assert_eq!(first.len(), 3 + OVERHEAD);
+        // Every datagram gets a nonce of its own
+        assert_ne!(first, second);
+        assert_eq!(receiver.open(&mut second.clone()), Some(&[1, 2, 3][..]));
+
+        second[NONCE_SIZE] ^= 1;
+        assert_eq!(receiver.open(&mut second), None);
+        assert_eq!(receiver.open(&mut [0; OVERHEAD - 1]), None);
+        let other = Cipher::new(Some(Key([7; KEY_SIZE])), None).unwrap();
+        assert_eq!(other.open(&mut first.clone()), None);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn authenticated_datagrams_stay_readable_and_open_only_untouched() {
+        let key = Some(HEX.parse().unwrap());
+        let mut sender = Cipher::new(None, key).unwrap();
+        let receiver = Cipher::new(None, key).unwrap();
+        assert_eq!(sender.overhead(), MAC_SIZE);
+        let mut signed = sender.seal(&[1, 2, 3]).to_vec();
+        assert_eq!(signed[..3], [1, 2, 3]);
+        assert_eq!(signed.len(), 3 + MAC_SIZE);
+        assert_eq!(receiver.open(&mut signed.clone()), Some(&[1, 2, 3][..]));
+
+        let other = Cipher::new(None, Some(Key([7; KEY_SIZE]))).unwrap();
+        assert_eq!(other.open(&mut signed.clone()), None);
+        signed[0] ^= 1;
+        assert_eq!(receiver.open(&mut signed), None);
+        assert_eq!(receiver.open(&mut [0; MAC_SIZE - 1]), None);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn key_exchange_agrees_on_keys_with_known_senders_only() {
+        let (sender_private, sender_public) = generate_identity().unwrap();
+        let (receiver_private, receiver_public) = generate_identity().unwrap();
+        let source = SocketAddr::from(([192, 168, 1, 10], 9000));
+        let mut sessions =
+            Sessions::new(None, None, Some(receiver_private), vec![sender_public]).unwrap();
+
+        let (initiator, mut message) = Initiator::new(sender_private, receiver_public, 0).unwrap();
+        assert!(matches!(
+            sessions.open(source, &mut message.clone()),
+            Opened::Handshake
+        ));
+        let reply = sessions.reply().to_vec();
+        // A repeated message is answered alike
+        assert!(matches!(
+            sessions.open(source, &mut message),
+            Opened::Handshake
+        ));
+        assert_eq!(sessions.reply(), reply);
+        let mut cipher = initiator.finish(&reply).unwrap();
+
+        let mut sealed = cipher.seal(&[1, 2, 3]).to_vec();
+        assert!(matches!(
+            sessions.open(source, &mut sealed),
+            Opened::Packet([1, 2, 3])
+        ));
+        let mut answer = sessions.seal(source, &[4, 5]).to_vec();
+        assert_eq!(cipher.open(&mut answer), Some(&[4, 5][..]));
+        // Other sources have no keys, and go unanswered until they exchange their own
+        let other = SocketAddr::from(([192, 168, 1, 11], 9000));
+        assert!(matches!(
+            sessions.open(other, &mut cipher.seal(&[1]).to_vec()),
+            Opened::Rejected
+        ));
+
+        // Senders holding an unknown key are turned away
+        let (stranger, _) = generate_identity().unwrap();
+        let (_, mut message) = Initiator::new(stranger, receiver_public, 0).unwrap();
+        assert!(matches!(
+            sessions.open(other, &mut message),
+            Opened::Rejected
+        ));
+    }
+}
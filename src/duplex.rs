@@ -0,0 +1,30 @@
+use std::thread::{self, JoinHandle};
+
+use jack::Client;
+
+use crate::{group::Group, receiver::Receiver, sender::Sender};
+
+// Sends and receives on one JACK client, for two-way links. The sender's input ports and the
+// receiver's output ports sit side by side, while each direction keeps its own socket and
+// network loop.
+pub struct Duplex {
+    sender: Sender,
+    receiver: Receiver,
+}
+
+impl Duplex {
+    pub fn new(sender: Sender, receiver: Receiver) -> Self {
+        Self { sender, receiver }
+    }
+
+    // Streams both ways on the calling thread until an error or a shutdown stops it. Either
+    // direction stopping stops the other, by requesting a shutdown.
+    pub fn run(self, client: Client) -> Result<(), &'static str> {
+        Group::new(vec![self.sender], vec![self.receiver]).run(client)
+    }
+
+    // Streams on a thread of its own, whose handle yields how it stopped
+    pub fn start(self, client: Client) -> JoinHandle<Result<(), &'static str>> {
+        thread::spawn(move || self.run(client))
+    }
+}
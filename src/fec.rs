@@ -0,0 +1,362 @@
+use std::{collections::VecDeque, time::Instant};
+
+use crate::packet::{FLAG_VERIFY, HEADER_SIZE, Header, sample_count_size};
+
+// Bytes at the start of a parity payload: data packets per group, parity packets per group,
+// and which parity packet of the group this is
+pub const PARITY_HEADER_SIZE: usize = 3;
+
+// Recent data packets a receiver keeps to recover lost ones from parity
+const HISTORY: usize = 128;
+
+// Recent parity packets a receiver keeps, a loss may only show once the next packet arrives
+const PARITIES: usize = 16;
+
+// Data packets per group and parity packets per group. Parity packet j of a group covers the
+// data packets whose index in the group leaves j when divided by the parity count, so a burst
+// of up to that many losses falls on different parity packets.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Layout {
+    pub data: u8,
+    pub parity: u8,
+}
+
+impl Layout {
+    // Packets after a gap within which the parity filling it should have arrived
+    fn window(self) -> u32 {
+        self.data as u32 + self.parity as u32
+    }
+
+    // Sequence numbers of the data packets covered by parity packet `index` of the group
+    // starting at `base`
+    fn members(self, base: u32, index: u8) -> impl Iterator<Item = u32> {
+        (0..self.data)
+            .filter(move |i| i % self.parity == index)
+            .map(move |i| base.wrapping_add(i as u32))
+    }
+}
+
+// XORs `data` into `parity`, which grows to fit and is zero padded
+fn xor_into(parity: &mut Vec<u8>, data: &[u8]) {
+    if parity.len() < data.len() {
+        parity.resize(data.len(), 0);
+    }
+    parity
+        .iter_mut()
+        .zip(data)
+        .for_each(|(parity, data)| *parity ^= data);
+}
+
+// Builds the parity packets of a sender, over whole datagrams so headers and sample counts
+// are recovered along with the audio
+pub struct Encoder {
+    layout: Layout,
+    base: u32,            // Sequence number of the first data packet in the group
+    added: u8,            // Data packets in the group so far
+    parity: Vec<Vec<u8>>, // XOR of the datagrams each parity packet covers
+}
+
+impl Encoder {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            base: 0,
+            added: 0,
+            parity: vec![Vec::new(); layout.parity as usize],
+        }
+    }
+
+    // Adds a data packet, returns whether the group is complete and its parity packets ready
+    pub fn add(&mut self, sequence: u32, datagram: &[u8]) -> bool {
+        if self.added == 0 {
+            self.base = sequence;
+            self.parity.iter_mut().for_each(Vec::clear);
+        }
+        let index = self.added % self.layout.parity;
+        xor_into(&mut self.parity[index as usize], datagram);
+        self.added += 1;
+        if self.added < self.layout.data {
+            return false;
+        }
+        self.added = 0;
+        true
+    }
+
+    // Writes parity packet `index` of the completed group into `buffer`
+    pub fn write_parity(&self, index: u8, mut header: Header, buffer: &mut Vec<u8>) {
+        let parity = &self.parity[index as usize];
+        header.sequence = self.base;
+        header.length = (PARITY_HEADER_SIZE + parity.len()) as u16;
+        buffer.clear();
+        buffer.resize(HEADER_SIZE, 0);
+        header.write(buffer);
+        buffer.extend_from_slice(&[self.layout.data, self.layout.parity, index]);
+        buffer.extend_from_slice(parity);
+    }
+}
+
+// A data packet waiting behind a gap
+struct Held {
+    sequence: u32,
+    datagram: Box<[u8]>,
+    recovered: bool,
+}
+
+// Recovers a receiver's lost packets from parity. Packets arriving after a gap are held back
+// until parity fills the gap or the group has passed, so a recovered packet is played in
+// its place rather than dropped as late.
+#[derive(Default)]
+pub struct Decoder {
+    layout: Option<Layout>, // Learned from the first parity packet, until then nothing is held
+    history: VecDeque<(u32, Box<[u8]>)>,
+    parities: VecDeque<(u32, Box<[u8]>)>, // Base sequence number and payload
+    held: Vec<Held>,                      // In sequence order
+    held_since: Option<Instant>,
+}
+
+impl Decoder {
+    // Keeps a parity packet covering the group starting at `base`, ignoring malformed ones
+    pub fn add_parity(&mut self, base: u32, payload: &[u8]) {
+        let [data, parity, index, ..] = *payload else {
+            return;
+        };
+        if parity == 0 || data < parity || index >= parity {
+            return;
+        }
+        self.layout = Some(Layout { data, parity });
+        if self.parities.len() == PARITIES {
+            self.parities.pop_front();
+        }
+        self.parities.push_back((base, payload.into()));
+    }
+
+    // Holds a data packet if it arrives after a gap that parity may still fill, or while
+    // earlier packets are held. `next` is the sequence number expected next.
+    pub fn hold(&mut self, next: Option<u32>, sequence: u32, datagram: &[u8]) -> bool {
+        let (Some(layout), Some(next)) = (self.layout, next) else {
+            return false;
+        };
+        let gap = sequence.wrapping_sub(next);
+        // Late packets and the one expected go straight through, and so does a jump too far
+        // for a group to cover
+        if gap == 0 || gap >= u32::MAX / 2 || (self.held.is_empty() && gap > layout.window()) {
+            return false;
+        }
+        let index = self.held.partition_point(|held| held.sequence < sequence);
+        if self
+            .held
+            .get(index)
+            .is_none_or(|held| held.sequence != sequence)
+        {
+            self.held.insert(
+                index,
+                Held {
+                    sequence,
+                    datagram: datagram.into(),
+                    recovered: false,
+                },
+            );
+        }
+        self.held_since.get_or_insert_with(Instant::now);
+        true
+    }
+
+    // Remembers a data packet that was played, for recovering others in its group
+    pub fn played(&mut self, sequence: u32, datagram: &[u8]) {
+        if self.layout.is_none() {
+            return;
+        }
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((sequence, datagram.into()));
+    }
+
+    // Takes the next held packet to play, along with whether it was recovered from parity.
+    // It is the one expected next, or the oldest once waiting any longer is pointless or
+    // `give_up` is set.
+    pub fn release(&mut self, next: Option<u32>, give_up: bool) -> Option<(Box<[u8]>, bool)> {
+        if let Some(next) = next {
+            self.recover(next);
+        }
+        let first = self.held.first()?;
+        let newest = self.held.last()?.sequence;
+        let layout = self.layout?;
+        let due = match next {
+            Some(next) => first.sequence == next || newest.wrapping_sub(next) > layout.window(),
+            None => true,
+        };
+        if !due && !give_up {
+            return None;
+        }
+        let held = self.held.remove(0);
+        if self.held.is_empty() {
+            self.held_since = None;
+        }
+        Some((held.datagram, held.recovered))
+    }
+
+    // When the oldest packet still held was held back
+    pub fn held_since(&self) -> Option<Instant> {
+        self.held_since
+    }
+
+    // Rebuilds a missing packet that has not been played yet wherever parity allows, keeping
+    // it with the held packets
+    fn recover(&mut self, next: u32) {
+        let Some(layout) = self.layout else {
+            return;
+        };
+        let mut index = 0;
+        while index < self.parities.len() {
+            let (base, payload) = &self.parities[index];
+            let members = || layout.members(*base, payload[2]);
+            let mut missing = members().filter(|&sequence| self.find(sequence).is_none());
+            let (Some(lost), None) = (missing.next(), missing.next()) else {
+                // Nothing to recover or too much, older parity packets drop out eventually
+                index += 1;
+                continue;
+            };
+            let mut datagram = payload[PARITY_HEADER_SIZE..].to_vec();
+            for other in members().filter_map(|sequence| self.find(sequence)) {
+                xor_into(&mut datagram, other);
+            }
+            self.parities.remove(index);
+            // Only worth it while its place has not been taken by silence
+            if lost.wrapping_sub(next) >= u32::MAX / 2 {
+                continue;
+            }
+            let Some(datagram) = trim(datagram) else {
+                continue;
+            };
+            if Header::read(&datagram).is_some_and(|header| header.sequence == lost) {
+                let position = self.held.partition_point(|held| held.sequence < lost);
+                self.held.insert(
+                    position,
+                    Held {
+                        sequence: lost,
+                        datagram: datagram.into(),
+                        recovered: true,
+                    },
+                );
+                self.held_since.get_or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    // A data packet that was played or is held
+    fn find(&self, sequence: u32) -> Option<&[u8]> {
+        let played = self
+            .history
+            .iter()
+            .rev()
+            .find(|(played, _)| *played == sequence);
+        match played {
+            Some((_, datagram)) => Some(datagram),
+            None => self
+                .held
+                .iter()
+                .find(|held| held.sequence == sequence)
+                .map(|held| &held.datagram[..]),
+        }
+    }
+}
+
+// Cuts the zero padding off a recovered datagram, going by its header
+fn trim(mut datagram: Vec<u8>) -> Option<Vec<u8>> {
+    let header = Header::read(&datagram)?;
+    let payload = HEADER_SIZE + header.length as usize;
+    // Sample counts start with their number of channels
+    let trailer = if header.flags & FLAG_VERIFY != 0 {
+        sample_count_size(*datagram.get(payload)? as usize)
+    } else {
+        0
+    };
+    let length = payload + trailer;
+    (length <= datagram.len()).then(|| {
+        datagram.truncate(length);
+        datagram
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(sequence: u32, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0; HEADER_SIZE];
+        Header {
+            sequence,
+            timestamp: 1000 + sequence as u64,
+            flags: 0,
+            stream: 0,
+            length: payload.len() as u16,
+        }
+        .write(&mut datagram);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    #[test]
+    fn parity_recovers_a_burst_across_subsets() {
+        let layout = Layout { data: 4, parity: 2 };
+        let sent: Vec<_> = (10..14)
+            .map(|sequence| datagram(sequence, &vec![sequence as u8; sequence as usize]))
+            .collect();
+        let mut encoder = Encoder::new(layout);
+        let mut parity = Vec::new();
+        let mut decoder = Decoder::default();
+        let header = Header::read(&sent[0]).unwrap();
+        for (sequence, datagram) in (10..).zip(&sent) {
+            if encoder.add(sequence, datagram) {
+                for index in 0..layout.parity {
+                    encoder.write_parity(index, header, &mut parity);
+                    decoder.add_parity(10, &parity[HEADER_SIZE..]);
+                }
+            }
+        }
+
+        // 11 and 12 are lost, 13 is held until parity fills the gap
+        decoder.played(10, &sent[0]);
+        assert!(decoder.hold(Some(11), 13, &sent[3]));
+        let (first, recovered) = decoder.release(Some(11), false).unwrap();
+        assert!(recovered);
+        assert_eq!(first[..], sent[1][..]);
+        decoder.played(11, &first);
+        assert_eq!(decoder.release(Some(12), false).unwrap().0[..], sent[2][..]);
+        let (last, recovered) = decoder.release(Some(13), false).unwrap();
+        assert!(!recovered);
+        assert_eq!(last[..], sent[3][..]);
+        assert!(decoder.release(Some(14), false).is_none());
+        assert!(decoder.held_since().is_none());
+    }
+
+    #[test]
+    fn packets_are_held_only_while_parity_can_help() {
+        let mut decoder = Decoder::default();
+        // Nothing is held before any parity arrived
+        assert!(!decoder.hold(Some(0), 2, &datagram(2, &[])));
+
+        decoder.add_parity(0, &[2, 1, 0, 0]);
+        assert!(!decoder.hold(None, 2, &datagram(2, &[])));
+        assert!(!decoder.hold(Some(2), 2, &datagram(2, &[])));
+        assert!(!decoder.hold(Some(2), 1, &datagram(1, &[])));
+        assert!(!decoder.hold(Some(2), 100, &datagram(100, &[])));
+
+        // Two losses in one subset cannot be recovered, the gap is played once the group passed
+        assert!(decoder.hold(Some(2), 4, &datagram(4, &[])));
+        assert!(decoder.release(Some(2), false).is_none());
+        assert!(decoder.hold(Some(2), 6, &datagram(6, &[])));
+        assert_eq!(
+            decoder.release(Some(2), false).unwrap().0[..4],
+            [0, 0, 0, 4]
+        );
+        assert_eq!(decoder.release(Some(5), true).unwrap().0[..4], [0, 0, 0, 6]);
+
+        // Malformed parity is ignored
+        let mut decoder = Decoder::default();
+        decoder.add_parity(0, &[1, 2, 0]);
+        decoder.add_parity(0, &[2, 1, 1]);
+        assert!(!decoder.hold(Some(0), 1, &datagram(1, &[])));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt, str::FromStr};
+
+// Sample encoding used on the wire and in the ring buffers. Samples are little-endian
+// whatever the host, so peers of either byte order understand each other, while the
+// common little-endian hosts need no swapping.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SampleFormat {
+    F32,
+    S16,
+    S24, // Packed in three bytes
+}
+
+// Full scale of 24-bit samples
+const S24_MAX: i32 = (1 << 23) - 1;
+
+impl SampleFormat {
+    // Size of a single encoded sample in bytes
+    pub fn sample_size(self) -> usize {
+        match self {
+            SampleFormat::F32 => size_of::<f32>(),
+            SampleFormat::S16 => size_of::<i16>(),
+            SampleFormat::S24 => 3,
+        }
+    }
+
+    // Identifier used when exchanging stream settings
+    pub fn id(self) -> u8 {
+        match self {
+            SampleFormat::F32 => 0,
+            SampleFormat::S16 => 1,
+            SampleFormat::S24 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(SampleFormat::F32),
+            1 => Some(SampleFormat::S16),
+            2 => Some(SampleFormat::S24),
+            _ => None,
+        }
+    }
+
+    // Encodes samples into a byte buffer, returns the number of bytes written
+    pub fn encode(
+        self,
+        samples: impl Iterator<Item = f32>,
+        mut dither: Option<&mut Dither>,
+        out: &mut [u8],
+    ) -> usize {
+        let mut written = 0;
+        for (chunk, sample) in out.chunks_exact_mut(self.sample_size()).zip(samples) {
+            match self {
+                SampleFormat::F32 => chunk.copy_from_slice(&sample.to_le_bytes()),
+                SampleFormat::S16 => {
+                    let noise = dither.as_mut().map_or(0.0, |dither| dither.next_tpdf());
+                    let quantized = (sample * i16::MAX as f32 + noise)
+                        .round()
+                        .clamp(i16::MIN as f32, i16::MAX as f32);
+                    chunk.copy_from_slice(&(quantized as i16).to_le_bytes());
+                }
+                SampleFormat::S24 => {
+                    let noise = dither.as_mut().map_or(0.0, |dither| dither.next_tpdf());
+                    let quantized = (sample * S24_MAX as f32 + noise)
+                        .round()
+                        .clamp(-S24_MAX as f32 - 1.0, S24_MAX as f32);
+                    chunk.copy_from_slice(&(quantized as i32).to_le_bytes()[..3]);
+                }
+            }
+            written += chunk.len();
+        }
+        written
+    }
+
+    // Decodes a byte buffer into samples, returns the number of samples written
+    pub fn decode(self, bytes: &[u8], out: &mut [f32]) -> usize {
+        let mut written = 0;
+        for (sample, chunk) in out.iter_mut().zip(bytes.chunks_exact(self.sample_size())) {
+            *sample = self.decode_sample(chunk);
+            written += 1;
+        }
+        written
+    }
+
+    // Decodes a single sample of exactly the sample size
+    fn decode_sample(self, chunk: &[u8]) -> f32 {
+        match self {
+            // Chunk sizes match the sample size, so unwrapping is safe
+            SampleFormat::F32 => f32::from_le_bytes(chunk.try_into().unwrap()),
+            SampleFormat::S16 => {
+                i16::from_le_bytes(chunk.try_into().unwrap()) as f32 / i16::MAX as f32
+            }
+            SampleFormat::S24 => {
+                // Shifting the top byte into place extends the sign
+                let value = i32::from_le_bytes([0, chunk[0], chunk[1], chunk[2]]) >> 8;
+                value as f32 / S24_MAX as f32
+            }
+        }
+    }
+
+    // Re-encodes samples of this format as `to` into `out`, which is resized to fit
+    pub fn convert(self, to: SampleFormat, bytes: &[u8], out: &mut Vec<u8>) {
+        let samples = bytes.chunks_exact(self.sample_size());
+        out.resize(samples.len() * to.sample_size(), 0);
+        to.encode(samples.map(|chunk| self.decode_sample(chunk)), None, out);
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(SampleFormat::F32),
+            "s16" => Ok(SampleFormat::S16),
+            "s24" => Ok(SampleFormat::S24),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SampleFormat::F32 => "f32",
+            SampleFormat::S16 => "s16",
+            SampleFormat::S24 => "s24",
+        })
+    }
+}
+
+// Triangular probability density dither, one LSB wide on each side
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift state must never be zero
+        Self { state: seed.max(1) }
+    }
+
+    // Next uniformly distributed value in [0, 1)
+    fn next_uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    // Next dither value in LSBs, in the range (-1, 1)
+    pub fn next_tpdf(&mut self) -> f32 {
+        self.next_uniform() - self.next_uniform()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_little_endian_and_convert_between_formats() {
+        let samples = [
+            0.0,
+            4096.0 / S24_MAX as f32,
+            -1.0,
+            1.0,
+            -1.0 / S24_MAX as f32,
+        ];
+        let mut s24 = [0; 15];
+        assert_eq!(
+            SampleFormat::S24.encode(samples.into_iter(), None, &mut s24),
+            15
+        );
+        assert_eq!(s24[6..9], [0x01, 0x00, 0x80]);
+        assert_eq!(s24[12..15], [0xff, 0xff, 0xff]);
+
+        // The other formats are little-endian on every host too
+        let mut s16 = [0; 2];
+        SampleFormat::S16.encode([1.0].into_iter(), None, &mut s16);
+        assert_eq!(s16, [0xff, 0x7f]);
+        let mut f32 = [0; 4];
+        SampleFormat::F32.encode([1.0].into_iter(), None, &mut f32);
+        assert_eq!(f32, [0x00, 0x00, 0x80, 0x3f]);
+        let mut decoded = [0.0; 5];
+        SampleFormat::S24.decode(&s24, &mut decoded);
+        assert_eq!(decoded, samples);
+
+        let mut s16 = Vec::new();
+        SampleFormat::S24.convert(SampleFormat::S16, &s24, &mut s16);
+        assert_eq!(s16.len(), 10);
+        SampleFormat::S16.decode(&s16, &mut decoded);
+        // Full scale stays full scale, the quietest steps are lost
+        assert_eq!(decoded, [0.0, 16.0 / i16::MAX as f32, -1.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::Ordering,
+        mpsc::{self, Receiver as Handoff},
+    },
+    thread::{self, JoinHandle},
+};
+
+use jack::{
+    Client, Control, Frames, LatencyType, NotificationHandler, PortId, ProcessHandler, ProcessScope,
+};
+
+use crate::{
+    connect,
+    latency::{self, Latency},
+    metrics::Metrics,
+    receiver::{self, Receiver},
+    sender::{self, Sender},
+    shutdown,
+};
+
+// Senders and receivers sharing one JACK client, such as the two directions of a duplex link
+// or the named streams of a config file. Each keeps its own ports, socket, buffers and
+// network loop, on a thread of its own.
+pub struct Group {
+    senders: Vec<Sender>,
+    receivers: Vec<Receiver>,
+}
+
+// Calls the process handlers of the streams, each from the period after it is set up
+struct Process<R, S> {
+    receivers: Vec<R>,
+    senders: Vec<S>,
+    pending_receivers: Handoff<R>,
+    pending_senders: Handoff<S>,
+}
+
+// Quits once any of the handlers does
+fn all(controls: impl IntoIterator<Item = Control>) -> Control {
+    controls
+        .into_iter()
+        .fold(Control::Continue, |all, control| match control {
+            Control::Quit => Control::Quit,
+            Control::Continue => all,
+        })
+}
+
+impl<R: ProcessHandler, S: ProcessHandler> ProcessHandler for Process<R, S> {
+    fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
+        // The vectors were given room for every stream, so joining does not allocate
+        while let Ok(mut receiver) = self.pending_receivers.try_recv() {
+            receiver.buffer_size(client, ps.n_frames());
+            self.receivers.push(receiver);
+        }
+        while let Ok(mut sender) = self.pending_senders.try_recv() {
+            sender.buffer_size(client, ps.n_frames());
+            self.senders.push(sender);
+        }
+        let received = all(self
+            .receivers
+            .iter_mut()
+            .map(|receiver| receiver.process(client, ps)));
+        let sent = all(self
+            .senders
+            .iter_mut()
+            .map(|sender| sender.process(client, ps)));
+        all([received, sent])
+    }
+
+    fn buffer_size(&mut self, client: &Client, frames: Frames) -> Control {
+        let received = all(self
+            .receivers
+            .iter_mut()
+            .map(|receiver| receiver.buffer_size(client, frames)));
+        let sent = all(self
+            .senders
+            .iter_mut()
+            .map(|sender| sender.buffer_size(client, frames)));
+        all([received, sent])
+    }
+}
+
+// Counts xruns for every stream, each one's metrics arriving once it is set up, and passes on
+// ports appearing for --connect
+struct Notifications(Arc<Mutex<Vec<Arc<Metrics>>>>);
+
+impl NotificationHandler for Notifications {
+    fn xrun(&mut self, _: &Client) -> Control {
+        let metrics = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        for metrics in metrics.iter() {
+            metrics.xruns.fetch_add(1, Ordering::Relaxed);
+        }
+        Control::Continue
+    }
+
+    fn port_registration(&mut self, _: &Client, _: PortId, registered: bool) {
+        if registered {
+            connect::port_appeared();
+        }
+    }
+}
+
+impl Group {
+    pub fn new(senders: Vec<Sender>, receivers: Vec<Receiver>) -> Self {
+        Self { senders, receivers }
+    }
+
+    // Streams on the calling thread until an error or a shutdown stops them. Any stream
+    // stopping stops the others, by requesting a shutdown, and the first error is returned.
+    pub fn run(self, client: Client) -> Result<(), &'static str> {
+        // A client has a single latency callback, reporting every stream
+        let captures: Vec<_> = self
+            .receivers
+            .iter()
+            .map(|_| Latency::new(LatencyType::Capture))
+            .collect();
+        let playbacks: Vec<_> = self
+            .senders
+            .iter()
+            .map(|_| Latency::new(LatencyType::Playback))
+            .collect();
+        latency::register(&client, &[captures.clone(), playbacks.clone()].concat())?;
+
+        // Streams are set up on the active client, so a sender waiting for its receiver's
+        // handshake holds up no other stream
+        let metrics = Arc::new(Mutex::new(Vec::new()));
+        let (receiver_handoff, pending_receivers) = mpsc::channel();
+        let (sender_handoff, pending_senders) = mpsc::channel();
+        let async_client = client
+            .activate_async(
+                Notifications(metrics.clone()),
+                Process {
+                    receivers: Vec::with_capacity(self.receivers.len()),
+                    senders: Vec::with_capacity(self.senders.len()),
+                    pending_receivers,
+                    pending_senders,
+                },
+            )
+            .map_err(|_| "unable to activate client")?;
+
+        let client = async_client.as_client();
+        let joined = |stream: Arc<Metrics>| {
+            let mut all = metrics.lock().unwrap_or_else(PoisonError::into_inner);
+            all.push(stream);
+        };
+        let result = thread::scope(|scope| {
+            let mut streams = Vec::new();
+            for (receiver, capture) in self.receivers.into_iter().zip(captures) {
+                let handoff = receiver_handoff.clone();
+                streams.push(scope.spawn(move || {
+                    let result: Result<(), &'static str> = try {
+                        let (metrics, process, receive) =
+                            receiver::prepare(client, receiver, capture)?;
+                        latency::recompute(client);
+                        joined(metrics);
+                        handoff
+                            .send(process)
+                            .map_err(|_| "JACK processing stopped")?;
+                        receive(client)?
+                    };
+                    shutdown::request();
+                    result
+                }));
+            }
+            for (sender, playback) in self.senders.into_iter().zip(playbacks) {
+                let handoff = sender_handoff.clone();
+                streams.push(scope.spawn(move || {
+                    let result: Result<(), &'static str> = try {
+                        let (metrics, process, send) = sender::prepare(client, sender, playback)?;
+                        latency::recompute(client);
+                        joined(metrics);
+                        handoff
+                            .send(process)
+                            .map_err(|_| "JACK processing stopped")?;
+                        send(client)?
+                    };
+                    shutdown::request();
+                    result
+                }));
+            }
+            // Every stream is joined before any error is returned
+            let stopped: Vec<_> = streams
+                .into_iter()
+                .map(|stream| stream.join().unwrap_or(Err("stream stopped unexpectedly")))
+                .collect();
+            stopped.into_iter().collect::<Result<(), _>>()
+        });
+        // Stop processing before the ports go away with the client
+        let _ = async_client.deactivate();
+        result
+    }
+
+    // Streams on a thread of its own, whose handle yields how it stopped
+    pub fn start(self, client: Client) -> JoinHandle<Result<(), &'static str>> {
+        thread::spawn(move || self.run(client))
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    io::{self, BufRead},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    process, ptr,
+    time::{Duration, Instant},
+};
+
+use crate::packet::{self, MAX_DATAGRAM_SIZE};
+
+// STUN message layout, RFC 5389
+const STUN_HEADER_SIZE: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 1;
+const FAMILY_IPV6: u8 = 2;
+
+// Attempts at asking the STUN server, and how long each waits for the answer
+const STUN_ATTEMPTS: u32 = 4;
+const STUN_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Connectivity checks go out to every remote candidate this often until one is answered or
+// the other end gives up
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How this end learns its public address and the other end's candidates
+#[derive(Clone)]
+pub struct Exchange {
+    // Server reporting the address NAT maps this end to
+    pub stun: Option<SocketAddr>,
+    // Candidates of the other end, read from stdin if not given
+    pub remote: Option<Vec<SocketAddr>>,
+}
+
+// Which end decides on the path. As in ICE, the controlling end picks the first pair whose
+// check is answered and starts sending, the controlled end keeps checking until it does.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Controlling, // The sender
+    Controlled,  // The receiver
+}
+
+// A STUN message, as far as a binding exchange needs it
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    Request([u8; 12]),                     // Binding request with its transaction id
+    Success([u8; 12], Option<SocketAddr>), // Binding response with the address seen
+}
+
+// Finds a path to the other end through the NATs in between, returning the address it is
+// reached at. The candidates of this end are printed for the other end, which is given them
+// by --remote-candidates or pastes them on stdin. Both ends then send checks to every
+// candidate, which opens the NAT mappings along the way, until a path works.
+pub fn establish(
+    socket: &UdpSocket,
+    exchange: &Exchange,
+    role: Role,
+) -> Result<SocketAddr, &'static str> {
+    let local = gather(socket, exchange.stun)?;
+    info!("pass the candidates below to the other end");
+    println!("candidates {}", join(&local));
+
+    let remote = match &exchange.remote {
+        Some(remote) => remote.clone(),
+        None => {
+            info!("paste the candidates of the other end");
+            let mut line = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|_| "unable to read the candidates")?;
+            parse_candidates(&line).ok_or("invalid candidates, expected addresses")?
+        }
+    };
+
+    let peer = check(socket, &remote, role)?;
+    info!("reached the other end at {}", peer);
+    Ok(peer)
+}
+
+// Addresses of this end: the bound one on each interface and, with a STUN server, the one
+// NAT maps it to
+fn gather(socket: &UdpSocket, stun: Option<SocketAddr>) -> Result<Vec<SocketAddr>, &'static str> {
+    let bound = socket
+        .local_addr()
+        .map_err(|_| "unable to bind to address")?;
+    let mut candidates: Vec<_> = if bound.ip().is_unspecified() {
+        interface_addresses(bound.is_ipv6())
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, bound.port()))
+            .collect()
+    } else {
+        vec![bound]
+    };
+
+    if let Some(stun) = stun {
+        let mapped = query(socket, stun)?;
+        info!("STUN server {} sees this end as {}", stun, mapped);
+        if !candidates.contains(&mapped) {
+            candidates.push(mapped);
+        }
+    }
+    if candidates.is_empty() {
+        return Err("no address to offer the other end, check --bind");
+    }
+    Ok(candidates)
+}
+
+// Asks a STUN server which address the socket's packets arrive from
+fn query(socket: &UdpSocket, stun: SocketAddr) -> Result<SocketAddr, &'static str> {
+    let transaction = transaction_id();
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    for _ in 0..STUN_ATTEMPTS {
+        socket
+            .send_to(&request(transaction), stun)
+            .map_err(|_| "unable to reach the STUN server")?;
+        let deadline = Instant::now() + STUN_TIMEOUT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket
+                .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+                .map_err(|_| "unable to configure socket")?;
+            let Ok((received, source)) = socket.recv_from(&mut buffer) else {
+                break;
+            };
+            if source == stun
+                && let Some(Message::Success(id, Some(mapped))) = parse(&buffer[..received])
+                && id == transaction
+            {
+                socket
+                    .set_read_timeout(None)
+                    .map_err(|_| "unable to configure socket")?;
+                return Ok(mapped);
+            }
+        }
+    }
+    Err("no answer from the STUN server")
+}
+
+// Sends binding requests to every remote candidate and answers those of the other end. The
+// controlling end is done once one of its requests is answered, the controlled end once
+// anything but a check arrives, the other end having picked its path. Only addresses among
+// the candidates, or that checked or answered this end, count, not any host that sends a
+// packet meanwhile.
+fn check(
+    socket: &UdpSocket,
+    remote: &[SocketAddr],
+    role: Role,
+) -> Result<SocketAddr, &'static str> {
+    let transaction = transaction_id();
+    let request = request(transaction);
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    let deadline = Instant::now() + CHECK_TIMEOUT;
+    let mut answered = false;
+    let mut known = remote.to_vec();
+    while Instant::now() < deadline {
+        for candidate in remote {
+            // Candidates of a family this socket cannot reach simply fail
+            let _ = socket.send_to(&request, candidate);
+        }
+
+        let round = Instant::now() + CHECK_INTERVAL;
+        while let Some(left) = round.checked_duration_since(Instant::now()) {
+            socket
+                .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+                .map_err(|_| "unable to configure socket")?;
+            let Ok((received, source)) = socket.recv_from(&mut buffer) else {
+                break;
+            };
+            let found = match parse(&buffer[..received]) {
+                Some(Message::Request(id)) => {
+                    let _ = socket.send_to(&success(id, source), source);
+                    if !known.contains(&source) {
+                        known.push(source);
+                    }
+                    None
+                }
+                Some(Message::Success(id, _)) if id == transaction => {
+                    if !answered {
+                        info!("path to {} works", source);
+                    }
+                    answered = true;
+                    if !known.contains(&source) {
+                        known.push(source);
+                    }
+                    (role == Role::Controlling).then_some(source)
+                }
+                Some(Message::Success(..)) => None,
+                None => (role == Role::Controlled && known.contains(&source)).then_some(source),
+            };
+            if let Some(peer) = found {
+                socket
+                    .set_read_timeout(None)
+                    .map_err(|_| "unable to configure socket")?;
+                return Ok(peer);
+            }
+        }
+    }
+    Err(if answered {
+        "the other end never started sending, check its candidates"
+    } else {
+        "no candidate of the other end answered, check --stun and the candidates"
+    })
+}
+
+// Candidates as passed around, addresses separated by spaces or commas after an optional
+// leading "candidates"
+fn parse_candidates(line: &str) -> Option<Vec<SocketAddr>> {
+    let line = line.trim();
+    let line = line.strip_prefix("candidates").unwrap_or(line);
+    let candidates: Option<Vec<_>> = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|candidate| !candidate.is_empty())
+        .map(|candidate| candidate.parse().ok())
+        .collect();
+    candidates.filter(|candidates| !candidates.is_empty())
+}
+
+fn join(candidates: &[SocketAddr]) -> String {
+    let candidates: Vec<_> = candidates.iter().map(SocketAddr::to_string).collect();
+    candidates.join(" ")
+}
+
+// Transaction id of this process's requests, unique enough to tell them from stray answers
+fn transaction_id() -> [u8; 12] {
+    let mut id = [0; 12];
+    id[..8].copy_from_slice(&packet::now_micros().to_be_bytes());
+    id[8..].copy_from_slice(&process::id().to_be_bytes());
+    id
+}
+
+// STUN header of a message with `length` bytes of attributes
+fn header(kind: u16, length: u16, transaction: [u8; 12]) -> [u8; STUN_HEADER_SIZE] {
+    let mut header = [0; STUN_HEADER_SIZE];
+    header[..2].copy_from_slice(&kind.to_be_bytes());
+    header[2..4].copy_from_slice(&length.to_be_bytes());
+    header[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    header[8..].copy_from_slice(&transaction);
+    header
+}
+
+fn request(transaction: [u8; 12]) -> [u8; STUN_HEADER_SIZE] {
+    header(BINDING_REQUEST, 0, transaction)
+}
+
+// Binding response telling the requester which address its request came from
+fn success(transaction: [u8; 12], source: SocketAddr) -> Vec<u8> {
+    let mask = xor_mask(transaction);
+    let port = source.port().to_be_bytes();
+    let mut value = vec![0, 0, port[0] ^ mask[0], port[1] ^ mask[1]];
+    match source.ip() {
+        IpAddr::V4(ip) => {
+            value[1] = FAMILY_IPV4;
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value[1] = FAMILY_IPV6;
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    // The port is masked by the start of the cookie, the address from its start again
+    for (byte, mask) in value[4..].iter_mut().zip(mask) {
+        *byte ^= mask;
+    }
+
+    let length = 4 + value.len() as u16;
+    let mut message = header(BINDING_SUCCESS, length, transaction).to_vec();
+    message.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(&value);
+    message
+}
+
+fn parse(datagram: &[u8]) -> Option<Message> {
+    let header = datagram.get(..STUN_HEADER_SIZE)?;
+    if u32::from_be_bytes(header[4..8].try_into().unwrap()) != MAGIC_COOKIE {
+        return None;
+    }
+    let kind = u16::from_be_bytes([header[0], header[1]]);
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let transaction: [u8; 12] = header[8..].try_into().unwrap();
+    let mut attributes = datagram.get(STUN_HEADER_SIZE..STUN_HEADER_SIZE + length)?;
+    match kind {
+        BINDING_REQUEST => Some(Message::Request(transaction)),
+        BINDING_SUCCESS => {
+            // Old servers only send the plain address
+            let mut mapped = None;
+            while let Some(attribute) = attributes.get(..4) {
+                let kind = u16::from_be_bytes([attribute[0], attribute[1]]);
+                let length = u16::from_be_bytes([attribute[2], attribute[3]]) as usize;
+                let value = attributes.get(4..4 + length)?;
+                match kind {
+                    XOR_MAPPED_ADDRESS => {
+                        mapped = decode_address(value, xor_mask(transaction));
+                        break;
+                    }
+                    MAPPED_ADDRESS => mapped = decode_address(value, [0; 16]),
+                    _ => {}
+                }
+                // Attributes are padded to four bytes
+                attributes = attributes
+                    .get((4 + length).next_multiple_of(4)..)
+                    .unwrap_or_default();
+            }
+            Some(Message::Success(transaction, mapped))
+        }
+        _ => None,
+    }
+}
+
+// XOR-MAPPED-ADDRESS hides the port and address behind the magic cookie and transaction id
+fn xor_mask(transaction: [u8; 12]) -> [u8; 16] {
+    let mut mask = [0; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(&transaction);
+    mask
+}
+
+fn decode_address(value: &[u8], mask: [u8; 16]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0], value.get(3)? ^ mask[1]]);
+    let mut octets = [0; 16];
+    let address = value.get(4..)?;
+    for (octet, (byte, mask)) in octets.iter_mut().zip(address.iter().zip(mask)) {
+        *octet = byte ^ mask;
+    }
+    match (value[1], address.len()) {
+        (FAMILY_IPV4, 4) => {
+            let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        (FAMILY_IPV6, 16) => Some(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(octets),
+            port,
+            0,
+            0,
+        ))),
+        _ => None,
+    }
+}
+
+// Addresses of this host's interfaces in one family, leaving out loopback and, being tied to
+// one link, IPv6 link-local ones
+fn interface_addresses(ipv6: bool) -> Vec<IpAddr> {
+    let mut found = Vec::new();
+    let mut addrs = ptr::null_mut();
+    // SAFETY: getifaddrs hands over a list that is only read until it is freed at the end,
+    // and the addresses of AF_INET and AF_INET6 entries are sockaddr_in and sockaddr_in6
+    unsafe {
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return found;
+        }
+        let mut entry = addrs;
+        while let Some(ifaddr) = entry.as_ref() {
+            let addr = ifaddr.ifa_addr;
+            let ip = match addr.as_ref().map(|addr| addr.sa_family as i32) {
+                Some(libc::AF_INET) if !ipv6 => {
+                    let addr = &*(addr as *const libc::sockaddr_in);
+                    Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        addr.sin_addr.s_addr,
+                    ))))
+                }
+                Some(libc::AF_INET6) if ipv6 => {
+                    let addr = &*(addr as *const libc::sockaddr_in6);
+                    Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+                }
+                _ => None,
+            };
+            if let Some(ip) = ip
+                && !ip.is_loopback()
+                && !matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local())
+            {
+                found.push(ip);
+            }
+            entry = ifaddr.ifa_next;
+        }
+        libc::freeifaddrs(addrs);
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn stun_messages_carry_the_mapped_address() {
+        // Binding response of RFC 5769, section 2.2, without its integrity and fingerprint
+        let mut response = vec![
+            0x01, 0x01, 0x00, 0x1c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+            0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        ];
+        response.extend_from_slice(&[0x80, 0x22, 0x00, 0x0b]);
+        response.extend_from_slice(b"test vector ");
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47]);
+        response.extend_from_slice(&[0xe1, 0x12, 0xa6, 0x43]);
+        let transaction = response[8..20].try_into().unwrap();
+        assert_eq!(
+            parse(&response),
+            Some(Message::Success(
+                transaction,
+                Some("192.0.2.1:32853".parse().unwrap())
+            ))
+        );
+
+        // Our own responses, in both families
+        for source in ["203.0.113.7:40123", "[2001:db8::7]:9000"] {
+            let source = source.parse().unwrap();
+            assert_eq!(
+                parse(&success(transaction, source)),
+                Some(Message::Success(transaction, Some(source)))
+            );
+        }
+        assert_eq!(
+            parse(&request(transaction)),
+            Some(Message::Request(transaction))
+        );
+        // Netaudio packets are no STUN messages
+        assert_eq!(parse(&[0; 32]), None);
+
+        assert_eq!(
+            parse_candidates("candidates 10.0.0.2:9000, 203.0.113.7:40123\n"),
+            Some(vec![
+                "10.0.0.2:9000".parse().unwrap(),
+                "203.0.113.7:40123".parse().unwrap()
+            ])
+        );
+        assert_eq!(parse_candidates("candidates\n"), None);
+    }
+
+    #[test]
+    fn checks_find_a_path_both_ways() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        // Unreachable candidates are skipped over
+        let controlled = thread::spawn(move || {
+            let exchange = Exchange {
+                stun: None,
+                remote: Some(vec!["127.0.0.1:1".parse().unwrap(), sender_addr]),
+            };
+            establish(&receiver, &exchange, Role::Controlled).unwrap()
+        });
+        let exchange = Exchange {
+            stun: None,
+            remote: Some(vec![receiver_addr]),
+        };
+        assert_eq!(
+            establish(&sender, &exchange, Role::Controlling).unwrap(),
+            receiver_addr
+        );
+        // The receiver is done once the stream starts, not when a stranger sends something
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.send_to(&[0; 32], receiver_addr).unwrap();
+        sender.send_to(&[0; 32], receiver_addr).unwrap();
+        assert_eq!(controlled.join().unwrap(), sender_addr);
+    }
+}
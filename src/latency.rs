@@ -0,0 +1,93 @@
+use std::{
+    ffi::c_void,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use jack::{Client, Frames, LatencyType, Port, Unowned, jack_sys};
+
+// A port and the latency range netaudio adds on its side of the network
+struct PortLatency {
+    group: u8, // Ports updated together, one group per stream
+    port: Port<Unowned>,
+    range: (Frames, Frames),
+}
+
+// Latency between netaudio's ports and the network, reported to JACK so latency-compensating
+// clients line up with the rest of the graph. The jack crate has no latency callback, so it
+// is registered through jack-sys. A client has a single callback, which reports every
+// `Latency` registered with it.
+pub struct Latency {
+    mode: LatencyType, // Capture for ports fed from the network, playback for ports feeding it
+    ports: Mutex<Vec<PortLatency>>,
+}
+
+impl Latency {
+    pub fn new(mode: LatencyType) -> Arc<Self> {
+        Arc::new(Self {
+            mode,
+            ports: Mutex::default(),
+        })
+    }
+
+    // Reports the latency of `ports` from now on
+    pub fn add_ports(
+        &self,
+        group: u8,
+        ports: impl IntoIterator<Item = Port<Unowned>>,
+        range: (Frames, Frames),
+    ) {
+        let mut all = self.ports.lock().unwrap_or_else(PoisonError::into_inner);
+        all.extend(
+            ports
+                .into_iter()
+                .map(|port| PortLatency { group, port, range }),
+        );
+    }
+
+    // Changes the latency of a group, JACK picks it up once latencies are recomputed
+    pub fn set(&self, group: u8, range: (Frames, Frames)) {
+        let mut all = self.ports.lock().unwrap_or_else(PoisonError::into_inner);
+        for port in all.iter_mut().filter(|port| port.group == group) {
+            port.range = range;
+        }
+    }
+
+    fn report(&self, mode: jack_sys::jack_latency_callback_mode_t) {
+        if mode != self.mode.to_ffi() {
+            return;
+        }
+        let all = self.ports.lock().unwrap_or_else(PoisonError::into_inner);
+        for port in all.iter() {
+            port.port.set_latency_range(self.mode, port.range);
+        }
+    }
+}
+
+unsafe extern "C" fn latency_callback(
+    mode: jack_sys::jack_latency_callback_mode_t,
+    arg: *mut c_void,
+) {
+    // SAFETY: arg comes from Box::into_raw in register and is never released
+    let latencies = unsafe { &*arg.cast_const().cast::<Vec<Arc<Latency>>>() };
+    for latency in latencies {
+        latency.report(mode);
+    }
+}
+
+// Registers the latency callback, which has to happen before the client is activated
+pub fn register(client: &Client, latencies: &[Arc<Latency>]) -> Result<(), &'static str> {
+    // The callback may run for as long as the client exists, so the list is never released
+    let arg = Box::into_raw(Box::new(latencies.to_vec())).cast();
+    // SAFETY: the client pointer is valid while client is borrowed, and arg outlives it
+    let result =
+        unsafe { jack_sys::jack_set_latency_callback(client.raw(), Some(latency_callback), arg) };
+    (result == 0)
+        .then_some(())
+        .ok_or("unable to register latency callback")
+}
+
+// Asks JACK to query the latency again after it changed
+pub fn recompute(client: &Client) {
+    // SAFETY: the client pointer is valid while client is borrowed
+    unsafe { jack_sys::jack_recompute_total_latencies(client.raw()) };
+}
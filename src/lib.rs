@@ -0,0 +1,39 @@
+#![feature(array_chunks, never_type, try_blocks)]
+
+// Streams audio between JACK clients over the network. A Sender reads JACK input ports and
+// sends them to a Receiver, which plays them on JACK output ports. A Duplex runs one of each
+// on a single client, a Group any number. The netaudio binary is a command line over these, other programs embed
+// them the same way.
+
+// First, for its macros to be in scope in the other modules
+#[macro_use]
+pub mod log;
+
+pub mod codec;
+pub mod connect;
+pub mod crypto;
+pub mod duplex;
+pub mod fec;
+pub mod format;
+pub mod group;
+pub mod ice;
+mod latency;
+mod lossless;
+pub mod mdns;
+mod metrics;
+mod osc;
+pub mod packet;
+mod quic;
+pub mod receiver;
+pub mod relay;
+mod resample;
+pub mod rtp;
+pub mod sender;
+pub mod shutdown;
+pub mod transport;
+mod warning;
+
+pub use duplex::Duplex;
+pub use group::Group;
+pub use receiver::Receiver;
+pub use sender::Sender;
@@ -0,0 +1,174 @@
+use std::{
+    io::{self, IsTerminal},
+    sync::OnceLock,
+};
+
+use tracing_subscriber::{EnvFilter, Registry, fmt, prelude::*, reload};
+
+// Used by the macros below. Applications embedding the library can install a subscriber of
+// their own instead of calling `init`, events come under the module's path, e.g.
+// `netaudio::receiver`.
+pub use tracing;
+
+// Prefix of every module's target
+const CRATE: &str = "netaudio";
+
+// Printed until --log-level or RUST_LOG say otherwise
+const DEFAULT_LEVEL: &str = "info";
+
+// Swaps the filter of the subscriber installed by `init`
+static RELOAD: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+// Builds the filter of a --log-level value: a level and module levels, separated by commas,
+// e.g. `warning,receiver=debug`. Modules are named after their source file, or by their full
+// path with `::`, and the most specific one counts.
+pub fn filter(spec: &str) -> Result<EnvFilter, &'static str> {
+    let mut directives = Vec::new();
+    for part in spec.split(',') {
+        directives.push(match part.split_once('=') {
+            Some((module, level)) if module.contains("::") || module == CRATE => {
+                format!("{}={}", module, level_name(level)?)
+            }
+            Some((module, level)) => format!("{}::{}={}", CRATE, module, level_name(level)?),
+            None => level_name(part)?.to_string(),
+        });
+    }
+    EnvFilter::builder()
+        .parse(directives.join(","))
+        .map_err(|_| "invalid --log-level")
+}
+
+// tracing's name for a level, taking `warning` as well
+fn level_name(level: &str) -> Result<&'static str, &'static str> {
+    match level {
+        "off" => Ok("off"),
+        "error" => Ok("error"),
+        "warning" | "warn" => Ok("warn"),
+        "info" => Ok("info"),
+        "debug" => Ok("debug"),
+        "trace" => Ok("trace"),
+        _ => Err("unknown log level, expected error, warning, info, debug or trace"),
+    }
+}
+
+// Prints events to stderr, filtered by RUST_LOG until `set_filter` is called. Only the first
+// subscriber installed in the process takes effect.
+pub fn init() {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL));
+    let (filter, handle) = reload::Layer::new(filter);
+    let output = fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    if tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .try_init()
+        .is_ok()
+    {
+        let _ = RELOAD.set(handle);
+    }
+}
+
+// Applies the --log-level value `spec` to the subscriber installed by `init` from now on
+pub fn set_filter(spec: &str) -> Result<(), &'static str> {
+    let filter = filter(spec)?;
+    if let Some(handle) = RELOAD.get() {
+        handle
+            .reload(filter)
+            .map_err(|_| "unable to change the log level")?;
+    }
+    Ok(())
+}
+
+// Each logs an event at its level under the calling module's path. Besides a message they
+// take tracing's fields, e.g. `info!(peer = %addr, "sending")`.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::tracing::error!($($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::tracing::warn!($($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::tracing::info!($($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::tracing::debug!($($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::tracing::trace!($($arg)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn filters_apply_per_module() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let writer = lines.clone();
+        let subscriber = fmt()
+            .with_env_filter(filter("warning,receiver=debug,transport=error").unwrap())
+            .with_writer(move || Writer(writer.clone()))
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(target: "netaudio::sender", "sender warning");
+            tracing::info!(target: "netaudio::sender", "sender info");
+            tracing::debug!(target: "netaudio::receiver", stream = 1, "receiver debug");
+            tracing::trace!(target: "netaudio::receiver", "receiver trace");
+            tracing::warn!(target: "netaudio::transport", "transport warning");
+            tracing::error!(target: "netaudio::transport", "transport error");
+        });
+        let output = String::from_utf8(lines.lock().unwrap().clone()).unwrap();
+        for shown in [
+            "sender warning",
+            "receiver debug stream=1",
+            "transport error",
+        ] {
+            assert!(output.contains(shown), "{} missing from {}", shown, output);
+        }
+        for hidden in ["sender info", "receiver trace", "transport warning"] {
+            assert!(!output.contains(hidden), "{} in {}", hidden, output);
+        }
+
+        assert!(filter("error,netaudio::ice=info").is_ok());
+        assert!(filter("verbose").is_err());
+        assert!(filter("receiver=loud").is_err());
+    }
+
+    // Collects what a test subscriber prints
+    struct Writer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use crate::format::SampleFormat;
+
+// Payload layouts, given by the first byte
+const VERBATIM: u8 = 0; // Samples in the wire format
+const RICE: u8 = 1; // Frame count, predictor order and Rice parameter per channel, residuals
+
+// Bytes of a Rice coded payload before the residuals
+fn rice_header_size(channels: usize) -> usize {
+    1 + 2 + 2 * channels
+}
+
+// Extra bytes a payload may take over the samples it carries
+pub const OVERHEAD: usize = 1;
+
+// f32 samples are coded as 24-bit integers, the resolution of typical studio converters
+const F32_SCALE: f32 = (1 << 23) as f32;
+
+// Highest predictor order, predicting from up to this many previous samples
+const MAX_ORDER: u8 = 2;
+
+// Highest Rice parameter, leaving room for the quotient in 32 bits
+const MAX_RICE_PARAMETER: u8 = 30;
+
+fn scale(format: SampleFormat) -> f32 {
+    match format {
+        SampleFormat::F32 => F32_SCALE,
+        SampleFormat::S16 => i16::MAX as f32,
+        SampleFormat::S24 => ((1 << 23) - 1) as f32,
+    }
+}
+
+// Sample represented by an integer, the way SampleFormat decodes it
+fn from_integer(format: SampleFormat, value: i32) -> f32 {
+    value as f32 / scale(format)
+}
+
+// Integer representing a sample, if the sample converts back to exactly the same bits. This
+// rules out samples between integer steps, negative zero, NaN and infinities.
+fn to_integer(format: SampleFormat, sample: f32) -> Option<i32> {
+    let value = (sample * scale(format)).round();
+    (value.abs() <= scale(format) + 1.0
+        && from_integer(format, value as i32).to_bits() == sample.to_bits())
+    .then_some(value as i32)
+}
+
+// Prediction of sample `n` from the ones before it, lower orders stand in at the start
+fn predict(samples: &[i32], n: usize, order: u8) -> i32 {
+    match (order as usize).min(n) {
+        0 => 0,
+        1 => samples[n - 1],
+        _ => samples[n - 1].wrapping_mul(2).wrapping_sub(samples[n - 2]),
+    }
+}
+
+// Maps signed residuals to unsigned ones, small magnitudes to small values
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+// Writes bits into a zeroed buffer, failing once it is full
+struct BitWriter<'a> {
+    out: &'a mut [u8],
+    bit: usize,
+}
+
+impl BitWriter<'_> {
+    fn write_bit(&mut self, one: bool) -> Option<()> {
+        let byte = self.out.get_mut(self.bit / 8)?;
+        if one {
+            *byte |= 0x80 >> (self.bit % 8);
+        }
+        self.bit += 1;
+        Some(())
+    }
+
+    // Quotient as that many zeros and a terminating one, then the low `k` bits
+    fn write_rice(&mut self, value: u32, k: u8) -> Option<()> {
+        let quotient = (value >> k) as usize;
+        if self.bit + quotient >= self.out.len() * 8 {
+            return None;
+        }
+        self.bit += quotient;
+        self.write_bit(true)?;
+        for shift in (0..k).rev() {
+            self.write_bit(value >> shift & 1 != 0)?;
+        }
+        Some(())
+    }
+}
+
+struct BitReader<'a> {
+    input: &'a [u8],
+    bit: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.input.get(self.bit / 8)?;
+        let one = byte & 0x80 >> (self.bit % 8) != 0;
+        self.bit += 1;
+        Some(one)
+    }
+
+    fn read_rice(&mut self, k: u8) -> Option<u32> {
+        let mut quotient = 0_u32;
+        while !self.read_bit()? {
+            quotient += 1;
+            // Nothing written by the encoder is this long
+            if quotient > u32::MAX >> k {
+                return None;
+            }
+        }
+        let mut value = quotient << k;
+        for shift in (0..k).rev() {
+            value |= (self.read_bit()? as u32) << shift;
+        }
+        Some(value)
+    }
+}
+
+// Lossless coder for packets of interleaved channels. Each channel is predicted from its
+// previous samples and the residuals are Rice coded. Packets holding samples between the
+// integer steps of the format, or that would not shrink, are sent verbatim.
+pub struct Coder {
+    samples: Vec<f32>,
+    channels: Vec<Vec<i32>>,
+    parameters: Vec<u8>, // Predictor order and Rice parameter per channel
+}
+
+impl Coder {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            samples: Vec::new(),
+            channels: vec![Vec::new(); channels],
+            parameters: vec![0; 2 * channels],
+        }
+    }
+
+    // Compresses interleaved samples in `format`, returns the number of bytes written to `out`,
+    // which needs room for the samples plus OVERHEAD
+    pub fn encode(&mut self, format: SampleFormat, pcm: &[u8], out: &mut [u8]) -> usize {
+        self.encode_rice(format, pcm, out).unwrap_or_else(|| {
+            out[0] = VERBATIM;
+            out[1..1 + pcm.len()].copy_from_slice(pcm);
+            1 + pcm.len()
+        })
+    }
+
+    fn encode_rice(&mut self, format: SampleFormat, pcm: &[u8], out: &mut [u8]) -> Option<usize> {
+        // Only worth it if smaller than the samples themselves
+        let count = self.channels.len();
+        let header_size = rice_header_size(count);
+        if pcm.len() <= header_size {
+            return None;
+        }
+        let frames = pcm.len() / (count * format.sample_size());
+        self.samples.resize(count * frames, 0.0);
+        format.decode(pcm, &mut self.samples);
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            channel.clear();
+            for &sample in self.samples.iter().skip(index).step_by(count) {
+                channel.push(to_integer(format, sample)?);
+            }
+        }
+
+        let out = &mut out[..pcm.len()];
+        out.fill(0);
+        out[0] = RICE;
+        out[1..3].copy_from_slice(&u16::try_from(frames).ok()?.to_be_bytes());
+        let mut writer = BitWriter {
+            out: &mut out[header_size..],
+            bit: 0,
+        };
+        let parameters = self.parameters.chunks_exact_mut(2);
+        for (channel, parameters) in self.channels.iter().zip(parameters) {
+            // The order leaving the smallest residuals
+            let residuals = |order| {
+                (0..channel.len())
+                    .map(move |n| zigzag(channel[n].wrapping_sub(predict(channel, n, order))))
+            };
+            let (order, sum) = (0..=MAX_ORDER)
+                .map(|order| (order, residuals(order).map(u64::from).sum::<u64>()))
+                .min_by_key(|&(_, sum)| sum)?;
+            // The parameter closest to the mean residual
+            let mean = sum / channel.len().max(1) as u64;
+            let k = (u64::BITS - mean.leading_zeros()).saturating_sub(1) as u8;
+            let k = k.min(MAX_RICE_PARAMETER);
+            for residual in residuals(order) {
+                writer.write_rice(residual, k)?;
+            }
+            parameters.copy_from_slice(&[order, k]);
+        }
+        let length = header_size + writer.bit.div_ceil(8);
+        out[3..header_size].copy_from_slice(&self.parameters);
+        (length < out.len()).then_some(length)
+    }
+
+    // Decompresses a payload into interleaved samples in `format`, returns the number of bytes
+    // written to `out`
+    pub fn decode(
+        &mut self,
+        payload: &[u8],
+        format: SampleFormat,
+        out: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        let count = self.channels.len();
+        let header_size = rice_header_size(count);
+        let frame_size = count * format.sample_size();
+        match payload.split_first() {
+            Some((&VERBATIM, samples)) => {
+                if !samples.len().is_multiple_of(frame_size) || samples.len() > out.len() {
+                    return Err("lossless payload is not a whole number of frames");
+                }
+                out[..samples.len()].copy_from_slice(samples);
+                Ok(samples.len())
+            }
+            Some((&RICE, _)) if payload.len() >= header_size => {
+                let frames = u16::from_be_bytes([payload[1], payload[2]]) as usize;
+                if frames * frame_size > out.len() {
+                    return Err("lossless payload too long");
+                }
+                let mut reader = BitReader {
+                    input: &payload[header_size..],
+                    bit: 0,
+                };
+                for (channel, parameters) in self.channels.iter_mut().zip(payload[3..].chunks(2)) {
+                    let (order, k) = (parameters[0], parameters[1]);
+                    if order > MAX_ORDER || k > MAX_RICE_PARAMETER {
+                        return Err("invalid lossless payload");
+                    }
+                    channel.clear();
+                    for n in 0..frames {
+                        let residual = reader.read_rice(k).ok_or("lossless payload truncated")?;
+                        let sample = unzigzag(residual).wrapping_add(predict(channel, n, order));
+                        channel.push(sample);
+                    }
+                }
+                let channels = &self.channels;
+                let samples = (0..frames)
+                    .flat_map(|n| channels.iter().map(move |channel| channel[n]))
+                    .map(|value| from_integer(format, value));
+                Ok(format.encode(samples, None, out))
+            }
+            _ => Err("invalid lossless payload"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes `samples` in `format`, returning the payload size after checking the decoded
+    // samples are bit for bit the same
+    fn round_trip(format: SampleFormat, channels: usize, samples: &[f32]) -> usize {
+        let mut pcm = vec![0; samples.len() * format.sample_size()];
+        format.encode(samples.iter().copied(), None, &mut pcm);
+        let mut coder = Coder::new(channels);
+        let mut payload = vec![0; pcm.len() + OVERHEAD];
+        let length = coder.encode(format, &pcm, &mut payload);
+
+        let mut decoded = vec![0; pcm.len()];
+        let written = Coder::new(channels)
+            .decode(&payload[..length], format, &mut decoded)
+            .unwrap();
+        assert_eq!(decoded[..written], pcm[..]);
+        length
+    }
+
+    #[test]
+    fn samples_survive_compression_exactly() {
+        let sine = |scale: f32| {
+            (0..960)
+                .map(|n| (n as f32 * 0.01).sin() * 0.5)
+                .map(move |sample| (sample * scale).round() / scale)
+                .collect::<Vec<_>>()
+        };
+        // Integer samples shrink, a quiet sine to less than half
+        let s16 = sine(i16::MAX as f32);
+        assert!(round_trip(SampleFormat::S16, 2, &s16) < s16.len());
+        let f32_24bit = sine(F32_SCALE);
+        assert!(round_trip(SampleFormat::F32, 2, &f32_24bit) < f32_24bit.len() * 4);
+        let s24 = sine(((1 << 23) - 1) as f32);
+        assert!(round_trip(SampleFormat::S24, 2, &s24) < s24.len() * 3);
+        assert_eq!(
+            round_trip(SampleFormat::F32, 2, &[0.0; 64]),
+            rice_header_size(2) + 8
+        );
+        // Any number of channels, each with parameters of its own
+        assert!(round_trip(SampleFormat::S16, 1, &s16) < s16.len());
+        assert!(round_trip(SampleFormat::S16, 6, &s16[..954]) < s16.len());
+
+        // Anything else goes through verbatim
+        let noise = [0.1, -0.0, f32::NAN, 1e-9, 2.5, -1.0];
+        assert_eq!(
+            round_trip(SampleFormat::F32, 2, &noise),
+            1 + noise.len() * 4
+        );
+        assert_eq!(round_trip(SampleFormat::S16, 2, &[1.0, -1.0]), 1 + 4);
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        let mut coder = Coder::new(2);
+        let mut out = [0; 64];
+        assert!(coder.decode(&[], SampleFormat::S16, &mut out).is_err());
+        assert!(
+            coder
+                .decode(&[VERBATIM, 1, 2, 3], SampleFormat::S16, &mut out)
+                .is_err()
+        );
+        assert!(
+            coder
+                .decode(&[RICE, 0, 4], SampleFormat::S16, &mut out)
+                .is_err()
+        );
+        // More frames than the residuals cover
+        let truncated = [RICE, 0, 5, 0, 0, 0, 0, 0xff];
+        assert!(
+            coder
+                .decode(&truncated, SampleFormat::S16, &mut out)
+                .is_err()
+        );
+        // A quotient running past the end
+        let zeros = [RICE, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        assert!(coder.decode(&zeros, SampleFormat::S16, &mut out).is_err());
+    }
+}
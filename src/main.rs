@@ -1,60 +1,1733 @@
-#![feature(array_chunks, never_type, try_blocks)]
+use std::{
+    env,
+    ffi::CString,
+    fs, iter,
+    net::{SocketAddr, SocketAddrV6, ToSocketAddrs},
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
 
-use std::{env, net::SocketAddr, process::ExitCode};
+use clap::{CommandFactory, Parser, builder::RangedU64ValueParser, error::ErrorKind};
+use jack::{Client, ClientOptions, PortFlags};
 
-use jack::{Client, ClientOptions};
+use netaudio::{
+    Duplex, Group, Receiver, Sender,
+    codec::Codec,
+    crypto::{self, Key},
+    error, fec,
+    format::SampleFormat,
+    ice, info, log, mdns,
+    packet::{DEFAULT_CHANNELS, HEADER_SIZE, MAX_DATAGRAM_SIZE},
+    receiver, relay, rtp,
+    sender::{self, OverrunPolicy},
+    shutdown,
+    transport::{self, MulticastInterface, Transport},
+};
 
 // Constants defining buffer sizes for audio processing
 const RING_BUFFER_SIZE: usize = 16384;
 const PACKET_SIZE: usize = 480;
 
+// Endpoint role requested with --mode
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Sender,
+    Receiver,
+    Relay,
+    Duplex,
+}
+
+impl FromStr for Mode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sender" => Ok(Mode::Sender),
+            "receiver" => Ok(Mode::Receiver),
+            "relay" => Ok(Mode::Relay),
+            "duplex" => Ok(Mode::Duplex),
+            _ => Err("unknown mode, expected sender, receiver, relay or duplex"),
+        }
+    }
+}
+
+// Endpoint role along with the addresses only that role needs
+enum Role {
+    Sender { peer_addr: Option<SocketAddr> }, // Without a peer, waits for a receiver to subscribe
+    Receiver,
+    Duplex { peer_addr: SocketAddr }, // Sends to the peer and receives on one client
+}
+
 // Structure to hold command-line arguments
 struct Args {
     bind_addr: SocketAddr,
-    send_addr: Option<SocketAddr>, // Optional destination address for sender mode
-}
-
-// Parses command-line arguments into program name and optional Args
-fn parse_args() -> (String, Option<Args>) {
-    let mut args = env::args();
-    (
-        // First argument is the program name
-        args.next().unwrap_or_default(),
-        try {
-            let bind_addr = args.next()?; // Get bind address
-            let send_addr = args.next(); // Get optional send address
-            Args {
-                bind_addr: bind_addr.parse().ok()?,
-                send_addr: send_addr.and_then(|addr| addr.parse().ok()),
+    role: Role,                        // Whether to send to a peer or receive
+    ping: bool,                        // Periodically probe the round-trip time (sender)
+    measure_latency: bool,             // Report capture-to-playout latency (receiver)
+    measure_jitter: bool,              // Report interarrival jitter (receiver)
+    format: SampleFormat,              // Wire sample format, must match on both ends
+    dither: bool,                      // Dither when reducing bit depth (sender)
+    overrun: OverrunPolicy,            // Ring buffer overrun handling (sender)
+    recovery_fade_ms: u32,             // Fade-in duration after an underrun (receiver)
+    planar: bool,                      // Planar wire format, must match on both ends
+    record: Option<PathBuf>,           // WAV file to record the received stream to (receiver)
+    tone: Option<f32>,                 // Test tone frequency replacing the inputs (sender)
+    keepalive: Option<Duration>,       // Longest gap before a keepalive is sent (sender)
+    ring_buffer_size: usize,           // Ring buffer capacity in bytes, a power of two
+    monitor: bool,                     // Echo the sent signal on local output ports (sender)
+    ttl: Option<u32>,                  // Outgoing TTL or hop limit (sender, --relay)
+    packet_size: usize,                // Payload bytes per packet, the receiver's wins
+    coalesce: u32,                     // JACK periods per datagram (sender)
+    warn_interval: Option<Duration>,   // Window for aggregating repeated warnings
+    silence_timeout: Option<Duration>, // Silent output before warning (receiver)
+    max_latency_ms: Option<u32>,       // Latency cap enforced by dropping audio (receiver)
+    transport: Transport,              // UDP, TCP or QUIC, must match on both ends
+    metrics: Option<SocketAddr>,       // Address serving Prometheus metrics over HTTP
+    http: Option<SocketAddr>,          // Address serving the JSON status over HTTP
+    stats_interval: Option<Duration>,  // Period of the printed stream summary
+    comfort_noise: Option<f32>,        // Noise level in dBFS filling underruns (receiver)
+    relay: Option<SocketAddr>,         // Address incoming packets are forwarded to (receiver)
+    stream_id: u8,                     // Stream id carried in every packet (sender)
+    max_streams: usize,                // Concurrent streams played on separate ports (receiver)
+    limit: bool,                       // Soft-clip the output at full scale (receiver)
+    dont_fragment: bool,               // Set the don't-fragment bit on audio packets (sender)
+    adaptive: Option<(usize, usize)>,  // Ring buffer bounds when adapting to underruns (receiver)
+    peer_stdin: bool,                  // Take new receiver addresses from stdin (sender)
+    send_cadence: Option<Duration>,    // Timer driving the send loop (sender)
+    loss_crossfade_ms: u32,            // Crossfade into audio after lost packets (receiver)
+    verify: bool,                      // Check sample counts end to end, on both ends
+    wait_for_stream: bool,             // Quiet until the first packet arrives (receiver)
+    v6only: Option<bool>,              // Whether an IPv6 bind address excludes IPv4
+    meter: bool,                       // Report input levels every second (sender)
+    redundancy: u32,                   // Times each audio packet is sent (sender)
+    redundancy_delay: Duration,        // Offset between copies of a packet (sender)
+    tcp_queue: Duration,               // Longest wait for a stalled TCP connection (sender)
+    codec: Codec,                      // Raw samples or compressed frames, on both ends
+    opus_bitrate: u32,                 // Opus bitrate in kbit/s (sender)
+    opus_frame: Duration,              // Audio in each Opus frame, on both ends
+    jitter_buffer: Option<(u32, u32)>, // Buffering bounds in ms when following jitter (receiver)
+    underrun_conceal_ms: u32,          // Fade-out of the last period on underruns (receiver)
+    fec: Option<fec::Layout>,          // Data and parity packets per FEC group (sender)
+    extra_peers: Vec<SocketAddr>,      // Further receivers sent every packet as well (sender)
+    rtp: Option<rtp::Encoding>,        // Plain RTP of these samples instead of netaudio packets
+    aes67: bool,                       // RTP with AES67's rate, packet time and PTP timestamps
+    // Interface multicast is sent out of (sender) or joined on (receiver)
+    multicast_interface: Option<MulticastInterface>,
+    advertise: Option<String>,    // Stream name announced via mDNS (sender)
+    connect_name: Option<String>, // Advertised stream to subscribe to (receiver)
+    drift_correction: bool,       // Resample against clock drift (receiver)
+    channels: u8,                 // Ports on either end, interleaved in port order
+    // Wire channel of each output port (receiver)
+    map: Option<Vec<Option<usize>>>,
+    frames_per_packet: Option<u16>, // Audio per packet the receiver follows (sender)
+    key: Option<Key>,               // Pre-shared key for encrypted packets, on both ends
+    auth_key: Option<Key>,          // Pre-shared key only authenticating packets, on both ends
+    identity: Option<Key>,          // Private key for exchanging session keys, on both ends
+    peer_keys: Vec<Key>,            // Public keys of the other end allowed to exchange keys
+    ice: Option<ice::Exchange>,     // Reach the other end through NAT by connectivity checks
+    osc: Option<SocketAddr>,        // Address taking OSC control messages (receiver)
+    // Host name of the peer and how often it is resolved again (sender)
+    resolve: Option<(String, Duration)>,
+    fanout: Vec<SocketAddr>, // Further receivers each sent the whole stream (sender)
+    name: Option<String>,    // Leads the port names of one of several streams
+    connect: Vec<String>,    // Patterns of the JACK ports our ports are connected to
+    strict_rate: bool,       // Refuse streams at another sample rate (receiver)
+    adaptive_bitrate: bool,  // Steer the Opus bitrate by reported loss (sender)
+}
+
+// What to do after starting the JACK client
+enum Command {
+    ListPorts, // Print the JACK ports and exit
+    Discover,  // Print the streams advertised via mDNS and exit
+    // Print a new private and public key pair and exit
+    GenerateIdentity,
+    // Forward packets to the next hop without JACK
+    Relay {
+        bind_addr: SocketAddr,
+        next_addr: SocketAddr,
+        transport: Transport,
+    },
+    Stream(Box<Args>), // Send or receive audio
+    // Send or receive the named streams of a config file on one client
+    Streams(Vec<Args>),
+}
+
+// Parses a socket address, also resolving named IPv6 zones such as `[fe80::1%eth0]:9000` and
+// host names such as `studio.example.com:9000`
+fn parse_addr(addr: &str) -> Option<SocketAddr> {
+    // The standard parser already handles numeric scope ids
+    if let Ok(addr) = addr.parse() {
+        return Some(addr);
+    }
+    // IPv6 first, IPv4 as a fallback
+    if let Some(host) = host_name(addr) {
+        let addrs: Vec<_> = host
+            .to_socket_addrs()
+            .map_err(|error| error!("unable to resolve {}: {}", host, error))
+            .ok()?
+            .collect();
+        return addrs
+            .iter()
+            .find(|addr| addr.is_ipv6())
+            .or(addrs.first())
+            .copied();
+    }
+
+    let (host, port) = addr.strip_prefix('[')?.rsplit_once("]:")?;
+    let (ip, zone) = host.split_once('%')?;
+    Some(SocketAddr::V6(SocketAddrV6::new(
+        ip.parse().ok()?,
+        port.parse().ok()?,
+        0,
+        interface_index(zone)?,
+    )))
+}
+
+// The address itself if it names a host rather than giving an IP address
+fn host_name(addr: &str) -> Option<String> {
+    (!addr.starts_with('[') && addr.parse::<SocketAddr>().is_err()).then(|| addr.to_string())
+}
+
+// Looks up the index of a network interface by name
+fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // SAFETY: name is a valid NUL-terminated string that outlives the call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+// Settings of a config file as flags: those applying to every stream, and those of each
+// `[stream.<name>]` table
+#[derive(Debug, Default, PartialEq)]
+struct Config {
+    flags: Vec<String>,
+    streams: Vec<(String, Vec<String>)>,
+}
+
+// Reads a TOML config file into the equivalent flags
+fn read_config(path: &str) -> Result<Config, clap::Error> {
+    let contents = fs::read_to_string(path)
+        .or_else(|error| usage_error(&format!("unable to read {}: {}", path, error)))?;
+    config_flags(&contents)
+        .or_else(|line| usage_error(&format!("expected `key = value` in {}: {}", path, line)))
+}
+
+// Turns `key = value` lines into flags, failing on the first line that is none. Values are
+// TOML strings, numbers, booleans or arrays of them, arrays repeating the flag. A
+// `[stream.<name>]` table holds the settings of one stream, other tables only group keys
+// that apply to every stream.
+fn config_flags(contents: &str) -> Result<Config, &str> {
+    let mut config = Config::default();
+    let mut stream = None;
+    for line in contents.lines() {
+        let setting = line[..find_unquoted(line, '#').unwrap_or(line.len())].trim();
+        if setting.is_empty() {
+            continue;
+        }
+        if let Some(table) = setting.strip_prefix('[') {
+            let table = table.strip_suffix(']').ok_or(line)?.trim();
+            stream = match table.strip_prefix("stream.") {
+                // Names lead port names and OSC addresses, so they avoid the separators of
+                // either, and of TOML tables
+                Some(name) if name.is_empty() || name.contains([':', '/', '.', ' ']) => {
+                    return Err(line);
+                }
+                Some(name) if config.streams.iter().any(|(other, _)| other == name) => {
+                    return Err(line);
+                }
+                Some(name) => {
+                    config.streams.push((name.to_string(), Vec::new()));
+                    Some(config.streams.len() - 1)
+                }
+                None => None,
+            };
+            continue;
+        }
+        let flags = match stream {
+            Some(index) => &mut config.streams[index].1,
+            None => &mut config.flags,
+        };
+        let (key, values) = setting
+            .split_once('=')
+            .and_then(|(key, value)| Some((key.trim(), config_values(value.trim())?)))
+            .ok_or(line)?;
+        let key = key.replace('_', "-");
+        // The positional argument names are accepted as aliases of --bind and --peer
+        let key = match key.as_str() {
+            "bind-addr" => "bind",
+            "send-addr" => "peer",
+            key => key,
+        };
+        for value in values {
+            match (key, value.as_str()) {
+                // Takes its value explicitly, since unset is neither true nor false
+                ("v6only", _) => flags.extend([format!("--{}", key), value]),
+                (_, "true") => flags.push(format!("--{}", key)),
+                (_, "false") => {}
+                _ => flags.extend([format!("--{}", key), value]),
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+// Index of the first `target` outside of quotes
+fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            // Only basic strings have escapes
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(open), c) if c == open && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, c) if c == target => return Some(index),
+            _ => {}
+        }
+        escaped = false;
+    }
+    None
+}
+
+// A value, or each element of an array on one line
+fn config_values(value: &str) -> Option<Vec<String>> {
+    let Some(elements) = value.strip_prefix('[') else {
+        return Some(vec![config_value(value)?]);
+    };
+    let mut rest = elements.strip_suffix(']')?.trim();
+    let mut values = Vec::new();
+    while !rest.is_empty() {
+        let end = find_unquoted(rest, ',').unwrap_or(rest.len());
+        values.push(config_value(rest[..end].trim())?);
+        rest = rest[end..]
+            .strip_prefix(',')
+            .unwrap_or_default()
+            .trim_start();
+    }
+    Some(values)
+}
+
+// A string, basic with escapes or literal, or a bare number or boolean
+fn config_value(value: &str) -> Option<String> {
+    if let Some(literal) = value.strip_prefix('\'') {
+        return literal.strip_suffix('\'').map(str::to_string);
+    }
+    let Some(basic) = value.strip_prefix('"') else {
+        return (!value.is_empty() && !value.contains(char::is_whitespace))
+            .then(|| value.to_string());
+    };
+    let basic = basic.strip_suffix('"')?;
+    let mut unescaped = String::new();
+    let mut chars = basic.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped)
+}
+
+// Reads addresses given through the environment into the equivalent flags, for deployments
+// where the command line is fixed
+fn env_flags() -> Vec<String> {
+    let mut flags = Vec::new();
+    for (var, flag) in [("NETAUDIO_BIND", "--bind"), ("NETAUDIO_PEER", "--peer")] {
+        if let Ok(value) = env::var(var) {
+            flags.extend([flag.to_string(), value]);
+        }
+    }
+    flags
+}
+
+// Shown below the help of every command
+const FOOTER: &str = "Flags may also come from a TOML file given with --config <file>, and --bind and \
+                      --peer from NETAUDIO_BIND and NETAUDIO_PEER, both overridden by the command \
+                      line.";
+
+// The command line: a subcommand naming the role, or the older form taking --mode or the
+// positional addresses, still accepted for existing scripts and config files
+#[derive(Parser)]
+#[command(
+    name = "netaudio",
+    about = "Low-latency audio streaming between JACK systems",
+    after_help = FOOTER,
+    args_override_self = true,
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Subcommand>,
+    #[command(flatten)]
+    legacy: LegacyFlags,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Stream the JACK inputs to a receiver
+    #[command(after_help = FOOTER)]
+    Send {
+        #[command(flatten)]
+        common: CommonFlags,
+        #[command(flatten)]
+        send: SendFlags,
+    },
+    /// Play a stream on JACK outputs
+    #[command(after_help = FOOTER)]
+    Recv {
+        #[command(flatten)]
+        common: CommonFlags,
+        #[command(flatten)]
+        recv: RecvFlags,
+    },
+    /// Forward packets to the next hop without JACK
+    #[command(after_help = FOOTER)]
+    Relay(RelayFlags),
+}
+
+// Every option of both ends, along with the role and the commands that only print
+#[derive(clap::Args)]
+struct LegacyFlags {
+    /// Role of this end: sender, receiver, relay or duplex
+    #[arg(long)]
+    mode: Option<Mode>,
+    /// Bind address and the peer's, the original form of --bind and --peer
+    #[arg(value_name = "ADDR")]
+    addrs: Vec<String>,
+    /// Print the capture and playback ports known to JACK and exit
+    #[arg(long)]
+    list_ports: bool,
+    /// Print the streams advertised via mDNS and exit
+    #[arg(long)]
+    discover: bool,
+    /// Print a new private and public key pair for --identity and exit
+    #[arg(long = "gen-identity")]
+    generate_identity: bool,
+    #[command(flatten)]
+    common: CommonFlags,
+    #[command(flatten)]
+    send: SendFlags,
+    #[command(flatten)]
+    recv: RecvFlags,
+}
+
+// Options of either end
+#[derive(clap::Args)]
+struct CommonFlags {
+    /// Local address
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    bind: Option<SocketAddr>,
+    /// udp, tcp or quic, on both ends
+    #[arg(long, default_value_t = Transport::Udp)]
+    transport: Transport,
+    /// Wire sample format: f32, s16 or s24, on both ends
+    #[arg(long, default_value_t = SampleFormat::F32, value_parser = sample_format)]
+    format: SampleFormat,
+    /// Send each channel's samples together, on both ends
+    #[arg(long)]
+    planar: bool,
+    /// Ports on either end
+    #[arg(
+        long,
+        default_value_t = DEFAULT_CHANNELS,
+        value_parser = clap::value_parser!(u8).range(1..),
+        overrides_with = "mono"
+    )]
+    channels: u8,
+    /// Same as --channels 1
+    #[arg(long, overrides_with = "channels")]
+    mono: bool,
+    /// Audio payload per packet, the receiver's wins
+    #[arg(long, value_name = "BYTES", value_parser = packet_size)]
+    packet_size: Option<usize>,
+    /// Ring buffer capacity, rounded up to a power of two
+    #[arg(long, value_name = "BYTES", default_value_t = RING_BUFFER_SIZE, value_parser = ring_buffer_size)]
+    ring_buffer_size: usize,
+    /// pcm, opus or lossless, on both ends
+    #[arg(long, default_value_t = Codec::Pcm)]
+    codec: Codec,
+    /// Audio in each Opus frame, on both ends
+    #[arg(long = "opus-frame-ms", value_name = "MS", default_value = "10", value_parser = opus_frame)]
+    opus_frame: Duration,
+    /// Plain RTP with l16 or l24 samples instead of netaudio packets
+    #[arg(long, value_parser = rtp_encoding)]
+    rtp: Option<rtp::Encoding>,
+    /// RTP with the rate, packet time and timestamps of AES67
+    #[arg(long)]
+    aes67: bool,
+    /// Check sample counts end to end, on both ends
+    #[arg(long)]
+    verify: bool,
+    /// TTL or hop limit of outgoing packets
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    ttl: Option<u8>,
+    /// Whether an IPv6 bind address excludes IPv4
+    #[arg(long)]
+    v6only: Option<bool>,
+    /// Interface multicast is sent out of or joined on, by name, index or IPv4 address
+    #[arg(long, value_name = "INTERFACE", value_parser = multicast_interface)]
+    multicast_interface: Option<MulticastInterface>,
+    /// Encrypt packets with this pre-shared key, hex or a file
+    #[arg(long, value_parser = Key::read)]
+    key: Option<Key>,
+    /// Authenticate packets with this pre-shared key, hex or a file
+    #[arg(long, value_parser = Key::read)]
+    auth_key: Option<Key>,
+    /// Private key for exchanging session keys, hex or a file
+    #[arg(long, value_parser = Key::read)]
+    identity: Option<Key>,
+    /// Public key of the other end allowed to exchange keys
+    #[arg(long, value_parser = Key::read)]
+    peer_key: Vec<Key>,
+    /// Reach the other end through NAT by connectivity checks
+    #[arg(long)]
+    ice: bool,
+    /// STUN server telling --ice the address the NAT maps this end to
+    #[arg(long, value_name = "HOST:PORT", value_parser = stun_server)]
+    stun: Option<SocketAddr>,
+    /// Candidates of the other end for --ice, instead of reading them from stdin
+    #[arg(long, value_name = "ADDR", value_delimiter = ',', value_parser = addr)]
+    remote_candidates: Option<Vec<SocketAddr>>,
+    /// Window for aggregating repeated warnings
+    #[arg(long, value_name = "SECONDS", value_parser = interval)]
+    warn_interval: Option<Duration>,
+    /// Print a summary of the stream this often
+    #[arg(long, value_name = "SECONDS", value_parser = interval)]
+    stats_interval: Option<Duration>,
+    /// Serve Prometheus metrics over HTTP
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    metrics: Option<SocketAddr>,
+    /// Serve the JSON status over HTTP
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    http: Option<SocketAddr>,
+    /// Leads the port names, keeping streams of a group apart
+    #[arg(long, value_parser = stream_name)]
+    name: Option<String>,
+    /// Connect our ports to the JACK ports matching this pattern
+    #[arg(long, value_name = "PATTERN")]
+    connect: Vec<String>,
+    /// Lines printed: a level and module levels, e.g. warning,receiver=info
+    #[arg(long, value_name = "FILTER", value_parser = log_level)]
+    log_level: Option<String>,
+    /// TOML file of flags, overridden by the command line
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+}
+
+// Options of the sending end
+#[derive(Parser)]
+struct SendFlags {
+    /// Address audio is sent to, or a host name
+    #[arg(long, value_name = "ADDR")]
+    peer: Option<String>,
+    /// Resolve the --peer host name again this often
+    #[arg(long, value_name = "SECONDS", value_parser = interval)]
+    resolve_interval: Option<Duration>,
+    /// Take new receiver addresses from stdin
+    #[arg(long)]
+    peer_stdin: bool,
+    /// Also send every packet to this address
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    extra_peer: Vec<SocketAddr>,
+    /// Also stream to this further receiver
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    fanout: Vec<SocketAddr>,
+    /// Announce the stream via mDNS under this name
+    #[arg(long, value_name = "NAME")]
+    advertise: Option<String>,
+    /// Periodically probe the round-trip time
+    #[arg(long)]
+    ping: bool,
+    /// Dither when reducing bit depth
+    #[arg(long)]
+    dither: bool,
+    /// drop-new or drop-old when the ring buffer is full
+    #[arg(long, default_value_t = OverrunPolicy::DropNew)]
+    overrun: OverrunPolicy,
+    /// Send a test tone of this frequency instead of the inputs
+    #[arg(long, value_name = "HZ", value_parser = tone)]
+    tone: Option<f32>,
+    /// Longest gap before a keepalive is sent
+    #[arg(long = "keepalive-ms", value_name = "MS", value_parser = milliseconds)]
+    keepalive: Option<Duration>,
+    /// Echo the sent signal on local output ports
+    #[arg(long)]
+    monitor: bool,
+    /// Print the input levels every second
+    #[arg(long)]
+    meter: bool,
+    /// JACK periods per datagram
+    #[arg(long, value_name = "PERIODS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    coalesce: u32,
+    /// Audio per packet in frames, the receiver follows
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    frames_per_packet: Option<u16>,
+    /// Stream id carried in every packet
+    #[arg(long, default_value_t = 0)]
+    stream_id: u8,
+    /// Set the don't-fragment bit on audio packets
+    #[arg(long)]
+    dont_fragment: bool,
+    /// Drive the send loop from a timer of this period
+    #[arg(long, value_name = "MS", value_parser = milliseconds)]
+    send_cadence: Option<Duration>,
+    /// Times each audio packet is sent
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=8))]
+    redundancy: u32,
+    /// Offset between copies of a packet
+    #[arg(long, value_name = "MS", default_value_t = 2)]
+    redundancy_delay_ms: u64,
+    /// Longest wait for a stalled TCP connection
+    #[arg(long, value_name = "MS", default_value_t = transport::DEFAULT_QUEUE_DELAY.as_millis() as u64)]
+    tcp_queue_ms: u64,
+    /// Data and parity packets per FEC group
+    #[arg(long, value_name = "DATA:PARITY", value_parser = fec_layout)]
+    fec: Option<fec::Layout>,
+    /// Opus bitrate in kbit/s
+    #[arg(long, value_name = "KBPS", default_value_t = 128, value_parser = clap::value_parser!(u32).range(6..=510))]
+    opus_bitrate: u32,
+    /// Steer the Opus bitrate of each receiver by the loss it reports
+    #[arg(long)]
+    adaptive_bitrate: bool,
+}
+
+// Options of the receiving end
+#[derive(Parser)]
+struct RecvFlags {
+    /// Report capture-to-playout latency
+    #[arg(long)]
+    measure_latency: bool,
+    /// Report interarrival jitter
+    #[arg(long)]
+    measure_jitter: bool,
+    /// Record the stream to this WAV file
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Forward incoming packets to this address
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    relay: Option<SocketAddr>,
+    /// Streams played on separate ports at once
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..=256))]
+    max_streams: usize,
+    /// Subscribe to the stream advertised under this name
+    #[arg(long, value_name = "NAME")]
+    connect_name: Option<String>,
+    /// Wire channel of each output port, e.g. 1:0,0:1
+    #[arg(long, value_name = "CHANNEL:PORT", value_delimiter = ',', value_parser = route)]
+    map: Option<Vec<(u8, u8)>>,
+    /// Grow and shrink the ring buffer between these sizes
+    #[arg(long, value_name = "MIN:MAX", value_parser = buffer_bounds)]
+    adaptive_buffer: Option<(usize, usize)>,
+    /// Follow the jitter with between these many ms buffered
+    #[arg(long, value_name = "MIN:MAX", value_parser = jitter_bounds)]
+    jitter_buffer: Option<(u32, u32)>,
+    /// Drop audio to keep the latency below this
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_latency_ms: Option<u32>,
+    /// Resample against clock drift
+    #[arg(long)]
+    drift_correction: bool,
+    /// Refuse streams at another sample rate
+    #[arg(long)]
+    strict_rate: bool,
+    /// Fade in over this long after an underrun
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    recovery_fade_ms: u32,
+    /// Fade out the last period over this long on underruns
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    underrun_conceal_ms: u32,
+    /// Crossfade into audio after lost packets
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    loss_crossfade_ms: u32,
+    /// Fill underruns with noise at this level
+    #[arg(long, value_name = "DBFS", value_parser = comfort_noise)]
+    comfort_noise: Option<f32>,
+    /// Soft-clip the output at full scale
+    #[arg(long)]
+    limit: bool,
+    /// Warn after this long of silent output, 0 never warns
+    #[arg(long, value_name = "SECONDS", default_value = "5", value_parser = seconds)]
+    silence_timeout: Duration,
+    /// Stay quiet until the first packet arrives
+    #[arg(long)]
+    wait_for_stream: bool,
+    /// Take OSC control messages on this address
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    osc: Option<SocketAddr>,
+}
+
+// Options of a relay
+#[derive(clap::Args)]
+struct RelayFlags {
+    /// Local address
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    bind: Option<SocketAddr>,
+    /// Next hop packets are forwarded to
+    #[arg(long, value_name = "ADDR", value_parser = addr)]
+    relay: Option<SocketAddr>,
+    /// udp, tcp or quic, on both ends
+    #[arg(long, default_value_t = Transport::Udp)]
+    transport: Transport,
+    /// Lines printed: a level and module levels, e.g. warning,receiver=info
+    #[arg(long, value_name = "FILTER", value_parser = log_level)]
+    log_level: Option<String>,
+}
+
+// Value parsers of the flags whose values are more than a plain number or name
+
+fn addr(value: &str) -> Result<SocketAddr, &'static str> {
+    parse_addr(value).ok_or("expected an address such as 192.168.1.20:9000")
+}
+
+fn seconds(value: &str) -> Result<Duration, &'static str> {
+    let seconds = value.parse().map_err(|_| "expected a number of seconds")?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| "expected a number of seconds")
+}
+
+fn interval(value: &str) -> Result<Duration, &'static str> {
+    Some(seconds(value)?)
+        .filter(|interval| !interval.is_zero())
+        .ok_or("expected a number of seconds above zero")
+}
+
+fn milliseconds(value: &str) -> Result<Duration, &'static str> {
+    match value.parse() {
+        Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+        _ => Err("expected a number of milliseconds above zero"),
+    }
+}
+
+fn opus_frame(value: &str) -> Result<Duration, &'static str> {
+    match value.parse::<f64>() {
+        Ok(ms) if ms > 0.0 => Ok(Duration::from_micros((ms * 1000.0).round() as u64)),
+        _ => Err("expected a number of milliseconds above zero"),
+    }
+}
+
+fn tone(value: &str) -> Result<f32, &'static str> {
+    match value.parse::<f32>() {
+        Ok(hz) if hz.is_finite() && hz > 0.0 => Ok(hz),
+        _ => Err("expected a frequency in Hz above zero"),
+    }
+}
+
+fn sample_format(value: &str) -> Result<SampleFormat, &'static str> {
+    value.parse().map_err(|_| "expected f32, s16 or s24")
+}
+
+fn rtp_encoding(value: &str) -> Result<rtp::Encoding, &'static str> {
+    value.parse().map_err(|_| "expected l16 or l24")
+}
+
+fn packet_size(value: &str) -> Result<usize, &'static str> {
+    value
+        .parse()
+        .ok()
+        .filter(|&size| size > 0 && size <= MAX_DATAGRAM_SIZE - HEADER_SIZE)
+        .ok_or("expected a size in bytes that fits a datagram")
+}
+
+fn ring_buffer_size(value: &str) -> Result<usize, &'static str> {
+    value
+        .parse()
+        .ok()
+        .and_then(ring_buffer_size_for)
+        .ok_or("expected a size of at least 2 bytes")
+}
+
+fn buffer_bounds(value: &str) -> Result<(usize, usize), &'static str> {
+    let (min, max) = value.split_once(':').ok_or("expected <min>:<max>")?;
+    let (min, max) = (ring_buffer_size(min)?, ring_buffer_size(max)?);
+    if min > max {
+        return Err("minimum exceeds the maximum");
+    }
+    Ok((min, max))
+}
+
+fn jitter_bounds(value: &str) -> Result<(u32, u32), &'static str> {
+    let (min, max) = value.split_once(':').ok_or("expected <min>:<max>")?;
+    let (min, max) = match (min.parse(), max.parse()) {
+        (Ok(min), Ok(max)) => (min, max),
+        _ => return Err("expected milliseconds as <min>:<max>"),
+    };
+    if min > max {
+        return Err("minimum exceeds the maximum");
+    }
+    Ok((min, max))
+}
+
+fn fec_layout(value: &str) -> Result<fec::Layout, &'static str> {
+    let (data, parity) = value.split_once(':').ok_or("expected <data>:<parity>")?;
+    let data = data
+        .parse()
+        .ok()
+        .filter(|n| (1..=64).contains(n))
+        .ok_or("expected 1 to 64 data packets")?;
+    let parity = parity
+        .parse()
+        .ok()
+        .filter(|&n| n >= 1)
+        .ok_or("expected at least one parity packet")?;
+    if parity > data {
+        return Err("needs at least as many data as parity packets");
+    }
+    Ok(fec::Layout { data, parity })
+}
+
+// An IPv4 address, or an interface name or index
+fn multicast_interface(value: &str) -> Result<MulticastInterface, &'static str> {
+    match value.parse() {
+        Ok(addr) => Ok(MulticastInterface::Address(addr)),
+        Err(_) => value
+            .parse()
+            .ok()
+            .or_else(|| interface_index(value))
+            .map(MulticastInterface::Index)
+            .ok_or("unknown interface"),
+    }
+}
+
+// A wire channel and the output port playing it
+fn route(value: &str) -> Result<(u8, u8), &'static str> {
+    value
+        .split_once(':')
+        .and_then(|(channel, port)| Some((channel.parse().ok()?, port.parse().ok()?)))
+        .ok_or("expected <channel>:<port>")
+}
+
+fn comfort_noise(value: &str) -> Result<f32, &'static str> {
+    value
+        .parse()
+        .ok()
+        .filter(|&db| db <= 0.0)
+        .ok_or("expected a level in dBFS of 0 or below")
+}
+
+fn stun_server(value: &str) -> Result<SocketAddr, &'static str> {
+    value
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or("unable to resolve the STUN server")
+}
+
+fn log_level(value: &str) -> Result<String, &'static str> {
+    log::filter(value).map(|_| value.to_string())
+}
+
+// Names lead port names and OSC addresses, so they avoid the separators of either
+fn stream_name(value: &str) -> Result<String, &'static str> {
+    if value.is_empty() || value.contains([':', '/']) {
+        return Err("cannot be empty or contain ':' or '/'");
+    }
+    Ok(value.to_string())
+}
+
+// Options of an end that was not asked for, as if none were given
+fn defaults<T: Parser>() -> T {
+    T::parse_from(["netaudio"])
+}
+
+// Parses command-line arguments into the Command to run
+fn parse_args() -> Result<Command, clap::Error> {
+    // Settings from the environment and a config file come first so command-line flags
+    // override them
+    let mut cli = env::args().skip(1).collect::<Vec<_>>();
+    // A leading subcommand stays in front of them, where clap expects it
+    let subcommand = match cli.first().map(String::as_str) {
+        Some("send" | "recv" | "relay" | "help") => Some(cli.remove(0)),
+        _ => None,
+    };
+    let config = match take_config(&mut cli)? {
+        Some(path) => read_config(&path)?,
+        None => Config::default(),
+    };
+    if config.streams.is_empty() {
+        return parse_flags(
+            subcommand
+                .into_iter()
+                .chain(env_flags())
+                .chain(config.flags)
+                .chain(cli),
+        );
+    }
+    if cli
+        .iter()
+        .any(|arg| arg == "--name" || arg.starts_with("--name="))
+    {
+        return usage_error("--name is given by the [stream.<name>] tables of the config");
+    }
+    // Each stream takes the shared settings, then its own, then the command line
+    let mut streams = Vec::new();
+    for (name, flags) in config.streams {
+        let flags = subcommand
+            .iter()
+            .cloned()
+            .chain(env_flags())
+            .chain(config.flags.iter().cloned())
+            .chain(flags)
+            .chain(["--name".to_string(), name.clone()])
+            .chain(cli.iter().cloned());
+        match parse_flags(flags) {
+            Ok(Command::Stream(args)) => streams.push(*args),
+            Ok(_) => return usage_error("config streams must send or receive audio"),
+            Err(error) => {
+                error!("invalid settings for stream {}", name);
+                return Err(error);
+            }
+        }
+    }
+    Ok(Command::Streams(streams))
+}
+
+// Takes every --config out of the command line, in either form, the last one counting. Clap
+// only sees the flag in a config file, where it is refused.
+fn take_config(cli: &mut Vec<String>) -> Result<Option<String>, clap::Error> {
+    let mut path = None;
+    while let Some(index) = cli
+        .iter()
+        .position(|arg| arg == "--config" || arg.starts_with("--config="))
+    {
+        let flag = cli.remove(index);
+        if let Some(value) = flag.strip_prefix("--config=") {
+            path = Some(value.to_string());
+        } else if index < cli.len() {
+            path = Some(cli.remove(index));
+        } else {
+            return usage_error("--config requires a file");
+        }
+    }
+    Ok(path)
+}
+
+// Reports a command-line error, which clap prints along with the usage
+fn usage_error<T>(message: &str) -> Result<T, clap::Error> {
+    Err(Cli::command().error(ErrorKind::ArgumentConflict, message))
+}
+
+// Addresses given without a flag of their own are checked after clap
+fn invalid_addr<T>(addr: &str) -> Result<T, clap::Error> {
+    Err(Cli::command().error(
+        ErrorKind::ValueValidation,
+        format!("invalid address {}", addr),
+    ))
+}
+
+// Parses the merged flags of the environment, config file and command line
+fn parse_flags(args: impl Iterator<Item = String>) -> Result<Command, clap::Error> {
+    let cli = Cli::try_parse_from(iter::once("netaudio".to_string()).chain(args))?;
+    let (mode, addrs, common, send, recv) = match cli.subcommand {
+        Some(Subcommand::Send { common, send }) => {
+            (Some(Mode::Sender), Vec::new(), common, send, defaults())
+        }
+        Some(Subcommand::Recv { common, recv }) => {
+            (Some(Mode::Receiver), Vec::new(), common, defaults(), recv)
+        }
+        Some(Subcommand::Relay(relay)) => {
+            if let Some(level) = relay.log_level {
+                log::set_filter(&level).or_else(usage_error)?;
             }
+            let Some(bind_addr) = relay.bind else {
+                return usage_error("missing --bind address");
+            };
+            let Some(next_addr) = relay.relay else {
+                return usage_error("relay mode requires --relay");
+            };
+            return Ok(Command::Relay {
+                bind_addr,
+                next_addr,
+                transport: relay.transport,
+            });
+        }
+        None => {
+            let mut legacy = cli.legacy;
+            if let Some(level) = legacy.common.log_level.take() {
+                log::set_filter(&level).or_else(usage_error)?;
+            }
+            if legacy.list_ports {
+                return Ok(Command::ListPorts);
+            } else if legacy.discover {
+                return Ok(Command::Discover);
+            } else if legacy.generate_identity {
+                return Ok(Command::GenerateIdentity);
+            }
+            (
+                legacy.mode,
+                legacy.addrs,
+                legacy.common,
+                legacy.send,
+                legacy.recv,
+            )
+        }
+    };
+
+    if common.config.is_some() {
+        return usage_error("--config cannot be given in a config file");
+    }
+    // Later flags win over earlier ones and the config file, so only the last is applied
+    if let Some(level) = common.log_level {
+        log::set_filter(&level).or_else(usage_error)?;
+    }
+    // The older positional form, `<bind_addr> [<send_addr>]`, replaces --bind and --peer
+    let (mut bind_addr, mut peer) = (common.bind, send.peer);
+    if let Some(bind) = addrs.first() {
+        if let Some(extra) = addrs.get(2) {
+            return usage_error(&format!("unexpected argument {}", extra));
+        }
+        bind_addr = Some(parse_addr(bind).map_or_else(|| invalid_addr(bind), Ok)?);
+        peer = addrs.get(1).cloned();
+    }
+    let Some(bind_addr) = bind_addr else {
+        return usage_error("missing --bind address");
+    };
+    let peer_host = peer.as_deref().and_then(host_name);
+    let mut peer_addr = match &peer {
+        Some(peer) => Some(parse_addr(peer).map_or_else(|| invalid_addr(peer), Ok)?),
+        None => None,
+    };
+    // Of the addresses a host name has, prefer one the bind address can reach
+    if let Some(host) = &peer_host {
+        peer_addr = sender::resolve(host, bind_addr).or(peer_addr);
+    }
+    peer_addr = peer_addr.map(|peer| transport::reachable(peer, bind_addr));
+    let (mut extra_peers, mut fanout) = (send.extra_peer, send.fanout);
+    for peer in extra_peers.iter_mut().chain(&mut fanout) {
+        *peer = transport::reachable(*peer, bind_addr);
+    }
+    let resolve = match (peer_host, send.resolve_interval) {
+        (Some(host), Some(interval)) => Some((host, interval)),
+        (None, Some(_)) => return usage_error("--resolve-interval requires a --peer host name"),
+        (_, None) => None,
+    };
+    let transport = common.transport;
+    if mode == Some(Mode::Relay) {
+        if peer_addr.is_some() {
+            return usage_error("relay mode forwards to --relay rather than --peer");
+        }
+        let Some(next_addr) = recv.relay else {
+            return usage_error("relay mode requires --relay");
+        };
+        return Ok(Command::Relay {
+            bind_addr,
+            next_addr,
+            transport,
+        });
+    }
+
+    let ice = match (common.ice, common.stun, common.remote_candidates) {
+        (true, stun, remote) => Some(ice::Exchange { stun, remote }),
+        (false, None, None) => None,
+        (false, ..) => return usage_error("--stun and --remote-candidates require --ice"),
+    };
+    if let Some(ice) = &ice {
+        if peer_addr.is_some() {
+            return usage_error("--ice finds the other end and does not take --peer");
+        }
+        if transport != Transport::Udp {
+            return usage_error("--ice requires the udp transport");
+        }
+        if send.advertise.is_some() || recv.connect_name.is_some() {
+            return usage_error("--ice replaces --advertise and --connect-name");
+        }
+        // Both would read stdin
+        if send.peer_stdin && ice.remote.is_none() {
+            return usage_error("--peer-stdin requires --remote-candidates with --ice");
+        }
+    }
+    // Without --mode a peer address implies sending, as the positional form always has
+    let role = match (mode, peer_addr) {
+        (Some(Mode::Sender) | None, Some(peer_addr)) => Role::Sender {
+            peer_addr: Some(peer_addr),
         },
-    )
+        // An advertised sender waits for receivers to find it
+        (Some(Mode::Sender) | None, None) if send.advertise.is_some() => {
+            Role::Sender { peer_addr: None }
+        }
+        // So does a sender finding its receiver through NAT
+        (Some(Mode::Sender), None) if ice.is_some() => Role::Sender { peer_addr: None },
+        (Some(Mode::Sender), None) => return usage_error("sender mode requires --peer"),
+        (Some(Mode::Duplex), _) if bind_addr.ip().is_multicast() => {
+            return usage_error("duplex mode requires a unicast --bind");
+        }
+        (Some(Mode::Duplex), Some(peer_addr)) => Role::Duplex { peer_addr },
+        (Some(Mode::Duplex), None) => return usage_error("duplex mode requires --peer"),
+        (Some(_), Some(_)) => return usage_error("receiver mode does not take --peer"),
+        (_, None) => Role::Receiver,
+    };
+    // Only a sender on UDP sends datagrams of its own choosing
+    if matches!(role, Role::Sender { .. } | Role::Duplex { .. }) && transport != Transport::Udp {
+        let udp_only = [
+            ("--advertise", send.advertise.is_some()),
+            ("--peer-stdin", send.peer_stdin),
+            ("--resolve-interval", resolve.is_some()),
+            ("--redundancy", send.redundancy > 1),
+            ("--extra-peer", !extra_peers.is_empty()),
+            ("--fanout", !fanout.is_empty()),
+            ("--fec", send.fec.is_some()),
+        ];
+        if let Some((flag, _)) = udp_only.iter().find(|(_, given)| *given) {
+            return usage_error(&format!("{} requires the udp transport", flag));
+        }
+    }
+    // Either a pre-shared key or session keys, exchanged with known peers only
+    let (key, auth_key, identity) = (common.key, common.auth_key, common.identity);
+    if key.is_some() && identity.is_some() {
+        return usage_error("--key cannot be combined with --identity");
+    }
+    if auth_key.is_some() && (key.is_some() || identity.is_some()) {
+        return usage_error("--auth-key cannot be combined with --key or --identity");
+    }
+    match (identity.is_some(), common.peer_key.len(), &role) {
+        (true, 0, _) => return usage_error("--identity requires --peer-key"),
+        (false, 1.., _) => return usage_error("--peer-key requires --identity"),
+        (_, 2.., Role::Sender { .. } | Role::Duplex { .. }) => {
+            return usage_error("a sender takes a single --peer-key");
+        }
+        _ => {}
+    }
+    // An adaptive ring buffer starts out within its bounds
+    let mut ring_buffer_size = common.ring_buffer_size;
+    if let (Role::Receiver | Role::Duplex { .. }, Some((min, max))) = (&role, recv.adaptive_buffer)
+    {
+        ring_buffer_size = ring_buffer_size.clamp(min, max);
+    }
+    // Pairs of a wire channel and the output port playing it
+    let map = match recv.map {
+        Some(pairs) => {
+            let mut routes = Vec::new();
+            for (channel, port) in pairs {
+                let port = port as usize;
+                if routes.len() <= port {
+                    routes.resize(port + 1, None);
+                }
+                if routes[port].replace(channel as usize).is_some() {
+                    return usage_error("--map feeds an output port twice");
+                }
+            }
+            Some(routes)
+        }
+        None => None,
+    };
+    let channels = if common.mono { 1 } else { common.channels };
+    Ok(Command::Stream(Box::new(Args {
+        bind_addr,
+        role,
+        ping: send.ping,
+        measure_latency: recv.measure_latency,
+        measure_jitter: recv.measure_jitter,
+        format: common.format,
+        dither: send.dither,
+        overrun: send.overrun,
+        recovery_fade_ms: recv.recovery_fade_ms,
+        planar: common.planar,
+        record: recv.record,
+        tone: send.tone,
+        keepalive: send.keepalive,
+        ring_buffer_size,
+        monitor: send.monitor,
+        ttl: common.ttl.map(u32::from),
+        // A mono packet holds as many frames as a stereo one by default
+        packet_size: common.packet_size.unwrap_or(match channels {
+            1 => PACKET_SIZE / 2,
+            _ => PACKET_SIZE,
+        }),
+        coalesce: send.coalesce,
+        warn_interval: common.warn_interval,
+        silence_timeout: Some(recv.silence_timeout).filter(|timeout| !timeout.is_zero()),
+        max_latency_ms: recv.max_latency_ms,
+        transport,
+        metrics: common.metrics,
+        http: common.http,
+        stats_interval: common.stats_interval,
+        comfort_noise: recv.comfort_noise,
+        relay: recv.relay,
+        stream_id: send.stream_id,
+        max_streams: recv.max_streams,
+        limit: recv.limit,
+        dont_fragment: send.dont_fragment,
+        adaptive: recv.adaptive_buffer,
+        peer_stdin: send.peer_stdin,
+        send_cadence: send.send_cadence,
+        loss_crossfade_ms: recv.loss_crossfade_ms,
+        verify: common.verify,
+        wait_for_stream: recv.wait_for_stream,
+        v6only: common.v6only,
+        meter: send.meter,
+        redundancy: send.redundancy,
+        redundancy_delay: Duration::from_millis(send.redundancy_delay_ms),
+        tcp_queue: Duration::from_millis(send.tcp_queue_ms),
+        codec: common.codec,
+        opus_bitrate: send.opus_bitrate,
+        opus_frame: common.opus_frame,
+        jitter_buffer: recv.jitter_buffer,
+        underrun_conceal_ms: recv.underrun_conceal_ms,
+        fec: send.fec,
+        extra_peers,
+        // AES67 is RTP, 24-bit unless asked otherwise
+        rtp: common.rtp.or(common.aes67.then_some(rtp::Encoding::L24)),
+        aes67: common.aes67,
+        multicast_interface: common.multicast_interface,
+        advertise: send.advertise,
+        connect_name: recv.connect_name,
+        drift_correction: recv.drift_correction,
+        channels,
+        map,
+        frames_per_packet: send.frames_per_packet,
+        key,
+        auth_key,
+        identity,
+        peer_keys: common.peer_key,
+        ice,
+        osc: recv.osc,
+        resolve,
+        fanout,
+        name: common.name,
+        connect: common.connect,
+        strict_rate: recv.strict_rate,
+        adaptive_bitrate: send.adaptive_bitrate,
+    })))
+}
+
+// Rounds a requested ring buffer size up to the power of two JACK would allocate anyway,
+// rejecting sizes that could not hold a single byte. Whether a packet fits is up to the
+// sender and receiver, which know the packet size in use.
+fn ring_buffer_size_for(requested: usize) -> Option<usize> {
+    // One byte of a JACK ring buffer is always left unused
+    (requested > 1)
+        .then(|| requested.checked_next_power_of_two())
+        .flatten()
+}
+
+// Logs the settings this end resolved to after merging the environment, config file and
+// command line, so both ends of a link can be compared at a glance
+fn log_config(args: &Args, client: &Client) {
+    let (mode, peer) = match args.role {
+        Role::Sender { peer_addr } => (
+            "sender",
+            match (peer_addr, &args.ice) {
+                (Some(addr), _) => addr.to_string(),
+                (None, Some(_)) => "ice".to_string(),
+                (None, None) => "subscriber".to_string(),
+            },
+        ),
+        Role::Receiver => ("receiver", "none".to_string()),
+        Role::Duplex { peer_addr } => ("duplex", peer_addr.to_string()),
+    };
+    info!(
+        name = args.name.as_deref(),
+        mode = %mode,
+        bind = %args.bind_addr,
+        peer = %peer,
+        transport = %args.transport,
+        channels = args.channels,
+        format = %args.format,
+        planar = args.planar,
+        codec = %args.codec,
+        packet_size = args.packet_size,
+        ring_buffer_size = args.ring_buffer_size,
+        sample_rate = client.sample_rate(),
+        period = client.buffer_size(),
+        "config"
+    );
+}
+
+// Prints the capture and playback ports known to JACK along with their types
+fn list_ports(client: &Client) {
+    for (title, flags) in [
+        ("Capture ports:", PortFlags::IS_OUTPUT),
+        ("Playback ports:", PortFlags::IS_INPUT),
+    ] {
+        println!("{}", title);
+        for name in client.ports(None, None, flags) {
+            let port_type = client
+                .port_by_name(&name)
+                .and_then(|port| port.port_type().ok())
+                .unwrap_or_default();
+            println!("  {} ({})", name, port_type);
+        }
+    }
+}
+
+// Prints the streams advertised on the local network along with their properties
+fn discover() -> Result<(), &'static str> {
+    println!("Streams:");
+    mdns::browse(mdns::BROWSE_TIMEOUT, |service| {
+        println!(
+            "  {} ({}) {}",
+            service.name,
+            service.addr,
+            service.properties.join(" ")
+        );
+        false
+    })
+}
+
+// Sender settings taken from the command line
+fn sender_options(args: &Args) -> sender::Options {
+    sender::Options {
+        ping: args.ping,
+        format: args.format,
+        dither: args.dither,
+        overrun: args.overrun,
+        planar: args.planar,
+        tone: args.tone,
+        keepalive: args.keepalive,
+        ring_buffer_size: args.ring_buffer_size,
+        monitor: args.monitor,
+        ttl: args.ttl,
+        packet_size: args.packet_size,
+        coalesce: args.coalesce,
+        warn_interval: args.warn_interval,
+        transport: args.transport,
+        metrics: args.metrics,
+        http: args.http,
+        stats_interval: args.stats_interval,
+        stream_id: args.stream_id,
+        dont_fragment: args.dont_fragment,
+        peer_stdin: args.peer_stdin,
+        send_cadence: args.send_cadence,
+        verify: args.verify,
+        v6only: args.v6only,
+        meter: args.meter,
+        redundancy: args.redundancy,
+        redundancy_delay: args.redundancy_delay,
+        tcp_queue: args.tcp_queue,
+        codec: args.codec,
+        opus_bitrate: args.opus_bitrate,
+        opus_frame: args.opus_frame,
+        fec: args.fec,
+        extra_peers: args.extra_peers.clone(),
+        rtp: args.rtp,
+        aes67: args.aes67,
+        multicast_interface: args.multicast_interface,
+        advertise: args.advertise.clone(),
+        channels: args.channels as usize,
+        frames_per_packet: args.frames_per_packet,
+        key: args.key,
+        auth_key: args.auth_key,
+        identity: args.identity,
+        peer_key: args.peer_keys.first().copied(),
+        ice: args.ice.clone(),
+        resolve: args.resolve.clone(),
+        fanout: args.fanout.clone(),
+        name: args.name.clone(),
+        connect: args.connect.clone(),
+        adaptive_bitrate: args.adaptive_bitrate,
+    }
+}
+
+// Receiver settings taken from the command line
+fn receiver_options(args: &Args) -> receiver::Options {
+    receiver::Options {
+        measure_latency: args.measure_latency,
+        measure_jitter: args.measure_jitter,
+        format: args.format,
+        recovery_fade_ms: args.recovery_fade_ms,
+        underrun_conceal_ms: args.underrun_conceal_ms,
+        planar: args.planar,
+        record: args.record.clone(),
+        ring_buffer_size: args.ring_buffer_size,
+        packet_size: args.packet_size,
+        warn_interval: args.warn_interval,
+        silence_timeout: args.silence_timeout,
+        max_latency_ms: args.max_latency_ms,
+        transport: args.transport,
+        metrics: args.metrics,
+        http: args.http,
+        stats_interval: args.stats_interval,
+        comfort_noise: args.comfort_noise,
+        relay: args.relay,
+        max_streams: args.max_streams,
+        limit: args.limit,
+        adaptive: args.adaptive,
+        loss_crossfade_ms: args.loss_crossfade_ms,
+        verify: args.verify,
+        wait_for_stream: args.wait_for_stream,
+        v6only: args.v6only,
+        codec: args.codec,
+        opus_frame: args.opus_frame,
+        jitter_buffer: args.jitter_buffer,
+        rtp: args.rtp,
+        aes67: args.aes67,
+        multicast_interface: args.multicast_interface,
+        connect_name: args.connect_name.clone(),
+        drift_correction: args.drift_correction,
+        channels: args.channels as usize,
+        map: args.map.clone(),
+        key: args.key,
+        auth_key: args.auth_key,
+        identity: args.identity,
+        peer_keys: args.peer_keys.clone(),
+        ice: args.ice.clone(),
+        osc: args.osc,
+        name: args.name.clone(),
+        connect: args.connect.clone(),
+        strict_rate: args.strict_rate,
+        ttl: args.ttl,
+    }
 }
 
-mod receiver;
-mod sender;
+// The sending half of a duplex stream. It goes out from the receiver's address on a port of
+// its own, and the receiver alone serves metrics.
+fn duplex_sender(args: &Args, peer_addr: SocketAddr) -> Sender {
+    let mut send_addr = args.bind_addr;
+    send_addr.set_port(0);
+    let options = sender::Options {
+        metrics: None,
+        http: None,
+        ..sender_options(args)
+    };
+    Sender::new(send_addr, Some(peer_addr), options)
+}
 
 fn main() -> ExitCode {
-    let (program_name, args) = parse_args();
-    let Some(args) = args else {
-        eprintln!("USAGE: {} <bind_addr> [<send_addr>]", program_name);
-        return ExitCode::FAILURE;
+    log::init();
+    let command = match parse_args() {
+        Ok(command) => command,
+        // Prints the usage, or the help that was asked for
+        Err(error) => error.exit(),
+    };
+
+    let streams = match command {
+        // A relay only forwards packets, so it runs without JACK
+        Command::Relay {
+            bind_addr,
+            next_addr,
+            transport,
+        } => {
+            let Err(error) = relay::start(bind_addr, next_addr, transport);
+            error!("{}", error);
+            return ExitCode::FAILURE;
+        }
+        // Discovery only listens to the network
+        Command::Discover => {
+            return match discover() {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(error) => {
+                    error!("{}", error);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        // A new identity only needs a source of randomness
+        Command::GenerateIdentity => {
+            return match crypto::generate_identity() {
+                Ok((private, public)) => {
+                    println!("private {}\npublic  {}", private, public);
+                    ExitCode::SUCCESS
+                }
+                Err(error) => {
+                    error!("{}", error);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Command::ListPorts => None,
+        Command::Stream(args) => Some(vec![*args]),
+        Command::Streams(streams) => Some(streams),
     };
 
     // Initialize JACK client with name "netaudio"
     let Ok((client, _)) = Client::new("netaudio", ClientOptions::default()) else {
-        eprintln!("unable to start JACK client");
+        error!("unable to start JACK client");
         return ExitCode::FAILURE;
     };
 
-    eprintln!("JACK system sample rate: {} Hz", client.sample_rate());
+    info!(
+        sample_rate = client.sample_rate(),
+        "JACK system sample rate"
+    );
+
+    let Some(streams) = streams else {
+        list_ports(&client);
+        return ExitCode::SUCCESS;
+    };
+
+    for args in &streams {
+        log_config(args, &client);
+    }
+
+    // Ctrl-C and service managers stop the stream instead of killing the process
+    if let Err(error) = shutdown::install() {
+        error!("{}", error);
+        return ExitCode::FAILURE;
+    }
 
-    // Start either sender or receiver based on arguments
-    let Err(error) = match args.send_addr {
-        Some(send_addr) => sender::start(client, args.bind_addr, send_addr),
-        None => receiver::start(client, args.bind_addr),
+    // Start the sender, the receiver or both based on arguments
+    let result = match streams.as_slice() {
+        [args] => match args.role {
+            Role::Sender { peer_addr } => {
+                Sender::new(args.bind_addr, peer_addr, sender_options(args)).run(client)
+            }
+            Role::Receiver => Receiver::new(args.bind_addr, receiver_options(args)).run(client),
+            Role::Duplex { peer_addr } => Duplex::new(
+                duplex_sender(args, peer_addr),
+                Receiver::new(args.bind_addr, receiver_options(args)),
+            )
+            .run(client),
+        },
+        // Streams of a config file share the client
+        streams => {
+            let mut senders = Vec::new();
+            let mut receivers = Vec::new();
+            for args in streams {
+                let receiver = || Receiver::new(args.bind_addr, receiver_options(args));
+                match args.role {
+                    Role::Sender { peer_addr } => {
+                        senders.push(Sender::new(args.bind_addr, peer_addr, sender_options(args)))
+                    }
+                    Role::Receiver => receivers.push(receiver()),
+                    Role::Duplex { peer_addr } => {
+                        senders.push(duplex_sender(args, peer_addr));
+                        receivers.push(receiver());
+                    }
+                }
+            }
+            Group::new(senders, receivers).run(client)
+        }
     };
 
-    eprintln!("[ERROR] {}", error);
-    ExitCode::FAILURE
+    match result {
+        Ok(()) => {
+            info!("stopped");
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            error!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_ipv6_addresses_keep_their_scope() {
+        let SocketAddr::V6(numeric) = parse_addr("[fe80::1%2]:9000").unwrap() else {
+            panic!("expected an IPv6 address");
+        };
+        assert_eq!(numeric.scope_id(), 2);
+        assert_eq!(numeric.port(), 9000);
+
+        let SocketAddr::V6(named) = parse_addr("[fe80::1%lo]:9000").unwrap() else {
+            panic!("expected an IPv6 address");
+        };
+        assert_eq!(Some(named.scope_id()), interface_index("lo"));
+        assert_ne!(named.scope_id(), 0);
+
+        assert!(parse_addr("[fe80::1%no-such-interface]:9000").is_none());
+        assert!(parse_addr("127.0.0.1:9000").is_some());
+    }
+
+    #[test]
+    fn host_names_are_told_apart_and_resolved() {
+        assert_eq!(
+            parse_addr("localhost:9000").map(|addr| addr.port()),
+            Some(9000)
+        );
+        assert_eq!(
+            host_name("localhost:9000").as_deref(),
+            Some("localhost:9000")
+        );
+        assert_eq!(host_name("[fe80::1%lo]:9000"), None);
+        assert_eq!(host_name("127.0.0.1:9000"), None);
+    }
+
+    #[test]
+    fn toml_config_turns_into_flags() {
+        let config = r#"
+            # Studio link
+            mode = "sender"   # the host with the microphones
+            [network]
+            bind_addr = '0.0.0.0:9000'
+            peer = "192.168.1.20:9000"
+            extra_peer = ["10.0.0.2:9000", "10.0.0.3:9000",]
+            [audio]
+            channels = 4
+            dither = true
+            monitor = false
+            v6only = false
+            advertise = "Booth \"A\" # 1"
+        "#;
+        let config = config_flags(config).unwrap();
+        assert!(config.streams.is_empty());
+        assert_eq!(
+            config.flags,
+            [
+                "--mode",
+                "sender",
+                "--bind",
+                "0.0.0.0:9000",
+                "--peer",
+                "192.168.1.20:9000",
+                "--extra-peer",
+                "10.0.0.2:9000",
+                "--extra-peer",
+                "10.0.0.3:9000",
+                "--channels",
+                "4",
+                "--dither",
+                "--v6only",
+                "false",
+                "--advertise",
+                "Booth \"A\" # 1",
+            ]
+        );
+
+        assert_eq!(config_flags("format s16"), Err("format s16"));
+        assert_eq!(
+            config_flags("peer = \"10.0.0.2:9000"),
+            Err("peer = \"10.0.0.2:9000")
+        );
+        assert_eq!(
+            config_flags("peer = [\"10.0.0.2:9000\""),
+            Err("peer = [\"10.0.0.2:9000\"")
+        );
+
+        // --config is found in either form, and refused inside a config file
+        let mut cli = ["--config=a.toml", "--mono", "--config", "b.toml"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(take_config(&mut cli).unwrap().as_deref(), Some("b.toml"));
+        assert_eq!(cli, ["--mono"]);
+        assert!(take_config(&mut vec!["--config".to_string()]).is_err());
+        let flags = ["send", "--bind", "0.0.0.0:0", "--config", "c.toml"];
+        assert!(parse_flags(flags.map(String::from).into_iter()).is_err());
+    }
+
+    #[test]
+    fn stream_tables_hold_the_settings_of_each_stream() {
+        let config = r#"
+            channels = 2
+            [stream.foh]
+            mode = "sender"
+            peer = "192.168.1.20:9000"
+            [stream.talkback]
+            mode = "receiver"
+            bind = "0.0.0.0:9002"
+            [logging]
+            log_level = "warn"
+        "#;
+        assert_eq!(
+            config_flags(config).unwrap(),
+            Config {
+                flags: vec![
+                    "--channels".into(),
+                    "2".into(),
+                    "--log-level".into(),
+                    "warn".into()
+                ],
+                streams: vec![
+                    (
+                        "foh".into(),
+                        vec![
+                            "--mode".into(),
+                            "sender".into(),
+                            "--peer".into(),
+                            "192.168.1.20:9000".into()
+                        ]
+                    ),
+                    (
+                        "talkback".into(),
+                        vec![
+                            "--mode".into(),
+                            "receiver".into(),
+                            "--bind".into(),
+                            "0.0.0.0:9002".into()
+                        ]
+                    ),
+                ],
+            }
+        );
+
+        assert_eq!(
+            config_flags("[stream.foh]\n[stream.foh]"),
+            Err("[stream.foh]")
+        );
+        assert_eq!(config_flags("[stream.a:b]"), Err("[stream.a:b]"));
+        assert_eq!(config_flags("[stream.]"), Err("[stream.]"));
+
+        let Ok(Command::Stream(args)) = parse_flags(
+            ["--bind", "0.0.0.0:9002", "--name", "talkback"]
+                .into_iter()
+                .map(String::from),
+        ) else {
+            panic!("expected a stream");
+        };
+        assert_eq!(args.name.as_deref(), Some("talkback"));
+        assert!(parse_flags(["--name", "a/b"].into_iter().map(String::from)).is_err());
+    }
+
+    #[test]
+    fn subcommands_take_the_options_of_their_end() {
+        Cli::command().debug_assert();
+        let parse = |args: &[&str]| parse_flags(args.iter().map(|arg| arg.to_string()));
+
+        let Ok(Command::Stream(args)) =
+            parse(&["send", "--bind", "0.0.0.0:0", "--peer", "[::1]:9000"])
+        else {
+            panic!("expected a stream");
+        };
+        assert!(matches!(args.role, Role::Sender { peer_addr: Some(_) }));
+        let Ok(Command::Stream(args)) = parse(&["recv", "--bind", "0.0.0.0:9000", "--limit"])
+        else {
+            panic!("expected a stream");
+        };
+        assert!(matches!(args.role, Role::Receiver) && args.limit);
+        assert!(parse(&["send", "--bind", "0.0.0.0:0", "--max-streams", "2"]).is_err());
+        assert!(parse(&["recv", "--bind", "0.0.0.0:9000", "--peer", "[::1]:9000"]).is_err());
+        assert!(parse(&["relay", "--bind", "0.0.0.0:9000", "--relay", "[::1]:9000"]).is_ok());
+
+        // The positional form stands for --bind and --peer, and later flags win
+        let Ok(Command::Stream(args)) =
+            parse(&["--channels", "4", "--mono", "0.0.0.0:0", "[::1]:9000"])
+        else {
+            panic!("expected a stream");
+        };
+        assert!(matches!(args.role, Role::Sender { peer_addr: Some(_) }));
+        assert_eq!(args.channels, 1);
+        assert!(parse(&["0.0.0.0:0", "[::1]:9000", "[::1]:9001"]).is_err());
+        assert!(parse(&["send", "--mode", "sender"]).is_err());
+        let send = |tone| {
+            parse(&[
+                "send",
+                "--bind",
+                "0.0.0.0:0",
+                "--peer",
+                "[::1]:9000",
+                "--tone",
+                tone,
+            ])
+        };
+        assert!(send("440").is_ok());
+        for tone in ["nan", "inf", "-440", "0"] {
+            assert!(send(tone).is_err());
+        }
+    }
+
+    #[test]
+    fn ring_buffer_size_is_rounded_to_power_of_two() {
+        assert_eq!(
+            ring_buffer_size_for(RING_BUFFER_SIZE),
+            Some(RING_BUFFER_SIZE)
+        );
+        assert_eq!(ring_buffer_size_for(10000), Some(16384));
+        assert_eq!(ring_buffer_size_for(PACKET_SIZE + 1), Some(512));
+        assert_eq!(ring_buffer_size_for(PACKET_SIZE), Some(512));
+        assert_eq!(ring_buffer_size_for(1), None);
+        assert_eq!(ring_buffer_size_for(usize::MAX), None);
+    }
 }
@@ -0,0 +1,447 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    ops::Range,
+    thread,
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+// Multicast group and port of mDNS, RFC 6762
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+// Service type senders are advertised under, RFC 6763
+const SERVICE: [&str; 3] = ["_netaudio", "_udp", "local"];
+
+// DNS record types and classes
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000; // Records only this host answers for
+
+// Flags of an authoritative response
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+// Size of a DNS message header
+const DNS_HEADER_SIZE: usize = 12;
+
+// Seconds other hosts may cache the records
+const RECORD_TTL: u32 = 120;
+
+// Time spent listening for advertised streams
+pub const BROWSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Unsolicited announcements at startup, a second apart as RFC 6762 asks
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// A stream advertised on the local network
+pub struct Service {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub properties: Vec<String>, // `key=value` pairs of the TXT record
+}
+
+// Appends a domain name, one length-prefixed label after another
+fn write_name<'a>(buffer: &mut Vec<u8>, labels: impl IntoIterator<Item = &'a str>) {
+    for label in labels {
+        // Labels are at most 63 bytes
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label);
+    }
+    buffer.push(0);
+}
+
+fn write_record<'a>(
+    buffer: &mut Vec<u8>,
+    name: impl IntoIterator<Item = &'a str>,
+    kind: u16,
+    class: u16,
+    data: &[u8],
+) {
+    write_name(buffer, name);
+    buffer.extend_from_slice(&kind.to_be_bytes());
+    buffer.extend_from_slice(&class.to_be_bytes());
+    buffer.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(data);
+}
+
+// Reads the domain name at `offset`, following compression pointers, returns its labels and
+// the offset after it
+fn read_name(message: &[u8], mut offset: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Pointers only lead backwards in well-formed messages, a bound keeps loops finite
+    for _ in 0..64 {
+        let length = *message.get(offset)? as usize;
+        match length {
+            0 => return Some((labels, end.unwrap_or(offset + 1))),
+            0xc0.. => {
+                let pointer = (length & 0x3f) << 8 | *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            _ => {
+                let label = message.get(offset + 1..offset + 1 + length)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + length;
+            }
+        }
+    }
+    None
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        message.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn is_name(labels: &[String], expected: &[impl AsRef<str>]) -> bool {
+    labels.len() == expected.len()
+        && labels
+            .iter()
+            .zip(expected)
+            .all(|(label, expected)| label.eq_ignore_ascii_case(expected.as_ref()))
+}
+
+// Names and types of the questions in a query, nothing for responses
+fn questions(message: &[u8]) -> Option<Vec<(Vec<String>, u16)>> {
+    if read_u16(message, 2)? & 0x8000 != 0 {
+        return Some(Vec::new());
+    }
+    let mut offset = DNS_HEADER_SIZE;
+    let mut questions = Vec::new();
+    for _ in 0..read_u16(message, 4)? {
+        let (name, next) = read_name(message, offset)?;
+        questions.push((name, read_u16(message, next)?));
+        offset = next + 4;
+    }
+    Some(questions)
+}
+
+// A resource record of a response, with its data left in the message
+struct Record {
+    name: Vec<String>,
+    kind: u16,
+    data: Range<usize>,
+}
+
+// Answers and additional records of a response, nothing for queries
+fn records(message: &[u8]) -> Option<Vec<Record>> {
+    if read_u16(message, 2)? & 0x8000 == 0 {
+        return Some(Vec::new());
+    }
+    let mut offset = DNS_HEADER_SIZE;
+    for _ in 0..read_u16(message, 4)? {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+    // Summed wider than the fields, which any host on the link may set
+    let count = (6..12)
+        .step_by(2)
+        .map(|field| read_u16(message, field).map(usize::from));
+    let mut records = Vec::new();
+    for _ in 0..count.sum::<Option<usize>>()? {
+        let (name, next) = read_name(message, offset)?;
+        let kind = read_u16(message, next)?;
+        let length = read_u16(message, next + 8)? as usize;
+        let data = next + 10..next + 10 + length;
+        message.get(data.clone())?;
+        records.push(Record { name, kind, data });
+        offset = next + 10 + length;
+    }
+    Some(records)
+}
+
+// Name of this host, under which its address is published
+fn host_name() -> String {
+    let mut buffer = [0; 256];
+    // SAFETY: the buffer is valid for its whole length, which is passed along
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(0);
+    match (result, std::str::from_utf8(&buffer[..length])) {
+        // Only the first label, the domain is always .local
+        (0, Ok(name)) if !name.is_empty() => name.split('.').next().unwrap_or(name).to_string(),
+        _ => "netaudio".to_string(),
+    }
+}
+
+// Address other hosts reach this one at, the one mDNS traffic leaves from
+fn local_ipv4() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+// Builds the response describing a stream: which instance offers the service, its port and
+// properties, and the address of its host
+fn response(
+    name: &str,
+    port: u16,
+    properties: &[String],
+    host: &str,
+    address: Ipv4Addr,
+) -> Vec<u8> {
+    let instance = || [name].into_iter().chain(SERVICE);
+    let host_name = || [host, "local"];
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&0_u16.to_be_bytes());
+    message.extend_from_slice(&FLAGS_RESPONSE.to_be_bytes());
+    for count in [0_u16, 4, 0, 0] {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+
+    let mut data = Vec::new();
+    write_name(&mut data, instance());
+    write_record(&mut message, SERVICE, TYPE_PTR, CLASS_IN, &data);
+
+    data.clear();
+    data.extend_from_slice(&[0, 0, 0, 0]); // Priority and weight
+    data.extend_from_slice(&port.to_be_bytes());
+    write_name(&mut data, host_name());
+    let class = CLASS_IN | CACHE_FLUSH;
+    write_record(&mut message, instance(), TYPE_SRV, class, &data);
+
+    data.clear();
+    for property in properties {
+        data.push(property.len().min(255) as u8);
+        data.extend_from_slice(&property.as_bytes()[..property.len().min(255)]);
+    }
+    write_record(&mut message, instance(), TYPE_TXT, class, &data);
+    write_record(&mut message, host_name(), TYPE_A, class, &address.octets());
+    message
+}
+
+// Joins the mDNS group on its port, next to any other responder on the host
+fn join() -> Result<UdpSocket, &'static str> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+        .map_err(|_| "unable to open mDNS socket")?;
+    socket
+        .set_reuse_address(true)
+        .map_err(|_| "unable to open mDNS socket")?;
+    socket
+        .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())
+        .map_err(|_| "unable to bind to the mDNS port")?;
+    socket
+        .join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)
+        .map_err(|_| "unable to join the mDNS group")?;
+    Ok(socket.into())
+}
+
+// Advertises a stream under `name` on the local network from a background thread, announcing
+// it at once and answering queries for it from then on
+pub fn advertise(name: &str, port: u16, properties: &[String]) -> Result<(), &'static str> {
+    let socket = join()?;
+    let address = local_ipv4().ok_or("no network address to advertise")?;
+    let response = response(name, port, properties, &host_name(), address);
+    let instance: Vec<String> = [name]
+        .into_iter()
+        .chain(SERVICE)
+        .map(String::from)
+        .collect();
+    info!("advertising stream {} via mDNS", name);
+
+    thread::spawn(move || {
+        let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+        for announcement in 0..ANNOUNCEMENTS {
+            if announcement > 0 {
+                thread::sleep(ANNOUNCE_INTERVAL);
+            }
+            let _ = socket.send_to(&response, group);
+        }
+
+        let mut buffer = [0; 9000];
+        let mut reply = response.clone();
+        while let Ok((length, source)) = socket.recv_from(&mut buffer) {
+            let Some(questions) = questions(&buffer[..length]) else {
+                continue;
+            };
+            let asked = questions.iter().any(|(name, kind)| {
+                (is_name(name, &SERVICE) && matches!(*kind, TYPE_PTR | TYPE_ANY))
+                    || is_name(name, &instance)
+            });
+            if !asked {
+                continue;
+            }
+            // Queries from other ports come from simple resolvers expecting a direct answer
+            // that carries their id
+            if source.port() == MDNS_PORT {
+                let _ = socket.send_to(&response, group);
+            } else {
+                reply[..2].copy_from_slice(&buffer[..2]);
+                let _ = socket.send_to(&reply, source);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Looks for advertised streams for up to `timeout`, passing each new one to `found` until it
+// returns true
+pub fn browse(
+    timeout: Duration,
+    mut found: impl FnMut(&Service) -> bool,
+) -> Result<(), &'static str> {
+    // Asking from a port of its own brings answers straight back
+    let socket =
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|_| "unable to open mDNS socket")?;
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    write_name(&mut query, SERVICE);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    socket
+        .send_to(&query, (MDNS_GROUP, MDNS_PORT))
+        .map_err(|_| "unable to send mDNS query")?;
+
+    let deadline = Instant::now() + timeout;
+    let mut seen = Vec::new();
+    let mut buffer = [0; 9000];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now())
+        && !remaining.is_zero()
+    {
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|_| "unable to configure socket")?;
+        let Ok((length, source)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let message = &buffer[..length];
+        let Some(records) = records(message) else {
+            continue;
+        };
+        for service in services(message, &records, source.ip()) {
+            if seen.contains(&service.name) {
+                continue;
+            }
+            seen.push(service.name.clone());
+            if found(&service) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Address of the stream advertised under `name`
+pub fn find(name: &str) -> Result<SocketAddr, &'static str> {
+    let mut addr = None;
+    browse(BROWSE_TIMEOUT, |service| {
+        addr = service
+            .name
+            .eq_ignore_ascii_case(name)
+            .then_some(service.addr);
+        addr.is_some()
+    })?;
+    addr.ok_or("no stream advertised under that name")
+}
+
+// Streams described by the records of one response, reached at the address published for
+// their host or else where the response came from
+fn services(message: &[u8], records: &[Record], source: IpAddr) -> Vec<Service> {
+    let record = |name: &[String], kind| {
+        records
+            .iter()
+            .find(|record| record.kind == kind && record.name == name)
+    };
+    let mut services = Vec::new();
+    for pointer in records
+        .iter()
+        .filter(|record| record.kind == TYPE_PTR && is_name(&record.name, &SERVICE))
+    {
+        let Some((instance, _)) = read_name(message, pointer.data.start) else {
+            continue;
+        };
+        let Some(srv) = record(&instance, TYPE_SRV).filter(|srv| srv.data.len() >= 7) else {
+            continue;
+        };
+        let Some(port) = read_u16(message, srv.data.start + 4) else {
+            continue;
+        };
+        let ip = read_name(message, srv.data.start + 6)
+            .and_then(|(host, _)| record(&host, TYPE_A))
+            .and_then(|a| <[u8; 4]>::try_from(&message[a.data.clone()]).ok())
+            .map_or(source, IpAddr::from);
+
+        let mut properties = Vec::new();
+        if let Some(txt) = record(&instance, TYPE_TXT) {
+            let mut data = &message[txt.data.clone()];
+            while let [length, rest @ ..] = data {
+                let length = (*length as usize).min(rest.len());
+                if length > 0 {
+                    properties.push(String::from_utf8_lossy(&rest[..length]).into_owned());
+                }
+                data = &rest[length..];
+            }
+        }
+        services.push(Service {
+            name: instance.first().cloned().unwrap_or_default(),
+            addr: SocketAddr::new(ip, port),
+            properties,
+        });
+    }
+    services
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_describe_the_stream() {
+        let properties = ["rate=48000".to_string(), "channels=2".to_string()];
+        let address = Ipv4Addr::new(192, 168, 1, 10);
+        let message = response("studio", 9000, &properties, "pi", address);
+        let records = records(&message).unwrap();
+        assert_eq!(records.len(), 4);
+
+        let source = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let services = services(&message, &records, source);
+        let [service] = &services[..] else {
+            panic!("expected one service");
+        };
+        assert_eq!(service.name, "studio");
+        assert_eq!(service.addr.port(), 9000);
+        assert_eq!(service.addr.ip(), IpAddr::from(address));
+        assert_eq!(service.properties, properties);
+
+        // Record counts adding up beyond 16 bits only make the response too short
+        let mut crafted = message.clone();
+        crafted[6..12].fill(0xff);
+        assert!(super::records(&crafted).is_none());
+    }
+
+    #[test]
+    fn queries_are_read_with_compressed_names() {
+        let mut query = vec![0, 7, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+        write_name(&mut query, SERVICE);
+        query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        // The second question points back at the service name after its own label
+        query.extend_from_slice(&[6, b's', b't', b'u', b'd', b'i', b'o', 0xc0, 12]);
+        query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        let questions = questions(&query).unwrap();
+        assert!(is_name(&questions[0].0, &SERVICE));
+        assert_eq!(questions[0].1, TYPE_PTR);
+        assert!(is_name(
+            &questions[1].0,
+            &["studio", "_NETAUDIO", "_udp", "local"]
+        ));
+        assert_eq!(questions[1].1, TYPE_SRV);
+
+        // A pointer to itself never ends
+        assert!(read_name(&[0xc0, 0], 0).is_none());
+        assert!(records(&query).unwrap().is_empty());
+    }
+}
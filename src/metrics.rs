@@ -0,0 +1,516 @@
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use jack::{Client, Control, NotificationHandler, PortId};
+
+use crate::{connect, packet};
+
+// Time allowed for a scraper to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Time without audio packets after which the status reports a stream as stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Counters read by the metrics endpoint. Most are updated by the network loop, the receiver's
+// buffer fill by the JACK process thread and xruns by JACK's notifications, all with relaxed
+// atomics that never block the real-time thread.
+#[derive(Default)]
+pub struct Metrics {
+    pub packets: AtomicU64,     // Audio packets sent or received
+    pub bytes: AtomicU64,       // Audio payload bytes sent or received
+    pub underruns: AtomicU64,   // JACK periods played without enough data (receiver)
+    pub overruns: AtomicU64,    // Packets or periods that did not fit the ring buffer
+    pub lost: AtomicU64,        // Packets skipped in the sequence (receiver)
+    pub late: AtomicU64,        // Packets arriving after being counted as lost (receiver)
+    pub duplicates: AtomicU64,  // Packets received more than once (receiver)
+    pub replayed: AtomicU64,    // Authenticated packets dropped as replays (receiver)
+    pub jitter: AtomicU64,      // Smoothed interarrival jitter in ms as f64 bits (receiver)
+    pub buffered: AtomicU64,    // Ring buffer fill in bytes
+    pub limited: AtomicU64,     // JACK periods the output limiter engaged in (receiver)
+    pub malformed: AtomicU64,   // Packets dropped for a bad header, length or key (receiver)
+    pub recovered: AtomicU64,   // Lost packets replaced by a copy or from parity (receiver)
+    pub sample_rate: AtomicU64, // JACK sample rate in Hz, set at startup
+    pub last_packet: AtomicU64, // Wall clock in µs of the newest audio packet, 0 before any
+    pub xruns: AtomicU64,       // Over- and underruns of the JACK server's own buffers
+    pub rtt: AtomicU64,         // Latest round-trip time in µs measured by --ping, 0 before any
+    pub report: AtomicU64,      // Packed loss report not yet taken by --adaptive-bitrate (sender)
+    pub destinations: Vec<Arc<DestinationMetrics>>, // One per --fanout destination (sender)
+}
+
+// Counters of a further receiver a sender fans the stream out to
+pub struct DestinationMetrics {
+    pub addr: SocketAddr,
+    pub packets: AtomicU64,  // Datagrams sent to it
+    pub failed: AtomicU64,   // Datagrams it missed through errors or while backing off
+    pub expected: AtomicU64, // Audio packets its loss reports account for
+    pub lost: AtomicU64,     // Audio packets it reported lost
+    pub report: AtomicU64,   // Packed loss report not yet taken by --adaptive-bitrate
+}
+
+impl DestinationMetrics {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            packets: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            expected: AtomicU64::new(0),
+            lost: AtomicU64::new(0),
+            report: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    // Formats the current values in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut text = String::new();
+        for (name, kind, help, value) in [
+            (
+                "packets_total",
+                "counter",
+                "Audio packets sent or received.",
+                load(&self.packets).to_string(),
+            ),
+            (
+                "bytes_total",
+                "counter",
+                "Audio payload bytes sent or received.",
+                load(&self.bytes).to_string(),
+            ),
+            (
+                "underruns_total",
+                "counter",
+                "JACK periods played without enough buffered audio.",
+                load(&self.underruns).to_string(),
+            ),
+            (
+                "overruns_total",
+                "counter",
+                "Audio dropped because the ring buffer was full.",
+                load(&self.overruns).to_string(),
+            ),
+            (
+                "packets_lost_total",
+                "counter",
+                "Packets missing from the sequence.",
+                load(&self.lost).to_string(),
+            ),
+            (
+                "packets_late_total",
+                "counter",
+                "Packets arriving after being counted as lost.",
+                load(&self.late).to_string(),
+            ),
+            (
+                "packets_duplicate_total",
+                "counter",
+                "Packets received more than once and dropped.",
+                load(&self.duplicates).to_string(),
+            ),
+            (
+                "packets_replayed_total",
+                "counter",
+                "Authenticated packets dropped as stale or replayed.",
+                load(&self.replayed).to_string(),
+            ),
+            (
+                "jitter_milliseconds",
+                "gauge",
+                "Smoothed RFC 3550 interarrival jitter.",
+                f64::from_bits(load(&self.jitter)).to_string(),
+            ),
+            (
+                "limited_periods_total",
+                "counter",
+                "JACK periods in which the output limiter engaged.",
+                load(&self.limited).to_string(),
+            ),
+            (
+                "packets_malformed_total",
+                "counter",
+                "Packets dropped because their length did not match the header.",
+                load(&self.malformed).to_string(),
+            ),
+            (
+                "packets_recovered_total",
+                "counter",
+                "Lost packets replaced by a redundant copy or rebuilt from parity.",
+                load(&self.recovered).to_string(),
+            ),
+            (
+                "xruns_total",
+                "counter",
+                "Xruns reported by the JACK server.",
+                load(&self.xruns).to_string(),
+            ),
+            (
+                "round_trip_milliseconds",
+                "gauge",
+                "Latest round-trip time measured by --ping, 0 before any.",
+                (load(&self.rtt) as f64 / 1000.0).to_string(),
+            ),
+            (
+                "buffered_bytes",
+                "gauge",
+                "Audio waiting in the ring buffer.",
+                load(&self.buffered).to_string(),
+            ),
+        ] {
+            let _ = write!(
+                text,
+                "# HELP netaudio_{0} {2}\n# TYPE netaudio_{0} {1}\nnetaudio_{0} {3}\n",
+                name, kind, help, value
+            );
+        }
+        // Each destination is a label of the same metrics
+        if !self.destinations.is_empty() {
+            for (name, help, value) in [
+                (
+                    "destination_packets_total",
+                    "Datagrams sent to a --fanout destination.",
+                    (|destination| &destination.packets) as fn(&DestinationMetrics) -> &AtomicU64,
+                ),
+                (
+                    "destination_failed_total",
+                    "Datagrams a --fanout destination missed through send errors.",
+                    |destination| &destination.failed,
+                ),
+                (
+                    "destination_lost_total",
+                    "Audio packets a --fanout destination reported lost.",
+                    |destination| &destination.lost,
+                ),
+            ] {
+                let _ = write!(
+                    text,
+                    "# HELP netaudio_{0} {1}\n# TYPE netaudio_{0} counter\n",
+                    name, help
+                );
+                for destination in &self.destinations {
+                    let _ = writeln!(
+                        text,
+                        "netaudio_{}{{destination=\"{}\"}} {}",
+                        name,
+                        destination.addr,
+                        load(value(destination))
+                    );
+                }
+            }
+        }
+        text
+    }
+
+    // Formats the current values as a JSON status document
+    fn status(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let last_packet = load(&self.last_packet);
+        let age = Duration::from_micros(packet::now_micros().saturating_sub(last_packet));
+        let (state, age_ms) = match last_packet {
+            0 => ("waiting", "null".to_string()),
+            _ if age < STALL_TIMEOUT => ("streaming", age.as_millis().to_string()),
+            _ => ("stalled", age.as_millis().to_string()),
+        };
+        let rtt_ms = match load(&self.rtt) {
+            0 => "null".to_string(),
+            rtt => (rtt as f64 / 1000.0).to_string(),
+        };
+        let mut text = format!(
+            "{{\"state\":\"{}\",\"last_packet_ms\":{},\"jitter_ms\":{},\"rtt_ms\":{}",
+            state,
+            age_ms,
+            f64::from_bits(load(&self.jitter)),
+            rtt_ms
+        );
+        for (name, value) in [
+            ("sample_rate", &self.sample_rate),
+            ("packets", &self.packets),
+            ("bytes", &self.bytes),
+            ("underruns", &self.underruns),
+            ("overruns", &self.overruns),
+            ("lost", &self.lost),
+            ("late", &self.late),
+            ("duplicates", &self.duplicates),
+            ("replayed", &self.replayed),
+            ("malformed", &self.malformed),
+            ("recovered", &self.recovered),
+            ("limited", &self.limited),
+            ("xruns", &self.xruns),
+            ("buffered_bytes", &self.buffered),
+        ] {
+            let _ = write!(text, ",\"{}\":{}", name, load(value));
+        }
+        if !self.destinations.is_empty() {
+            let destinations: Vec<_> = self
+                .destinations
+                .iter()
+                .map(|destination| {
+                    format!(
+                        "{{\"addr\":\"{}\",\"packets\":{},\"failed\":{},\"expected\":{},\"lost\":{}}}",
+                        destination.addr,
+                        load(&destination.packets),
+                        load(&destination.failed),
+                        load(&destination.expected),
+                        load(&destination.lost)
+                    )
+                })
+                .collect();
+            let _ = write!(text, ",\"destinations\":[{}]", destinations.join(","));
+        }
+        text.push('}');
+        text
+    }
+}
+
+// Counter values at the start of a summary interval
+#[derive(Default)]
+struct Totals {
+    packets: u64,
+    bytes: u64,
+    lost: u64,
+    underruns: u64,
+    overruns: u64,
+    xruns: u64,
+    destinations: Vec<[u64; 4]>, // Packets, failed, expected and lost of each destination
+}
+
+impl Metrics {
+    fn totals(&self) -> Totals {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        Totals {
+            packets: load(&self.packets),
+            bytes: load(&self.bytes),
+            lost: load(&self.lost),
+            underruns: load(&self.underruns),
+            overruns: load(&self.overruns),
+            xruns: load(&self.xruns),
+            destinations: self
+                .destinations
+                .iter()
+                .map(|destination| {
+                    [
+                        load(&destination.packets),
+                        load(&destination.failed),
+                        load(&destination.expected),
+                        load(&destination.lost),
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    // Describes the `elapsed` time since `start` in one line. Buffered bytes drain at
+    // `bytes_per_second`, which turns the fill into time.
+    fn summary(
+        &self,
+        start: &Totals,
+        elapsed: Duration,
+        direction: &str,
+        bytes_per_second: f64,
+    ) -> String {
+        let end = self.totals();
+        let packets = end.packets - start.packets;
+        let lost = end.lost - start.lost;
+        let seconds = elapsed.as_secs_f64();
+        let buffered = self.buffered.load(Ordering::Relaxed) as f64;
+        let mut summary = format!(
+            "last {:.1}s: {} packets {}, {:.1} kB/s, {:.1}% lost, {} underruns, {} overruns, \
+             {} JACK xruns, {:.1} ms buffered",
+            seconds,
+            packets,
+            direction,
+            (end.bytes - start.bytes) as f64 / seconds / 1000.0,
+            match packets + lost {
+                0 => 0.0,
+                expected => lost as f64 * 100.0 / expected as f64,
+            },
+            end.underruns - start.underruns,
+            end.overruns - start.overruns,
+            end.xruns - start.xruns,
+            buffered * 1000.0 / bytes_per_second
+        );
+        for ((destination, end), start) in self
+            .destinations
+            .iter()
+            .zip(&end.destinations)
+            .zip(&start.destinations)
+        {
+            let [packets, failed, expected, lost] = [0, 1, 2, 3].map(|i| end[i] - start[i]);
+            let _ = write!(
+                summary,
+                "; {}: {} packets, {} failed, {:.1}% lost",
+                destination.addr,
+                packets,
+                failed,
+                match expected {
+                    0 => 0.0,
+                    expected => lost as f64 * 100.0 / expected as f64,
+                }
+            );
+        }
+        summary
+    }
+}
+
+// Prints a summary of the stream every `interval` on a background thread, `direction` being
+// what happens to the packets, sent or received. Named streams lead with their name.
+pub fn report(
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    name: Option<String>,
+    direction: &'static str,
+    bytes_per_second: f64,
+) {
+    thread::spawn(move || {
+        let mut start = (metrics.totals(), Instant::now());
+        loop {
+            thread::sleep(interval);
+            let end = (metrics.totals(), Instant::now());
+            let elapsed = end.1 - start.1;
+            let summary = metrics.summary(&start.0, elapsed, direction, bytes_per_second);
+            match &name {
+                Some(name) => info!("{}: {}", name, summary),
+                None => info!("{}", summary),
+            }
+            start = end;
+        }
+    });
+}
+
+// Counts the xruns JACK reports, and passes on ports appearing for --connect
+pub struct Notifications(pub Arc<Metrics>);
+
+impl NotificationHandler for Notifications {
+    fn xrun(&mut self, _: &Client) -> Control {
+        self.0.xruns.fetch_add(1, Ordering::Relaxed);
+        Control::Continue
+    }
+
+    fn port_registration(&mut self, _: &Client, _: PortId, registered: bool) {
+        if registered {
+            connect::port_appeared();
+        }
+    }
+}
+
+// Answers a single HTTP request, serving the metrics at /metrics and the status at /status
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    // The request line is all that matters, and it fits the first read
+    let mut request = [0; 1024];
+    let received = stream.read(&mut request)?;
+    let request = &request[..received];
+    let (status, content_type, body) = if request.starts_with(b"GET /metrics ") {
+        ("200 OK", "text/plain; version=0.0.4", metrics.render())
+    } else if request.starts_with(b"GET /status ") {
+        ("200 OK", "application/json", metrics.status())
+    } else {
+        ("404 Not Found", "text/plain", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+// Serves the metrics and status over HTTP on a background thread
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), &'static str> {
+    let listener = TcpListener::bind(addr).map_err(|_| "unable to bind metrics address")?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &metrics);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_reports_the_stream_as_json() {
+        let metrics = Metrics::default();
+        metrics.sample_rate.store(48000, Ordering::Relaxed);
+        assert!(
+            metrics
+                .status()
+                .starts_with(r#"{"state":"waiting","last_packet_ms":null,"#)
+        );
+
+        metrics.packets.store(100, Ordering::Relaxed);
+        metrics
+            .last_packet
+            .store(packet::now_micros(), Ordering::Relaxed);
+        let status = metrics.status();
+        assert!(status.starts_with(r#"{"state":"streaming","last_packet_ms":0,"#));
+        assert!(status.contains(r#","sample_rate":48000,"packets":100,"#));
+        assert!(status.ends_with(r#","buffered_bytes":0}"#));
+
+        metrics.last_packet.store(1, Ordering::Relaxed);
+        assert!(metrics.status().starts_with(r#"{"state":"stalled","#));
+    }
+
+    #[test]
+    fn summary_covers_the_interval_only() {
+        let metrics = Metrics::default();
+        metrics.packets.store(10, Ordering::Relaxed);
+        metrics.underruns.store(5, Ordering::Relaxed);
+        let start = metrics.totals();
+
+        metrics.packets.store(110, Ordering::Relaxed);
+        metrics.bytes.store(96000, Ordering::Relaxed);
+        metrics.lost.store(25, Ordering::Relaxed);
+        metrics.underruns.store(7, Ordering::Relaxed);
+        metrics.buffered.store(9600, Ordering::Relaxed);
+        metrics.xruns.store(1, Ordering::Relaxed);
+        assert_eq!(
+            metrics.summary(&start, Duration::from_secs(2), "received", 96000.0),
+            "last 2.0s: 100 packets received, 48.0 kB/s, 20.0% lost, 2 underruns, 0 overruns, \
+             1 JACK xruns, 100.0 ms buffered"
+        );
+    }
+
+    #[test]
+    fn destinations_are_reported_each() {
+        let addr = "10.0.0.2:9000".parse().unwrap();
+        let metrics = Metrics {
+            destinations: vec![Arc::new(DestinationMetrics::new(addr))],
+            ..Metrics::default()
+        };
+        let start = metrics.totals();
+        let destination = &metrics.destinations[0];
+        destination.packets.store(98, Ordering::Relaxed);
+        destination.failed.store(2, Ordering::Relaxed);
+        destination.expected.store(100, Ordering::Relaxed);
+        destination.lost.store(5, Ordering::Relaxed);
+
+        assert!(
+            metrics
+                .render()
+                .contains("netaudio_destination_failed_total{destination=\"10.0.0.2:9000\"} 2\n")
+        );
+        assert!(metrics.status().ends_with(
+            r#","destinations":[{"addr":"10.0.0.2:9000","packets":98,"failed":2,"expected":100,"lost":5}]}"#
+        ));
+        assert!(
+            metrics
+                .summary(&start, Duration::from_secs(1), "sent", 96000.0)
+                .ends_with("; 10.0.0.2:9000: 98 packets, 2 failed, 5.0% lost")
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+    thread,
+};
+
+use crate::{metrics::Metrics, packet::MAX_DATAGRAM_SIZE};
+
+// Addresses the receiver answers to, applying to every stream on the server. Inserting a
+// stream's name, as in /netaudio/<name>/gain, applies them to that stream only.
+const PREFIX: &str = "/netaudio/";
+const GAIN: &str = "/netaudio/gain";
+const MUTE: &str = "/netaudio/mute";
+const STATS: &str = "/netaudio/stats";
+
+// Gains accepted over OSC in dB, beyond these a typo is more likely than intent
+const MIN_GAIN_DB: f32 = -120.0;
+const MAX_GAIN_DB: f32 = 24.0;
+
+// Settings changed at runtime over OSC. The process callback reads them once per period,
+// atomics keep that free of locks.
+pub struct Controls {
+    gain: AtomicU32, // Linear output gain as f32 bits
+    muted: AtomicBool,
+}
+
+impl Controls {
+    pub fn new() -> Self {
+        Self {
+            gain: AtomicU32::new(1.0_f32.to_bits()),
+            muted: AtomicBool::new(false),
+        }
+    }
+
+    // Linear gain the outputs should play at, 0 while muted
+    pub fn level(&self) -> f32 {
+        if self.muted.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            f32::from_bits(self.gain.load(Ordering::Relaxed))
+        }
+    }
+
+    pub fn mute(&self) {
+        self.muted.store(true, Ordering::Relaxed);
+    }
+
+    fn gain_db(&self) -> f32 {
+        20.0 * f32::from_bits(self.gain.load(Ordering::Relaxed)).log10()
+    }
+}
+
+// An argument of one of the OSC 1.0 standard types
+#[derive(Debug, PartialEq)]
+enum Argument {
+    Int(i32),
+    Float(f32),
+    String(String),
+    True,
+    False,
+}
+
+impl Argument {
+    fn number(&self) -> Option<f32> {
+        match *self {
+            Argument::Int(value) => Some(value as f32),
+            Argument::Float(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// A stream taking messages from an OSC server
+struct Stream {
+    name: Option<String>,
+    controls: Arc<Controls>,
+    metrics: Arc<Metrics>,
+    bytes_per_second: f64,
+}
+
+impl Stream {
+    // Leads the log lines of named streams
+    fn label(&self) -> String {
+        self.name
+            .as_deref()
+            .map(|name| format!("{}: ", name))
+            .unwrap_or_default()
+    }
+
+    // Address stats of this stream are sent back on
+    fn stats_address(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}{}/stats", PREFIX, name),
+            None => STATS.to_string(),
+        }
+    }
+
+    fn stats(&self) -> Vec<Argument> {
+        let (metrics, controls) = (&self.metrics, &self.controls);
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let buffered_ms = load(&metrics.buffered) as f64 * 1000.0 / self.bytes_per_second;
+        vec![
+            Argument::Float(buffered_ms as f32),
+            Argument::Float(f64::from_bits(load(&metrics.jitter)) as f32),
+            Argument::Int(load(&metrics.underruns) as i32),
+            Argument::Int(load(&metrics.lost) as i32),
+            Argument::Int(load(&metrics.late) as i32),
+            Argument::Float(controls.gain_db()),
+            if controls.muted.load(Ordering::Relaxed) {
+                Argument::True
+            } else {
+                Argument::False
+            },
+        ]
+    }
+}
+
+// Streams served on each OSC address, so the streams of a group share one server
+type Streams = Arc<Mutex<Vec<Stream>>>;
+static SERVERS: Mutex<Vec<(SocketAddr, Streams)>> = Mutex::new(Vec::new());
+
+// Listens for OSC messages on a background thread, setting `controls` and answering stats
+// requests from `metrics`. Streams given the address of a running server join it, answering
+// under their `name`.
+pub fn serve(
+    addr: SocketAddr,
+    name: Option<String>,
+    controls: Arc<Controls>,
+    metrics: Arc<Metrics>,
+    bytes_per_second: f64,
+) -> Result<(), &'static str> {
+    let stream = Stream {
+        name,
+        controls,
+        metrics,
+        bytes_per_second,
+    };
+    let mut servers = SERVERS.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some((_, streams)) = servers.iter().find(|(server, _)| *server == addr) {
+        let mut streams = streams.lock().unwrap_or_else(PoisonError::into_inner);
+        if streams.iter().any(|served| served.name == stream.name) {
+            return Err("streams sharing an OSC address need names of their own");
+        }
+        streams.push(stream);
+        return Ok(());
+    }
+    let socket = UdpSocket::bind(addr).map_err(|_| "unable to bind OSC address")?;
+    let streams = Arc::new(Mutex::new(vec![stream]));
+    servers.push((addr, streams.clone()));
+    thread::spawn(move || {
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        while let Ok((received, source)) = socket.recv_from(&mut buffer) {
+            let Some((address, arguments)) = parse(&buffer[..received]) else {
+                warning!("ignoring malformed OSC message from {}", source);
+                continue;
+            };
+            let streams = streams.lock().unwrap_or_else(PoisonError::into_inner);
+            // The streams a message is for, and the address it names for all of them
+            let (targets, command): (Vec<_>, _) = match address
+                .strip_prefix(PREFIX)
+                .and_then(|rest| rest.split_once('/'))
+            {
+                Some((name, command)) => (
+                    streams
+                        .iter()
+                        .filter(|stream| stream.name.as_deref() == Some(name))
+                        .collect(),
+                    format!("{}{}", PREFIX, command),
+                ),
+                None => (streams.iter().collect(), address.clone()),
+            };
+            if targets.is_empty() {
+                warning!("ignoring OSC message to {}", address);
+                continue;
+            }
+            match (command.as_str(), arguments.first()) {
+                (GAIN, Some(gain)) => match gain.number() {
+                    Some(db) if (MIN_GAIN_DB..=MAX_GAIN_DB).contains(&db) => {
+                        let gain = 10.0_f32.powf(db / 20.0);
+                        for stream in targets {
+                            stream
+                                .controls
+                                .gain
+                                .store(gain.to_bits(), Ordering::Relaxed);
+                            info!("{}gain set to {:.1} dB over OSC", stream.label(), db);
+                        }
+                    }
+                    _ => warning!(
+                        "OSC gain must be between {} and {} dB",
+                        MIN_GAIN_DB,
+                        MAX_GAIN_DB
+                    ),
+                },
+                // Without an argument, or with anything but false or zero, mutes
+                (MUTE, argument) => {
+                    let muted = match argument {
+                        Some(Argument::False) => false,
+                        Some(argument) => argument.number() != Some(0.0),
+                        None => true,
+                    };
+                    for stream in targets {
+                        stream.controls.muted.store(muted, Ordering::Relaxed);
+                        let action = if muted { "muted" } else { "unmuted" };
+                        info!("{}{} over OSC", stream.label(), action);
+                    }
+                }
+                // Each stream replies on an address of its own
+                (STATS, _) => {
+                    for stream in targets {
+                        let reply = encode(&stream.stats_address(), &stream.stats());
+                        let _ = socket.send_to(&reply, source);
+                    }
+                }
+                _ => warning!("ignoring OSC message to {}", address),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Reads an OSC string, NUL-terminated and padded to four bytes, returning the rest
+fn read_string(data: &[u8]) -> Option<(String, &[u8])> {
+    let length = data.iter().position(|&byte| byte == 0)?;
+    let string = std::str::from_utf8(&data[..length]).ok()?.to_string();
+    let rest = data.get((length + 1).next_multiple_of(4)..)?;
+    Some((string, rest))
+}
+
+fn write_string(message: &mut Vec<u8>, string: &str) {
+    message.extend_from_slice(string.as_bytes());
+    let padded = (string.len() + 1).next_multiple_of(4);
+    message.resize(message.len() + padded - string.len(), 0);
+}
+
+// Splits a message into its address and arguments. Bundles are not taken apart.
+fn parse(datagram: &[u8]) -> Option<(String, Vec<Argument>)> {
+    let (address, rest) = read_string(datagram)?;
+    if !address.starts_with('/') {
+        return None;
+    }
+    // Old senders may leave out the type tags when there are no arguments
+    if rest.is_empty() {
+        return Some((address, Vec::new()));
+    }
+    let (tags, mut rest) = read_string(rest)?;
+    let mut arguments = Vec::new();
+    for tag in tags.strip_prefix(',')?.chars() {
+        let argument = match tag {
+            'i' | 'f' => {
+                let bytes: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+                rest = &rest[4..];
+                match tag {
+                    'i' => Argument::Int(i32::from_be_bytes(bytes)),
+                    _ => Argument::Float(f32::from_be_bytes(bytes)),
+                }
+            }
+            's' => {
+                let (string, next) = read_string(rest)?;
+                rest = next;
+                Argument::String(string)
+            }
+            'T' => Argument::True,
+            'F' => Argument::False,
+            _ => return None,
+        };
+        arguments.push(argument);
+    }
+    Some((address, arguments))
+}
+
+fn encode(address: &str, arguments: &[Argument]) -> Vec<u8> {
+    let mut message = Vec::new();
+    write_string(&mut message, address);
+    let tags: String = arguments
+        .iter()
+        .map(|argument| match argument {
+            Argument::Int(_) => 'i',
+            Argument::Float(_) => 'f',
+            Argument::String(_) => 's',
+            Argument::True => 'T',
+            Argument::False => 'F',
+        })
+        .collect();
+    write_string(&mut message, &format!(",{}", tags));
+    for argument in arguments {
+        match argument {
+            Argument::Int(value) => message.extend_from_slice(&value.to_be_bytes()),
+            Argument::Float(value) => message.extend_from_slice(&value.to_be_bytes()),
+            Argument::String(value) => write_string(&mut message, value),
+            Argument::True | Argument::False => {}
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn messages_set_controls_and_stats_come_back() {
+        // As sent by common control surfaces
+        let mut gain = b"/netaudio/gain\0\0,f\0\0".to_vec();
+        gain.extend_from_slice(&(-6.0_f32).to_be_bytes());
+        assert_eq!(encode(GAIN, &[Argument::Float(-6.0)]), gain);
+        assert_eq!(
+            parse(&gain),
+            Some((GAIN.to_string(), vec![Argument::Float(-6.0)]))
+        );
+        assert_eq!(
+            parse(b"/netaudio/stats\0"),
+            Some((STATS.to_string(), vec![]))
+        );
+        assert_eq!(parse(b"netaudio\0\0\0\0,\0\0\0"), None);
+        assert_eq!(parse(b"/netaudio/mute\0\0,i\0\0\0\0"), None);
+
+        let controls = Arc::new(Controls::new());
+        let metrics = Arc::new(Metrics::default());
+        metrics.buffered.store(9600, Ordering::Relaxed);
+        metrics.underruns.store(3, Ordering::Relaxed);
+        let addr = {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        serve(addr, None, controls.clone(), metrics, 96000.0).unwrap();
+
+        let surface = UdpSocket::bind("127.0.0.1:0").unwrap();
+        surface
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        surface.send_to(&gain, addr).unwrap();
+        surface
+            .send_to(&encode(MUTE, &[Argument::Int(1)]), addr)
+            .unwrap();
+        surface.send_to(&encode(STATS, &[]), addr).unwrap();
+        let mut buffer = [0; 256];
+        let received = surface.recv(&mut buffer).unwrap();
+        let (address, arguments) = parse(&buffer[..received]).unwrap();
+        assert_eq!(address, STATS);
+        assert_eq!(arguments[0], Argument::Float(100.0));
+        assert_eq!(arguments[2], Argument::Int(3));
+        assert!((arguments[5].number().unwrap() + 6.0).abs() < 1e-4);
+        assert_eq!(arguments[6], Argument::True);
+        assert_eq!(controls.level(), 0.0);
+
+        surface
+            .send_to(&encode(MUTE, &[Argument::False]), addr)
+            .unwrap();
+        surface.send_to(&encode(STATS, &[]), addr).unwrap();
+        surface.recv(&mut buffer).unwrap();
+        assert!((controls.level() - 0.501).abs() < 1e-3);
+    }
+
+    #[test]
+    fn named_streams_share_a_server() {
+        let addr = {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        let foh = Arc::new(Controls::new());
+        let monitor = Arc::new(Controls::new());
+        for (name, controls) in [("foh", &foh), ("monitor", &monitor)] {
+            let metrics = Arc::new(Metrics::default());
+            serve(addr, Some(name.into()), controls.clone(), metrics, 96000.0).unwrap();
+        }
+        let metrics = Arc::new(Metrics::default());
+        assert!(serve(addr, Some("foh".into()), foh.clone(), metrics, 96000.0).is_err());
+
+        let surface = UdpSocket::bind("127.0.0.1:0").unwrap();
+        surface
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        surface
+            .send_to(&encode(GAIN, &[Argument::Float(-6.0)]), addr)
+            .unwrap();
+        surface
+            .send_to(&encode("/netaudio/foh/mute", &[]), addr)
+            .unwrap();
+        surface.send_to(&encode(STATS, &[]), addr).unwrap();
+        let mut buffer = [0; 256];
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let received = surface.recv(&mut buffer).unwrap();
+            let (address, arguments) = parse(&buffer[..received]).unwrap();
+            replies.push((address, arguments[6] == Argument::True));
+        }
+        replies.sort();
+        assert_eq!(
+            replies,
+            [
+                ("/netaudio/foh/stats".to_string(), true),
+                ("/netaudio/monitor/stats".to_string(), false)
+            ]
+        );
+        assert_eq!(foh.level(), 0.0);
+        assert!((monitor.level() - 0.501).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    cmp, fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{codec::Codec, format::SampleFormat};
+
+// Size of the header prepended to every packet
+pub const HEADER_SIZE: usize = 16;
+
+// Largest possible UDP payload, enough to hold any datagram
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// Largest payload a header may declare, anything longer cannot have come in one datagram
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE;
+
+// Header flags
+pub const FLAG_PING: u8 = 1 << 0; // Round-trip probe, echoed back by the receiver
+pub const FLAG_PONG: u8 = 1 << 1; // Echoed probe
+pub const FLAG_KEEPALIVE: u8 = 1 << 2; // Heartbeat without audio, sent during gaps
+pub const FLAG_HELLO: u8 = 1 << 3; // Stream settings request, answered with a StreamConfig
+pub const FLAG_REPORT: u8 = 1 << 4; // Receiver feedback carrying a LossReport
+pub const FLAG_END: u8 = 1 << 5; // Last audio packet of the stream
+pub const FLAG_VERIFY: u8 = 1 << 6; // Payload followed by a SampleCount
+pub const FLAG_COPY: u8 = 1 << 7; // Redundant copy of an audio packet sent before
+
+// FEC parity over earlier audio packets. No bits are left, so it combines two flags that never
+// occur together otherwise, receivers without FEC ignore it like any keepalive.
+pub const FLAG_PARITY: u8 = FLAG_KEEPALIVE | FLAG_COPY;
+
+// Key exchange message, exchanged in the clear before anything is sealed with its keys
+pub const FLAG_HANDSHAKE: u8 = FLAG_HELLO | FLAG_REPORT;
+
+// Channels of a stream unless --channels says otherwise, and of peers that do not say
+pub const DEFAULT_CHANNELS: u8 = 2;
+
+// JACK port name for `channel` of `channels` interleaved ones: left and right for stereo, the
+// bare prefix for mono and numbers from 1 otherwise
+pub fn port_name(prefix: &str, channel: usize, channels: usize) -> String {
+    match channels {
+        1 => prefix.to_string(),
+        2 => format!("{}_{}", prefix, ["l", "r"][channel]),
+        _ => format!("{}_{}", prefix, channel + 1),
+    }
+}
+
+// Prefix of the JACK ports of a kind, led by the stream's name when it has one so that the
+// streams of a group keep apart
+pub fn port_prefix(name: Option<&str>, kind: &str) -> String {
+    match name {
+        Some(name) => format!("{}_{}", name, kind),
+        None => kind.to_string(),
+    }
+}
+
+// Sizes of the serialized control payloads
+pub const STREAM_CONFIG_SIZE: usize = 12;
+pub const LOSS_REPORT_SIZE: usize = 8;
+pub const PROBE_SIZE: usize = 8;
+
+// Per-packet header, serialized in network byte order
+#[derive(Clone, Copy)]
+pub struct Header {
+    pub sequence: u32,
+    pub timestamp: u64, // Capture time in microseconds since the UNIX epoch
+    pub flags: u8,
+    pub stream: u8,  // Stream id, separating streams sent to the same receiver
+    pub length: u16, // Payload length in bytes
+}
+
+impl Header {
+    // Serializes the header into the beginning of a buffer
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&self.sequence.to_be_bytes());
+        buffer[4..12].copy_from_slice(&self.timestamp.to_be_bytes());
+        buffer[12] = self.flags;
+        buffer[13] = self.stream;
+        buffer[14..16].copy_from_slice(&self.length.to_be_bytes());
+    }
+
+    // Parses the header from the beginning of a buffer
+    pub fn read(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            sequence: u32::from_be_bytes(buffer.get(0..4)?.try_into().ok()?),
+            timestamp: u64::from_be_bytes(buffer.get(4..12)?.try_into().ok()?),
+            flags: *buffer.get(12)?,
+            stream: *buffer.get(13)?,
+            length: u16::from_be_bytes(buffer.get(14..16)?.try_into().ok()?),
+        })
+    }
+}
+
+// Stream settings a receiver expects, exchanged before audio starts
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StreamConfig {
+    pub packet_size: u16,
+    pub format: SampleFormat,
+    pub planar: bool,
+    pub codec: Codec,
+    pub sample_rate: Option<u32>, // JACK sample rate, unknown for peers from before it was sent
+    pub channels: u8,
+    pub frames_per_packet: Option<u16>, // Set by senders the receiver should follow instead
+}
+
+impl StreamConfig {
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..2].copy_from_slice(&self.packet_size.to_be_bytes());
+        buffer[2] = self.format.id();
+        buffer[3] = self.planar as u8;
+        buffer[4] = self.codec.id();
+        buffer[5..9].copy_from_slice(&self.sample_rate.unwrap_or(0).to_be_bytes());
+        buffer[9] = self.channels;
+        buffer[10..12].copy_from_slice(&self.frames_per_packet.unwrap_or(0).to_be_bytes());
+    }
+
+    pub fn read(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            packet_size: u16::from_be_bytes(buffer.get(0..2)?.try_into().ok()?),
+            format: SampleFormat::from_id(*buffer.get(2)?)?,
+            planar: *buffer.get(3)? != 0,
+            // Receivers from before codecs were added only know PCM
+            codec: match buffer.get(4) {
+                Some(&id) => Codec::from_id(id)?,
+                None => Codec::Pcm,
+            },
+            sample_rate: buffer
+                .get(5..9)
+                .map(|rate| u32::from_be_bytes([rate[0], rate[1], rate[2], rate[3]]))
+                .filter(|&rate| rate > 0),
+            // Always stereo before the channel count was sent
+            channels: buffer.get(9).copied().unwrap_or(DEFAULT_CHANNELS),
+            frames_per_packet: buffer
+                .get(10..12)
+                .map(|frames| u16::from_be_bytes([frames[0], frames[1]]))
+                .filter(|&frames| frames > 0),
+        })
+    }
+}
+
+// Packet loss seen by a receiver over the last report interval
+#[derive(Clone, Copy, Default)]
+pub struct LossReport {
+    pub expected: u32, // Audio packets the sender sent, judging by sequence numbers
+    pub lost: u32,     // Audio packets that never arrived
+}
+
+impl LossReport {
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&self.expected.to_be_bytes());
+        buffer[4..8].copy_from_slice(&self.lost.to_be_bytes());
+    }
+
+    pub fn read(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            expected: u32::from_be_bytes(buffer.get(0..4)?.try_into().ok()?),
+            lost: u32::from_be_bytes(buffer.get(4..8)?.try_into().ok()?),
+        })
+    }
+
+    // Share of expected packets that were lost, in percent
+    pub fn percentage(&self) -> f64 {
+        if self.expected == 0 {
+            0.0
+        } else {
+            self.lost as f64 / self.expected as f64 * 100.0
+        }
+    }
+
+    // Packs the report into one word, handing the latest one between threads in an atomic.
+    // Zero stands for no report, one covering no packets has nothing to act on anyway.
+    pub fn pack(&self) -> u64 {
+        (self.expected as u64) << 32 | self.lost as u64
+    }
+
+    pub fn unpack(word: u64) -> Option<Self> {
+        (word >> 32 != 0).then_some(Self {
+            expected: (word >> 32) as u32,
+            lost: word as u32,
+        })
+    }
+}
+
+// Timing carried by round-trip probes, in microseconds. A ping holds the sender's latest
+// round-trip time, 0 before the first, and its pong the wall clock time the ping arrived at.
+// Peers from before either send the header alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe(pub u64);
+
+impl Probe {
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..8].copy_from_slice(&self.0.to_be_bytes());
+    }
+
+    pub fn read(buffer: &[u8]) -> Option<Self> {
+        Some(Self(u64::from_be_bytes(buffer.get(0..8)?.try_into().ok()?)))
+    }
+}
+
+// Milliseconds from one microsecond timestamp to another, negative if `to` is earlier, as
+// happens between hosts whose clocks disagree
+pub fn millis_between(from: u64, to: u64) -> f64 {
+    to.wrapping_sub(from) as i64 as f64 / 1000.0
+}
+
+// Bytes of the sample counts of `channels` channels, their number and a total for each
+pub fn sample_count_size(channels: usize) -> usize {
+    1 + 8 * channels
+}
+
+// Samples per channel sent before a packet, trailing its payload with --verify. FLAG_VERIFY
+// tells parse to expect it, receivers without --verify skip it.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct SampleCount(pub Vec<u64>);
+
+impl SampleCount {
+    pub fn new(channels: usize) -> Self {
+        Self(vec![0; channels])
+    }
+
+    pub fn size(&self) -> usize {
+        sample_count_size(self.0.len())
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0] = self.0.len() as u8;
+        for (count, bytes) in self.0.iter().zip(buffer[1..].chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&count.to_be_bytes());
+        }
+    }
+
+    pub fn read(buffer: &[u8]) -> Option<Self> {
+        let channels = *buffer.first()? as usize;
+        let counts = buffer.get(1..sample_count_size(channels))?;
+        Some(Self(
+            counts
+                .chunks_exact(8)
+                .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+                .collect(),
+        ))
+    }
+
+    // Counts `samples` interleaved samples, continuing with the channel after the last one
+    // counted. Earlier channels are ahead by one sample while a frame is incomplete.
+    pub fn add_interleaved(&mut self, samples: usize) {
+        let channels = self.0.len();
+        let Some(&first) = self.0.first() else {
+            return;
+        };
+        let next = self.0.iter().position(|&count| count < first).unwrap_or(0);
+        for (channel, count) in self.0.iter_mut().enumerate() {
+            // Position of the channel's first sample among the new ones
+            let offset = (channel + channels - next) % channels;
+            if offset < samples {
+                *count += ((samples - offset - 1) / channels + 1) as u64;
+            }
+        }
+    }
+
+    // Samples per channel this count is ahead of `other`
+    pub fn divergence(&self, other: &Self) -> Vec<i64> {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(count, other)| count.wrapping_sub(*other) as i64)
+            .collect()
+    }
+}
+
+// Reasons a datagram is rejected
+pub enum PacketError {
+    MissingHeader { received: usize },
+    Truncated { declared: usize, received: usize },
+    Excess { declared: usize, received: usize },
+    TooLong { declared: usize },
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::MissingHeader { received } => {
+                write!(f, "packet too short for header, got {} bytes", received)
+            }
+            PacketError::Truncated { declared, received } => write!(
+                f,
+                "truncated packet, expected {} payload bytes, got {}",
+                declared, received
+            ),
+            PacketError::Excess { declared, received } => write!(
+                f,
+                "packet longer than declared, expected {} payload bytes, got {}",
+                declared, received
+            ),
+            PacketError::TooLong { declared } => write!(
+                f,
+                "declared payload of {} bytes exceeds the maximum of {}",
+                declared, MAX_PAYLOAD_SIZE
+            ),
+        }
+    }
+}
+
+// Splits a datagram into its header and the payload declared by it. The datagram must hold
+// exactly the declared payload, plus the sample counts if flagged, so payloads can vary in
+// size from packet to packet.
+pub fn parse(datagram: &[u8]) -> Result<(Header, &[u8]), PacketError> {
+    let header = Header::read(datagram).ok_or(PacketError::MissingHeader {
+        received: datagram.len(),
+    })?;
+    let declared = header.length as usize;
+    if declared > MAX_PAYLOAD_SIZE {
+        return Err(PacketError::TooLong { declared });
+    }
+    let payload = &datagram[HEADER_SIZE..];
+    // Sample counts start with their number of channels
+    let trailer = if header.flags & FLAG_VERIFY != 0 {
+        payload
+            .get(declared)
+            .map_or(1, |&channels| sample_count_size(channels as usize))
+    } else {
+        0
+    };
+    let error = match payload.len().cmp(&(declared + trailer)) {
+        cmp::Ordering::Equal => return Ok((header, &payload[..declared])),
+        cmp::Ordering::Less => PacketError::Truncated {
+            declared: declared + trailer,
+            received: payload.len(),
+        },
+        cmp::Ordering::Greater => PacketError::Excess {
+            declared: declared + trailer,
+            received: payload.len(),
+        },
+    };
+    Err(error)
+}
+
+// Wall clock time in microseconds, comparable across hosts with synchronized clocks
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_micros() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_counts_follow_the_interleaving() {
+        let mut count = SampleCount::new(2);
+        count.add_interleaved(4);
+        assert_eq!(count, SampleCount(vec![2, 2]));
+
+        // An odd number of samples leaves the channels one apart, and the next sample is right
+        count.add_interleaved(3);
+        assert_eq!(count, SampleCount(vec![4, 3]));
+        count.add_interleaved(1);
+        assert_eq!(count, SampleCount(vec![4, 4]));
+
+        let sent = SampleCount(vec![4, 5]);
+        assert_eq!(count.divergence(&sent), [0, -1]);
+
+        let mut buffer = [0; 17];
+        count.write(&mut buffer);
+        assert_eq!(SampleCount::read(&buffer), Some(count));
+        assert_eq!(SampleCount::read(&buffer[..16]), None);
+    }
+
+    #[test]
+    fn sample_counts_cover_any_number_of_channels() {
+        let mut mono = SampleCount::new(1);
+        mono.add_interleaved(5);
+        assert_eq!(mono, SampleCount(vec![5]));
+
+        // Seven samples of three channels end after the first channel of the third frame
+        let mut count = SampleCount::new(3);
+        count.add_interleaved(7);
+        assert_eq!(count, SampleCount(vec![3, 2, 2]));
+        count.add_interleaved(4);
+        assert_eq!(count, SampleCount(vec![4, 4, 3]));
+
+        let mut buffer = [0; 25];
+        assert_eq!(count.size(), buffer.len());
+        count.write(&mut buffer);
+        assert_eq!(SampleCount::read(&buffer), Some(count));
+    }
+
+    #[test]
+    fn probe_delays_may_be_negative() {
+        let mut buffer = [0; PROBE_SIZE];
+        Probe(1_500).write(&mut buffer);
+        assert_eq!(Probe::read(&buffer), Some(Probe(1_500)));
+        assert_eq!(Probe::read(&[]), None);
+        assert_eq!(millis_between(1_000, 3_500), 2.5);
+        // A receiver clock behind the sender's
+        assert_eq!(millis_between(3_500, 1_000), -2.5);
+    }
+
+    #[test]
+    fn declared_length_must_match_the_datagram() {
+        let mut datagram = [0; HEADER_SIZE + 8 + 17];
+        let mut header = Header {
+            sequence: 0,
+            timestamp: 0,
+            flags: 0,
+            stream: 0,
+            length: 8,
+        };
+        header.write(&mut datagram);
+        let (_, payload) = parse(&datagram[..HEADER_SIZE + 8]).ok().unwrap();
+        assert_eq!(payload.len(), 8);
+        assert!(matches!(
+            parse(&datagram[..HEADER_SIZE + 4]),
+            Err(PacketError::Truncated { .. })
+        ));
+        assert!(matches!(parse(&datagram), Err(PacketError::Excess { .. })));
+
+        // Sample counts are only expected when flagged, two channels of them here
+        header.flags = FLAG_VERIFY;
+        header.write(&mut datagram);
+        datagram[HEADER_SIZE + 8] = 2;
+        let (_, payload) = parse(&datagram).ok().unwrap();
+        assert_eq!(payload.len(), 8);
+
+        header.length = u16::MAX;
+        header.write(&mut datagram);
+        assert!(matches!(parse(&datagram), Err(PacketError::TooLong { .. })));
+    }
+
+    #[test]
+    fn stream_configs_from_older_peers_fill_in_defaults() {
+        let config = StreamConfig {
+            packet_size: 480,
+            format: SampleFormat::S16,
+            planar: false,
+            codec: Codec::Lossless,
+            sample_rate: Some(48000),
+            channels: 2,
+            frames_per_packet: Some(120),
+        };
+        let mut buffer = [0; STREAM_CONFIG_SIZE];
+        config.write(&mut buffer);
+        assert_eq!(StreamConfig::read(&buffer), Some(config));
+
+        // Peers from before the sample rate and channels only send the first five bytes
+        let older = StreamConfig::read(&buffer[..5]).unwrap();
+        assert_eq!(older.sample_rate, None);
+        assert_eq!(older.channels, 2);
+        assert_eq!(older.frames_per_packet, None);
+        assert_eq!(StreamConfig::read(&buffer[..4]).unwrap().codec, Codec::Pcm);
+        assert_eq!(StreamConfig::read(&buffer[..3]), None);
+    }
+}
@@ -0,0 +1,408 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+#[cfg(feature = "quic")]
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError, mpsc},
+    thread,
+    time::Duration,
+};
+
+#[cfg(feature = "quic")]
+use quinn::{
+    ClientConfig, Connection, Endpoint, EndpointConfig, SendDatagramError, ServerConfig,
+    TokioRuntime, TransportConfig,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self, DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    },
+};
+#[cfg(feature = "quic")]
+use socket2::{Protocol, Type};
+
+#[cfg(feature = "quic")]
+use crate::{packet::MAX_DATAGRAM_SIZE, transport};
+
+// Protocol named in the TLS handshake, so other QUIC services turn netaudio away
+#[cfg(feature = "quic")]
+const ALPN: &[u8] = b"netaudio";
+
+// Name the receiver's certificate is issued to and the sender asks for
+#[cfg(feature = "quic")]
+const SERVER_NAME: &str = "netaudio";
+
+// Interval between QUIC keepalives, which hold NAT mappings open while audio pauses
+#[cfg(feature = "quic")]
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// A connection delivering nothing for this long is considered dead
+#[cfg(feature = "quic")]
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Delay between attempts to re-establish a lost connection
+#[cfg(feature = "quic")]
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Connects to a receiver over QUIC, returning the socket the sender loop uses in place of a
+// UDP socket connected to the receiver. Packets travel as unreliable QUIC datagrams (RFC
+// 9221), so nothing is retransmitted. Lost connections are re-established in the background,
+// dropping the audio sent in the meantime.
+#[cfg(feature = "quic")]
+pub fn connect<T: ToSocketAddrs>(
+    bind: T,
+    peer: T,
+    ttl: Option<u32>,
+    v6only: Option<bool>,
+) -> Result<UdpSocket, &'static str> {
+    let resolve = |addr: T| addr.to_socket_addrs().ok()?.next();
+    let bind = resolve(bind).ok_or("unable to bind to address")?;
+    let peer = resolve(peer).ok_or("unable to connect")?;
+    let socket = transport::bind_socket(bind, Type::DGRAM, Protocol::UDP, v6only, false)
+        .map_err(|_| "unable to bind to address")?;
+    if let Some(ttl) = ttl {
+        match peer {
+            SocketAddr::V4(_) => socket.set_ttl_v4(ttl),
+            SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl),
+        }
+        .map_err(|_| "unable to set TTL")?;
+    }
+    let (local, relay) = transport::loopback_pair().map_err(|_| "unable to create relay socket")?;
+
+    // The connection runs on a runtime of its own, reporting back once it is up
+    let (connected, result) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build();
+        let Ok(runtime) = runtime else {
+            let _ = connected.send(Err("unable to start async runtime"));
+            return;
+        };
+        runtime.block_on(async move {
+            let endpoint = match client_endpoint(socket.into()) {
+                Ok(endpoint) => endpoint,
+                Err(error) => {
+                    let _ = connected.send(Err(error));
+                    return;
+                }
+            };
+            let Some(relay) = async_socket(relay) else {
+                let _ = connected.send(Err("unable to create relay socket"));
+                return;
+            };
+            let mut connection = match open(&endpoint, peer).await {
+                Some(connection) => connection,
+                None => {
+                    let _ = connected.send(Err("unable to connect"));
+                    return;
+                }
+            };
+            let _ = connected.send(Ok(()));
+
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                forward(&connection, &relay, &mut buffer).await;
+                warning!("QUIC connection lost, reconnecting");
+                connection = loop {
+                    tokio::time::sleep(RECONNECT_INTERVAL).await;
+                    if let Some(connection) = open(&endpoint, peer).await {
+                        break connection;
+                    }
+                };
+                info!("QUIC connection re-established");
+
+                // Audio queued up while disconnected is stale by now
+                while relay.try_recv(&mut buffer).is_ok() {}
+            }
+        });
+    });
+    result.recv().map_err(|_| "unable to connect")??;
+    Ok(local)
+}
+
+// Accepts senders over QUIC, returning the socket the receiver loop uses in place of a bound
+// UDP socket. Every connection feeds the receiver, replies go back on the newest one.
+#[cfg(feature = "quic")]
+pub fn listen<T: ToSocketAddrs>(bind: T, v6only: Option<bool>) -> Result<UdpSocket, &'static str> {
+    let bind = bind
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or("unable to bind to address")?;
+    let socket = transport::bind_socket(bind, Type::DGRAM, Protocol::UDP, v6only, false)
+        .map_err(|_| "unable to bind to address")?;
+    let server_config = server_config()?;
+    let (local, relay) = transport::loopback_pair().map_err(|_| "unable to create relay socket")?;
+
+    let (started, result) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build();
+        let Ok(runtime) = runtime else {
+            let _ = started.send(Err("unable to start async runtime"));
+            return;
+        };
+        runtime.block_on(async move {
+            let endpoint = Endpoint::new(
+                EndpointConfig::default(),
+                Some(server_config),
+                socket.into(),
+                Arc::new(TokioRuntime),
+            );
+            let (Ok(endpoint), Some(relay)) = (endpoint, async_socket(relay)) else {
+                let _ = started.send(Err("unable to bind to address"));
+                return;
+            };
+            let _ = started.send(Ok(()));
+            let relay = Arc::new(relay);
+            let current: Arc<Mutex<Option<Connection>>> = Arc::default();
+
+            // Replies from the receiver loop go to the sender connected last
+            let reply_relay = relay.clone();
+            let reply_connection = current.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+                while let Ok(length) = reply_relay.recv(&mut buffer).await {
+                    let connection = reply_connection
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clone();
+                    if let Some(connection) = connection {
+                        let _ = connection.send_datagram(buffer[..length].to_vec().into());
+                    }
+                }
+            });
+
+            while let Some(incoming) = endpoint.accept().await {
+                let relay = relay.clone();
+                let current = current.clone();
+                tokio::spawn(async move {
+                    let Ok(connection) = incoming.await else {
+                        return;
+                    };
+                    let peer = connection.remote_address();
+                    info!("QUIC connection from {}", peer);
+                    *current.lock().unwrap_or_else(PoisonError::into_inner) =
+                        Some(connection.clone());
+                    while let Ok(datagram) = connection.read_datagram().await {
+                        let _ = relay.send(&datagram).await;
+                    }
+                    warning!("QUIC connection from {} closed", peer);
+                });
+            }
+        });
+    });
+    result.recv().map_err(|_| "unable to bind to address")??;
+    Ok(local)
+}
+
+// Hands a loopback socket to the runtime
+#[cfg(feature = "quic")]
+fn async_socket(socket: UdpSocket) -> Option<tokio::net::UdpSocket> {
+    socket.set_nonblocking(true).ok()?;
+    tokio::net::UdpSocket::from_std(socket).ok()
+}
+
+// Establishes a connection, nothing if the receiver cannot be reached
+#[cfg(feature = "quic")]
+async fn open(endpoint: &Endpoint, peer: SocketAddr) -> Option<Connection> {
+    endpoint.connect(peer, SERVER_NAME).ok()?.await.ok()
+}
+
+// Moves packets between the sender loop and the connection until the connection is lost
+#[cfg(feature = "quic")]
+async fn forward(connection: &Connection, relay: &tokio::net::UdpSocket, buffer: &mut [u8]) {
+    let mut warned = false;
+    loop {
+        tokio::select! {
+            received = relay.recv(buffer) => {
+                let Ok(length) = received else {
+                    return;
+                };
+                match connection.send_datagram(buffer[..length].to_vec().into()) {
+                    Ok(()) => {}
+                    // The path allows less than a packet, which needs no reconnecting
+                    Err(SendDatagramError::TooLarge) => {
+                        if !warned {
+                            warning!(
+                                "packet of {} bytes exceeds the {} bytes a QUIC \
+                                 datagram can carry on this path, dropping, lower --packet-size",
+                                length,
+                                connection.max_datagram_size().unwrap_or(0)
+                            );
+                            warned = true;
+                        }
+                    }
+                    Err(_) => return,
+                }
+            }
+            datagram = connection.read_datagram() => {
+                match datagram {
+                    Ok(datagram) => {
+                        let _ = relay.send(&datagram).await;
+                    }
+                    Err(_) => return,
+                }
+            }
+        }
+    }
+}
+
+// Keepalives and the idle timeout, shared by both ends
+#[cfg(feature = "quic")]
+fn transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config
+        .keep_alive_interval(Some(KEEPALIVE_INTERVAL))
+        .max_idle_timeout(IDLE_TIMEOUT.try_into().ok());
+    Arc::new(config)
+}
+
+// TLS 1.3 with the ring provider, which QUIC requires
+#[cfg(feature = "quic")]
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+#[cfg(feature = "quic")]
+fn client_endpoint(socket: UdpSocket) -> Result<Endpoint, &'static str> {
+    let provider = provider();
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|_| "unable to configure QUIC")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(tls).map_err(|_| "unable to configure QUIC")?;
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+
+    let mut endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        socket,
+        Arc::new(TokioRuntime),
+    )
+    .map_err(|_| "unable to bind to address")?;
+    endpoint.set_default_client_config(config);
+    Ok(endpoint)
+}
+
+// The receiver's TLS setup, with a self-signed certificate made for this run
+#[cfg(feature = "quic")]
+fn server_config() -> Result<ServerConfig, &'static str> {
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+        .map_err(|_| "unable to create a QUIC certificate")?;
+    let certificate = certified.cert.der().clone();
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|_| "unable to configure QUIC")?
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], key.into())
+        .map_err(|_| "unable to configure QUIC")?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls).map_err(|_| "unable to configure QUIC")?;
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// Takes whatever certificate the receiver presents, as it makes a new one every run. The
+// handshake signature is still checked, so the connection is encrypted to whoever holds the
+// certificate, but who that is takes --key, --auth-key or --identity to establish.
+#[cfg(feature = "quic")]
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+#[cfg(feature = "quic")]
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer,
+        _: &[CertificateDer],
+        _: &ServerName,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Stand-ins when built without QUIC, which fail to start
+#[cfg(not(feature = "quic"))]
+pub fn connect<T: ToSocketAddrs>(
+    _: T,
+    _: T,
+    _: Option<u32>,
+    _: Option<bool>,
+) -> Result<UdpSocket, &'static str> {
+    Err("built without QUIC support, rebuild with --features quic")
+}
+
+#[cfg(not(feature = "quic"))]
+pub fn listen<T: ToSocketAddrs>(_: T, _: Option<bool>) -> Result<UdpSocket, &'static str> {
+    Err("built without QUIC support, rebuild with --features quic")
+}
+
+#[cfg(all(test, feature = "quic"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_cross_a_quic_connection_both_ways() {
+        // The socket handed back is a loopback one, so pick the QUIC port beforehand
+        let probe = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        let receiver = listen(("127.0.0.1", port), None).unwrap();
+        let sender = connect(("0.0.0.0", 0), ("127.0.0.1", port), None, None).unwrap();
+        let timeout = Some(Duration::from_secs(2));
+        receiver.set_read_timeout(timeout).unwrap();
+        sender.set_read_timeout(timeout).unwrap();
+
+        let mut buffer = [0; 16];
+        sender.send(b"audio").unwrap();
+        let length = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"audio");
+        receiver.send(b"report").unwrap();
+        let length = sender.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"report");
+    }
+}
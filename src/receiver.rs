@@ -1,138 +1,2999 @@
 use std::{
-    net::{ToSocketAddrs, UdpSocket},
-    sync::mpsc,
+    fs::File,
+    io::BufWriter,
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    slice,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use jack::{AudioOut, Client, Control, RingBuffer, contrib::ClosureProcessHandler};
+use hound::{WavSpec, WavWriter};
+use jack::{
+    AudioOut, Client, Control, Frames, LatencyType, Port, ProcessHandler, ProcessScope, RingBuffer,
+    RingBufferReader, RingBufferWriter, contrib::ClosureProcessHandler,
+};
+use socket2::SockRef;
+
+use crate::{
+    codec::{self, Codec, Decoder},
+    connect::{self, Connections},
+    crypto::{Cipher, Key, Opened, Sessions},
+    fec,
+    format::SampleFormat,
+    ice,
+    latency::{self, Latency},
+    mdns,
+    metrics::{self, Metrics, Notifications},
+    osc::{self, Controls},
+    packet::{
+        self, FLAG_COPY, FLAG_END, FLAG_HANDSHAKE, FLAG_HELLO, FLAG_KEEPALIVE, FLAG_PARITY,
+        FLAG_PING, FLAG_PONG, FLAG_REPORT, FLAG_VERIFY, HEADER_SIZE, Header, LOSS_REPORT_SIZE,
+        LossReport, MAX_DATAGRAM_SIZE, MAX_PAYLOAD_SIZE, PROBE_SIZE, Probe, STREAM_CONFIG_SIZE,
+        SampleCount, StreamConfig, port_name, port_prefix,
+    },
+    quic,
+    resample::{Drift, Resampler},
+    rtp::{self, AES67_SAMPLE_RATE},
+    sender, shutdown,
+    transport::{self, MulticastInterface, Retry, Transport},
+    warning::{WarningCounter, XrunWarnings},
+};
+
+// Interval between latency reports
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Interval between jitter reports
+const JITTER_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Interval between limiter reports
+const LIMITER_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Interval between recording header updates
+const RECORDING_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Interval at which periodic work is handled while no packets arrive
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Interval between updates of the drift correction, and between logging it
+const DRIFT_INTERVAL: Duration = Duration::from_secs(1);
+const DRIFT_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Interval between requests for the stream of an advertised sender while none arrives
+const SUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Interval between loss reports sent back to the sender
+const LOSS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sequence jumps beyond this many packets are treated as a sender restart rather than loss
+const MAX_SEQUENCE_GAP: u32 = 4096;
+
+// Packets behind the newest an authenticated stream still takes, as many as `seen` tracks.
+// Older ones are dropped as replays, much like IPsec's anti-replay window.
+const REPLAY_WINDOW: u32 = 64;
+
+// How far a sender's clock may step back within a run before its packets look replayed
+const REPLAY_CLOCK_SLACK: u64 = 1_000_000;
+
+// Time packets are held behind a gap at most, should the parity to fill it never arrive
+const FEC_HOLD_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Silence on the link after which the stream is reported as timed out
+const STREAM_TIMEOUT: Duration = Duration::from_secs(2);
 
-use crate::{PACKET_SIZE, RING_BUFFER_SIZE};
+// Output peak below which the receiver counts as silent, -60 dBFS
+const SILENCE_THRESHOLD: f32 = 0.001;
 
-// Splits interleaved stereo buffer into separate left/right iterators
-fn deinterleave<T: Copy>(a: &[T]) -> Option<(impl Iterator<Item = T>, impl Iterator<Item = T>)> {
-    // Ensure even number of samples
-    (a.len() % 2 == 0).then(|| {
-        (
-            a.iter().step_by(2).copied(),         // Left channel (even indices)
-            a.iter().skip(1).step_by(2).copied(), // Right channel (odd indices)
-        )
-    })
+// Underruns within ADAPTIVE_WINDOW after which an adaptive ring buffer is grown
+const ADAPTIVE_UNDERRUNS: usize = 3;
+const ADAPTIVE_WINDOW: Duration = Duration::from_secs(10);
+
+// Time without underruns or resizes after which an adaptive ring buffer is shrunk
+const ADAPTIVE_STABLE: Duration = Duration::from_secs(60);
+
+// Multiple of the smoothed jitter a jitter buffer holds on top of one packet
+const JITTER_MARGIN: f64 = 4.0;
+
+// Time over which a jitter buffer tracks its lowest fill, audio never dipped into is dropped
+const JITTER_WINDOW: Duration = Duration::from_secs(10);
+
+// Level above which the limiter starts bending the signal towards full scale
+const LIMITER_KNEE: f32 = 0.9;
+
+// Picks the samples of channel `index` out of a buffer of `channels` interleaved channels
+fn deinterleave<T: Copy>(
+    interleaved: &[T],
+    index: usize,
+    channels: usize,
+) -> Option<impl Iterator<Item = T>> {
+    // Ensure whole frames
+    interleaved
+        .len()
+        .is_multiple_of(channels)
+        .then(|| interleaved.iter().skip(index).step_by(channels).copied())
 }
 
-// Messages for cross-thread communication
-enum Message {
-    InvalidBufferLengths,
-    Underrun { expected: usize, available: usize },
+// Checks that output port buffers of the given lengths can take a period of audio with
+// `wire_channels` channels, returning the number of samples to decode. Every port spans the
+// same period, but the wire may carry fewer or more channels than there are ports.
+fn samples_per_period(
+    port_lengths: impl IntoIterator<Item = usize>,
+    wire_channels: usize,
+) -> Option<usize> {
+    let mut lengths = port_lengths.into_iter();
+    let frames = lengths.next()?;
+    lengths
+        .all(|length| length == frames)
+        .then_some(frames * wire_channels)
 }
 
-// Receiver main function
-pub fn start<T: ToSocketAddrs>(client: Client, bind: T) -> Result<!, &'static str> {
-    // Register JACK output ports for left and right channels
-    let mut out_port_l = client
-        .register_port("out_l", AudioOut::default())
-        .map_err(|_| "unable to register port")?;
-    let mut out_port_r = client
-        .register_port("out_r", AudioOut::default())
-        .map_err(|_| "unable to register port")?;
-
-    // Bind UDP socket for receiving audio data
-    let socket = UdpSocket::bind(bind).map_err(|_| "unable to bind to address")?;
+// Reorders a packet of encoded samples made of one block per channel into interleaved
+// samples
+fn from_planar(
+    planar: &[u8],
+    sample_size: usize,
+    channels: usize,
+) -> Option<impl Iterator<Item = &[u8]>> {
+    // Ensure whole frames
+    let frames = planar.len() / (channels * sample_size);
+    planar
+        .len()
+        .is_multiple_of(channels * sample_size)
+        .then(|| {
+            (0..frames).flat_map(move |frame| {
+                (0..channels).map(move |channel| {
+                    let start = (channel * frames + frame) * sample_size;
+                    &planar[start..start + sample_size]
+                })
+            })
+        })
+}
 
-    // Channel for sending warnings from audio thread to main thread
-    let (sender, receiver) = mpsc::channel();
+// Scales one channel by a gain ramping linearly from `from` to `to` over the period, so gain
+// changes do not click
+fn apply_level(samples: &mut [f32], from: f32, to: f32) {
+    if from == 1.0 && to == 1.0 {
+        return;
+    }
+    let step = (to - from) / samples.len() as f32;
+    for (index, sample) in samples.iter_mut().enumerate() {
+        *sample *= from + step * (index + 1) as f32;
+    }
+}
+
+// Linearly ramps the gain of one channel up from silence over `length` frames, `remaining`
+// frames are left. Returns the frames left after this period.
+fn fade_in(samples: &mut [f32], mut remaining: usize, length: usize) -> usize {
+    for sample in samples {
+        if remaining == 0 {
+            break;
+        }
+        *sample *= 1.0 - remaining as f32 / length as f32;
+        remaining -= 1;
+    }
+    remaining
+}
+
+// Soft-clips samples above the knee so they approach but never reach full scale, leaving
+// quieter samples untouched. Returns whether any sample was limited.
+fn soft_clip(samples: &mut [f32]) -> bool {
+    const HEADROOM: f32 = 1.0 - LIMITER_KNEE;
+    let mut engaged = false;
+    for sample in samples {
+        let magnitude = sample.abs();
+        // x / (1 + x) bends like tanh but costs a division, and is 0 below the knee
+        let excess = (magnitude - LIMITER_KNEE).max(0.0) / HEADROOM;
+        let limited = magnitude.min(LIMITER_KNEE) + HEADROOM * excess / (1.0 + excess);
+        *sample = limited.copysign(*sample);
+        engaged |= excess > 0.0;
+    }
+    engaged
+}
+
+// Buffered bytes an adaptive stream waits for after an underrun before playing again, a
+// quarter of its ring buffer in whole frames
+fn resume_level(capacity: usize, frame_size: usize) -> usize {
+    let level = capacity / 4;
+    level - level % frame_size
+}
+
+// Buffered bytes a stream following the jitter waits for after an underrun: one packet plus a
+// margin over the smoothed jitter in whole packets, within the bounds in milliseconds and at
+// most half the ring buffer, in whole frames
+fn jitter_target(
+    jitter_ms: f64,
+    (min_ms, max_ms): (u32, u32),
+    packet_size: usize,
+    capacity: usize,
+    bytes_per_second: f64,
+    frame_size: usize,
+) -> usize {
+    let bytes = |ms: f64| (bytes_per_second * ms / 1000.0) as usize;
+    let margin = bytes(JITTER_MARGIN * jitter_ms).next_multiple_of(packet_size);
+    let target = (packet_size + margin)
+        .clamp(bytes(min_ms as f64), bytes(max_ms as f64))
+        .min(capacity / 2);
+    target - target % frame_size
+}
+
+// Latency a stream's ring buffer adds, in frames: from the level playback resumes at to a
+// full buffer, or to the latency cap if that is lower
+fn latency_range(
+    capacity: usize,
+    resume_level: usize,
+    max_buffered: Option<usize>,
+    frame_size: usize,
+) -> (Frames, Frames) {
+    let max = (capacity - 1).min(max_buffered.unwrap_or(usize::MAX));
+    (
+        (resume_level / frame_size) as Frames,
+        (max / frame_size) as Frames,
+    )
+}
+
+// Fills in for `missing` bytes of lost packets with silence so the audio after a gap keeps
+// its timing, leaving room for the `reserved` bytes of the packet that follows. Returns the
+// number of bytes written.
+fn conceal(writer: &mut RingBufferWriter, missing: usize, reserved: usize) -> usize {
+    // Silence encodes to zero bytes in every sample format
+    const SILENCE: [u8; 1024] = [0; 1024];
+    let concealed = missing.min(writer.space().saturating_sub(reserved));
+    let mut remaining = concealed;
+    while remaining > 0 {
+        remaining -= writer.write_buffer(&SILENCE[..remaining.min(SILENCE.len())]);
+    }
+    concealed
+}
+
+// Gain of the last good frame held over lost packets, `elapsed` frames after the loss, fading
+// to silence over `length` frames
+fn tail_gain(elapsed: usize, length: usize) -> f32 {
+    (1.0 - elapsed as f32 / length as f32).max(0.0)
+}
+
+// Adds channel `channel` of the last period played, interleaved in `held`, to the output of
+// an underrun, fading out over `length` frames. It is played backwards, then forwards and so
+// on, so it joins up with the audio before it without a jump. `elapsed` frames have been
+// concealed so far, returns how many have been after this period.
+fn conceal_underrun(
+    out: &mut [f32],
+    held: &[f32],
+    channel: usize,
+    channels: usize,
+    mut elapsed: usize,
+    length: usize,
+) -> usize {
+    let frames = held.len() / channels;
+    for sample in out {
+        if elapsed >= length {
+            break;
+        }
+        let position = elapsed % (2 * frames);
+        let index = if position < frames {
+            frames - 1 - position
+        } else {
+            position - frames
+        };
+        *sample += held[index * channels + channel] * tail_gain(elapsed, length);
+        elapsed += 1;
+    }
+    elapsed
+}
+
+// Like conceal, but fills the start of the gap with `tail` fading out over `length` frames
+// instead of dropping straight to silence. Returns the number of frames written.
+fn conceal_tail(
+    writer: &mut RingBufferWriter,
+    format: SampleFormat,
+    tail: &[f32],
+    missing: usize,
+    reserved: usize,
+    length: usize,
+) -> usize {
+    let frame_size = tail.len() * format.sample_size();
+    let frames = missing.min(writer.space().saturating_sub(reserved)) / frame_size;
+    let faded = frames.min(length);
+    let mut frame = vec![0; frame_size];
+    for elapsed in 0..faded {
+        let gain = tail_gain(elapsed, length);
+        let written = format.encode(tail.iter().map(|sample| sample * gain), None, &mut frame);
+        writer.write_buffer(&frame[..written]);
+    }
+    conceal(writer, (frames - faded) * frame_size, reserved);
+    frames
+}
+
+// Crossfade from the concealment of lost packets into the audio that follows them
+struct Recovery {
+    tail: Vec<f32>, // Frame held over the gap
+    elapsed: usize, // Frames since the gap started
+    mixed: usize,   // Received frames crossfaded so far
+}
+
+// Writes audio to a stream's ring buffer, crossfading the first `length` frames after lost
+// packets from the concealment tail, which may span several packets. The last frame written
+// is left in `last`, which holds one sample per channel.
+fn write_audio(
+    writer: &mut RingBufferWriter,
+    format: SampleFormat,
+    audio: &[u8],
+    recovery: &mut Option<Recovery>,
+    length: usize,
+    last: &mut [f32],
+) {
+    let frame_size = last.len() * format.sample_size();
+    last.fill(0.0);
+    let mut head = 0;
+    if let Some(fade) = recovery {
+        let mut frame = vec![0; frame_size];
+        for encoded in audio.chunks_exact(frame_size).take(length - fade.mixed) {
+            format.decode(encoded, last);
+            let gain = (fade.mixed + 1) as f32 / (length + 1) as f32;
+            let tail = (1.0 - gain) * tail_gain(fade.elapsed, length);
+            for (sample, held) in last.iter_mut().zip(&fade.tail) {
+                *sample = *sample * gain + held * tail;
+            }
+            let written = format.encode(last.iter().copied(), None, &mut frame);
+            writer.write_buffer(&frame[..written]);
+            fade.elapsed += 1;
+            fade.mixed += 1;
+            head += frame_size;
+        }
+        if fade.mixed >= length {
+            *recovery = None;
+        }
+    }
+    writer.write_buffer(&audio[head..]);
+    if head < audio.len() {
+        format.decode(&audio[audio.len() - frame_size..], last);
+    }
+}
+
+// White noise from a xorshift generator, cheap enough for the process callback
+struct ComfortNoise {
+    state: u32,
+    amplitude: f32,
+}
+
+impl ComfortNoise {
+    fn new(dbfs: f32) -> Self {
+        Self {
+            state: 0x9e37_79b9, // Any nonzero seed works
+            amplitude: 10.0_f32.powf(dbfs / 20.0),
+        }
+    }
+
+    fn fill(&mut self, out: &mut [f32]) {
+        for sample in out {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            // Map to [-1, 1)
+            *sample = self.amplitude * (self.state as f32 / 2_147_483_648.0 - 1.0);
+        }
+    }
+}
+
+// Running capture-to-playout latency statistics, in milliseconds
+struct LatencyStats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl LatencyStats {
+    fn new() -> Self {
+        Self {
+            min: f64::INFINITY,
+            max: 0.0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, latency: f64) {
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+        self.sum += latency;
+        self.count += 1;
+    }
+
+    // Prints the collected statistics and starts a new window
+    fn report(&mut self) {
+        if self.count > 0 {
+            info!(
+                "latency min/avg/max: {:.2}/{:.2}/{:.2} ms",
+                self.min,
+                self.sum / self.count as f64,
+                self.max
+            );
+        }
+        *self = Self::new();
+    }
+}
+
+// Interarrival jitter estimate as defined by RFC 3550, in milliseconds
+struct Jitter {
+    last_transit: Option<i64>, // Arrival minus send time of the previous packet, in microseconds
+    instantaneous: f64,        // Transit difference between the last two packets
+    smoothed: f64,             // Running estimate, each difference weighted by 1/16
+}
+
+impl Jitter {
+    fn new() -> Self {
+        Self {
+            last_transit: None,
+            instantaneous: 0.0,
+            smoothed: 0.0,
+        }
+    }
+
+    // Updates the estimate with a packet's send and arrival times, in microseconds. The clocks
+    // need not be synchronized since only differences in transit time count.
+    fn add(&mut self, sent: u64, arrival: u64) {
+        let transit = arrival as i64 - sent as i64;
+        if let Some(last_transit) = self.last_transit.replace(transit) {
+            self.instantaneous = (transit - last_transit).abs() as f64 / 1000.0;
+            self.smoothed += (self.instantaneous - self.smoothed) / 16.0;
+        }
+    }
+
+    // Prints the current estimate, naming the stream when there is more than one
+    fn report(&self, stream: Option<u8>) {
+        if self.last_transit.is_some() {
+            info!(
+                "{}jitter instantaneous/smoothed: {:.2}/{:.2} ms",
+                stream
+                    .map(|id| format!("stream {} ", id))
+                    .unwrap_or_default(),
+                self.instantaneous,
+                self.smoothed
+            );
+        }
+    }
+}
+
+// Receiver configuration
+pub struct Options {
+    pub measure_latency: bool,             // Report capture-to-playout latency
+    pub measure_jitter: bool,              // Report interarrival jitter
+    pub format: SampleFormat,              // Wire sample format
+    pub recovery_fade_ms: u32,             // Fade-in duration after an underrun, 0 to disable
+    pub underrun_conceal_ms: u32,          // Fade-out of the last period on underruns, 0 to disable
+    pub planar: bool,                      // Packets carry each channel as a contiguous block
+    pub record: Option<PathBuf>,           // WAV file receiving a copy of the incoming stream
+    pub ring_buffer_size: usize,           // Ring buffer capacity in bytes, a power of two
+    pub packet_size: usize,                // Payload bytes per packet advertised to senders
+    pub warn_interval: Option<Duration>,   // Aggregate repeated warnings over this window
+    pub silence_timeout: Option<Duration>, // Warn when packets arrive but the output stays silent
+    pub max_latency_ms: Option<u32>,       // Discard the oldest buffered audio beyond this
+    pub transport: Transport,              // Datagrams or a TCP stream
+    pub metrics: Option<SocketAddr>,       // Address serving Prometheus metrics
+    pub http: Option<SocketAddr>,          // Address serving the JSON status
+    pub stats_interval: Option<Duration>,  // Print a summary of the stream this often
+    pub comfort_noise: Option<f32>,        // Noise level in dBFS played on underruns
+    pub relay: Option<SocketAddr>,         // Receiver to forward incoming packets to
+    pub max_streams: usize,                // Concurrent streams played on separate ports
+    pub limit: bool,                       // Soft-clip the output instead of exceeding full scale
+    pub adaptive: Option<(usize, usize)>,  // Ring buffer size bounds when resizing on underruns
+    pub loss_crossfade_ms: u32,            // Crossfade into audio after lost packets, 0 to disable
+    pub verify: bool,                      // Check sample counts against the sender's
+    pub wait_for_stream: bool,             // Stay quiet until the first packet arrives
+    pub v6only: Option<bool>,              // IPv6 only or dual-stack, platform-aware if unset
+    pub codec: Codec,                      // Raw samples or compressed frames
+    pub opus_frame: Duration,              // Audio in each Opus frame, sets the packet size
+    pub jitter_buffer: Option<(u32, u32)>, // Buffering bounds in ms following the measured jitter
+    pub rtp: Option<rtp::Encoding>,        // Receive plain RTP, of these samples unless stated
+    pub aes67: bool,                       // RTP from AES67 devices, with PTP timestamps
+    // Interface a multicast bind address joins its group on, instead of the default one
+    pub multicast_interface: Option<MulticastInterface>,
+    pub connect_name: Option<String>, // Advertised stream to subscribe to
+    pub drift_correction: bool,       // Resample to hold the buffer level against drift
+    pub channels: usize,              // Carried on the wire, played in this order by default
+    // Wire channel each output port plays, none for silence, instead of one port per channel
+    pub map: Option<Vec<Option<usize>>>,
+    pub key: Option<Key>, // Pre-shared key packets are encrypted and authenticated with
+    pub auth_key: Option<Key>, // Pre-shared key packets are only authenticated with
+    pub identity: Option<Key>, // Private key proving this receiver to senders
+    pub peer_keys: Vec<Key>, // Public keys of the senders session keys are exchanged with
+    pub ice: Option<ice::Exchange>, // Open a path through NAT to the sender before receiving
+    pub osc: Option<SocketAddr>, // Address taking gain, mute and stats messages over OSC
+    pub name: Option<String>, // Leads the port names, keeping streams of a group apart
+    pub connect: Vec<String>, // Patterns of the playback ports the outputs are connected to
+    pub strict_rate: bool, // Refuse streams at another sample rate instead of resampling
+    pub ttl: Option<u32>, // Outgoing TTL or hop limit of --relay
+}
+
+// Debugging tap writing the received stream to a WAV file, off the real-time thread
+struct Recorder {
+    writer: WavWriter<BufWriter<File>>,
+    samples: Box<[f32]>,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    fn create(path: &Path, sample_rate: u32, channels: u16) -> Result<Self, &'static str> {
+        let spec = WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        Ok(Self {
+            writer: WavWriter::create(path, spec).map_err(|_| "unable to create recording")?,
+            samples: vec![0.0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            last_flush: Instant::now(),
+        })
+    }
+
+    // Decodes interleaved samples and appends them to the file
+    fn write(&mut self, format: SampleFormat, encoded: &[u8]) -> hound::Result<()> {
+        let decoded = format.decode(encoded, &mut self.samples);
+        self.samples[..decoded]
+            .iter()
+            .try_for_each(|&sample| self.writer.write_sample(sample))
+    }
+
+    // Periodically updates the header so the file stays valid if the process is killed
+    fn flush_if_due(&mut self) -> hound::Result<()> {
+        if self.last_flush.elapsed() >= RECORDING_FLUSH_INTERVAL {
+            self.last_flush = Instant::now();
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Destination for the received stream besides the JACK outputs. Sinks are fed from the
+// network thread, so they may block without disturbing playout.
+enum Sink {
+    Record(Recorder), // Decoded samples appended to a WAV file
+    // Packets forwarded unchanged to another receiver, sealed again
+    Relay(UdpSocket, Cipher, Retry),
+}
+
+impl Sink {
+    // Connects to the next receiver over the stream's own transport, set up as a sender would
+    fn relay(
+        destination: SocketAddr,
+        options: &Options,
+        cipher: Cipher,
+    ) -> Result<Self, &'static str> {
+        let bind: SocketAddr = match destination {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = match options.transport {
+            Transport::Udp => {
+                let socket = transport::bind_udp(bind, options.v6only, None)?;
+                socket
+                    .connect(destination)
+                    .map_err(|_| "unable to connect relay socket")?;
+                if let Some(ttl) = options.ttl {
+                    sender::set_ttl(SockRef::from(&socket), ttl)?;
+                }
+                if let Some(interface) = options.multicast_interface {
+                    sender::set_multicast_interface(SockRef::from(&socket), interface)?;
+                }
+                socket
+            }
+            Transport::Tcp => transport::connect(
+                bind,
+                destination,
+                options.ttl,
+                options.v6only,
+                transport::DEFAULT_QUEUE_DELAY,
+            )?,
+            Transport::Quic => quic::connect(bind, destination, options.ttl, options.v6only)?,
+        };
+        let retry = Retry::new(format!("relay to {}", destination));
+        Ok(Sink::Relay(socket, cipher, retry))
+    }
+
+    // Passes on an audio packet, given both as received and as interleaved samples. Relays
+    // forward every stream, recordings only hold stream 0.
+    fn write(
+        &mut self,
+        format: SampleFormat,
+        stream: u8,
+        datagram: &[u8],
+        interleaved: &[u8],
+    ) -> Result<(), &'static str> {
+        match self {
+            Sink::Record(_) if stream != 0 => Ok(()),
+            Sink::Record(recorder) => recorder
+                .write(format, interleaved)
+                .map_err(|_| "unable to write recording"),
+            // Packets are skipped while the destination is unreachable, as a sender's are
+            Sink::Relay(_, _, retry) if !retry.due() => Ok(()),
+            Sink::Relay(socket, cipher, retry) => retry
+                .check(socket.send(cipher.seal(datagram)))
+                .map(|_| ())
+                .map_err(|_| "unable to relay packet"),
+        }
+    }
+
+    fn flush_if_due(&mut self) -> Result<(), &'static str> {
+        match self {
+            Sink::Record(recorder) => recorder
+                .flush_if_due()
+                .map_err(|_| "unable to write recording"),
+            Sink::Relay(..) => Ok(()),
+        }
+    }
+}
+
+// Scratch buffers for the process callback, sized for the current JACK period
+struct ProcessBuffers {
+    encoded: Vec<u8>,        // Samples read from the ring buffer
+    deinterleaved: Vec<f32>, // Decoded interleaved samples
+    frames: usize,           // Period the buffers are sized for
+    oversized: bool,         // Whether a larger period was reported since the last resize
+}
 
-    // Create ring buffer for inter-thread communication
-    let (mut ring_buffer_reader, mut ring_buffer_writer) = RingBuffer::new(RING_BUFFER_SIZE)
+impl ProcessBuffers {
+    fn new(frames: usize, channels: usize, sample_size: usize) -> Self {
+        let mut buffers = Self {
+            encoded: Vec::new(),
+            deinterleaved: Vec::new(),
+            frames: 0,
+            oversized: false,
+        };
+        buffers.resize(frames, channels, sample_size);
+        buffers
+    }
+
+    fn resize(&mut self, frames: usize, channels: usize, sample_size: usize) {
+        self.encoded.resize(frames * channels * sample_size, 0);
+        self.deinterleaved.resize(frames * channels, 0.0);
+        self.frames = frames;
+        self.oversized = false;
+    }
+}
+
+// Playout side of a stream, owned by the process callback
+struct Playout {
+    id: u8,
+    outs: Vec<Port<AudioOut>>, // An output port per channel
+    buffer: PlayoutBuffer,
+    fade_remaining: usize,  // Samples left in the fade-in armed by an underrun
+    state: PlayoutState,    // Reported to the network thread on every change
+    report_underruns: bool, // Cleared until the stream first plays with --wait-for-stream
+    pending_trim: Arc<AtomicUsize>,
+    underrun_silence: Arc<AtomicUsize>,
+    resume_level: Arc<AtomicUsize>,
+    held: Vec<f32>, // Last period played, interleaved, sized for the current period
+    held_frames: usize, // Frames of it that are valid, 0 if none
+    concealed: usize, // Frames of it played out since the underrun
+}
+
+// Playback state of a stream
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PlayoutState {
+    Waiting,      // No packet yet with --wait-for-stream, nothing is reported
+    Prebuffering, // Filling up to the resume level, at startup or after an underrun
+    Playing,
+}
+
+// Reading end of a stream's ring buffer, along with the one replacing it after a resize
+struct PlayoutBuffer {
+    reader: RingBufferReader,
+    next_reader: Option<RingBufferReader>, // Read once the current ring buffer is empty
+}
+
+impl PlayoutBuffer {
+    // Bytes buffered in the current and replacement ring buffers
+    fn available(&self) -> usize {
+        self.reader.space() + self.next_reader.as_ref().map_or(0, |next| next.space())
+    }
+
+    // Fills `buffer` with the oldest buffered bytes, which must be available
+    fn read(&mut self, buffer: &mut [u8], messages: &mpsc::Sender<Message>) {
+        let read = self.reader.read_buffer(buffer);
+        if read < buffer.len() {
+            self.switch_reader(messages);
+            self.reader.read_buffer(&mut buffer[read..]);
+        }
+    }
+
+    // Discards up to `bytes` of the oldest buffered audio
+    fn advance(&mut self, bytes: usize, messages: &mpsc::Sender<Message>) {
+        let current = bytes.min(self.reader.space());
+        self.reader.advance(current);
+        if current < bytes {
+            self.switch_reader(messages);
+            let remaining = (bytes - current).min(self.reader.space());
+            self.reader.advance(remaining);
+        }
+    }
+
+    // Moves on to the replacement ring buffer, handing the old one back so it is freed off
+    // the real-time thread
+    fn switch_reader(&mut self, messages: &mpsc::Sender<Message>) {
+        if let Some(next) = self.next_reader.take() {
+            let old = mem::replace(&mut self.reader, next);
+            let _ = messages.send(Message::Release(old));
+        }
+    }
+
+    // Queues a replacement ring buffer. One still waiting to be reached is skipped along
+    // with the audio in the current ring buffer.
+    fn replace(&mut self, reader: RingBufferReader, messages: &mpsc::Sender<Message>) {
+        if let Some(skipped) = self.next_reader.replace(reader) {
+            let old = mem::replace(&mut self.reader, skipped);
+            let _ = messages.send(Message::Release(old));
+        }
+    }
+}
+
+// Changes handed from the network thread to the process callback
+enum Handoff {
+    Open(Playout), // A stream seen for the first time
+    Resize {
+        stream: u8,
+        reader: RingBufferReader, // Played once the current ring buffer is empty
+    },
+}
+
+// Network side of a stream
+struct Stream {
+    id: u8,
+    ring_buffer_writer: RingBufferWriter,
+    packet_size: usize, // The receiver's unless the sender sets frames per packet
+    capacity: usize,    // Size of the ring buffer being written
+    pending_trim: Arc<AtomicUsize>, // Oldest buffered bytes to discard, whole frames
+    underrun_silence: Arc<AtomicUsize>, // Encoded bytes of silence played since the last packet
+    next_sequence: Option<u32>,
+    seen: u64,   // Recently received sequence numbers, bit n is n packets before the newest
+    copied: u64, // Bits of `seen` played from a copy whose original has not arrived
+    // Sender's clock at the newest packet, kept when a stream ends
+    newest_timestamp: u64,
+    loss: LossReport,
+    last_loss_report: Instant,
+    jitter: Jitter,
+    resume_level: Arc<AtomicUsize>,
+    last_packet: Instant,
+    underruns: usize, // Underruns while receiving since adapt_since
+    adapt_since: Instant,
+    last_underrun: Instant,
+    last_resize: Instant,
+    low_water: Option<usize>, // Lowest fill when a packet arrived since jitter_since
+    jitter_since: Instant,
+    last_frame: Vec<f32>,          // Last frame written, held over lost packets
+    recovery: Option<Recovery>,    // Crossfade in progress after lost packets
+    timeline: Option<SampleCount>, // Samples per channel written or credited, with --verify
+    divergence: Vec<i64>,          // Last reported difference from the sender's count
+    state: PlayoutState,           // Last state reported by the audio thread
+    played: bool,
+    recovered: u32, // Packets replaced by copies or parity since the last loss report
+    codec: Codec,   // Starts out as the receiver's, senders may switch exact codecs
+    format: SampleFormat, // On the wire, the sender's if it names one and sends raw samples
+    decoder: Option<Decoder>, // Decompresses the stream's packets unless they carry samples
+    fec: fec::Decoder, // Recovers lost packets once the sender sends parity
+    resampler: Option<Resampler>, // Set when the sender's handshake names a different rate
+    drift: Drift,   // Estimated with --drift-correction
+    refused: bool,  // Runs at another sample rate with --strict-rate, its audio is dropped
+    last_drift_update: Instant,
+    last_drift_report: Instant,
+}
+
+// JACK output port names of a stream, stream 0 keeps the names of a single-stream receiver
+fn port_names(name: Option<&str>, id: u8, channels: usize) -> Vec<String> {
+    let prefix = match id {
+        0 => port_prefix(name, "out"),
+        _ => format!("{}_{}", port_prefix(name, "out"), id),
+    };
+    (0..channels)
+        .map(|channel| port_name(&prefix, channel, channels))
+        .collect()
+}
+
+// Output ports of each stream, as many as channels unless --map says otherwise
+fn output_ports(options: &Options) -> usize {
+    options.map.as_ref().map_or(options.channels, Vec::len)
+}
+
+// Registers the output ports and ring buffer of a stream, usable on an active client too
+fn open_stream(
+    client: &Client,
+    id: u8,
+    options: &Options,
+    resume_level: usize,
+    state: PlayoutState,
+) -> Result<(Stream, Playout), &'static str> {
+    let ring_buffer_size = options.ring_buffer_size;
+    let codec = options.codec;
+    let channels = options.channels;
+    let decoder = codec.decoder(client.sample_rate(), channels)?;
+    let outs = port_names(options.name.as_deref(), id, output_ports(options))
+        .iter()
+        .map(|name| {
+            client
+                .register_port(name, AudioOut::default())
+                .map_err(|_| "unable to register port")
+        })
+        .collect::<Result<_, _>>()?;
+    let (ring_buffer_reader, ring_buffer_writer) = RingBuffer::new(ring_buffer_size)
         .map_err(|_| "unable to create ring buffer")?
         .into_reader_writer();
-    // Buffer for deinterleaving
-    let mut deinterleave_channels_buffer = [0.0; RING_BUFFER_SIZE * 2];
-
-    let _async_client = client
-        .activate_async(
-            (),
-            ClosureProcessHandler::new(move |_, ps| {
-                // Get audio buffers from JACK
-                let data_to_receive_l = out_port_l.as_mut_slice(ps);
-                let data_to_receive_r = out_port_r.as_mut_slice(ps);
-                let amount_to_receive = data_to_receive_l.len() + data_to_receive_r.len();
-
-                // Validate buffer sizes
-                if amount_to_receive > deinterleave_channels_buffer.len()
-                    || data_to_receive_l.len() != data_to_receive_r.len()
-                {
-                    let _ = sender.send(Message::InvalidBufferLengths);
-                    return Control::Quit;
-                }
-
-                // Check for underrun (not enough data)
-                let rb_space = ring_buffer_reader.space();
-                if rb_space < amount_to_receive * size_of::<f32>() {
-                    // Fill with silence on underrun
-                    data_to_receive_l.fill(0.0);
-                    data_to_receive_r.fill(0.0);
-                    let _ = sender.send(Message::Underrun {
-                        expected: amount_to_receive * size_of::<f32>(),
+    let pending_trim = Arc::new(AtomicUsize::new(0));
+    let underrun_silence = Arc::new(AtomicUsize::new(0));
+    let resume_level = Arc::new(AtomicUsize::new(resume_level));
+    Ok((
+        Stream {
+            id,
+            ring_buffer_writer,
+            packet_size: options.packet_size,
+            capacity: ring_buffer_size,
+            pending_trim: pending_trim.clone(),
+            underrun_silence: underrun_silence.clone(),
+            next_sequence: None,
+            seen: 0,
+            copied: 0,
+            newest_timestamp: 0,
+            loss: LossReport::default(),
+            last_loss_report: Instant::now(),
+            jitter: Jitter::new(),
+            resume_level: resume_level.clone(),
+            last_packet: Instant::now(),
+            underruns: 0,
+            adapt_since: Instant::now(),
+            last_underrun: Instant::now(),
+            last_resize: Instant::now(),
+            low_water: None,
+            jitter_since: Instant::now(),
+            last_frame: vec![0.0; channels],
+            recovery: None,
+            timeline: None,
+            divergence: Vec::new(),
+            state,
+            played: false,
+            recovered: 0,
+            codec,
+            format: options.format,
+            decoder,
+            fec: fec::Decoder::default(),
+            resampler: None,
+            refused: false,
+            drift: Drift::default(),
+            last_drift_update: Instant::now(),
+            last_drift_report: Instant::now(),
+        },
+        Playout {
+            id,
+            outs,
+            buffer: PlayoutBuffer {
+                reader: ring_buffer_reader,
+                next_reader: None,
+            },
+            fade_remaining: 0,
+            state,
+            report_underruns: !options.wait_for_stream,
+            pending_trim,
+            underrun_silence,
+            resume_level,
+            held: vec![0.0; client.buffer_size() as usize * channels],
+            held_frames: 0,
+            concealed: 0,
+        },
+    ))
+}
+
+// State of the process callback
+struct Process {
+    playouts: Vec<Playout>, // Capacity for every allowed stream is reserved up front
+    handoffs: mpsc::Receiver<Handoff>,
+    buffers: ProcessBuffers,
+    format: SampleFormat,
+    channels: usize,            // Carried on the wire
+    routes: Vec<Option<usize>>, // Wire channel each output port plays, none for silence
+    fade_length: usize,
+    conceal_length: usize, // Frames the last period fades out over on underruns, 0 to disable
+    comfort_noise: Option<ComfortNoise>, // Played on underruns instead of silence if set
+    output_peak: Arc<AtomicU32>, // Highest output peak since last read, as f32 bits
+    limit: bool,
+    limited: Arc<AtomicU64>, // Periods the limiter engaged in since last read
+    controls: Arc<Controls>, // Gain and mute, set over OSC
+    metrics: Arc<Metrics>,   // Takes the buffer fill once per period
+    level: f32,              // Gain the last period ended at
+    messages: mpsc::Sender<Message>,
+}
+
+impl Process {
+    // Plays out one period of every stream, fails if processing has to stop
+    fn process(&mut self, ps: &ProcessScope) -> Result<(), Message> {
+        for handoff in self.handoffs.try_iter() {
+            match handoff {
+                // Does not allocate, the capacity is already there
+                Handoff::Open(playout) => self.playouts.push(playout),
+                Handoff::Resize { stream, reader } => {
+                    if let Some(playout) = self.playouts.iter_mut().find(|p| p.id == stream) {
+                        playout.buffer.replace(reader, &self.messages);
+                    }
+                }
+            }
+        }
+
+        // JACK may hand over an empty period during xruns or reconfiguration, there is nothing
+        // to play and no underrun to report
+        if ps.n_frames() == 0 {
+            return Ok(());
+        }
+
+        // Gain changes ramp over the period
+        let (from, level) = (self.level, self.controls.level());
+        self.level = level;
+
+        // A period the buffers have not been resized for yet plays silence, the buffer size
+        // callback comes before the next one
+        if ps.n_frames() as usize > self.buffers.frames {
+            if !self.buffers.oversized {
+                self.buffers.oversized = true;
+                let _ = self.messages.send(Message::PeriodTooLarge {
+                    period: ps.n_frames() as usize * self.channels,
+                    capacity: self.buffers.deinterleaved.len(),
+                });
+            }
+            for port in self
+                .playouts
+                .iter_mut()
+                .flat_map(|playout| &mut playout.outs)
+            {
+                port.as_mut_slice(ps).fill(0.0);
+            }
+            return Ok(());
+        }
+
+        let sample_size = self.format.sample_size();
+        let channels = self.channels;
+        let mut peak = 0.0_f32;
+        for playout in &mut self.playouts {
+            // Validate the sizes of the audio buffers from JACK
+            let amount_to_receive = samples_per_period(
+                playout
+                    .outs
+                    .iter_mut()
+                    .map(|port| port.as_mut_slice(ps).len()),
+                channels,
+            )
+            .ok_or(Message::InvalidBufferLengths)?;
+            let bytes_to_receive = amount_to_receive * sample_size;
+
+            // Only the reading side may discard buffered audio
+            let trim = playout.pending_trim.load(Ordering::Acquire);
+            if trim > 0 {
+                playout.buffer.advance(trim, &self.messages);
+                playout.pending_trim.store(0, Ordering::Release);
+            }
+
+            // Check for underrun (not enough data), then wait for the buffer to refill to the
+            // resume level before playing again. A stream waiting for its first packet stays
+            // that way until anything is buffered.
+            let rb_space = playout.buffer.available();
+            let underrun = rb_space < bytes_to_receive;
+            let state = match playout.state {
+                PlayoutState::Waiting if rb_space == 0 => PlayoutState::Waiting,
+                _ if underrun => PlayoutState::Prebuffering,
+                PlayoutState::Playing => PlayoutState::Playing,
+                _ if rb_space < playout.resume_level.load(Ordering::Relaxed) => {
+                    PlayoutState::Prebuffering
+                }
+                _ => PlayoutState::Playing,
+            };
+            if state != playout.state {
+                playout.report_underruns |= state == PlayoutState::Playing;
+                playout.state = state;
+                let _ = self.messages.send(Message::State {
+                    stream: playout.id,
+                    state,
+                });
+            }
+            if state != PlayoutState::Playing {
+                // Mask a short dropout with the audio played just before it, the fade carries
+                // on while the buffer refills
+                let held = (playout.held_frames * channels == amount_to_receive)
+                    .then(|| &playout.held[..amount_to_receive]);
+                let mut concealed = playout.concealed;
+                for (port, &route) in playout.outs.iter_mut().zip(&self.routes) {
+                    let data_to_receive = port.as_mut_slice(ps);
+                    // Fill with silence or comfort noise on underrun
+                    match &mut self.comfort_noise {
+                        Some(noise) => noise.fill(data_to_receive),
+                        None => data_to_receive.fill(0.0),
+                    }
+                    if let (Some(held), Some(channel)) = (held, route) {
+                        concealed = conceal_underrun(
+                            data_to_receive,
+                            held,
+                            channel,
+                            channels,
+                            playout.concealed,
+                            self.conceal_length,
+                        );
+                    }
+                    apply_level(data_to_receive, from, level);
+                }
+                playout.concealed = concealed;
+                playout
+                    .underrun_silence
+                    .fetch_add(bytes_to_receive, Ordering::Relaxed);
+                playout.fade_remaining = self.fade_length;
+                if underrun && playout.report_underruns {
+                    let _ = self.messages.send(Message::Underrun {
+                        stream: playout.id,
+                        expected: bytes_to_receive,
                         available: rb_space,
                     });
-                } else {
-                    // Read from ring buffer and deinterleave
-                    ring_buffer_reader.read_buffer(bytemuck::cast_slice_mut(
-                        &mut deinterleave_channels_buffer[0..amount_to_receive],
-                    ));
-                    // The buffer size is already multiplied by 2, so unwrapping is safe
-                    let (l, r) = deinterleave(&deinterleave_channels_buffer).unwrap();
-                    data_to_receive_l
-                        .iter_mut()
-                        .zip(l)
-                        .for_each(|(buffer_val, data)| *buffer_val = data);
-                    data_to_receive_r
+                }
+            } else {
+                // Read from ring buffer, decode and deinterleave
+                let encoded = &mut self.buffers.encoded[0..bytes_to_receive];
+                playout.buffer.read(encoded, &self.messages);
+                let decoded = &mut self.buffers.deinterleaved[0..amount_to_receive];
+                self.format.decode(encoded, decoded);
+                if self.conceal_length > 0
+                    && let Some(held) = playout.held.get_mut(..amount_to_receive)
+                {
+                    held.copy_from_slice(decoded);
+                    playout.held_frames = amount_to_receive / channels;
+                    playout.concealed = 0;
+                }
+                let mut fade_remaining = playout.fade_remaining;
+                let mut limited = false;
+                for (port, &route) in playout.outs.iter_mut().zip(&self.routes) {
+                    let data_to_receive = port.as_mut_slice(ps);
+                    // Outputs no channel is mapped to stay silent
+                    let Some(channel) = route else {
+                        data_to_receive.fill(0.0);
+                        continue;
+                    };
+                    // Quit cleanly rather than panicking on a partial frame
+                    let samples = deinterleave(decoded, channel, channels)
+                        .ok_or(Message::InvalidBufferLengths)?;
+                    data_to_receive
                         .iter_mut()
-                        .zip(r)
+                        .zip(samples)
                         .for_each(|(buffer_val, data)| *buffer_val = data);
+
+                    // Suppress the click when resuming after an underrun
+                    fade_remaining =
+                        fade_in(data_to_receive, playout.fade_remaining, self.fade_length);
+                    apply_level(data_to_receive, from, level);
+
+                    limited |= self.limit && soft_clip(data_to_receive);
+                }
+                playout.fade_remaining = fade_remaining;
+                if limited {
+                    self.limited.fetch_add(1, Ordering::Relaxed);
                 }
+            }
+
+            for port in &mut playout.outs {
+                peak = port
+                    .as_mut_slice(ps)
+                    .iter()
+                    .fold(peak, |peak, sample| peak.max(sample.abs()));
+            }
+        }
+
+        // Non-negative floats order the same as their bits, so fetch_max works
+        self.output_peak
+            .fetch_max(peak.to_bits(), Ordering::Relaxed);
+        let buffered: usize = self.playouts.iter().map(|p| p.buffer.available()).sum();
+        self.metrics
+            .buffered
+            .store(buffered as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// Where an audio packet falls in the sequence
+enum Arrival {
+    InOrder { missing: u32 },     // Newest so far, after this many lost packets
+    Late,                         // Older than the newest, its place already filled with silence
+    Duplicate { expected: bool }, // Already received, expected of copies and of what they beat
+    Replayed,                     // Stale or from an earlier run, on an authenticated stream
+}
+
+// Messages for cross-thread communication
+enum Message {
+    InvalidBufferLengths,
+    PeriodTooLarge {
+        period: usize,
+        capacity: usize,
+    }, // In samples
+    PeriodChanged {
+        frames: usize,
+    },
+    RingBufferTooSmall {
+        period_bytes: usize,
+        ring_buffer_size: usize,
+    },
+    Underrun {
+        stream: u8,
+        expected: usize,
+        available: usize,
+    },
+    State {
+        stream: u8,
+        state: PlayoutState,
+    },
+    Release(RingBufferReader), // A drained ring buffer, freed on the receiving side
+}
 
-                Control::Continue
-            }),
-        )
+// A stream from senders played on JACK output ports
+pub struct Receiver {
+    bind: SocketAddr,
+    options: Options,
+}
+
+impl Receiver {
+    pub fn new(bind: SocketAddr, options: Options) -> Self {
+        Self { bind, options }
+    }
+
+    // Receives into `client` on the calling thread until an error or a shutdown stops it
+    pub fn run(self, client: Client) -> Result<(), &'static str> {
+        start(client, self)
+    }
+
+    // Receives on a thread of its own, whose handle yields how it stopped
+    pub fn start(self, client: Client) -> JoinHandle<Result<(), &'static str>> {
+        thread::spawn(move || self.run(client))
+    }
+}
+
+// Receiver main function
+fn start(client: Client, receiver: Receiver) -> Result<(), &'static str> {
+    let latency = Latency::new(LatencyType::Capture);
+    latency::register(&client, slice::from_ref(&latency))?;
+    let (metrics, process, network) = prepare(&client, receiver, latency)?;
+    let async_client = client
+        .activate_async(Notifications(metrics), process)
         .map_err(|_| "unable to activate client")?;
+    let result = network(async_client.as_client());
+    let _ = async_client.deactivate();
+    result
+}
 
-    // Main network receive loop
-    let mut buffer = [0; PACKET_SIZE];
-    loop {
-        // Handle messages from audio thread
-        receiver.try_iter().for_each(|message| match message {
-            Message::InvalidBufferLengths => eprintln!("[WARNING] invalid buffer lengths"),
-            Message::Underrun {
-                expected,
-                available,
-            } => eprintln!(
-                "[WARNING] underrun, expected to read {} bytes, {} available",
-                expected, available
-            ),
-        });
+// Sets up receiving into `client`, returning the metrics, the handler for the process
+// callback and the network loop to run on the client once it is active
+#[allow(clippy::type_complexity)]
+pub(crate) fn prepare(
+    client: &Client,
+    Receiver { bind, mut options }: Receiver,
+    latency: Arc<Latency>,
+) -> Result<
+    (
+        Arc<Metrics>,
+        impl ProcessHandler + use<>,
+        impl FnOnce(&Client) -> Result<(), &'static str> + use<>,
+    ),
+    &'static str,
+> {
+    // Rate at which buffered audio drains, used to estimate playout time
+    let format = options.format;
+    let sample_size = format.sample_size();
+    let channels = options.channels;
+    let frame_size = channels * sample_size;
+    let bytes_per_second = client.sample_rate() as f64 * frame_size as f64;
 
-        // Receive UDP packet
-        let received = socket
-            .recv_from(&mut buffer)
-            .map_err(|_| "unable to receive data")?
-            .0;
-        if received == buffer.len() {
-            // Write valid packets to ring buffer
-            let rb_space = ring_buffer_writer.space();
-            if rb_space >= buffer.len() {
-                ring_buffer_writer.write_buffer(&buffer);
-            } else {
-                eprintln!(
-                    "[WARNING] overrun, expected to write {} bytes, {} available",
-                    buffer.len(),
-                    rb_space
-                );
-            }
-        } else {
-            eprintln!(
-                "[WARNING] invalid packet size, expected {}, got {}, dropping",
-                PACKET_SIZE, received
-            );
+    // Length of the fade-in armed by underruns
+    let fade_length = client.sample_rate() * options.recovery_fade_ms as usize / 1000;
+    // Length of the crossfade into audio after lost packets, in frames
+    let crossfade_length = client.sample_rate() * options.loss_crossfade_ms as usize / 1000;
+
+    // Fails early when built without encryption
+    let cipher = Cipher::new(options.key, options.auth_key)?;
+    let sessions = Sessions::new(
+        options.key,
+        options.auth_key,
+        options.identity,
+        options.peer_keys.clone(),
+    )?;
+    if options.identity.is_some() && options.relay.is_some() {
+        return Err("--relay cannot be combined with --identity");
+    }
+
+    // Open the sinks before activating the client so failures are reported early
+    let mut sinks = Vec::new();
+    if let Some(path) = &options.record {
+        sinks.push(Sink::Record(Recorder::create(
+            path,
+            client.sample_rate() as u32,
+            channels as u16,
+        )?));
+    }
+    if let Some(destination) = options.relay {
+        sinks.push(Sink::relay(destination, &options, cipher.clone())?);
+    }
+
+    let routes = options
+        .map
+        .clone()
+        .unwrap_or_else(|| (0..channels).map(Some).collect());
+    if routes.iter().flatten().any(|&channel| channel >= channels) {
+        return Err("--map names a channel beyond --channels");
+    }
+    // Codecs work on interleaved samples
+    if options.planar && options.codec != Codec::Pcm {
+        return Err("--planar requires --codec pcm");
+    }
+    // With Opus every packet carries one frame, which takes the place of --packet-size
+    if options.codec == Codec::Opus {
+        options.packet_size =
+            codec::opus_packet_size(client.sample_rate(), channels, options.opus_frame, format)?;
+    }
+    if !options.packet_size.is_multiple_of(frame_size) {
+        return Err("packet size must be a whole number of frames");
+    }
+    // An adaptive ring buffer may shrink down to its lower bound
+    let ring_buffer_size = options
+        .adaptive
+        .map_or(options.ring_buffer_size, |(min, _)| min);
+    if options.packet_size >= ring_buffer_size {
+        return Err("ring buffer must be larger than a packet");
+    }
+    // RTP sources send raw interleaved samples and none of the netaudio extras
+    if options.rtp.is_some() {
+        if options.transport != Transport::Udp {
+            return Err("--rtp requires the udp transport");
+        }
+        if options.codec != Codec::Pcm || options.planar {
+            return Err("--rtp requires --codec pcm without --planar");
+        }
+        if options.verify
+            || options.key.is_some()
+            || options.auth_key.is_some()
+            || options.identity.is_some()
+        {
+            return Err("--rtp cannot be combined with --verify, --key, --auth-key or --identity");
+        }
+        // Only PTP timestamps tell when the audio was captured
+        if options.measure_latency && !options.aes67 {
+            return Err("--rtp cannot be combined with --measure-latency, except with --aes67");
         }
     }
+    if options.aes67 && client.sample_rate() != AES67_SAMPLE_RATE {
+        return Err("--aes67 requires a JACK sample rate of 48 kHz");
+    }
+    let rtp = options
+        .rtp
+        .map(|encoding| rtp::Decoder::new(encoding, client.sample_rate(), channels, options.aes67));
+
+    // Bind socket for receiving audio data
+    let socket = match options.transport {
+        Transport::Udp => transport::bind_udp(bind, options.v6only, options.multicast_interface)?,
+        Transport::Tcp => transport::listen(bind, options.v6only)?,
+        Transport::Quic => quic::listen(bind, options.v6only)?,
+    };
+    // The first packet of the stream ends the checks and is lost, the handshake is repeated
+    if let Some(exchange) = &options.ice {
+        ice::establish(&socket, exchange, ice::Role::Controlled)?;
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    metrics
+        .sample_rate
+        .store(client.sample_rate() as u64, Ordering::Relaxed);
+    let controls = Arc::new(Controls::new());
+
+    // Advertised senders wait to be asked for their stream
+    let subscription = match &options.connect_name {
+        Some(_) if options.transport != Transport::Udp => {
+            return Err("--connect-name requires the udp transport");
+        }
+        Some(name) => {
+            let sender = mdns::find(name)?;
+            info!("found stream {} at {}", name, sender);
+            Some(sender)
+        }
+        None => None,
+    };
+
+    // Channel for sending warnings from audio thread to main thread
+    let (sender, receiver) = mpsc::channel();
+
+    // Stream 0 always exists, further streams are opened as their packets arrive
+    let initial_resume_level = match (options.jitter_buffer, options.adaptive) {
+        (Some(bounds), _) => jitter_target(
+            0.0,
+            bounds,
+            options.packet_size,
+            options.ring_buffer_size,
+            bytes_per_second,
+            frame_size,
+        ),
+        (None, Some(_)) => resume_level(options.ring_buffer_size, frame_size),
+        (None, None) => 0,
+    };
+    // With --wait-for-stream, stream 0 stays quiet until its first packet. Other streams are
+    // opened by their first packet.
+    let initial_state = if options.wait_for_stream {
+        PlayoutState::Waiting
+    } else {
+        PlayoutState::Prebuffering
+    };
+    let (stream, playout) = open_stream(client, 0, &options, initial_resume_level, initial_state)?;
+    let (handoff_sender, handoff_receiver) = mpsc::channel();
+    let max_buffered = options.max_latency_ms.map(|ms| {
+        let bytes = (bytes_per_second * ms as f64 / 1000.0) as usize;
+        bytes - bytes % frame_size
+    });
+
+    // Tell JACK how far behind the network the outputs play
+    latency.add_ports(
+        0,
+        playout.outs.iter().map(Port::clone_unowned),
+        latency_range(
+            options.ring_buffer_size,
+            initial_resume_level,
+            max_buffered,
+            frame_size,
+        ),
+    );
+
+    let connections = Connections::new(options.connect.clone(), true);
+    connections.add_ports(playout.outs.iter().map(Port::clone_unowned));
+
+    let mut playouts = Vec::with_capacity(options.max_streams);
+    playouts.push(playout);
+
+    // Highest output peak since the network thread last looked, as f32 bits
+    let output_peak = Arc::new(AtomicU32::new(0));
+    let limited = Arc::new(AtomicU64::new(0));
+    let process = Process {
+        playouts,
+        handoffs: handoff_receiver,
+        // Buffers for decoding and deinterleaving, shared by all streams
+        buffers: ProcessBuffers::new(client.buffer_size() as usize, channels, sample_size),
+        format,
+        channels,
+        routes,
+        fade_length,
+        conceal_length: client.sample_rate() * options.underrun_conceal_ms as usize / 1000,
+        // Underruns play noise at this level instead of silence if requested
+        comfort_noise: options.comfort_noise.map(ComfortNoise::new),
+        output_peak: output_peak.clone(),
+        limit: options.limit,
+        limited: limited.clone(),
+        controls: controls.clone(),
+        level: controls.level(),
+        metrics: metrics.clone(),
+        messages: sender,
+    };
+
+    let process = ClosureProcessHandler::with_state(
+        process,
+        |process, _, ps| match process.process(ps) {
+            Ok(()) => Control::Continue,
+            Err(message) => {
+                let _ = process.messages.send(message);
+                Control::Quit
+            }
+        },
+        // Grow the scratch buffers when the period changes, outside of processing
+        move |process, _, frames| {
+            if frames as usize != process.buffers.frames {
+                let _ = process.messages.send(Message::PeriodChanged {
+                    frames: frames as usize,
+                });
+            }
+            process
+                .buffers
+                .resize(frames as usize, channels, sample_size);
+            for playout in &mut process.playouts {
+                playout.held.resize(frames as usize * channels, 0.0);
+                playout.held_frames = 0;
+            }
+            let period_bytes = frames as usize * frame_size;
+            if period_bytes >= ring_buffer_size {
+                let _ = process.messages.send(Message::RingBufferTooSmall {
+                    period_bytes,
+                    ring_buffer_size,
+                });
+            }
+            Control::Continue
+        },
+    );
+
+    let warn_interval = options.warn_interval;
+    // Summaries count underruns, overruns and xruns in place of the warnings
+    let stats_interval = options.stats_interval;
+    let summarized = move |what| match stats_interval {
+        Some(_) => WarningCounter::silent(what),
+        None => WarningCounter::new(what, warn_interval),
+    };
+    // Authentication alone drops forged packets silently
+    let forged = options
+        .auth_key
+        .is_none()
+        .then(|| WarningCounter::new("packets failing authentication", warn_interval));
+
+    // Served once everything above has been checked, so a failing stream leaves nothing behind
+    if let Some(addr) = options.osc {
+        let name = options.name.clone();
+        osc::serve(
+            addr,
+            name,
+            controls.clone(),
+            metrics.clone(),
+            bytes_per_second,
+        )?;
+    }
+    for addr in [options.metrics, options.http].into_iter().flatten() {
+        metrics::serve(addr, metrics.clone())?;
+    }
+    if let Some(interval) = options.stats_interval {
+        let name = options.name.clone();
+        metrics::report(
+            metrics.clone(),
+            interval,
+            name,
+            "received",
+            bytes_per_second,
+        );
+    }
+    let returned_metrics = metrics.clone();
+    let network = move |client: &Client| {
+        let kept = connections.clone();
+        let result = connect::keep(client, &kept, || {
+            run(
+                socket,
+                Network {
+                    options,
+                    sample_size,
+                    frame_size,
+                    bytes_per_second,
+                    client,
+                    streams: vec![stream],
+                    handoffs: handoff_sender,
+                    initial_resume_level,
+                    messages: receiver,
+                    interleave_buffer: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+                    resample_buffer: Vec::new(),
+                    convert_buffer: Vec::new(),
+                    concealed: Vec::new(),
+                    rtp,
+                    subscription,
+                    last_subscribed: None,
+                    latency_stats: LatencyStats::new(),
+                    last_latency_report: Instant::now(),
+                    last_jitter_report: Instant::now(),
+                    sinks,
+                    last_received: Instant::now(),
+                    timed_out: false,
+                    reply: [0; HEADER_SIZE + STREAM_CONFIG_SIZE + LOSS_REPORT_SIZE],
+                    output_peak,
+                    limited,
+                    last_limiter_report: Instant::now(),
+                    last_audio: Instant::now(),
+                    last_sound: Instant::now(),
+                    silent: false,
+                    max_buffered,
+                    crossfade_length,
+                    latency,
+                    trims: WarningCounter::new("latency trims", warn_interval),
+                    underruns: summarized("underruns"),
+                    overruns: summarized("overruns"),
+                    xruns: match stats_interval {
+                        Some(_) => XrunWarnings::silent(),
+                        None => XrunWarnings::new(warn_interval),
+                    },
+                    duplicates: WarningCounter::new("duplicate packets", warn_interval),
+                    replays: WarningCounter::new("replayed packets", warn_interval),
+                    rejected: WarningCounter::new(
+                        "packets of streams over the limit",
+                        warn_interval,
+                    ),
+                    forged,
+                    metrics,
+                    connections,
+                },
+                sessions,
+            )
+        });
+        // Fade out over the next period instead of cutting off the output, and let it play
+        if result.is_ok() {
+            controls.mute();
+            let period = client.buffer_size() as f64 / client.sample_rate() as f64;
+            thread::sleep(Duration::from_secs_f64(2.0 * period));
+        }
+        result
+    };
+    Ok((returned_metrics, process, network))
+}
+
+// Network side of the receiver, independent of how datagrams are received
+struct Network<'a> {
+    options: Options,
+    sample_size: usize,
+    frame_size: usize, // Bytes per frame of all channels
+    bytes_per_second: f64,
+    client: &'a Client, // Registers the ports of streams opened at runtime
+    streams: Vec<Stream>,
+    handoffs: mpsc::Sender<Handoff>,
+    initial_resume_level: usize, // Resume level of streams opened at the initial ring buffer size
+    messages: mpsc::Receiver<Message>,
+    interleave_buffer: Box<[u8]>,
+    resample_buffer: Vec<u8>,
+    convert_buffer: Vec<u8>, // Samples converted from a stream's wire format
+    concealed: Vec<u8>,      // Audio the codec made up for packets lost before this one
+    rtp: Option<rtp::Decoder>, // Converts incoming RTP with --rtp
+    subscription: Option<SocketAddr>, // Sender found with --connect-name
+    last_subscribed: Option<Instant>,
+    latency_stats: LatencyStats,
+    last_latency_report: Instant,
+    last_jitter_report: Instant,
+    sinks: Vec<Sink>,
+    last_received: Instant,
+    timed_out: bool,
+    reply: [u8; HEADER_SIZE + STREAM_CONFIG_SIZE + LOSS_REPORT_SIZE], // Large enough for any reply
+    output_peak: Arc<AtomicU32>,
+    limited: Arc<AtomicU64>,
+    last_limiter_report: Instant,
+    last_audio: Instant, // Last audio packet, as opposed to any packet
+    last_sound: Instant, // Last time the output peak crossed the silence threshold
+    silent: bool,
+    max_buffered: Option<usize>, // Buffered bytes corresponding to --max-latency-ms
+    crossfade_length: usize,     // Frames crossfaded after lost packets
+    latency: Arc<Latency>,
+    trims: WarningCounter,
+    underruns: WarningCounter,
+    overruns: WarningCounter,
+    xruns: XrunWarnings,
+    duplicates: WarningCounter,
+    replays: WarningCounter,
+    rejected: WarningCounter,
+    forged: Option<WarningCounter>,
+    metrics: Arc<Metrics>,
+    connections: Arc<Connections>, // Connects the ports of streams opened at runtime
+}
+
+impl Network<'_> {
+    // Handles messages from audio thread, fails if it stopped processing
+    fn handle_messages(&mut self) -> Result<(), &'static str> {
+        for message in self.messages.try_iter() {
+            match message {
+                Message::InvalidBufferLengths => {
+                    return Err("invalid buffer lengths, JACK processing stopped");
+                }
+                Message::PeriodTooLarge { period, capacity } => warning!(
+                    "JACK period of {} samples exceeds the buffer capacity of {} samples, \
+                     playing silence until the buffers are resized",
+                    period,
+                    capacity
+                ),
+                Message::PeriodChanged { frames } => {
+                    info!("JACK period changed to {} frames", frames)
+                }
+                Message::RingBufferTooSmall {
+                    period_bytes,
+                    ring_buffer_size,
+                } => error!(
+                    "JACK period of {} bytes does not fit the {} byte ring buffer, \
+                 try --ring-buffer-size {}",
+                    period_bytes,
+                    ring_buffer_size,
+                    (2 * period_bytes).next_power_of_two()
+                ),
+                Message::Underrun {
+                    stream,
+                    expected,
+                    available,
+                } => {
+                    // Only underruns while packets arrive say anything about the buffer size
+                    if let Some(stream) = self.streams.iter_mut().find(|s| s.id == stream)
+                        && stream.last_packet.elapsed() < STREAM_TIMEOUT
+                    {
+                        stream.underruns += 1;
+                        stream.last_underrun = Instant::now();
+                    }
+                    self.metrics.underruns.fetch_add(1, Ordering::Relaxed);
+                    if self.underruns.record() {
+                        warning!(
+                            "underrun, expected to read {} bytes, {} available",
+                            expected,
+                            available
+                        );
+                    }
+                }
+                Message::State { stream, state } => {
+                    let Some(stream) = self.streams.iter_mut().find(|s| s.id == stream) else {
+                        continue;
+                    };
+                    match (stream.state, state) {
+                        (PlayoutState::Waiting, _) => {
+                            info!(stream = stream.id, "first packet received, prebuffering")
+                        }
+                        (_, PlayoutState::Playing) if !stream.played => {
+                            info!(stream = stream.id, "playing");
+                            stream.played = true;
+                        }
+                        _ => {}
+                    }
+                    stream.state = state;
+                }
+                Message::Release(reader) => drop(reader),
+            }
+        }
+        Ok(())
+    }
+
+    // Drops a datagram that failed authentication: forged, sealed with another key, or from a
+    // sender without session keys
+    fn reject_forged(&mut self) {
+        self.metrics.malformed.fetch_add(1, Ordering::Relaxed);
+        if let Some(forged) = &mut self.forged
+            && forged.record()
+        {
+            warning!("packet failed authentication, dropping");
+        }
+    }
+
+    // Handles a received datagram, returns a reply to send back to its source if needed
+    fn handle_datagram(&mut self, datagram: &[u8]) -> Option<&[u8]> {
+        let Some(mut rtp) = self.rtp.take() else {
+            return self.handle_packet(datagram);
+        };
+        // RTP sources get no replies, they would not understand them
+        match rtp.decode(datagram, self.options.format) {
+            Ok(Some(packet)) => {
+                self.handle_packet(packet);
+            }
+            Ok(None) => {}
+            Err(error) => {
+                self.metrics.malformed.fetch_add(1, Ordering::Relaxed);
+                warning!("{}, dropping", error);
+            }
+        }
+        self.rtp = Some(rtp);
+        None
+    }
+
+    // Handles a packet in netaudio's own format
+    fn handle_packet(&mut self, datagram: &[u8]) -> Option<&[u8]> {
+        let parsed = packet::parse(datagram);
+
+        // Any well-formed packet proves the link is alive
+        if parsed.is_ok() {
+            self.last_received = Instant::now();
+            if self.timed_out {
+                info!("stream resumed");
+                self.timed_out = false;
+            }
+        }
+
+        match parsed {
+            Err(error) => {
+                self.metrics.malformed.fetch_add(1, Ordering::Relaxed);
+                warning!("{}, dropping", error);
+            }
+            // Senders exchanging keys expect a receiver with --identity
+            Ok((header, _)) if header.flags & FLAG_HANDSHAKE == FLAG_HANDSHAKE => {
+                warning!("key exchange from a sender, but --identity is not set");
+            }
+            // Echo round-trip probes back to the sender, with the time they arrived at
+            Ok((mut header, payload)) if header.flags & FLAG_PING != 0 => {
+                let arrived = packet::now_micros();
+                if let Some(Probe(rtt)) = Probe::read(payload).filter(|&Probe(rtt)| rtt > 0) {
+                    self.metrics.rtt.store(rtt, Ordering::Relaxed);
+                    info!(
+                        "round-trip time: {:.2} ms, one-way: {:.2} ms, by the clocks {:.2} ms",
+                        rtt as f64 / 1000.0,
+                        rtt as f64 / 2000.0,
+                        packet::millis_between(header.timestamp, arrived)
+                    );
+                }
+                header.flags = FLAG_PONG;
+                header.length = PROBE_SIZE as u16;
+                header.write(&mut self.reply);
+                Probe(arrived).write(&mut self.reply[HEADER_SIZE..]);
+                return Some(&self.reply[..HEADER_SIZE + PROBE_SIZE]);
+            }
+            // Tell senders which settings this receiver expects
+            Ok((mut header, payload)) if header.flags & FLAG_HELLO != 0 => {
+                // Senders announce their settings, older ones send none
+                let requested = StreamConfig::read(payload);
+                let sample_rate = self.client.sample_rate() as u32;
+                let index = match (self.stream_index(header.stream), requested) {
+                    (Some(index), Some(requested)) => {
+                        // The sender stops once it sees the reply
+                        if requested.channels as usize != self.options.channels {
+                            warning!(
+                                "stream {} sends {} channels, expected {}",
+                                header.stream,
+                                requested.channels,
+                                self.options.channels
+                            );
+                        }
+                        if let Some(frames) = requested.frames_per_packet {
+                            self.follow_packet_size(index, frames);
+                        }
+                        let codec = self.negotiate(index, requested.codec);
+                        self.follow_sample_rate(index, requested.sample_rate, codec);
+                        self.follow_format(index, requested.format);
+                        Some(index)
+                    }
+                    (index, _) => index,
+                };
+                let (packet_size, codec, format) = match index {
+                    Some(index) => {
+                        let stream = &self.streams[index];
+                        (stream.packet_size, stream.codec, stream.format)
+                    }
+                    None => (
+                        self.options.packet_size,
+                        self.options.codec,
+                        self.options.format,
+                    ),
+                };
+                header.length = STREAM_CONFIG_SIZE as u16;
+                header.write(&mut self.reply);
+                StreamConfig {
+                    packet_size: packet_size as u16,
+                    format,
+                    planar: self.options.planar,
+                    codec,
+                    sample_rate: Some(sample_rate),
+                    channels: self.options.channels as u8,
+                    frames_per_packet: None,
+                }
+                .write(&mut self.reply[HEADER_SIZE..]);
+                return Some(&self.reply[..HEADER_SIZE + STREAM_CONFIG_SIZE]);
+            }
+            // Parity packets fill gaps, receivers without FEC take them for keepalives
+            Ok((header, payload)) if header.flags & FLAG_PARITY == FLAG_PARITY => {
+                let index = self.stream_index(header.stream)?;
+                self.streams[index].fec.add_parity(header.sequence, payload);
+                self.release_held(index, false);
+            }
+            // Keepalives only refresh the liveness timer
+            Ok((header, _)) if header.flags & FLAG_KEEPALIVE != 0 => {}
+            // Compressed payloads are checked by the decoder instead
+            Ok((header, payload))
+                if self.stream_codec(header.stream) == Codec::Pcm
+                    && !payload
+                        .len()
+                        .is_multiple_of(self.wire_frame_size(header.stream)) =>
+            {
+                warning!(
+                    "payload of {} bytes is not a whole number of frames, dropping",
+                    payload.len()
+                )
+            }
+            Ok((header, payload)) => {
+                trace!(
+                    stream = header.stream,
+                    sequence = header.sequence,
+                    length = header.length,
+                    "audio packet"
+                );
+                let index = self.stream_index(header.stream)?;
+                // Packets behind a gap wait for parity to fill it
+                let stream = &mut self.streams[index];
+                if !stream
+                    .fec
+                    .hold(stream.next_sequence, header.sequence, datagram)
+                {
+                    self.play(index, datagram, header, payload, false);
+                }
+                self.release_held(index, false);
+
+                // Piggyback loss reports on incoming audio, which carries the sender address
+                return self.loss_report(index);
+            }
+        }
+        None
+    }
+
+    // Plays an audio packet of a stream, `recovered` from parity or as it arrived
+    fn play(
+        &mut self,
+        index: usize,
+        datagram: &[u8],
+        header: Header,
+        payload: &[u8],
+        recovered: bool,
+    ) {
+        // Audio at a refused sample rate would play at the wrong speed and pitch
+        if self.streams[index].refused {
+            return;
+        }
+        // Held packets count as arriving when played, the jitter includes waiting for parity
+        let received_at = packet::now_micros();
+        let sample_size = self.sample_size;
+        // Repeats would be played twice, throwing off the timing
+        let arrival = self.track_sequence(index, header, header.flags & FLAG_COPY != 0);
+        if let Arrival::Replayed = arrival {
+            self.metrics.replayed.fetch_add(1, Ordering::Relaxed);
+            if self.replays.record() {
+                warning!("replayed packet {}, dropping", header.sequence);
+            }
+            return;
+        }
+        if let Arrival::Duplicate { expected } = arrival {
+            // Redundant copies, and originals arriving over a slower path, are dropped quietly
+            if expected {
+                return;
+            }
+            self.metrics.duplicates.fetch_add(1, Ordering::Relaxed);
+            if self.duplicates.record() {
+                warning!("duplicate packet {}, dropping", header.sequence);
+            }
+            return;
+        }
+        self.streams[index].fec.played(header.sequence, datagram);
+        // Sample counts the sender appended after the payload with --verify, which cannot be
+        // compared once resampled
+        let resampled = self.streams[index].resampler.is_some();
+        let sent = (self.options.verify && header.flags & FLAG_VERIFY != 0 && !resampled)
+            .then(|| SampleCount::read(&datagram[HEADER_SIZE + payload.len()..]))
+            .flatten();
+        let stream = &mut self.streams[index];
+        stream.last_packet = Instant::now();
+        let underrun_silence = stream.underrun_silence.swap(0, Ordering::Relaxed);
+        stream.jitter.add(header.timestamp, received_at);
+        let metrics = &self.metrics;
+        metrics.packets.fetch_add(1, Ordering::Relaxed);
+        metrics.last_packet.store(received_at, Ordering::Relaxed);
+        metrics
+            .bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        metrics
+            .jitter
+            .store(stream.jitter.smoothed.to_bits(), Ordering::Relaxed);
+        self.last_audio = Instant::now();
+
+        let format = self.options.format;
+        // Opus conceals losses from its state, so it has to before decoding what follows them.
+        // More than fits the ring buffer would never be played.
+        self.concealed.clear();
+        if let Arrival::InOrder { missing } = arrival
+            && let Some(decoder) = &mut stream.decoder
+        {
+            for _ in 0..missing {
+                match decoder.conceal(format, &mut self.interleave_buffer) {
+                    Ok(length) if length > 0 && self.concealed.len() < stream.capacity => {
+                        self.concealed
+                            .extend_from_slice(&self.interleave_buffer[..length]);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        let wire_sample_size = stream.format.sample_size();
+        let interleaved = if self.options.planar {
+            let planar = from_planar(payload, wire_sample_size, self.options.channels);
+            self.interleave_buffer
+                .chunks_exact_mut(wire_sample_size)
+                // Already checked for whole frames, so unwrapping is safe
+                .zip(planar.unwrap())
+                .for_each(|(buffer_val, data)| buffer_val.copy_from_slice(data));
+            &self.interleave_buffer[..payload.len()]
+        } else if let Some(decoder) = &mut stream.decoder {
+            match decoder.decode(payload, format, &mut self.interleave_buffer) {
+                Ok(length) => &self.interleave_buffer[..length],
+                Err(error) => {
+                    self.metrics.malformed.fetch_add(1, Ordering::Relaxed);
+                    warning!("{}, dropping", error);
+                    return;
+                }
+            }
+        } else {
+            payload
+        };
+        let interleaved = if stream.format != format {
+            stream
+                .format
+                .convert(format, interleaved, &mut self.convert_buffer);
+            &self.convert_buffer[..]
+        } else {
+            interleaved
+        };
+        let interleaved = match &mut stream.resampler {
+            Some(resampler) => {
+                resampler.process(format, interleaved, &mut self.resample_buffer);
+                &self.resample_buffer[..]
+            }
+            None => interleaved,
+        };
+
+        // Pass on everything that arrived, regardless of whether it gets played
+        self.sinks.retain_mut(|sink| {
+            sink.write(format, header.stream, datagram, interleaved)
+                .map_err(|error| warning!("{}, stopping it", error))
+                .is_ok()
+        });
+
+        // Write valid packets to ring buffer, a late one's place was already taken by silence
+        let writer = &mut stream.ring_buffer_writer;
+        let rb_space = writer.space();
+        let buffered = stream.capacity - 1 - rb_space;
+        stream.low_water = Some(stream.low_water.map_or(buffered, |low| low.min(buffered)));
+        match arrival {
+            Arrival::InOrder { missing } if rb_space >= interleaved.len() => {
+                // Silence already played while waiting covers part of the gap
+                let missing_bytes =
+                    (missing as usize * interleaved.len()).saturating_sub(underrun_silence);
+                let crossfade_length = self.crossfade_length;
+                let concealed_bytes = if !self.concealed.is_empty() {
+                    // The end of what the codec made up joins the packet, silence stands in
+                    // for anything it could not
+                    let limit = missing_bytes.min(writer.space().saturating_sub(interleaved.len()));
+                    let limit = limit - limit % self.frame_size;
+                    let made_up = &self.concealed[self.concealed.len().saturating_sub(limit)..];
+                    let silence = conceal(writer, limit - made_up.len(), interleaved.len());
+                    writer.write_buffer(made_up);
+                    stream.recovery = None;
+                    silence + made_up.len()
+                } else if missing > 0 && crossfade_length > 0 {
+                    // After an underrun the output already went silent, nothing to hold
+                    let mut tail = stream.last_frame.clone();
+                    if underrun_silence > 0 {
+                        tail.fill(0.0);
+                    }
+                    let concealed = conceal_tail(
+                        writer,
+                        format,
+                        &tail,
+                        missing_bytes,
+                        interleaved.len(),
+                        crossfade_length,
+                    );
+                    stream.recovery = Some(Recovery {
+                        tail,
+                        elapsed: concealed,
+                        mixed: 0,
+                    });
+                    concealed * self.frame_size
+                } else {
+                    conceal(writer, missing_bytes, interleaved.len())
+                };
+                write_audio(
+                    writer,
+                    format,
+                    interleaved,
+                    &mut stream.recovery,
+                    crossfade_length,
+                    &mut stream.last_frame,
+                );
+                // A packet rebuilt from parity stands in for an original that was lost, copies
+                // are counted once their original fails to turn up
+                if recovered {
+                    stream.recovered += 1;
+                    self.metrics.recovered.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(sent) = sent {
+                    // Silence played out on underruns counts as far as it was
+                    // credited against the gap
+                    let credited = (missing as usize * interleaved.len()).min(underrun_silence);
+                    self.verify(
+                        index,
+                        sent,
+                        (concealed_bytes + credited) / sample_size,
+                        interleaved.len() / sample_size,
+                    );
+                }
+                self.cap_latency(index);
+
+                if self.options.measure_latency {
+                    // Time in flight plus time until the end of this packet is played out
+                    let in_flight = packet::now_micros().saturating_sub(header.timestamp);
+                    let buffered = self.buffered(index) as f64;
+                    self.latency_stats
+                        .add(in_flight as f64 / 1000.0 + buffered / self.bytes_per_second * 1000.0);
+                }
+            }
+            Arrival::InOrder { .. } => {
+                self.metrics.overruns.fetch_add(1, Ordering::Relaxed);
+                if self.overruns.record() {
+                    warning!(
+                        "overrun, expected to write {} bytes, {} available",
+                        payload.len(),
+                        rb_space
+                    );
+                }
+            }
+            Arrival::Late | Arrival::Duplicate { .. } | Arrival::Replayed => {}
+        }
+        let buffered = self.buffered(index);
+        let stream = &mut self.streams[index];
+        if self.options.drift_correction && stream.state == PlayoutState::Playing {
+            stream.drift.add(buffered as f64 / self.bytes_per_second);
+        }
+
+        // A finished stream is not a timeout, and a new one starts its own sequence
+        if header.flags & FLAG_END != 0 {
+            info!(stream = header.stream, "sender ended the stream");
+            // Other streams may still be running
+            self.timed_out = self.streams.len() == 1;
+            let stream = &mut self.streams[index];
+            stream.next_sequence = None;
+            stream.jitter = Jitter::new();
+            stream.timeline = None;
+            stream.fec = fec::Decoder::default();
+        }
+    }
+
+    // Plays the packets held behind a gap that are due, with `give_up` all of them even if
+    // parity may still fill the gap
+    fn release_held(&mut self, index: usize, give_up: bool) {
+        loop {
+            let stream = &mut self.streams[index];
+            let Some((datagram, recovered)) = stream.fec.release(stream.next_sequence, give_up)
+            else {
+                break;
+            };
+            // Recovered packets are only known to be well-formed once parsed
+            if let Ok((header, payload)) = packet::parse(&datagram) {
+                self.play(index, &datagram, header, payload, recovered);
+            }
+        }
+    }
+
+    // Codec of a stream, or the one it will start out with
+    fn stream_codec(&self, id: u8) -> Codec {
+        self.streams
+            .iter()
+            .find(|stream| stream.id == id)
+            .map_or(self.options.codec, |stream| stream.codec)
+    }
+
+    // Bytes per frame of a stream's packets, or of the ones it will start out with
+    fn wire_frame_size(&self, id: u8) -> usize {
+        let format = self
+            .streams
+            .iter()
+            .find(|stream| stream.id == id)
+            .map_or(self.options.format, |stream| stream.format);
+        self.options.channels * format.sample_size()
+    }
+
+    // Takes raw samples in the format a stream's sender names, converting them to the
+    // receiver's on arrival. Compressed streams decode to the receiver's format by themselves.
+    fn follow_format(&mut self, index: usize, requested: SampleFormat) {
+        let stream = &mut self.streams[index];
+        let format = match stream.codec {
+            Codec::Pcm => requested,
+            _ => self.options.format,
+        };
+        if format == stream.format {
+            return;
+        }
+        if format != self.options.format {
+            info!(
+                "stream {} sends {} samples, converting to {}",
+                stream.id, format, self.options.format
+            );
+        }
+        stream.format = format;
+    }
+
+    // Switches a stream to the codec its sender asks for if both decode to exact samples, so
+    // lossless compression can be chosen per stream. Returns the codec the stream uses.
+    fn negotiate(&mut self, index: usize, requested: Codec) -> Codec {
+        let stream = &mut self.streams[index];
+        if requested == stream.codec
+            || !requested.is_exact()
+            || !stream.codec.is_exact()
+            || self.options.planar
+        {
+            return stream.codec;
+        }
+        match requested.decoder(self.client.sample_rate(), self.options.channels) {
+            Ok(decoder) => {
+                info!("stream {} uses the {} codec", stream.id, requested);
+                stream.codec = requested;
+                stream.decoder = decoder;
+            }
+            Err(error) => warning!("{}, stream {} keeps its codec", error, stream.id),
+        }
+        stream.codec
+    }
+
+    // Takes on the packet size of a sender that sets its frames per packet, which the jitter
+    // buffer plans with. Sizes no packet can carry are ignored.
+    fn follow_packet_size(&mut self, index: usize, frames: u16) {
+        let packet_size = frames as usize * self.frame_size;
+        let stream = &mut self.streams[index];
+        if packet_size == stream.packet_size || packet_size > MAX_PAYLOAD_SIZE {
+            return;
+        }
+        info!(
+            "stream {} sends {} frames per packet ({} bytes)",
+            stream.id, frames, packet_size
+        );
+        stream.packet_size = packet_size;
+    }
+
+    // Resamples a stream whose sender runs at another sample rate, or refuses it with
+    // --strict-rate or at a rate no sound card runs at. Opus decodes to the receiver's rate by
+    // itself.
+    fn follow_sample_rate(&mut self, index: usize, rate: Option<u32>, codec: Codec) {
+        let sample_rate = self.client.sample_rate() as u32;
+        let rate = rate.filter(|&rate| rate != sample_rate && codec != Codec::Opus);
+        let stream = &mut self.streams[index];
+        if stream.refused && rate.is_none() {
+            info!("stream {} now matches the sample rate, playing", stream.id);
+        }
+        stream.refused = self.options.strict_rate && rate.is_some();
+        match rate {
+            Some(rate) if self.options.strict_rate => {
+                error!(
+                    "sample rate mismatch: stream {} runs at {} Hz, JACK here at {} Hz, \
+                     refusing to play it",
+                    stream.id, rate, sample_rate
+                );
+                stream.resampler = None;
+            }
+            Some(rate) => match Resampler::new(rate, sample_rate, self.options.channels) {
+                Some(mut resampler) => {
+                    warning!(
+                        "sample rate mismatch: stream {} runs at {} Hz, JACK here at {} Hz, \
+                         resampling",
+                        stream.id,
+                        rate,
+                        sample_rate
+                    );
+                    resampler.set_correction(stream.drift.correction());
+                    stream.resampler = Some(resampler);
+                }
+                None => {
+                    error!(
+                        "stream {} announces a sample rate of {} Hz, which cannot be \
+                         resampled to {} Hz, refusing to play it",
+                        stream.id, rate, sample_rate
+                    );
+                    stream.refused = true;
+                    stream.resampler = None;
+                }
+            },
+            // Drift correction starts over at the same rate on its next update
+            None if stream.resampler.as_ref().is_some_and(Resampler::converts) => {
+                info!("stream {} no longer needs resampling", stream.id);
+                stream.resampler = None;
+            }
+            None => {}
+        }
+    }
+
+    // Counts audio packets skipped, arriving late or repeated according to their sequence
+    // numbers. `copy` packets are redundant copies or were sent over an extra path. Packets
+    // only an attacker could have sent are turned away when they are authenticated.
+    fn track_sequence(&mut self, index: usize, header: Header, copy: bool) -> Arrival {
+        let authenticated = self.options.key.is_some()
+            || self.options.auth_key.is_some()
+            || self.options.identity.is_some();
+        let stream = &mut self.streams[index];
+        let (sequence, timestamp) = (header.sequence, header.timestamp);
+        if authenticated
+            && replayed(
+                stream.next_sequence,
+                stream.newest_timestamp,
+                sequence,
+                timestamp,
+            )
+        {
+            return Arrival::Replayed;
+        }
+        let Some(next) = stream.next_sequence else {
+            // First packet of a stream
+            stream.loss.expected += 1;
+            stream.next_sequence = Some(sequence.wrapping_add(1));
+            stream.newest_timestamp = stream.newest_timestamp.max(timestamp);
+            stream.seen = 1;
+            stream.copied = copy as u64;
+            return Arrival::InOrder { missing: 0 };
+        };
+
+        let gap = sequence.wrapping_sub(next);
+        if gap < MAX_SEQUENCE_GAP {
+            stream.loss.expected += gap + 1;
+            stream.loss.lost += gap;
+            self.metrics.lost.fetch_add(gap.into(), Ordering::Relaxed);
+            stream.next_sequence = Some(sequence.wrapping_add(1));
+            stream.newest_timestamp = stream.newest_timestamp.max(timestamp);
+            // Bit 0 of the window always stands for the newest packet
+            stream.seen = stream.seen.checked_shl(gap + 1).unwrap_or(0) | 1;
+            // Copies leaving the window without their original stood in for a lost packet
+            let expired = match gap + 1 {
+                shift @ 1..64 => stream.copied >> (64 - shift),
+                _ => stream.copied,
+            };
+            stream.recovered += expired.count_ones();
+            self.metrics
+                .recovered
+                .fetch_add(expired.count_ones().into(), Ordering::Relaxed);
+            stream.copied = stream.copied.checked_shl(gap + 1).unwrap_or(0) | copy as u64;
+            Arrival::InOrder { missing: gap }
+        } else if gap.wrapping_neg() < MAX_SEQUENCE_GAP {
+            // Packets older than the window cannot be told apart from late ones
+            let bit = 1_u64.checked_shl(gap.wrapping_neg() - 1).unwrap_or(0);
+            if stream.seen & bit != 0 {
+                let expected = copy || stream.copied & bit != 0;
+                if !copy {
+                    stream.copied &= !bit;
+                }
+                return Arrival::Duplicate { expected };
+            }
+            stream.seen |= bit;
+            // A packet already counted as lost showed up after all
+            stream.loss.lost = stream.loss.lost.saturating_sub(1);
+            self.metrics.late.fetch_add(1, Ordering::Relaxed);
+            Arrival::Late
+        } else {
+            // The sender restarted, start counting afresh
+            stream.loss.expected += 1;
+            stream.next_sequence = Some(sequence.wrapping_add(1));
+            stream.newest_timestamp = stream.newest_timestamp.max(timestamp);
+            stream.seen = 1;
+            stream.copied = copy as u64;
+            stream.timeline = None;
+            Arrival::InOrder { missing: 0 }
+        }
+    }
+
+    // Builds a request for the stream of the sender found with --connect-name, every second
+    // while no audio arrives, as the request or the sender may have gone missing
+    fn next_subscription(&mut self) -> Option<([u8; HEADER_SIZE], SocketAddr)> {
+        let sender = self.subscription?;
+        let due = |instant: Instant| instant.elapsed() >= SUBSCRIBE_INTERVAL;
+        if !due(self.last_audio) || !self.last_subscribed.is_none_or(due) {
+            return None;
+        }
+        let mut request = [0; HEADER_SIZE];
+        Header {
+            sequence: 0,
+            timestamp: packet::now_micros(),
+            flags: FLAG_HELLO,
+            stream: 0,
+            length: 0,
+        }
+        .write(&mut request);
+        self.last_subscribed = Some(Instant::now());
+        Some((request, sender))
+    }
+
+    // Builds a loss report for the sender if one is due
+    fn loss_report(&mut self, index: usize) -> Option<&[u8]> {
+        let stream = &mut self.streams[index];
+        if stream.last_loss_report.elapsed() < LOSS_REPORT_INTERVAL {
+            return None;
+        }
+        if stream.recovered > 0 {
+            info!(
+                "stream {} recovered {} lost packets from redundant copies or parity",
+                stream.id, stream.recovered
+            );
+            stream.recovered = 0;
+        }
+        // The sender hears about this too, but only if reports reach it
+        if stream.loss.lost > 0 {
+            warning!(
+                "stream {} lost {} of {} packets ({:.1}%)",
+                stream.id,
+                stream.loss.lost,
+                stream.loss.expected,
+                stream.loss.percentage()
+            );
+        }
+
+        Header {
+            sequence: 0,
+            timestamp: packet::now_micros(),
+            flags: FLAG_REPORT,
+            stream: stream.id,
+            length: LOSS_REPORT_SIZE as u16,
+        }
+        .write(&mut self.reply);
+        stream.loss.write(&mut self.reply[HEADER_SIZE..]);
+        stream.loss = LossReport::default();
+        stream.last_loss_report = Instant::now();
+        Some(&self.reply[..HEADER_SIZE + LOSS_REPORT_SIZE])
+    }
+
+    // Bytes waiting to be played out on a stream
+    fn buffered(&mut self, index: usize) -> usize {
+        let stream = &mut self.streams[index];
+        stream.capacity - 1 - stream.ring_buffer_writer.space()
+    }
+
+    // Compares the samples per channel in a stream's timeline with the sender's count before
+    // the packet, reporting whenever the difference changes. `filled` samples stood in for lost
+    // packets ahead of the packet, which carries `received` samples.
+    fn verify(&mut self, index: usize, sent: SampleCount, filled: usize, received: usize) {
+        let stream = &mut self.streams[index];
+        // Joining a running stream starts from the sender's count, as does a sender
+        // restarting with other channels
+        if stream
+            .timeline
+            .as_ref()
+            .is_none_or(|timeline| timeline.0.len() != sent.0.len())
+        {
+            stream.timeline = Some(sent.clone());
+            stream.divergence.clear();
+        }
+        let Some(timeline) = &mut stream.timeline else {
+            return;
+        };
+        timeline.add_interleaved(filled);
+        let divergence = timeline.divergence(&sent);
+        timeline.add_interleaved(received);
+        // Nothing diverged before the first comparison
+        if stream.divergence.is_empty() {
+            stream.divergence = vec![0; divergence.len()];
+        }
+        if divergence == stream.divergence {
+            return;
+        }
+        if divergence.iter().all(|&difference| difference == 0) {
+            info!("stream {} samples aligned with the sender again", stream.id);
+        } else {
+            // Named as the ports are, left and right for stereo
+            let differences: Vec<_> = divergence
+                .iter()
+                .enumerate()
+                .map(|(channel, difference)| match divergence.len() {
+                    1 => format!("{:+}", difference),
+                    2 => format!("{} {:+}", ["left", "right"][channel], difference),
+                    _ => format!("channel {} {:+}", channel + 1, difference),
+                })
+                .collect();
+            warning!(
+                "stream {} samples diverged from the sender, {}",
+                stream.id,
+                differences.join(", ")
+            );
+        }
+        stream.divergence = divergence;
+    }
+
+    // Finds the stream a packet belongs to, opening a new one while under the limit
+    fn stream_index(&mut self, id: u8) -> Option<usize> {
+        if let Some(index) = self.streams.iter().position(|stream| stream.id == id) {
+            return Some(index);
+        }
+        if self.streams.len() >= self.options.max_streams {
+            if self.rejected.record() {
+                warning!(
+                    "stream {} exceeds the limit of {} streams, dropping",
+                    id,
+                    self.options.max_streams
+                );
+            }
+            return None;
+        }
+
+        let (stream, playout) = match open_stream(
+            self.client,
+            id,
+            &self.options,
+            self.initial_resume_level,
+            PlayoutState::Prebuffering,
+        ) {
+            Ok(opened) => opened,
+            Err(error) => {
+                if self.rejected.record() {
+                    warning!("{}, dropping stream {}", error, id);
+                }
+                return None;
+            }
+        };
+        let ports: Vec<_> = playout.outs.iter().map(Port::clone_unowned).collect();
+        self.connections
+            .add_ports(ports.iter().map(Port::clone_unowned));
+        self.handoffs.send(Handoff::Open(playout)).ok()?;
+        let range = latency_range(
+            stream.capacity,
+            self.initial_resume_level,
+            self.max_buffered,
+            self.frame_size,
+        );
+        self.latency.add_ports(id, ports, range);
+        latency::recompute(self.client);
+        info!(
+            "new stream {}, playing on {}",
+            id,
+            port_names(
+                self.options.name.as_deref(),
+                id,
+                output_ports(&self.options)
+            )
+            .join(", ")
+        );
+        self.streams.push(stream);
+        Some(self.streams.len() - 1)
+    }
+
+    // Grows the ring buffer of streams that keep running dry and shrinks it again once they
+    // have been stable for a while
+    fn adapt(&mut self) {
+        let Some((min, max)) = self.options.adaptive else {
+            return;
+        };
+        for index in 0..self.streams.len() {
+            let stream = &mut self.streams[index];
+            let capacity = if stream.underruns >= ADAPTIVE_UNDERRUNS && stream.capacity < max {
+                stream.capacity * 2
+            } else if stream.capacity > min
+                && stream.last_underrun.elapsed() >= ADAPTIVE_STABLE
+                && stream.last_resize.elapsed() >= ADAPTIVE_STABLE
+            {
+                stream.capacity / 2
+            } else {
+                if stream.adapt_since.elapsed() >= ADAPTIVE_WINDOW {
+                    stream.underruns = 0;
+                    stream.adapt_since = Instant::now();
+                }
+                continue;
+            };
+            if let Err(error) = self.resize(index, capacity) {
+                warning!("{}, keeping the current ring buffer", error);
+            }
+        }
+    }
+
+    // Replaces the ring buffer of a stream. The audio thread plays out what is left in the
+    // old one before moving on, so nothing already received is lost.
+    fn resize(&mut self, index: usize, capacity: usize) -> Result<(), &'static str> {
+        let (reader, writer) = RingBuffer::new(capacity)
+            .map_err(|_| "unable to create ring buffer")?
+            .into_reader_writer();
+        let stream = &mut self.streams[index];
+        self.handoffs
+            .send(Handoff::Resize {
+                stream: stream.id,
+                reader,
+            })
+            .map_err(|_| "JACK processing stopped")?;
+
+        info!(
+            "stream {} ring buffer {} to {} bytes ({:.1} ms)",
+            stream.id,
+            if capacity > stream.capacity {
+                "grown"
+            } else {
+                "shrunk"
+            },
+            capacity,
+            capacity as f64 / self.bytes_per_second * 1000.0
+        );
+        // The old writer is dropped here, its reader still holds the ring buffer
+        stream.ring_buffer_writer = writer;
+        stream.capacity = capacity;
+        // A stream following the jitter keeps its target, as far as the new buffer allows
+        let level = match self.options.jitter_buffer {
+            Some(_) => {
+                let level = stream
+                    .resume_level
+                    .load(Ordering::Relaxed)
+                    .min(capacity / 2);
+                level - level % self.frame_size
+            }
+            None => resume_level(capacity, self.frame_size),
+        };
+        stream.resume_level.store(level, Ordering::Relaxed);
+        stream.underruns = 0;
+        stream.adapt_since = Instant::now();
+        stream.last_resize = Instant::now();
+
+        let range = latency_range(capacity, level, self.max_buffered, self.frame_size);
+        self.latency.set(stream.id, range);
+        latency::recompute(self.client);
+        Ok(())
+    }
+
+    // Sets the level streams wait for after an underrun from their measured jitter, and drops
+    // audio that stayed buffered for a whole window without being needed
+    fn follow_jitter(&mut self) {
+        let Some(bounds) = self.options.jitter_buffer else {
+            return;
+        };
+        let frame_size = self.frame_size;
+        for stream in &mut self.streams {
+            let target = jitter_target(
+                stream.jitter.smoothed,
+                bounds,
+                stream.packet_size,
+                stream.capacity,
+                self.bytes_per_second,
+                frame_size,
+            );
+            if stream.resume_level.swap(target, Ordering::Relaxed) != target {
+                info!(
+                    "stream {} jitter buffer target {:.1} ms, jitter {:.2} ms",
+                    stream.id,
+                    target as f64 / self.bytes_per_second * 1000.0,
+                    stream.jitter.smoothed
+                );
+                let range = latency_range(stream.capacity, target, self.max_buffered, frame_size);
+                self.latency.set(stream.id, range);
+                latency::recompute(self.client);
+            }
+
+            if stream.jitter_since.elapsed() < JITTER_WINDOW {
+                continue;
+            }
+            // Keep one packet of slack so a steady stream is not trimmed over and over
+            let surplus = stream
+                .low_water
+                .take()
+                .unwrap_or_default()
+                .saturating_sub(target + stream.packet_size);
+            let surplus = surplus - surplus % frame_size;
+            stream.jitter_since = Instant::now();
+            if surplus > 0 && stream.pending_trim.load(Ordering::Acquire) == 0 {
+                stream.pending_trim.store(surplus, Ordering::Release);
+                info!(
+                    "stream {} jitter buffer shrunk by {:.1} ms",
+                    stream.id,
+                    surplus as f64 / self.bytes_per_second * 1000.0
+                );
+            }
+        }
+    }
+
+    // Asks the audio thread to discard the oldest buffered audio once the buffer holds more
+    // than the maximum latency, trading a small glitch for bounded delay
+    fn cap_latency(&mut self, index: usize) {
+        let Some(max_buffered) = self.max_buffered else {
+            return;
+        };
+        // Wait for a previous request to be carried out so it is not repeated
+        if self.streams[index].pending_trim.load(Ordering::Acquire) > 0 {
+            return;
+        }
+
+        let buffered = self.buffered(index);
+        if buffered <= max_buffered {
+            return;
+        }
+        let excess = buffered - max_buffered;
+        let excess = excess.next_multiple_of(self.frame_size);
+        self.streams[index]
+            .pending_trim
+            .store(excess, Ordering::Release);
+        if self.trims.record() {
+            warning!(
+                "latency above {} ms, discarding {:.1} ms of buffered audio",
+                self.options.max_latency_ms.unwrap_or_default(),
+                excess as f64 / self.bytes_per_second * 1000.0
+            );
+        }
+    }
+
+    // Runs periodic work
+    fn tick(&mut self) {
+        self.trims.flush();
+        self.underruns.flush();
+        self.overruns.flush();
+        self.duplicates.flush();
+        self.replays.flush();
+        self.rejected.flush();
+        self.xruns.check(self.metrics.xruns.load(Ordering::Relaxed));
+        if let Some(forged) = &mut self.forged {
+            forged.flush();
+        }
+
+        self.sinks.retain_mut(|sink| {
+            sink.flush_if_due()
+                .map_err(|error| warning!("{}, stopping it", error))
+                .is_ok()
+        });
+
+        if self.options.measure_latency
+            && self.last_latency_report.elapsed() >= LATENCY_REPORT_INTERVAL
+        {
+            self.latency_stats.report();
+            self.last_latency_report = Instant::now();
+        }
+
+        if self.options.measure_jitter
+            && self.last_jitter_report.elapsed() >= JITTER_REPORT_INTERVAL
+        {
+            let several = self.streams.len() > 1;
+            for stream in &self.streams {
+                stream.jitter.report(several.then_some(stream.id));
+            }
+            self.last_jitter_report = Instant::now();
+        }
+
+        if self.options.limit && self.last_limiter_report.elapsed() >= LIMITER_REPORT_INTERVAL {
+            let limited = self.limited.swap(0, Ordering::Relaxed);
+            self.metrics.limited.fetch_add(limited, Ordering::Relaxed);
+            if limited > 0 {
+                warning!(
+                    "limiter engaged in {} periods in last {:.1}s, check the gain upstream",
+                    limited,
+                    self.last_limiter_report.elapsed().as_secs_f64()
+                );
+            }
+            self.last_limiter_report = Instant::now();
+        }
+
+        // A receiver still waiting for its first packet has nothing to time out
+        let waiting = self
+            .streams
+            .iter()
+            .all(|stream| stream.state == PlayoutState::Waiting);
+        if !self.timed_out && !waiting && self.last_received.elapsed() >= STREAM_TIMEOUT {
+            warning!(
+                "stream timed out, no packets for {} ms",
+                STREAM_TIMEOUT.as_millis()
+            );
+            self.timed_out = true;
+        }
+
+        if let Some(silence_timeout) = self.options.silence_timeout {
+            self.check_silence(silence_timeout);
+        }
+
+        // Stop waiting for parity that is not coming, once the stream stalls
+        for index in 0..self.streams.len() {
+            let held_since = self.streams[index].fec.held_since();
+            if held_since.is_some_and(|since| since.elapsed() >= FEC_HOLD_TIMEOUT) {
+                self.release_held(index, true);
+            }
+        }
+
+        self.adapt();
+        self.follow_jitter();
+        self.correct_drift();
+    }
+
+    // Speeds up or slows down playback of each stream by resampling, so its buffer level
+    // stays at the resume level however far the sender's clock drifts from JACK's
+    fn correct_drift(&mut self) {
+        if !self.options.drift_correction {
+            return;
+        }
+        let sample_rate = self.client.sample_rate() as u32;
+        let channels = self.options.channels;
+        for stream in &mut self.streams {
+            let elapsed = stream.last_drift_update.elapsed();
+            if elapsed < DRIFT_INTERVAL {
+                continue;
+            }
+            stream.last_drift_update = Instant::now();
+            let target = stream.resume_level.load(Ordering::Relaxed) as f64 / self.bytes_per_second;
+            let Some(correction) = stream.drift.update(target, elapsed.as_secs_f64()) else {
+                continue;
+            };
+            if stream.resampler.is_none() {
+                stream.resampler = Resampler::new(sample_rate, sample_rate, channels);
+            }
+            let Some(resampler) = &mut stream.resampler else {
+                continue;
+            };
+            resampler.set_correction(correction);
+            if stream.last_drift_report.elapsed() >= DRIFT_REPORT_INTERVAL {
+                info!(
+                    "stream {} drift correction {:+.0} ppm",
+                    stream.id,
+                    correction * 1e6
+                );
+                stream.last_drift_report = Instant::now();
+            }
+        }
+    }
+
+    // Tells a silent source or missing routing apart from a network problem
+    fn check_silence(&mut self, timeout: Duration) {
+        let peak = f32::from_bits(self.output_peak.swap(0, Ordering::Relaxed));
+        if peak >= SILENCE_THRESHOLD {
+            self.last_sound = Instant::now();
+            if self.silent {
+                info!("output is no longer silent");
+                self.silent = false;
+            }
+        } else if !self.silent
+            && self.last_sound.elapsed() >= timeout
+            && self.last_audio.elapsed() < STREAM_TIMEOUT
+        {
+            warning!("receiving packets but output is silent");
+            self.silent = true;
+        }
+    }
+}
+
+// Sends a datagram unless pausing after transient errors, only other errors stop the stream
+#[cfg(not(feature = "async"))]
+fn send_to(
+    socket: &UdpSocket,
+    retry: &mut Retry,
+    datagram: &[u8],
+    addr: SocketAddr,
+) -> Result<(), &'static str> {
+    if retry.due() {
+        retry
+            .check(socket.send_to(datagram, addr))
+            .map_err(|_| "unable to send data")?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+async fn send_to_async(
+    socket: &tokio::net::UdpSocket,
+    retry: &mut Retry,
+    datagram: &[u8],
+    addr: SocketAddr,
+) -> Result<(), &'static str> {
+    if retry.due() {
+        retry
+            .check(socket.send_to(datagram, addr).await)
+            .map_err(|_| "unable to send data")?;
+    }
+    Ok(())
+}
+
+// Main network receive loop
+#[cfg(not(feature = "async"))]
+fn run(
+    socket: UdpSocket,
+    mut network: Network<'_>,
+    mut sessions: Sessions,
+) -> Result<(), &'static str> {
+    // Wake up periodically so timeouts are noticed while no packets arrive
+    socket
+        .set_read_timeout(Some(TICK_INTERVAL))
+        .map_err(|_| "unable to configure socket")?;
+
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    let mut receive_retry = Retry::new("receive");
+    let mut send_retry = Retry::new("send");
+    while !shutdown::requested() {
+        network.handle_messages()?;
+
+        // Receive UDP packet
+        let received = match socket.recv_from(&mut buffer) {
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                None
+            }
+            result => receive_retry
+                .check(result)
+                .map_err(|_| "unable to receive data")?,
+        };
+        match received {
+            Some((received, source)) => match sessions.open(source, &mut buffer[..received]) {
+                Opened::Packet(datagram) => {
+                    if let Some(reply) = network.handle_datagram(datagram) {
+                        send_to(
+                            &socket,
+                            &mut send_retry,
+                            sessions.seal(source, reply),
+                            source,
+                        )?;
+                    }
+                }
+                Opened::Handshake => {
+                    info!("exchanged session keys with {}", source);
+                    send_to(&socket, &mut send_retry, sessions.reply(), source)?;
+                }
+                Opened::Rejected => network.reject_forged(),
+            },
+            // Pause after errors that may go away instead of spinning on them
+            None => thread::sleep(receive_retry.pause()),
+        }
+
+        network.tick();
+        if let Some((request, sender)) = network.next_subscription() {
+            send_to(
+                &socket,
+                &mut send_retry,
+                sessions.seal(sender, &request),
+                sender,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Main network receive loop, driven by a single-threaded tokio runtime
+#[cfg(feature = "async")]
+fn run(socket: UdpSocket, network: Network<'_>, sessions: Sessions) -> Result<(), &'static str> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|_| "unable to start async runtime")?
+        .block_on(run_async(socket, network, sessions))
+}
+
+#[cfg(feature = "async")]
+async fn run_async(
+    socket: UdpSocket,
+    mut network: Network<'_>,
+    mut sessions: Sessions,
+) -> Result<(), &'static str> {
+    socket
+        .set_nonblocking(true)
+        .map_err(|_| "unable to configure socket")?;
+    let socket =
+        tokio::net::UdpSocket::from_std(socket).map_err(|_| "unable to configure socket")?;
+
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+    let mut receive_retry = Retry::new("receive");
+    let mut send_retry = Retry::new("send");
+    while !shutdown::requested() {
+        tokio::select! {
+            // Receive UDP packet, pausing after errors that may go away until the next tick
+            received = socket.recv_from(&mut buffer), if receive_retry.due() => {
+                let received = receive_retry.check(received);
+                let Some((received, source)) = received.map_err(|_| "unable to receive data")?
+                else {
+                    continue;
+                };
+                match sessions.open(source, &mut buffer[..received]) {
+                    Opened::Packet(datagram) => {
+                        if let Some(reply) = network.handle_datagram(datagram) {
+                            send_to_async(&socket, &mut send_retry, sessions.seal(source, reply), source).await?;
+                        }
+                    }
+                    Opened::Handshake => {
+                        info!("exchanged session keys with {}", source);
+                        send_to_async(&socket, &mut send_retry, sessions.reply(), source).await?;
+                    }
+                    Opened::Rejected => network.reject_forged(),
+                }
+            }
+            _ = ticker.tick() => {
+                network.handle_messages()?;
+                network.tick();
+                if let Some((request, sender)) = network.next_subscription() {
+                    send_to_async(&socket, &mut send_retry, sessions.seal(sender, &request), sender).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether a packet of an authenticated stream is a replay. Within a run the sender's clock
+// only moves on, so a packet taking a place ahead has to be about as recent as the newest,
+// and one restarting the stream newer still. Packets behind the newest are left to `seen`
+// unless they fall out of its window.
+fn replayed(next: Option<u32>, newest: u64, sequence: u32, timestamp: u64) -> bool {
+    let Some(next) = next else {
+        return timestamp <= newest;
+    };
+    let gap = sequence.wrapping_sub(next);
+    let behind = gap.wrapping_neg();
+    if gap < MAX_SEQUENCE_GAP {
+        timestamp.saturating_add(REPLAY_CLOCK_SLACK) < newest
+    } else if behind < MAX_SEQUENCE_GAP {
+        behind > REPLAY_WINDOW
+    } else {
+        timestamp <= newest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deinterleave_rejects_partial_frames() {
+        assert!(deinterleave(&[0.0_f32; 3], 0, 2).is_none());
+
+        let channel = |index, channels| {
+            deinterleave(&[1, 2, 3, 4, 5, 6], index, channels)
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(channel(0, 2), [1, 3, 5]);
+        assert_eq!(channel(1, 2), [2, 4, 6]);
+        assert_eq!(channel(2, 3), [3, 6]);
+        assert_eq!(channel(0, 1), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn planar_packets_are_interleaved_per_channel() {
+        let planar = [1, 3, 5, 2, 4, 6];
+        let interleaved: Vec<u8> = from_planar(&planar, 1, 2)
+            .unwrap()
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(interleaved, [1, 2, 3, 4, 5, 6]);
+        let interleaved: Vec<u8> = from_planar(&planar, 1, 3)
+            .unwrap()
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(interleaved, [1, 5, 4, 3, 2, 6]);
+        assert!(from_planar(&planar, 2, 2).is_none());
+    }
+
+    #[test]
+    fn period_validation_allows_channel_count_changes() {
+        // Mono on the wire played out on two ports
+        assert_eq!(samples_per_period([256, 256], 1), Some(256));
+        // Stereo on the wire played out on one port
+        assert_eq!(samples_per_period([256], 2), Some(512));
+        assert_eq!(samples_per_period([256, 256], 2), Some(512));
+
+        assert_eq!(samples_per_period([256, 128], 2), None);
+        assert_eq!(samples_per_period([], 2), None);
+    }
+
+    #[test]
+    fn empty_periods_produce_empty_output() {
+        assert_eq!(samples_per_period([0, 0], 2), Some(0));
+
+        assert_eq!(deinterleave::<f32>(&[], 1, 2).unwrap().count(), 0);
+        assert_eq!(from_planar(&[], 4, 2).unwrap().count(), 0);
+
+        assert_eq!(fade_in(&mut [], 10, 10), 10);
+        assert!(!soft_clip(&mut []));
+
+        for format in [SampleFormat::F32, SampleFormat::S16, SampleFormat::S24] {
+            assert_eq!(format.decode(&[], &mut []), 0);
+            assert_eq!(format.encode(std::iter::empty(), None, &mut []), 0);
+        }
+    }
+
+    #[test]
+    fn underruns_replay_the_last_period_back_and_forth() {
+        let held = [1.0, -1.0, 2.0, -2.0, 3.0, -3.0];
+        let (mut l, mut r) = ([0.0; 8], [0.0; 8]);
+        let elapsed = conceal_underrun(&mut l, &held, 0, 2, 0, 1000);
+        assert_eq!(conceal_underrun(&mut r, &held, 1, 2, 0, 1000), elapsed);
+        let gain = |n| tail_gain(n, 1000);
+        let expected = [3.0, 2.0, 1.0, 1.0, 2.0, 3.0, 3.0, 2.0];
+        for (n, (&sample, expected)) in l.iter().zip(expected).enumerate() {
+            assert_eq!(sample, expected * gain(n));
+        }
+        assert_eq!(r[0], -3.0);
+        assert_eq!(elapsed, 8);
+
+        // Nothing is added once faded out
+        let mut l = [0.0; 4];
+        assert_eq!(conceal_underrun(&mut l, &held, 0, 2, elapsed, 10), 10);
+        assert_eq!(l, [tail_gain(8, 10), tail_gain(9, 10), 0.0, 0.0]);
+    }
+
+    #[test]
+    fn jitter_target_follows_jitter_within_bounds() {
+        // 48 kHz f32 stereo, 384 bytes per ms, packets of 480 bytes
+        let target =
+            |jitter_ms, bounds| jitter_target(jitter_ms, bounds, 480, 1 << 20, 384000.0, 8);
+        assert_eq!(target(0.0, (0, 100)), 480);
+        // 4 ms of margin rounded up to whole packets
+        assert_eq!(target(1.0, (0, 100)), 480 * 5);
+        assert_eq!(target(0.0, (5, 100)), 1920);
+        assert_eq!(target(50.0, (0, 20)), 7680);
+        // Never more than half the ring buffer
+        assert_eq!(jitter_target(50.0, (0, 100), 480, 4096, 384000.0, 8), 2048);
+    }
+
+    #[test]
+    fn replays_are_caught_by_sequence_and_sender_clock() {
+        let newest = 10_000_000;
+        // Ahead of the newest and about as recent, or a little behind within the window
+        assert!(!replayed(Some(100), newest, 105, newest + 50_000));
+        assert!(!replayed(Some(100), newest, 100, newest - 500_000));
+        assert!(!replayed(Some(100), newest, 100 - REPLAY_WINDOW, newest));
+        // Behind the window, or ahead with a timestamp from long ago
+        assert!(replayed(Some(100), newest, 99 - REPLAY_WINDOW, newest));
+        assert!(replayed(Some(100), newest, 105, newest - 2_000_000));
+        // A restart or a stream starting over after its end has to be newer still
+        assert!(!replayed(Some(100), newest, 50_000, newest + 1));
+        assert!(replayed(Some(100), newest, 50_000, newest));
+        assert!(!replayed(None, newest, 0, newest + 1));
+        assert!(replayed(None, newest, 0, newest - 1));
+    }
 }
@@ -0,0 +1,162 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+#[cfg(not(feature = "async"))]
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    packet::MAX_DATAGRAM_SIZE,
+    quic,
+    transport::{self, Retry, Transport},
+};
+
+// Forwards packets from any sender to the next hop and passes replies from the next hop back
+// to the most recent sender, without touching JACK
+pub fn start(bind: SocketAddr, next: SocketAddr, transport: Transport) -> Result<!, &'static str> {
+    let unspecified: SocketAddr = match next {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let (upstream, downstream) = match transport {
+        Transport::Udp => {
+            let upstream = UdpSocket::bind(bind).map_err(|_| "unable to bind to address")?;
+            let downstream =
+                UdpSocket::bind(unspecified).map_err(|_| "unable to bind to address")?;
+            downstream.connect(next).map_err(|_| "unable to connect")?;
+            (upstream, downstream)
+        }
+        Transport::Tcp => (
+            transport::listen(bind, None)?,
+            transport::connect(
+                unspecified,
+                next,
+                None,
+                None,
+                transport::DEFAULT_QUEUE_DELAY,
+            )?,
+        ),
+        Transport::Quic => (
+            quic::listen(bind, None)?,
+            quic::connect(unspecified, next, None, None)?,
+        ),
+    };
+
+    info!("relaying packets to {}", next);
+    run(upstream, downstream)
+}
+
+// Notes where packets are coming from, replies are sent there
+fn track_source(current: &mut Option<SocketAddr>, source: SocketAddr) {
+    if *current != Some(source) {
+        info!("relaying packets from {}", source);
+        *current = Some(source);
+    }
+}
+
+// Main relay loop
+#[cfg(not(feature = "async"))]
+fn run(upstream: UdpSocket, downstream: UdpSocket) -> Result<!, &'static str> {
+    let source = Arc::new(Mutex::new(None));
+
+    // Pass replies from the next hop back on a separate thread
+    let reply_upstream = upstream.try_clone().map_err(|_| "unable to clone socket")?;
+    let reply_downstream = downstream
+        .try_clone()
+        .map_err(|_| "unable to clone socket")?;
+    let reply_source = source.clone();
+    thread::spawn(move || {
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            match reply_downstream.recv(&mut buffer) {
+                Ok(received) => {
+                    let source = *reply_source.lock().unwrap_or_else(PoisonError::into_inner);
+                    if let Some(source) = source {
+                        let _ = reply_upstream.send_to(&buffer[..received], source);
+                    }
+                }
+                // Raised while the next hop is not running yet or the network is down
+                Err(error) if transport::is_transient(&error) => {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    let mut receive_retry = Retry::new("receive");
+    let mut send_retry = Retry::new("forward");
+    loop {
+        let received = receive_retry
+            .check(upstream.recv_from(&mut buffer))
+            .map_err(|_| "unable to receive data")?;
+        // Pause after errors that may go away instead of spinning on them
+        let Some((received, from)) = received else {
+            thread::sleep(receive_retry.pause());
+            continue;
+        };
+        track_source(
+            &mut source.lock().unwrap_or_else(PoisonError::into_inner),
+            from,
+        );
+        if send_retry.due() {
+            send_retry
+                .check(downstream.send(&buffer[..received]))
+                .map_err(|_| "unable to send data")?;
+        }
+    }
+}
+
+// Main relay loop, driven by a single-threaded tokio runtime
+#[cfg(feature = "async")]
+fn run(upstream: UdpSocket, downstream: UdpSocket) -> Result<!, &'static str> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|_| "unable to start async runtime")?
+        .block_on(run_async(upstream, downstream))
+}
+
+#[cfg(feature = "async")]
+async fn run_async(upstream: UdpSocket, downstream: UdpSocket) -> Result<!, &'static str> {
+    let configure = |socket: UdpSocket| {
+        socket.set_nonblocking(true).ok()?;
+        tokio::net::UdpSocket::from_std(socket).ok()
+    };
+    let upstream = configure(upstream).ok_or("unable to configure socket")?;
+    let downstream = configure(downstream).ok_or("unable to configure socket")?;
+
+    let mut source = None;
+    let mut upstream_buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let mut downstream_buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let mut receive_retry = Retry::new("receive");
+    let mut send_retry = Retry::new("forward");
+    loop {
+        tokio::select! {
+            // Forward packets to the next hop
+            received = upstream.recv_from(&mut upstream_buffer), if receive_retry.due() => {
+                let received = receive_retry.check(received);
+                let Some((received, from)) = received.map_err(|_| "unable to receive data")?
+                else {
+                    continue;
+                };
+                track_source(&mut source, from);
+                if send_retry.due() {
+                    send_retry
+                        .check(downstream.send(&upstream_buffer[..received]).await)
+                        .map_err(|_| "unable to send data")?;
+                }
+            }
+            // Pause after errors that may go away instead of spinning on them
+            _ = tokio::time::sleep(receive_retry.pause()), if !receive_retry.due() => {}
+            // Pass replies back to the sender
+            received = downstream.recv(&mut downstream_buffer) => {
+                if let (Ok(received), Some(source)) = (received, source) {
+                    let _ = upstream.send_to(&downstream_buffer[..received], source).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::ops::RangeInclusive;
+
+use crate::format::SampleFormat;
+
+// Sample rates converted between, which keeps the ratio within 48 either way. Rates beyond
+// them come from no sound card, and the output of a packet would grow without bound.
+pub const RATES: RangeInclusive<u32> = 8000..=384_000;
+
+// Input frames kept from the previous packet, the interpolation looks one frame back and two
+// ahead
+const HISTORY: usize = 3;
+
+// Gains of the drift controller, per second of level error and per second of it integrated
+// over a second, chosen for critical damping
+const DRIFT_KP: f64 = 0.05;
+const DRIFT_KI: f64 = DRIFT_KP * DRIFT_KP / 4.0;
+
+// Largest drift corrected, 1000 ppm or less than 2 cents of pitch, well beyond what sound
+// cards drift
+const MAX_DRIFT_CORRECTION: f64 = 0.001;
+
+// Converts interleaved audio between sample rates by cubic interpolation, carrying its
+// position and the last frames over from packet to packet so they join up without clicks.
+// There is no anti-aliasing filter, which is inaudible for the small ratios between common
+// rates.
+pub struct Resampler {
+    ratio: f64,    // Input frames per output frame between the nominal rates
+    step: f64,     // Input frames per output frame, corrected for drift
+    position: f64, // Of the next output frame, counted in frames from the first
+    channels: usize,
+    samples: Vec<f32>, // The last input frames followed by the packet being converted
+    frame: Vec<f32>,   // Output frame being encoded
+}
+
+impl Resampler {
+    // None unless both rates are within RATES
+    pub fn new(from: u32, to: u32, channels: usize) -> Option<Self> {
+        if !RATES.contains(&from) || !RATES.contains(&to) {
+            return None;
+        }
+        let ratio = from as f64 / to as f64;
+        Some(Self {
+            ratio,
+            step: ratio,
+            position: 1.0,
+            channels,
+            samples: vec![0.0; HISTORY * channels],
+            frame: vec![0.0; channels],
+        })
+    }
+
+    // Whether the nominal rates differ, rather than only drift being corrected
+    pub fn converts(&self) -> bool {
+        self.ratio != 1.0
+    }
+
+    // Consumes input faster by this fraction, or slower if negative
+    pub fn set_correction(&mut self, correction: f64) {
+        self.step = self.ratio * (1.0 + correction);
+    }
+
+    // Converts the samples in `input` into `out`, which is cleared first
+    pub fn process(&mut self, format: SampleFormat, input: &[u8], out: &mut Vec<u8>) {
+        let channels = self.channels;
+        let sample_size = format.sample_size();
+        let whole = input.len() / (channels * sample_size) * channels * sample_size;
+        let start = self.samples.len();
+        self.samples.resize(start + whole / sample_size, 0.0);
+        format.decode(&input[..whole], &mut self.samples[start..]);
+        let frames = self.samples.len() / channels;
+
+        out.clear();
+        while (self.position as usize) + 2 < frames {
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+            let points = &self.samples[(index - 1) * channels..(index + 3) * channels];
+            for (channel, sample) in self.frame.iter_mut().enumerate() {
+                let [p0, p1, p2, p3] = [0, 1, 2, 3].map(|n| points[n * channels + channel]);
+                *sample = cubic(p0, p1, p2, p3, t);
+            }
+            let length = out.len();
+            out.resize(length + channels * sample_size, 0);
+            format.encode(self.frame.iter().copied(), None, &mut out[length..]);
+            self.position += self.step;
+        }
+
+        // Keep the frames the next output frames still need
+        let consumed = frames - HISTORY;
+        self.samples.drain(..consumed * channels);
+        self.position -= consumed as f64;
+    }
+}
+
+// Clock drift between two ends, estimated from how far the ring buffer level strays from its
+// target. A PI controller turns it into the resampling correction that holds the level there.
+// Its time constant of about 20 seconds keeps jitter from reaching the pitch.
+#[derive(Default)]
+pub struct Drift {
+    sum: f64,        // Levels added since the last update, in seconds of audio
+    count: u32,      // Levels added since the last update
+    integral: f64,   // Level error integrated over time, in seconds times seconds
+    correction: f64, // Fraction by which input is consumed faster than nominal
+}
+
+impl Drift {
+    pub fn add(&mut self, level: f64) {
+        self.sum += level;
+        self.count += 1;
+    }
+
+    // Updates the correction from the levels added over the last `elapsed` seconds, nothing
+    // if none were added
+    pub fn update(&mut self, target: f64, elapsed: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let error = self.sum / self.count as f64 - target;
+        self.sum = 0.0;
+        self.count = 0;
+        // The integral alone may not exceed the bound, so it recovers quickly once the level
+        // comes back
+        let bound = MAX_DRIFT_CORRECTION / DRIFT_KI;
+        self.integral = (self.integral + error * elapsed).clamp(-bound, bound);
+        self.correction = (DRIFT_KP * error + DRIFT_KI * self.integral)
+            .clamp(-MAX_DRIFT_CORRECTION, MAX_DRIFT_CORRECTION);
+        Some(self.correction)
+    }
+
+    pub fn correction(&self) -> f64 {
+        self.correction
+    }
+}
+
+// Catmull-Rom spline through four samples, at `t` between the middle two
+fn cubic(p0: f32, p1: f32, p2: f32, p3: f32, t: f32) -> f32 {
+    p1 + 0.5
+        * t
+        * (p2 - p0 + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + t * (3.0 * (p1 - p2) + p3 - p0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(samples: impl Iterator<Item = f32>) -> Vec<u8> {
+        let samples: Vec<_> = samples.collect();
+        let mut encoded = vec![0; samples.len() * 4];
+        SampleFormat::F32.encode(samples.into_iter(), None, &mut encoded);
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Vec<f32> {
+        let mut samples = vec![0.0; encoded.len() / 4];
+        SampleFormat::F32.decode(encoded, &mut samples);
+        samples
+    }
+
+    #[test]
+    fn resampling_keeps_the_rate_and_the_waveform_across_packets() {
+        let mut resampler = Resampler::new(44100, 48000, 2).unwrap();
+        let mut out = Vec::new();
+        let mut output = Vec::new();
+        // A ramp, which the interpolation reproduces exactly, 100 packets of 441 frames
+        for packet in 0..100 {
+            let frames = (packet * 441..(packet + 1) * 441).flat_map(|frame| {
+                let sample = frame as f32 / 44100.0;
+                [sample, -sample]
+            });
+            resampler.process(SampleFormat::F32, &encode(frames), &mut out);
+            output.extend(decode(&out));
+        }
+
+        let frames = output.len() / 2;
+        assert!(frames.abs_diff(48000) <= 3, "{} frames", frames);
+        // Past the silent history at the start, output frame n lies at input frame
+        // n * 44100 / 48000, shifted by the two frames of history
+        for (n, frame) in output.chunks_exact(2).enumerate().skip(4) {
+            let expected = (n as f32 * 44100.0 / 48000.0 - 2.0) / 44100.0;
+            assert!((frame[0] - expected).abs() < 1e-5, "frame {}", n);
+            assert_eq!(frame[1], -frame[0]);
+        }
+    }
+
+    #[test]
+    fn absurd_rates_are_refused() {
+        assert!(Resampler::new(1, 48000, 2).is_none());
+        assert!(Resampler::new(48000, u32::MAX, 2).is_none());
+        assert!(Resampler::new(8000, 384_000, 2).is_some());
+    }
+
+    #[test]
+    fn drift_correction_settles_on_the_drift() {
+        // The sender's clock runs 200 ppm fast, the buffer starts 5 ms above its 20 ms target
+        let drift = 200e-6;
+        let target = 0.020;
+        let mut level = 0.025;
+        let mut estimator = Drift::default();
+        assert_eq!(estimator.update(target, 1.0), None);
+        for _ in 0..600 {
+            estimator.add(level);
+            let correction = estimator.update(target, 1.0).unwrap();
+            level += drift - correction;
+        }
+        assert!((estimator.correction() - drift).abs() < 1e-6);
+        assert!((level - target).abs() < 1e-4, "level {}", level);
+
+        // Beyond what can be corrected the correction stays at its bound
+        estimator.add(10.0);
+        assert_eq!(estimator.update(target, 1.0), Some(MAX_DRIFT_CORRECTION));
+    }
+}
@@ -0,0 +1,492 @@
+use std::{fmt, net::SocketAddr, process, str::FromStr};
+
+use crate::{
+    format::SampleFormat,
+    packet::{self, HEADER_SIZE, Header, MAX_PAYLOAD_SIZE},
+};
+
+// Size of the fixed RTP header, without CSRCs or extensions
+pub const RTP_HEADER_SIZE: usize = 12;
+
+// RTP version 2, the only one in use
+const VERSION: u8 = 2;
+
+// Static payload types of RFC 3551 for 16-bit samples at 44.1 kHz
+const PT_L16_STEREO: u8 = 10;
+const PT_L16_MONO: u8 = 11;
+
+// Dynamic payload types used for everything else, as receivers are told out of band
+const PT_L16_DYNAMIC: u8 = 96;
+const PT_L24_DYNAMIC: u8 = 97;
+
+// Payload types 72-76 with the marker bit are RTCP, sent to the same port by some sources
+const RTCP_TYPES: std::ops::RangeInclusive<u8> = 200..=204;
+
+// Full scale of a 24-bit sample
+const L24_MAX: f32 = 8388607.0;
+
+// The one sample rate all AES67 devices support
+pub const AES67_SAMPLE_RATE: usize = 48000;
+
+// Microseconds on the TAI clock, which PTP daemons such as linuxptp's phc2sys keep in step
+// with the grandmaster. Elsewhere the system clock stands in for it.
+#[cfg(target_os = "linux")]
+fn tai_micros() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: time is a valid timespec for the call to fill in
+    unsafe { libc::clock_gettime(libc::CLOCK_TAI, &mut time) };
+    time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1000
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tai_micros() -> u64 {
+    packet::now_micros()
+}
+
+// Media clock ticks since the PTP epoch, as AES67 timestamps count them
+fn media_clock(micros: u64, sample_rate: u64) -> u64 {
+    (micros as u128 * sample_rate as u128 / 1_000_000) as u64
+}
+
+// Sample encoding of an RTP payload, big-endian signed integers as in RFC 3190 and 3551
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    L16,
+    L24,
+}
+
+impl Encoding {
+    pub fn sample_size(self) -> usize {
+        match self {
+            Encoding::L16 => 2,
+            Encoding::L24 => 3,
+        }
+    }
+
+    // Mono and stereo L16 at 44.1 kHz have static payload types, the rest are dynamic
+    pub fn payload_type(self, sample_rate: usize, channels: usize) -> u8 {
+        match (self, channels) {
+            (Encoding::L16, 1) if sample_rate == 44100 => PT_L16_MONO,
+            (Encoding::L16, 2) if sample_rate == 44100 => PT_L16_STEREO,
+            (Encoding::L16, _) => PT_L16_DYNAMIC,
+            (Encoding::L24, _) => PT_L24_DYNAMIC,
+        }
+    }
+
+    fn write(self, sample: f32, out: &mut [u8]) {
+        match self {
+            Encoding::L16 => {
+                let quantized = (sample * i16::MAX as f32)
+                    .round()
+                    .clamp(i16::MIN as f32, i16::MAX as f32);
+                out.copy_from_slice(&(quantized as i16).to_be_bytes());
+            }
+            Encoding::L24 => {
+                let quantized = (sample * L24_MAX).round().clamp(-L24_MAX - 1.0, L24_MAX);
+                out.copy_from_slice(&(quantized as i32).to_be_bytes()[1..]);
+            }
+        }
+    }
+
+    fn read(self, bytes: &[u8]) -> f32 {
+        match self {
+            Encoding::L16 => i16::from_be_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32,
+            // Shifting back down extends the sign
+            Encoding::L24 => {
+                (i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8) as f32 / L24_MAX
+            }
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l16" => Ok(Encoding::L16),
+            "l24" => Ok(Encoding::L24),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::L16 => "l16",
+            Encoding::L24 => "l24",
+        })
+    }
+}
+
+// Turns a sender's audio payloads into RTP packets
+pub struct Encoder {
+    encoding: Encoding,
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32, // Media clock, one tick per frame
+    marker: bool,   // Set on the first packet, which starts a talkspurt
+    sample_rate: u64,
+    channels: usize,
+    ptp: bool, // Start the media clock at the PTP time rather than at random
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding, sample_rate: usize, channels: usize, ptp: bool) -> Self {
+        // RFC 3550 asks for random starting points, the clock and process id will do
+        let seed = (packet::now_micros() as u32) ^ process::id().rotate_left(16);
+        Self {
+            encoding,
+            payload_type: encoding.payload_type(sample_rate, channels),
+            ssrc: seed,
+            sequence: seed.rotate_left(8) as u16,
+            timestamp: seed.rotate_left(16),
+            marker: true,
+            sample_rate: sample_rate as u64,
+            channels,
+            ptp,
+        }
+    }
+
+    // Session description for receivers that need one, such as AES67 devices, of packets
+    // from `source` to `destination` lasting `packet_time` milliseconds
+    pub fn sdp(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        ttl: Option<u32>,
+        packet_time: f64,
+    ) -> String {
+        let family = if destination.is_ipv4() { "IP4" } else { "IP6" };
+        // Only IPv4 multicast connections state a TTL
+        let scope = if destination.is_ipv4() && destination.ip().is_multicast() {
+            format!("/{}", ttl.unwrap_or(1))
+        } else {
+            String::new()
+        };
+        let encoding = match self.encoding {
+            Encoding::L16 => "L16",
+            Encoding::L24 => "L24",
+        };
+        let mut sdp = format!(
+            "v=0\n\
+             o=- {ssrc} 0 IN {family} {source}\n\
+             s=netaudio\n\
+             c=IN {family} {destination}{scope}\n\
+             t=0 0\n\
+             m=audio {port} RTP/AVP {pt}\n\
+             a=rtpmap:{pt} {encoding}/{rate}/{channels}\n\
+             a=ptime:{packet_time}",
+            ssrc = self.ssrc,
+            source = source.ip(),
+            destination = destination.ip(),
+            port = destination.port(),
+            pt = self.payload_type,
+            rate = self.sample_rate,
+            channels = self.channels,
+        );
+        if self.ptp {
+            sdp.push_str("\na=ts-refclk:ptp=IEEE1588-2008:traceable\na=mediaclk:direct=0");
+        }
+        sdp
+    }
+
+    // Largest packet written for a payload of `payload_size` bytes of `format` samples
+    pub fn packet_size(&self, format: SampleFormat, payload_size: usize) -> usize {
+        RTP_HEADER_SIZE + payload_size / format.sample_size() * self.encoding.sample_size()
+    }
+
+    // Writes an RTP packet carrying the interleaved `payload` into `out`, returns its length
+    pub fn write(&mut self, format: SampleFormat, payload: &[u8], out: &mut [u8]) -> usize {
+        // The media clock follows the frames sent from there on, as JACK's clock is assumed to
+        // be locked to PTP like any AES67 device's
+        if self.marker && self.ptp {
+            self.timestamp = media_clock(tai_micros(), self.sample_rate) as u32;
+        }
+        out[0] = VERSION << 6;
+        out[1] = (self.marker as u8) << 7 | self.payload_type;
+        out[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        out[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        out[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+
+        let sample_size = self.encoding.sample_size();
+        let mut length = RTP_HEADER_SIZE;
+        let mut sample = [0.0];
+        for (chunk, out) in payload
+            .chunks_exact(format.sample_size())
+            .zip(out[RTP_HEADER_SIZE..].chunks_exact_mut(sample_size))
+        {
+            format.decode(chunk, &mut sample);
+            self.encoding.write(sample[0], out);
+            length += sample_size;
+        }
+
+        let frames = payload.len() / (self.channels * format.sample_size());
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(frames as u32);
+        self.marker = false;
+        length
+    }
+}
+
+// Sequence numbers and timestamps of the source being received, extended to full width
+struct Source {
+    ssrc: u32,
+    sequence: u32,
+    timestamp: u64,
+}
+
+// Turns RTP packets from any source into packets as a netaudio sender would have sent them,
+// so the rest of the receiver handles them like its own
+pub struct Decoder {
+    encoding: Encoding, // Assumed for dynamic payload types
+    sample_rate: u64,
+    channels: usize, // Played, and assumed for dynamic payload types
+    ptp: bool,       // Timestamps count from the PTP epoch, giving the capture time
+    source: Option<Source>,
+    buffer: Vec<u8>,
+    sample: Vec<f32>,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding, sample_rate: usize, channels: usize, ptp: bool) -> Self {
+        Self {
+            encoding,
+            sample_rate: sample_rate as u64,
+            channels,
+            ptp,
+            source: None,
+            buffer: Vec::new(),
+            sample: Vec::new(),
+        }
+    }
+
+    // Converts an RTP packet into a packet of `format` samples. RTCP sent to the same port
+    // gives `None`.
+    pub fn decode(
+        &mut self,
+        datagram: &[u8],
+        format: SampleFormat,
+    ) -> Result<Option<&[u8]>, &'static str> {
+        if datagram.len() < RTP_HEADER_SIZE {
+            return Err("RTP packet too short");
+        }
+        if datagram[0] >> 6 != VERSION {
+            return Err("not an RTP packet");
+        }
+        if RTCP_TYPES.contains(&datagram[1]) {
+            return Ok(None);
+        }
+
+        // CSRCs and header extensions carry nothing needed here
+        let mut start = RTP_HEADER_SIZE + 4 * (datagram[0] & 0x0f) as usize;
+        if datagram[0] & 0x10 != 0 {
+            let words = datagram
+                .get(start + 2..start + 4)
+                .ok_or("RTP packet too short")?;
+            start += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+        }
+        let mut end = datagram.len();
+        if datagram[0] & 0x20 != 0 {
+            end = end.saturating_sub(datagram[end - 1] as usize);
+        }
+        let payload = datagram.get(start..end).ok_or("RTP packet too short")?;
+
+        let (encoding, channels) = match datagram[1] & 0x7f {
+            PT_L16_STEREO => (Encoding::L16, 2),
+            PT_L16_MONO => (Encoding::L16, 1),
+            _ => (self.encoding, self.channels),
+        };
+        if channels != 1 && channels != self.channels {
+            return Err("RTP stream has a different number of channels");
+        }
+        let frame_size = channels * encoding.sample_size();
+        if !payload.len().is_multiple_of(frame_size) {
+            return Err("RTP payload is not a whole number of frames");
+        }
+        let frames = payload.len() / frame_size;
+        let length = frames * self.channels * format.sample_size();
+        if length > MAX_PAYLOAD_SIZE {
+            return Err("RTP payload too large");
+        }
+
+        let sequence = u16::from_be_bytes([datagram[2], datagram[3]]);
+        let timestamp = u32::from_be_bytes(datagram[4..8].try_into().unwrap());
+        let ssrc = u32::from_be_bytes(datagram[8..12].try_into().unwrap());
+        let source = match &mut self.source {
+            Some(source) if source.ssrc == ssrc => {
+                // Differences are signed, so reordered packets step back
+                let step = sequence.wrapping_sub(source.sequence as u16) as i16;
+                source.sequence = source.sequence.wrapping_add_signed(step.into());
+                let step = timestamp.wrapping_sub(source.timestamp as u32) as i32;
+                source.timestamp = source.timestamp.wrapping_add_signed(step.into());
+                source
+            }
+            previous => {
+                // A new source jumps far enough for the stream to start afresh
+                let base = previous
+                    .as_ref()
+                    .map_or(0, |source| source.sequence.wrapping_add(u32::MAX / 2));
+                self.source.insert(Source {
+                    ssrc,
+                    sequence: base.wrapping_add(sequence.into()),
+                    timestamp: timestamp.into(),
+                })
+            }
+        };
+
+        // Mono is played on every channel
+        self.sample.clear();
+        for frame in payload.chunks_exact(frame_size) {
+            let samples = frame.chunks_exact(encoding.sample_size());
+            let samples = samples.cycle().take(self.channels);
+            self.sample
+                .extend(samples.map(|sample| encoding.read(sample)));
+        }
+        let captured = if self.ptp {
+            // The full timestamp is the one nearest the PTP time now, given in UNIX time like
+            // the capture times of netaudio packets
+            let tai = tai_micros();
+            let now = media_clock(tai, self.sample_rate);
+            let ticks = now.wrapping_add_signed((timestamp.wrapping_sub(now as u32) as i32).into());
+            let captured = (ticks as u128 * 1_000_000 / self.sample_rate as u128) as u64;
+            captured.wrapping_sub(tai.wrapping_sub(packet::now_micros()))
+        } else {
+            // Media clock in microseconds, enough for jitter but not for latency
+            source.timestamp * 1_000_000 / self.sample_rate
+        };
+        self.buffer.resize(HEADER_SIZE + length, 0);
+        Header {
+            sequence: source.sequence,
+            timestamp: captured,
+            flags: 0,
+            stream: 0,
+            length: length as u16,
+        }
+        .write(&mut self.buffer);
+        format.encode(
+            self.sample.iter().copied(),
+            None,
+            &mut self.buffer[HEADER_SIZE..],
+        );
+        Ok(Some(&self.buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_survive_a_round_trip() {
+        let samples = [0.0, 1.0, -1.0, 0.5, -0.25, 0.125];
+        for (encoding, format) in [
+            (Encoding::L16, SampleFormat::S16),
+            (Encoding::L24, SampleFormat::F32),
+        ] {
+            let mut payload = vec![0; samples.len() * format.sample_size()];
+            format.encode(samples.into_iter(), None, &mut payload);
+            let mut encoder = Encoder::new(encoding, 48000, 2, false);
+            let mut rtp = vec![0; encoder.packet_size(format, payload.len())];
+            assert_eq!(encoder.write(format, &payload, &mut rtp), rtp.len());
+            assert_eq!(rtp[1], 0x80 | encoding.payload_type(48000, 2));
+            let second = encoder.write(format, &payload, &mut rtp.clone());
+            assert_eq!(second, rtp.len());
+
+            let mut decoder = Decoder::new(encoding, 48000, 2, false);
+            let packet = decoder.decode(&rtp, format).unwrap().unwrap();
+            let (header, decoded) = (Header::read(packet).unwrap(), &packet[HEADER_SIZE..]);
+            assert_eq!(header.sequence, u16::from_be_bytes([rtp[2], rtp[3]]) as u32);
+            let mut out = [0.0; 6];
+            format.decode(decoded, &mut out);
+            for (out, sample) in out.iter().zip(samples) {
+                assert!((out - sample).abs() < 1e-4, "{} != {}", out, sample);
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_extend_across_wraps_and_sources() {
+        let mut decoder = Decoder::new(Encoding::L16, 44100, 2, false);
+        let mut packet = |ssrc: u32, sequence: u16, payload_type: u8| {
+            let mut rtp = vec![0x80, payload_type];
+            rtp.extend(sequence.to_be_bytes());
+            rtp.extend(0_u32.to_be_bytes());
+            rtp.extend(ssrc.to_be_bytes());
+            rtp.extend([0x40, 0x00]);
+            let packet = decoder.decode(&rtp, SampleFormat::S16).unwrap().unwrap();
+            let (header, payload) = (Header::read(packet).unwrap(), &packet[HEADER_SIZE..]);
+            // Mono is duplicated onto both channels
+            assert_eq!(payload.len(), 4);
+            header.sequence
+        };
+        assert_eq!(packet(1, 65535, PT_L16_MONO), 65535);
+        assert_eq!(packet(1, 0, PT_L16_MONO), 65536);
+        assert_eq!(packet(1, 65534, PT_L16_MONO), 65534);
+        let restarted = packet(2, 7, PT_L16_MONO);
+        assert!(restarted.wrapping_sub(65537) > 4096);
+
+        assert!(
+            decoder
+                .decode(&[0x80, 200, 0, 0], SampleFormat::S16)
+                .is_err()
+        );
+        let rtcp = [0x80, 200, 0, 6, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert!(decoder.decode(&rtcp, SampleFormat::S16).unwrap().is_none());
+        let odd = [0x80, PT_L16_STEREO, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0];
+        assert!(decoder.decode(&odd, SampleFormat::S16).is_err());
+        // Stereo cannot be spread over other channel counts
+        let mut decoder = Decoder::new(Encoding::L16, 44100, 4, false);
+        let stereo = [
+            0x80,
+            PT_L16_STEREO,
+            0,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            0,
+        ];
+        assert!(decoder.decode(&stereo, SampleFormat::S16).is_err());
+    }
+
+    #[test]
+    fn ptp_timestamps_give_the_capture_time() {
+        let format = SampleFormat::F32;
+        let payload = [0; 48 * 2 * 4];
+        let mut encoder = Encoder::new(Encoding::L24, AES67_SAMPLE_RATE, 2, true);
+        let mut rtp = vec![0; encoder.packet_size(format, payload.len())];
+        encoder.write(format, &payload, &mut rtp);
+        let timestamp = u32::from_be_bytes(rtp[4..8].try_into().unwrap()) as u64;
+        let now = media_clock(tai_micros(), AES67_SAMPLE_RATE as u64);
+        assert!(now.wrapping_sub(timestamp) as u32 <= 48);
+
+        let mut decoder = Decoder::new(Encoding::L24, AES67_SAMPLE_RATE, 2, true);
+        let packet = decoder.decode(&rtp, format).unwrap().unwrap();
+        let captured = Header::read(packet).unwrap().timestamp;
+        assert!(packet::now_micros().abs_diff(captured) < 10_000);
+
+        let sdp = encoder.sdp(
+            "192.168.1.10:5004".parse().unwrap(),
+            "239.69.1.1:5004".parse().unwrap(),
+            None,
+            1.0,
+        );
+        assert!(sdp.contains("c=IN IP4 239.69.1.1/1\n"));
+        assert!(sdp.contains("a=rtpmap:97 L24/48000/2\na=ptime:1\n"));
+        assert!(sdp.ends_with("a=mediaclk:direct=0"));
+    }
+}
@@ -1,117 +1,2187 @@
+#[cfg(not(feature = "async"))]
+use std::sync::mpsc::RecvTimeoutError;
 use std::{
-    net::{ToSocketAddrs, UdpSocket},
-    sync::mpsc::{self, RecvError},
+    collections::VecDeque,
+    fmt, mem,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    slice,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use jack::{AudioIn, Client, Control, RingBuffer, contrib::ClosureProcessHandler};
+use jack::{
+    AudioIn, AudioOut, Client, Control, Frames, LatencyType, Port, ProcessHandler, RingBuffer,
+    RingBufferReader, contrib::ClosureProcessHandler,
+};
+use socket2::SockRef;
+
+use crate::{
+    codec::{self, BitrateControl, Codec, Encoder},
+    connect::{self, Connections},
+    crypto::{self, Cipher, Initiator, Key},
+    fec::{self, PARITY_HEADER_SIZE},
+    format::{Dither, SampleFormat},
+    ice,
+    latency::{self, Latency},
+    mdns,
+    metrics::{self, DestinationMetrics, Metrics, Notifications},
+    packet::{
+        self, FLAG_COPY, FLAG_END, FLAG_HANDSHAKE, FLAG_HELLO, FLAG_KEEPALIVE, FLAG_PARITY,
+        FLAG_PING, FLAG_PONG, FLAG_REPORT, FLAG_VERIFY, HEADER_SIZE, Header, LOSS_REPORT_SIZE,
+        LossReport, MAX_PAYLOAD_SIZE, PROBE_SIZE, Probe, STREAM_CONFIG_SIZE, SampleCount,
+        StreamConfig, port_name, port_prefix, sample_count_size,
+    },
+    quic,
+    rtp::{self, AES67_SAMPLE_RATE, RTP_HEADER_SIZE},
+    shutdown,
+    transport::{self, MulticastInterface, Retry, Transport},
+    warning::{WarningCounter, XrunWarnings},
+};
+
+// Pause after a failed read of receiver feedback, which is not worth hurrying for
+const FEEDBACK_PAUSE: Duration = Duration::from_millis(100);
+
+// Interval between round-trip probes
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time to wait for each handshake reply, and how often to ask
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+const HANDSHAKE_ATTEMPTS: usize = 4;
+
+// Largest UDP payload that fits a 1500 byte Ethernet MTU over IPv4, the limit for coalescing
+const MTU_PAYLOAD_SIZE: usize = 1472;
+
+// Test tone level, -6 dBFS
+const TONE_AMPLITUDE: f32 = 0.5;
+
+// Interval between input level reports
+const METER_INTERVAL: Duration = Duration::from_secs(1);
+
+// Writes the samples of channel `index` into their places in a buffer of `channels`
+// interleaved channels
+fn interleave_into<T: Copy>(samples: &[T], index: usize, channels: usize, interleaved: &mut [T]) {
+    interleaved
+        .iter_mut()
+        .skip(index)
+        .step_by(channels)
+        .zip(samples)
+        .for_each(|(buffer_val, &sample)| *buffer_val = sample);
+}
+
+// Reorders an interleaved packet of encoded samples into one block per channel
+fn to_planar(
+    interleaved: &[u8],
+    sample_size: usize,
+    channels: usize,
+) -> Option<impl Iterator<Item = &[u8]>> {
+    // Ensure whole frames
+    interleaved
+        .len()
+        .is_multiple_of(channels * sample_size)
+        .then(|| {
+            let samples = interleaved.chunks_exact(sample_size);
+            (0..channels).flat_map(move |channel| samples.clone().skip(channel).step_by(channels))
+        })
+}
+
+// Sine wave generator used as a deterministic test source
+struct Tone {
+    phase: f32,     // Current phase in cycles, in [0, 1)
+    increment: f32, // Phase advance per sample
+}
+
+impl Tone {
+    fn new(frequency: f32, sample_rate: usize) -> Self {
+        Self {
+            phase: 0.0,
+            increment: frequency / sample_rate as f32,
+        }
+    }
+
+    fn fill(&mut self, out: &mut [f32]) {
+        for sample in out {
+            *sample = TONE_AMPLITUDE * (self.phase * std::f32::consts::TAU).sin();
+            self.phase = (self.phase + self.increment).fract();
+        }
+    }
+}
+
+// Level of one input channel, measured by the process callback and read and reset by the
+// network thread
+#[derive(Default)]
+struct InputLevel {
+    peak: AtomicU32,       // Highest absolute sample since last read, as f32 bits
+    square_sum: AtomicU64, // Sum of squared samples since last read, as f64 bits
+    samples: AtomicU64,
+}
+
+impl InputLevel {
+    // Adds a period of samples, without locking or allocating
+    fn add(&self, samples: &[f32]) {
+        let (peak, square_sum) = samples.iter().fold((0.0_f32, 0.0_f64), |(peak, sum), &s| {
+            (peak.max(s.abs()), sum + f64::from(s) * f64::from(s))
+        });
+        // Non-negative floats order the same as their bits, so fetch_max works
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        let _ = self
+            .square_sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + square_sum).to_bits())
+            });
+        self.samples
+            .fetch_add(samples.len() as u64, Ordering::Relaxed);
+    }
+
+    // Peak and RMS level in dBFS since the last call, starting over
+    fn take(&self) -> (f32, f32) {
+        let peak = f32::from_bits(self.peak.swap(0, Ordering::Relaxed));
+        let square_sum = f64::from_bits(self.square_sum.swap(0, Ordering::Relaxed));
+        let samples = self.samples.swap(0, Ordering::Relaxed).max(1);
+        let rms = (square_sum / samples as f64).sqrt() as f32;
+        (20.0 * peak.log10(), 20.0 * rms.log10())
+    }
+}
+
+// Audio fed into the send pipeline
+enum Source {
+    Ports(Vec<Port<AudioIn>>), // A JACK input port per channel
+    Tone(Tone),
+}
+
+// What to do when the ring buffer has no room for a new period
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverrunPolicy {
+    DropNew, // Discard the incoming period
+    DropOld, // Discard the oldest buffered audio to make room
+}
+
+impl FromStr for OverrunPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-new" => Ok(OverrunPolicy::DropNew),
+            "drop-old" => Ok(OverrunPolicy::DropOld),
+            "block" => Err("the block overrun policy would stall the real-time thread"),
+            _ => Err("unknown overrun policy"),
+        }
+    }
+}
+
+impl fmt::Display for OverrunPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OverrunPolicy::DropNew => "drop-new",
+            OverrunPolicy::DropOld => "drop-old",
+        })
+    }
+}
+
+// Sender configuration
+pub struct Options {
+    pub ping: bool,                      // Periodically probe the round-trip time
+    pub format: SampleFormat,            // Wire sample format
+    pub dither: bool,                    // Apply TPDF dither when reducing bit depth
+    pub overrun: OverrunPolicy,          // Ring buffer overrun handling
+    pub planar: bool,                    // Send each channel as a contiguous block
+    pub tone: Option<f32>,               // Send a sine wave of this frequency instead of the inputs
+    pub keepalive: Option<Duration>,     // Longest gap between packets before a keepalive is sent
+    pub ring_buffer_size: usize,         // Ring buffer capacity in bytes, a power of two
+    pub monitor: bool,                   // Echo the transmitted signal on local output ports
+    pub ttl: Option<u32>,                // Outgoing TTL or hop limit, 1-255
+    pub packet_size: usize,              // Payload bytes per packet, replaced by the receiver's
+    pub coalesce: u32,                   // JACK periods gathered into each datagram, 1 to disable
+    pub warn_interval: Option<Duration>, // Aggregate repeated warnings over this window
+    pub transport: Transport,            // Datagrams or a TCP stream
+    pub metrics: Option<SocketAddr>,     // Address serving Prometheus metrics
+    pub http: Option<SocketAddr>,        // Address serving the JSON status
+    pub stream_id: u8,                   // Identifies this stream to a multi-stream receiver
+    pub dont_fragment: bool,             // Fail on packets above the path MTU, never fragment
+    pub peer_stdin: bool,                // Read replacement receiver addresses from stdin
+    pub send_cadence: Option<Duration>,  // Send on this timer instead of after each JACK period
+    pub verify: bool,                    // Append the samples sent so far to every packet
+    pub meter: bool,                     // Report input peak and RMS levels every second
+    pub redundancy: u32,                 // Times each audio packet is sent, 1 to disable
+    pub redundancy_delay: Duration,      // Offset between the copies of a packet
+    pub tcp_queue: Duration,             // Wait for a stalled TCP connection before dropping
+    pub v6only: Option<bool>,            // IPv6 only or dual-stack, platform-aware if unset
+    pub codec: Codec,                    // Raw samples or compressed frames
+    pub opus_bitrate: u32,               // Opus bitrate in kbit/s
+    pub opus_frame: Duration,            // Audio in each Opus frame, sets the packet size
+    pub fec: Option<fec::Layout>,        // Data and parity packets per FEC group
+    pub extra_peers: Vec<SocketAddr>,    // Further receivers, each sent a copy of every packet
+    pub rtp: Option<rtp::Encoding>,      // Send plain RTP packets of these samples instead
+    pub aes67: bool,                     // RTP as AES67 devices expect it, with PTP timestamps
+    // Interface multicast is sent out of, instead of the one the routing table picks
+    pub multicast_interface: Option<MulticastInterface>,
+    pub advertise: Option<String>, // Stream name announced via mDNS
+    pub channels: usize,           // Input ports, interleaved in this order
+    // Replaces --packet-size, and the receiver follows it instead of the other way round
+    pub frames_per_packet: Option<u16>,
+    pub key: Option<Key>, // Pre-shared key encrypting and authenticating every packet
+    pub auth_key: Option<Key>, // Pre-shared key only authenticating every packet
+    pub identity: Option<Key>, // Private key proving this sender to the receiver
+    pub peer_key: Option<Key>, // Receiver's public key, session keys are exchanged with it
+    pub ice: Option<ice::Exchange>, // Find the receiver through NAT instead of being given it
+    pub stats_interval: Option<Duration>, // Print a summary of the stream this often
+    pub resolve: Option<(String, Duration)>, // Peer host name and how often to resolve it again
+    pub fanout: Vec<SocketAddr>, // Further receivers, each sent the whole stream
+    pub name: Option<String>, // Leads the port names, keeping streams of a group apart
+    pub connect: Vec<String>, // Patterns of the capture ports the inputs are connected to
+    pub adaptive_bitrate: bool, // Steer the Opus bitrate of each receiver by its loss reports
+}
+
+// Latency of the inputs on their way to the network at a period of `frames`: audio waits for
+// a whole packet, or the coalesced periods, and at most a full ring buffer
+fn input_latency(options: &Options, frames: usize) -> (Frames, Frames) {
+    let frame_size = options.channels * options.format.sample_size();
+    let gathered = match options.coalesce {
+        1 => options.packet_size / frame_size,
+        periods => periods as usize * frames,
+    };
+    (
+        gathered as Frames,
+        (options.ring_buffer_size / frame_size) as Frames,
+    )
+}
+
+// Rejects combinations of options that cannot work, before any port or socket is opened
+fn check_options(options: &Options, sample_rate: usize) -> Result<(), &'static str> {
+    // Frequencies from Nyquist up would alias to another tone
+    if options
+        .tone
+        .is_some_and(|hz| !(hz > 0.0 && hz < sample_rate as f32 / 2.0))
+    {
+        return Err("--tone must lie between 0 Hz and half the JACK sample rate");
+    }
+    // Codecs work on interleaved samples
+    if options.planar && options.codec != Codec::Pcm {
+        return Err("--planar requires --codec pcm");
+    }
+    // Packets hold the same audio whatever the channels and format
+    if options.frames_per_packet.is_some() && (options.codec == Codec::Opus || options.aes67) {
+        return Err("--frames-per-packet cannot be combined with --codec opus or --aes67");
+    }
+    // With Opus every packet carries one frame, which takes the place of --packet-size
+    if options.codec == Codec::Opus && options.coalesce > 1 {
+        return Err("--coalesce cannot be combined with --codec opus");
+    }
+    if options.aes67 && options.coalesce > 1 {
+        return Err("--aes67 cannot be combined with --coalesce");
+    }
+    if options.adaptive_bitrate && options.codec != Codec::Opus {
+        return Err("--adaptive-bitrate requires --codec opus");
+    }
+    // RTP carries raw interleaved samples to receivers that know nothing of netaudio
+    if options.rtp.is_some() {
+        if options.transport != Transport::Udp {
+            return Err("--rtp requires the udp transport");
+        }
+        if options.codec != Codec::Pcm || options.planar {
+            return Err("--rtp requires --codec pcm without --planar");
+        }
+        if options.verify
+            || options.ping
+            || options.keepalive.is_some()
+            || options.redundancy > 1
+            || options.fec.is_some()
+            || !options.extra_peers.is_empty()
+            || options.key.is_some()
+            || options.auth_key.is_some()
+            || options.identity.is_some()
+        {
+            return Err(
+                "--rtp cannot be combined with --verify, --ping, --keepalive-ms, \
+                 --redundancy, --fec, --extra-peer, --key, --auth-key or --identity",
+            );
+        }
+    }
+    // Session keys hold for the one receiver they were exchanged with
+    if options.identity.is_some() {
+        if !options.extra_peers.is_empty() || !options.fanout.is_empty() || options.peer_stdin {
+            return Err(
+                "--identity cannot be combined with --extra-peer, --fanout or --peer-stdin",
+            );
+        }
+        if options.peer_key.is_none() {
+            return Err("--identity requires --peer-key");
+        }
+    }
+    if options.transport != Transport::Udp {
+        if options.advertise.is_some() {
+            return Err("--advertise requires the udp transport");
+        }
+        if options.peer_stdin {
+            return Err("--peer-stdin requires the udp transport");
+        }
+        if options.resolve.is_some() {
+            return Err("--resolve-interval requires the udp transport");
+        }
+        if options.redundancy > 1 {
+            return Err("--redundancy requires the udp transport");
+        }
+        if !options.extra_peers.is_empty() {
+            return Err("--extra-peer requires the udp transport");
+        }
+        if !options.fanout.is_empty() {
+            return Err("--fanout requires the udp transport");
+        }
+        if options.fec.is_some() {
+            return Err("--fec requires the udp transport");
+        }
+    }
+    Ok(())
+}
+
+// Sets the TTL or hop limit that applies to the connected destination
+pub fn set_ttl(socket: SockRef, ttl: u32) -> Result<(), &'static str> {
+    let destination = socket
+        .peer_addr()
+        .ok()
+        .and_then(|addr| addr.as_socket())
+        .ok_or("unable to set TTL")?;
+    match destination.ip() {
+        IpAddr::V4(ip) if ip.is_multicast() => socket.set_multicast_ttl_v4(ttl),
+        IpAddr::V4(_) => socket.set_ttl_v4(ttl),
+        IpAddr::V6(ip) if ip.is_multicast() => socket.set_multicast_hops_v6(ttl),
+        IpAddr::V6(_) => socket.set_unicast_hops_v6(ttl),
+    }
+    .map_err(|_| "unable to set TTL")
+}
+
+// Sends multicast out of `interface` rather than the one the routing table picks
+pub fn set_multicast_interface(
+    socket: SockRef,
+    interface: MulticastInterface,
+) -> Result<(), &'static str> {
+    let local = socket
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_socket())
+        .ok_or("unable to set the multicast interface")?;
+    match local {
+        SocketAddr::V4(_) => socket.set_multicast_if_v4(&interface.ipv4()?),
+        SocketAddr::V6(_) => socket.set_multicast_if_v6(interface.ipv6()?),
+    }
+    .map_err(|_| "unable to set the multicast interface")
+}
+
+// Sets the don't-fragment bit, so packets larger than the path MTU fail to send instead of
+// being fragmented
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: SockRef) -> Result<(), &'static str> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = match socket.local_addr().ok().and_then(|addr| addr.as_socket()) {
+        Some(SocketAddr::V4(_)) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        Some(SocketAddr::V6(_)) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+        None => return Err("unable to set don't-fragment"),
+    };
+    // SAFETY: value is a valid c_int that outlives the call, and its size is passed along
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&raw const value).cast(),
+            size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    (result == 0)
+        .then_some(())
+        .ok_or("unable to set don't-fragment")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_: SockRef) -> Result<(), &'static str> {
+    Err("don't-fragment is not supported on this platform")
+}
+
+// Path MTU the kernel currently knows for the connected destination
+#[cfg(target_os = "linux")]
+fn path_mtu(socket: SockRef) -> Option<u32> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name) = match socket.peer_addr().ok()?.as_socket()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut length = size_of_val(&mtu) as libc::socklen_t;
+    // SAFETY: mtu and length are valid for writes and length holds the size of mtu
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&raw mut mtu).cast(),
+            &mut length,
+        )
+    };
+    (result == 0).then_some(mtu as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_mtu(_: SockRef) -> Option<u32> {
+    None
+}
+
+// Explains a failed audio send, pointing at the path MTU when the packet did not fit
+fn send_error(socket: SockRef, error: std::io::Error) -> &'static str {
+    if error.raw_os_error() != Some(libc::EMSGSIZE) {
+        return "unable to send data";
+    }
+    match path_mtu(socket) {
+        Some(mtu) => error!(
+            "packet too large for the path MTU of {} bytes, lower --packet-size",
+            mtu
+        ),
+        None => error!("packet too large for the path MTU, lower --packet-size"),
+    }
+    "packet too large for path MTU"
+}
+
+// Sends a datagram unless pausing after transient errors, only other errors stop the stream
+#[cfg(not(feature = "async"))]
+fn send(socket: &UdpSocket, retry: &mut Retry, datagram: &[u8]) -> Result<(), &'static str> {
+    if retry.due() {
+        retry
+            .check(socket.send(datagram))
+            .map_err(|error| send_error(SockRef::from(socket), error))?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+async fn send_async(
+    socket: &tokio::net::UdpSocket,
+    retry: &mut Retry,
+    datagram: &[u8],
+) -> Result<(), &'static str> {
+    if retry.due() {
+        retry
+            .check(socket.send(datagram).await)
+            .map_err(|error| send_error(SockRef::from(socket), error))?;
+    }
+    Ok(())
+}
 
-use crate::{PACKET_SIZE, RING_BUFFER_SIZE};
+// Scratch buffers for the process callback, sized for the current JACK period
+struct ProcessBuffers {
+    samples: Vec<f32>,    // Interleaved samples
+    interleaved: Vec<u8>, // Encoded interleaved samples
+    tone: Vec<f32>,       // Test tone samples for one channel
+    frames: usize,        // Period the buffers are sized for
+    oversized: bool,      // Whether a larger period was reported since the last resize
+}
+
+impl ProcessBuffers {
+    fn new(frames: usize, channels: usize, sample_size: usize) -> Self {
+        let mut buffers = Self {
+            samples: Vec::new(),
+            interleaved: Vec::new(),
+            tone: Vec::new(),
+            frames: 0,
+            oversized: false,
+        };
+        buffers.resize(frames, channels, sample_size);
+        buffers
+    }
+
+    fn resize(&mut self, frames: usize, channels: usize, sample_size: usize) {
+        self.samples.resize(frames * channels, 0.0);
+        self.interleaved.resize(frames * channels * sample_size, 0);
+        self.tone.resize(frames, 0.0);
+        self.frames = frames;
+        self.oversized = false;
+    }
+}
+
+// Asks the receiver for the stream settings it expects, adopting its packet size. Settings
+// that cannot be adopted stop the sender before any audio is sent.
+fn handshake(
+    socket: &UdpSocket,
+    options: &mut Options,
+    sample_rate: u32,
+    cipher: &mut Cipher,
+) -> Result<(), &'static str> {
+    // The request carries the sender's settings, so the receiver can follow its choice of codec
+    let mut request = [0; HEADER_SIZE + STREAM_CONFIG_SIZE];
+    Header {
+        sequence: 0,
+        timestamp: packet::now_micros(),
+        flags: FLAG_HELLO,
+        stream: options.stream_id,
+        length: STREAM_CONFIG_SIZE as u16,
+    }
+    .write(&mut request);
+    StreamConfig {
+        packet_size: options.packet_size as u16,
+        format: options.format,
+        planar: options.planar,
+        codec: options.codec,
+        sample_rate: Some(sample_rate),
+        channels: options.channels as u8,
+        frames_per_packet: options.frames_per_packet,
+    }
+    .write(&mut request[HEADER_SIZE..]);
+
+    socket
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|_| "unable to configure socket")?;
+    let mut buffer = [0; HEADER_SIZE + STREAM_CONFIG_SIZE + crypto::OVERHEAD];
+    let expected = (0..HANDSHAKE_ATTEMPTS).find_map(|attempt| {
+        debug!(attempt, "asking the receiver for its stream settings");
+        socket.send(cipher.seal(&request)).ok()?;
+        let received = socket.recv(&mut buffer).ok()?;
+        match packet::parse(cipher.open(&mut buffer[..received])?) {
+            Ok((header, payload)) if header.flags & FLAG_HELLO != 0 => StreamConfig::read(payload),
+            _ => None,
+        }
+    });
+    socket
+        .set_read_timeout(None)
+        .map_err(|_| "unable to configure socket")?;
+
+    let Some(expected) = expected else {
+        warning!("no handshake reply from receiver, using local settings");
+        return Ok(());
+    };
+    debug!(
+        packet_size = expected.packet_size,
+        format = %expected.format,
+        codec = %expected.codec,
+        channels = expected.channels,
+        "receiver answered with its stream settings"
+    );
+    // The receiver resamples to its own rate, or refuses the stream with --strict-rate
+    if let Some(expected) = expected.sample_rate
+        && expected != sample_rate
+        && options.codec != Codec::Opus
+    {
+        warning!(
+            "sample rate mismatch: JACK runs at {} Hz here and at {} Hz on the receiver",
+            sample_rate,
+            expected
+        );
+    }
+    if expected.channels as usize != options.channels {
+        return Err("receiver expects a different number of channels");
+    }
+    if expected.format != options.format {
+        return Err("receiver expects a different sample format, check --format");
+    }
+    if expected.planar != options.planar {
+        return Err("receiver expects a different wire layout, check --planar");
+    }
+    if expected.codec != options.codec {
+        return Err("receiver expects a different codec, check --codec");
+    }
+    // Opus frames only come in a few sizes, so the receiver's cannot simply be adopted
+    if options.codec == Codec::Opus && expected.packet_size as usize != options.packet_size {
+        return Err("receiver expects a different opus frame, check --opus-frame-ms");
+    }
+    if expected.packet_size as usize != options.packet_size {
+        // Receivers follow a sender's frames per packet, older ones play them all the same
+        if options.frames_per_packet.is_some() {
+            info!(
+                "receiver expects {} byte packets, keeping --frames-per-packet",
+                expected.packet_size
+            );
+            return Ok(());
+        }
+        info!(
+            "using the receiver's packet size of {} bytes",
+            expected.packet_size
+        );
+        options.packet_size = expected.packet_size as usize;
+    }
+    Ok(())
+}
+
+// Exchanges session keys with the receiver, which has to know this sender's public key
+fn exchange_keys(
+    socket: &UdpSocket,
+    identity: Key,
+    peer: Key,
+    stream: u8,
+) -> Result<Cipher, &'static str> {
+    let (initiator, request) = Initiator::new(identity, peer, stream)?;
+    socket
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|_| "unable to configure socket")?;
+    let mut buffer = [0; HEADER_SIZE + crypto::HANDSHAKE_SIZE];
+    let reply = (0..HANDSHAKE_ATTEMPTS).find_map(|_| {
+        socket.send(&request).ok()?;
+        let received = socket.recv(&mut buffer).ok()?;
+        let (header, _) = packet::parse(&buffer[..received]).ok()?;
+        (header.flags & FLAG_HANDSHAKE == FLAG_HANDSHAKE).then_some(received)
+    });
+    socket
+        .set_read_timeout(None)
+        .map_err(|_| "unable to configure socket")?;
+
+    // Receivers stay silent towards senders they do not know
+    let received = reply.ok_or("no key exchange reply, check --identity and --peer-key")?;
+    let cipher = initiator
+        .finish(&buffer[..received])
+        .ok_or("key exchange with the receiver failed")?;
+    info!("exchanged session keys with the receiver");
+    Ok(cipher)
+}
 
-// Combines left/right channels into interleaved iterator
-fn interleave<T: Copy>(a: &[T], b: &[T]) -> Option<impl Iterator<Item = T>> {
-    // Ensure equal channel lengths and interleave samples
-    (a.len() == b.len()).then(|| a.iter().zip(b).flat_map(|(&l, &r)| [l, r]))
+// Waits for a receiver to ask for the stream, then sends to it. With a key only receivers
+// holding it are heard.
+fn wait_for_subscriber(socket: &UdpSocket, cipher: &Cipher) -> Result<(), &'static str> {
+    info!("waiting for a receiver to subscribe");
+    let mut buffer = [0; HEADER_SIZE + STREAM_CONFIG_SIZE + crypto::OVERHEAD];
+    loop {
+        let (received, source) = socket
+            .recv_from(&mut buffer)
+            .map_err(|_| "unable to receive data")?;
+        if let Some(datagram) = cipher.open(&mut buffer[..received])
+            && let Ok((header, _)) = packet::parse(datagram)
+            && header.flags & FLAG_HELLO != 0
+        {
+            socket.connect(source).map_err(|_| "unable to connect")?;
+            info!(receiver = %source, "receiver subscribed");
+            return Ok(());
+        }
+    }
 }
 
 // Messages for cross-thread communication
 enum Message {
     Ready,
     InvalidBufferLengths,
-    Overrun { expected: usize, available: usize },
+    PeriodTooLarge {
+        period: usize,
+        capacity: usize,
+    }, // In samples
+    PeriodChanged {
+        frames: usize,
+    },
+    RingBufferTooSmall {
+        period_bytes: usize,
+        ring_buffer_size: usize,
+    },
+    Overrun {
+        expected: usize,
+        available: usize,
+        policy: OverrunPolicy, // Policy that was actually applied
+    },
+    Repoint(SocketAddr), // New receiver address, read from stdin or resolved again
+}
+
+// Reads receiver addresses from stdin, one per line, so the stream can be moved to another
+// receiver without restarting
+fn read_peers(sender: mpsc::Sender<Message>) {
+    for line in std::io::stdin().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+        {
+            Some(peer) => {
+                if sender.send(Message::Repoint(peer)).is_err() {
+                    break;
+                }
+            }
+            None => warning!("invalid address {} on stdin, ignoring", line),
+        }
+    }
+}
+
+// Resolves `host` to an address a socket bound to `bind` can reach. IPv6 sockets prefer IPv6
+// addresses and fall back to IPv4 ones, IPv4 sockets only take IPv4.
+pub fn resolve(host: &str, bind: SocketAddr) -> Option<SocketAddr> {
+    let addrs: Vec<_> = host.to_socket_addrs().ok()?.collect();
+    let preferred = match bind {
+        SocketAddr::V4(_) => addrs.iter().find(|addr| addr.is_ipv4()),
+        SocketAddr::V6(_) => addrs.iter().find(|addr| addr.is_ipv6()).or(addrs.first()),
+    };
+    preferred.map(|&addr| transport::reachable(addr, bind))
+}
+
+// Resolves the receiver's host name again every `interval`, so the stream follows it to a new
+// address, e.g. one published by dynamic DNS
+fn follow_host(
+    host: String,
+    interval: Duration,
+    mut peer: SocketAddr,
+    sender: mpsc::Sender<Message>,
+) {
+    loop {
+        thread::sleep(interval);
+        match resolve(&host, peer) {
+            Some(resolved) if resolved != peer => {
+                info!("{} now resolves to {}", host, resolved);
+                if sender.send(Message::Repoint(resolved)).is_err() {
+                    break;
+                }
+                peer = resolved;
+            }
+            Some(_) => {}
+            None => warning!("unable to resolve {}, still sending to {}", host, peer),
+        }
+    }
+}
+
+// Points the socket at a new receiver, carrying on with the same stream
+fn repoint(socket: SockRef, peer: SocketAddr, ttl: Option<u32>) {
+    let result: Result<(), &'static str> = try {
+        socket
+            .connect(&peer.into())
+            .map_err(|_| "unable to connect")?;
+        // Multicast and unicast destinations take different TTL options
+        if let Some(ttl) = ttl {
+            set_ttl(socket, ttl)?;
+        }
+    };
+    match result {
+        Ok(()) => info!(peer = %peer, "sending"),
+        Err(error) => warning!("{}, unable to switch to {}", error, peer),
+    }
+}
+
+// Reports echoed probes and loss reports sent back by the receiver, keeping the round-trip
+// time in `metrics` for the next probe to tell the receiver
+fn handle_feedback(datagram: &[u8], metrics: &Metrics) {
+    match packet::parse(datagram) {
+        Ok((header, payload)) if header.flags & FLAG_PONG != 0 => {
+            let now = packet::now_micros();
+            let rtt = now.saturating_sub(header.timestamp);
+            metrics.rtt.store(rtt, Ordering::Relaxed);
+            // The clocks split the round trip if they are synchronized, halving it assumes a
+            // symmetric path instead
+            match Probe::read(payload) {
+                Some(Probe(arrived)) => info!(
+                    "round-trip time: {:.2} ms, one-way: {:.2} ms, by the clocks {:.2} ms there \
+                     and {:.2} ms back",
+                    rtt as f64 / 1000.0,
+                    rtt as f64 / 2000.0,
+                    packet::millis_between(header.timestamp, arrived),
+                    packet::millis_between(arrived, now)
+                ),
+                None => info!(
+                    "round-trip time: {:.2} ms, one-way: {:.2} ms",
+                    rtt as f64 / 1000.0,
+                    rtt as f64 / 2000.0
+                ),
+            }
+        }
+        Ok((header, payload)) if header.flags & FLAG_REPORT != 0 => {
+            if let Some(report) = LossReport::read(payload) {
+                metrics.report.store(report.pack(), Ordering::Relaxed);
+            }
+            if let Some(report) = LossReport::read(payload).filter(|report| report.lost > 0) {
+                warning!(
+                    lost = report.lost,
+                    expected = report.expected,
+                    percent = format_args!("{:.1}", report.percentage()),
+                    "receiver lost packets"
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+// A further receiver given with --fanout, sent the stream from a socket of its own. It backs
+// off from errors on its own, so a receiver going away holds up neither the stream nor the
+// other receivers.
+struct Destination {
+    socket: UdpSocket,
+    retry: Retry,
+    failing: bool, // Set after an error other than a transient one, until a send succeeds
+    metrics: Arc<DestinationMetrics>,
+    encoder: Option<Encoder>, // Its own with --adaptive-bitrate, others share the stream's
+    bitrate: BitrateControl,
+    buffer: Vec<u8>, // Packet encoded for it alone
+}
+
+impl Destination {
+    fn send(&mut self, datagram: &[u8]) {
+        let counter = if !self.retry.due() {
+            &self.metrics.failed
+        } else {
+            match self.retry.check(self.socket.send(datagram)) {
+                Ok(Some(_)) => {
+                    self.failing = false;
+                    &self.metrics.packets
+                }
+                Ok(None) => &self.metrics.failed,
+                Err(error) => {
+                    if !mem::replace(&mut self.failing, true) {
+                        warning!("unable to send to {}: {}", self.metrics.addr, error);
+                    }
+                    &self.metrics.failed
+                }
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Counts the losses a --fanout receiver reports back, until its socket fails
+fn follow_reports(socket: UdpSocket, cipher: Cipher, metrics: Arc<DestinationMetrics>) {
+    let mut buffer = [0; HEADER_SIZE + LOSS_REPORT_SIZE + crypto::OVERHEAD];
+    loop {
+        match socket.recv(&mut buffer) {
+            Ok(received) => {
+                if let Some(datagram) = cipher.open(&mut buffer[..received])
+                    && let Ok((header, payload)) = packet::parse(datagram)
+                    && header.flags & FLAG_REPORT != 0
+                    && let Some(report) = LossReport::read(payload)
+                {
+                    metrics
+                        .expected
+                        .fetch_add(report.expected as u64, Ordering::Relaxed);
+                    metrics
+                        .lost
+                        .fetch_add(report.lost as u64, Ordering::Relaxed);
+                    metrics.report.store(report.pack(), Ordering::Relaxed);
+                    if report.lost > 0 {
+                        warning!(
+                            destination = %metrics.addr,
+                            lost = report.lost,
+                            expected = report.expected,
+                            percent = format_args!("{:.1}", report.percentage()),
+                            "receiver lost packets"
+                        );
+                    }
+                }
+            }
+            Err(error) if transport::is_transient(&error) => thread::sleep(FEEDBACK_PAUSE),
+            Err(_) => break,
+        }
+    }
+}
+
+// A stream from JACK input ports to a receiver
+pub struct Sender {
+    bind: SocketAddr,
+    peer: Option<SocketAddr>, // None waits for a subscriber, or finds the receiver with --ice
+    options: Options,
+}
+
+impl Sender {
+    pub fn new(bind: SocketAddr, peer: Option<SocketAddr>, options: Options) -> Self {
+        Self {
+            bind,
+            peer,
+            options,
+        }
+    }
+
+    // Streams from `client` on the calling thread until an error or a shutdown stops it
+    pub fn run(self, client: Client) -> Result<(), &'static str> {
+        start(client, self)
+    }
+
+    // Streams on a thread of its own, whose handle yields how it stopped
+    pub fn start(self, client: Client) -> JoinHandle<Result<(), &'static str>> {
+        thread::spawn(move || self.run(client))
+    }
 }
 
 // Sender main function
-pub fn start<T: ToSocketAddrs>(client: Client, bind: T, send: T) -> Result<!, &'static str> {
-    // Register JACK input ports for left and right channels
-    let in_port_l = client
-        .register_port("in_l", AudioIn::default())
-        .map_err(|_| "unable to register port")?;
-    let in_port_r = client
-        .register_port("in_r", AudioIn::default())
-        .map_err(|_| "unable to register port")?;
-
-    // Configure UDP socket for sending
-    let socket = UdpSocket::bind(bind).map_err(|_| "unable to bind to address")?;
-    socket.connect(send).map_err(|_| "unable to connect")?;
+fn start(client: Client, sender: Sender) -> Result<(), &'static str> {
+    let latency = Latency::new(LatencyType::Playback);
+    latency::register(&client, slice::from_ref(&latency))?;
+    let (metrics, process, network) = prepare(&client, sender, latency)?;
+    let async_client = client
+        .activate_async(Notifications(metrics), process)
+        .map_err(|_| "unable to activate client")?;
+    let result = network(async_client.as_client());
+    // Stop processing before the ports go away with the client
+    let _ = async_client.deactivate();
+    result
+}
+
+// Sets up sending from `client`, returning the metrics, the handler for the process callback
+// and the network loop to run once the client is active. Ports may be registered on an
+// active client too.
+#[allow(clippy::type_complexity)]
+pub(crate) fn prepare(
+    client: &Client,
+    Sender {
+        bind,
+        peer: send,
+        mut options,
+    }: Sender,
+    latency: Arc<Latency>,
+) -> Result<
+    (
+        Arc<Metrics>,
+        impl ProcessHandler + use<>,
+        impl FnOnce(&Client) -> Result<(), &'static str> + use<>,
+    ),
+    &'static str,
+> {
+    check_options(&options, client.sample_rate())?;
+    let channels = options.channels;
+    let name = options.name.as_deref();
+    let mut source = match options.tone {
+        Some(frequency) => Source::Tone(Tone::new(frequency, client.sample_rate())),
+        // Register a JACK input port for each channel
+        None => Source::Ports(
+            (0..channels)
+                .map(|channel| {
+                    client
+                        .register_port(
+                            &port_name(&port_prefix(name, "in"), channel, channels),
+                            AudioIn::default(),
+                        )
+                        .map_err(|_| "unable to register port")
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+    };
+
+    // Register passthrough ports for monitoring what is being sent
+    let mut monitor_ports = if options.monitor {
+        let ports = (0..channels).map(|channel| {
+            client
+                .register_port(
+                    &port_name(&port_prefix(name, "mon"), channel, channels),
+                    AudioOut::default(),
+                )
+                .map_err(|_| "unable to register port")
+        });
+        Some(ports.collect::<Result<Vec<_>, _>>()?)
+    } else {
+        None
+    };
+
+    // Fails early when built without encryption
+    let mut cipher = Cipher::new(options.key, options.auth_key)?;
+
+    // Configure socket for sending
+    let socket = match options.transport {
+        Transport::Udp => {
+            let socket = transport::bind_udp(bind, options.v6only, None)?;
+            if let Some(name) = &options.advertise {
+                let port = socket
+                    .local_addr()
+                    .map_err(|_| "unable to bind to address")?
+                    .port();
+                let properties = [
+                    format!("rate={}", client.sample_rate()),
+                    format!("channels={}", channels),
+                    format!("format={}", options.format),
+                    format!("codec={}", options.codec),
+                ];
+                mdns::advertise(name, port, &properties)?;
+            }
+            match (send, &options.ice) {
+                (Some(send), _) => socket.connect(send).map_err(|_| "unable to connect")?,
+                (None, Some(exchange)) => {
+                    let receiver = ice::establish(&socket, exchange, ice::Role::Controlling)?;
+                    socket.connect(receiver).map_err(|_| "unable to connect")?
+                }
+                (None, None) => wait_for_subscriber(&socket, &cipher)?,
+            }
+            if let Some(ttl) = options.ttl {
+                set_ttl(SockRef::from(&socket), ttl)?;
+            }
+            if let Some(interface) = options.multicast_interface {
+                set_multicast_interface(SockRef::from(&socket), interface)?;
+            }
+            if options.dont_fragment {
+                set_dont_fragment(SockRef::from(&socket))?;
+            }
+            socket
+        }
+        Transport::Tcp => {
+            let send = send.ok_or("the tcp and quic transports require --peer")?;
+            transport::connect(bind, send, options.ttl, options.v6only, options.tcp_queue)?
+        }
+        Transport::Quic => {
+            let send = send.ok_or("the tcp and quic transports require --peer")?;
+            quic::connect(bind, send, options.ttl, options.v6only)?
+        }
+    };
+
+    let metrics = Arc::new(Metrics {
+        destinations: options
+            .fanout
+            .iter()
+            .map(|&addr| Arc::new(DestinationMetrics::new(addr)))
+            .collect(),
+        ..Metrics::default()
+    });
+    metrics
+        .sample_rate
+        .store(client.sample_rate() as u64, Ordering::Relaxed);
+
+    // Packets hold the same audio whatever the channels and format
+    if let Some(frames) = options.frames_per_packet {
+        options.packet_size = frames as usize * channels * options.format.sample_size();
+    }
+    // With Opus every packet carries one frame, which takes the place of --packet-size
+    if options.codec == Codec::Opus {
+        options.packet_size = codec::opus_packet_size(
+            client.sample_rate(),
+            channels,
+            options.opus_frame,
+            options.format,
+        )?;
+    }
+    let encoder = options
+        .codec
+        .encoder(client.sample_rate(), channels, options.opus_bitrate)?;
+
+    // AES67 devices all take 48 kHz in packets of 1 ms
+    if options.aes67 {
+        if client.sample_rate() != AES67_SAMPLE_RATE {
+            return Err("--aes67 requires a JACK sample rate of 48 kHz");
+        }
+        options.packet_size = AES67_SAMPLE_RATE / 1000 * channels * options.format.sample_size();
+    }
+    // Session keys hold for the one receiver they were exchanged with
+    if let Some(identity) = options.identity {
+        let peer = options.peer_key.ok_or("--identity requires --peer-key")?;
+        cipher = exchange_keys(&socket, identity, peer, options.stream_id)?;
+    }
+
+    // Agree on stream settings before any audio is sent, RTP receivers would not answer
+    if options.rtp.is_none() {
+        handshake(
+            &socket,
+            &mut options,
+            client.sample_rate() as u32,
+            &mut cipher,
+        )?;
+    }
+
+    // Tell JACK how long the inputs take to reach the network
+    if let Source::Ports(in_ports) = &source {
+        latency.add_ports(
+            0,
+            in_ports.iter().map(Port::clone_unowned),
+            input_latency(&options, client.buffer_size() as usize),
+        );
+    }
+    let connections = Connections::new(options.connect.clone(), false);
+    if let Source::Ports(in_ports) = &source {
+        connections.add_ports(in_ports.iter().map(Port::clone_unowned));
+    }
+    if !options
+        .packet_size
+        .is_multiple_of(channels * options.format.sample_size())
+    {
+        return Err("packet size must be a whole number of frames");
+    }
+    if options.packet_size >= options.ring_buffer_size {
+        return Err("ring buffer must be larger than a packet");
+    }
+    if options.packet_size + options.codec.overhead() > MAX_PAYLOAD_SIZE {
+        return Err("packet size too large for the codec, lower --packet-size");
+    }
+    if options.packet_size + options.codec.overhead() + cipher.overhead() > MAX_PAYLOAD_SIZE {
+        return Err("packet size too large to seal, lower --packet-size");
+    }
+    // Sample counts grow with the channels
+    if options.verify
+        && options.packet_size
+            + options.codec.overhead()
+            + cipher.overhead()
+            + sample_count_size(channels)
+            > MAX_PAYLOAD_SIZE
+    {
+        return Err("packet size too large for --verify sample counts, lower --packet-size");
+    }
+    // Parity packets carry a whole audio packet, header and sample counts included
+    let largest_packet = HEADER_SIZE + options.packet_size + options.codec.overhead();
+    if options.fec.is_some()
+        && largest_packet + sample_count_size(channels) + PARITY_HEADER_SIZE > MAX_PAYLOAD_SIZE
+    {
+        return Err("packet size too large for --fec parity, lower --packet-size");
+    }
+
+    // Further paths go out from the same address on ports of their own, routed separately
+    let mut paths = Vec::new();
+    for &peer in &options.extra_peers {
+        let mut bind = socket
+            .local_addr()
+            .map_err(|_| "unable to bind to address")?;
+        bind.set_port(0);
+        let path = transport::bind_udp(bind, options.v6only, None)?;
+        path.connect(peer).map_err(|_| "unable to connect")?;
+        if let Some(ttl) = options.ttl {
+            set_ttl(SockRef::from(&path), ttl)?;
+        }
+        if options.dont_fragment {
+            set_dont_fragment(SockRef::from(&path))?;
+        }
+        info!(peer = %peer, "also sending on an extra path");
+        paths.push(path);
+    }
+
+    // So do further receivers, each sending back loss reports of its own
+    let mut destinations = Vec::new();
+    for destination in &metrics.destinations {
+        let mut bind = socket
+            .local_addr()
+            .map_err(|_| "unable to bind to address")?;
+        bind.set_port(0);
+        let fanout = transport::bind_udp(bind, options.v6only, None)?;
+        fanout
+            .connect(destination.addr)
+            .map_err(|_| "unable to connect")?;
+        if let Some(ttl) = options.ttl {
+            set_ttl(SockRef::from(&fanout), ttl)?;
+        }
+        if options.dont_fragment {
+            set_dont_fragment(SockRef::from(&fanout))?;
+        }
+        let reports = fanout.try_clone().map_err(|_| "unable to clone socket")?;
+        let (report_cipher, report_metrics) = (cipher.clone(), destination.clone());
+        thread::spawn(move || follow_reports(reports, report_cipher, report_metrics));
+        info!(destination = %destination.addr, "also streaming");
+        // Opus keeps state from frame to frame, so each bitrate takes an encoder of its own
+        let own_encoder = match options.adaptive_bitrate {
+            true => options
+                .codec
+                .encoder(client.sample_rate(), channels, options.opus_bitrate)?,
+            false => None,
+        };
+        destinations.push(Destination {
+            socket: fanout,
+            retry: Retry::new(format!("send to {}", destination.addr)),
+            failing: false,
+            metrics: destination.clone(),
+            encoder: own_encoder,
+            bitrate: BitrateControl::new(options.opus_bitrate),
+            buffer: Vec::new(),
+        });
+    }
 
     // Channel for audio thread communication
     let (sender, receiver) = mpsc::channel();
+    if options.peer_stdin {
+        let peer_sender = sender.clone();
+        thread::spawn(move || read_peers(peer_sender));
+    }
+    if let Some((host, interval)) = options.resolve.clone()
+        && let Ok(peer) = socket.peer_addr()
+    {
+        let peer_sender = sender.clone();
+        thread::spawn(move || follow_host(host, interval, peer, peer_sender));
+    }
 
-    // Create ring buffer and interleaving buffer
-    let (mut ring_buffer_reader, mut ring_buffer_writer) = RingBuffer::new(RING_BUFFER_SIZE)
+    // Create ring buffer and interleaving buffer holding encoded samples
+    let (ring_buffer_reader, mut ring_buffer_writer) = RingBuffer::new(options.ring_buffer_size)
         .map_err(|_| "unable to create ring buffer")?
         .into_reader_writer();
-    let mut interleave_channels_buffer = [0.0; RING_BUFFER_SIZE * 2];
-
-    let _async_client = client
-        .activate_async(
-            (),
-            ClosureProcessHandler::new(move |_, ps| {
-                // Get input audio buffers
-                let data_to_send_l = in_port_l.as_slice(ps);
-                let data_to_send_r = in_port_r.as_slice(ps);
-                let amount_to_send = data_to_send_l.len() + data_to_send_r.len();
-
-                // Validate buffer sizes
-                if amount_to_send > interleave_channels_buffer.len()
-                    || data_to_send_l.len() != data_to_send_r.len()
-                {
+    let format = options.format;
+    let sample_size = format.sample_size();
+    let overrun = options.overrun;
+    let ring_buffer_size = options.ring_buffer_size;
+    let buffers = ProcessBuffers::new(client.buffer_size() as usize, channels, sample_size);
+    let period_sender = sender.clone();
+    let coalesce = options.coalesce;
+    let timer_driven = options.send_cadence.is_some();
+    let mut pending_periods = 0;
+
+    // The reader is shared so the audio thread can discard the oldest audio on overrun,
+    // the audio thread only ever tries to lock it
+    let ring_buffer_reader = Arc::new(Mutex::new(ring_buffer_reader));
+    let overrun_reader = ring_buffer_reader.clone();
+
+    // Input levels, measured by the audio thread and reported by the network thread
+    let levels = options.meter.then(|| {
+        (0..channels)
+            .map(|_| InputLevel::default())
+            .collect::<Arc<[_]>>()
+    });
+    let input_levels = levels.clone();
+
+    // Dither generator, seeded once outside of the audio thread
+    let mut dither = options
+        .dither
+        .then(|| Dither::new(packet::now_micros() as u32));
+
+    // RTP timestamps count frames at the JACK sample rate
+    let rtp = options
+        .rtp
+        .map(|encoding| rtp::Encoder::new(encoding, client.sample_rate(), channels, options.aes67));
+    // AES67 receivers are set up from a session description, usually pasted into their
+    // control software
+    if options.aes67
+        && let Some(rtp) = &rtp
+        && let (Ok(source), Ok(destination)) = (socket.local_addr(), socket.peer_addr())
+    {
+        let packet_time = options.packet_size as f64 * 1000.0
+            / (channels * sample_size * client.sample_rate()) as f64;
+        info!(
+            "session description for AES67 receivers:\n{}",
+            rtp.sdp(source, destination, options.ttl, packet_time)
+        );
+    }
+
+    let process = ClosureProcessHandler::with_state(
+        buffers,
+        move |buffers, _, ps| {
+            // JACK may hand over an empty period during xruns or reconfiguration,
+            // there is nothing to send and no reason to wake the network thread
+            if ps.n_frames() == 0 {
+                return Control::Continue;
+            }
+
+            let frames = ps.n_frames() as usize;
+            let amount_to_send = frames * channels;
+            let bytes_to_send = amount_to_send * sample_size;
+
+            // A period the buffers have not been resized for yet is skipped, the buffer size
+            // callback comes before the next one
+            if bytes_to_send > buffers.interleaved.len() {
+                if !buffers.oversized {
+                    buffers.oversized = true;
+                    let _ = sender.send(Message::PeriodTooLarge {
+                        period: amount_to_send,
+                        capacity: buffers.interleaved.len() / sample_size,
+                    });
+                }
+                for port in monitor_ports.iter_mut().flatten() {
+                    port.as_mut_slice(ps).fill(0.0);
+                }
+                return Control::Continue;
+            }
+            if let Source::Tone(tone) = &mut source {
+                tone.fill(&mut buffers.tone[..frames]);
+            }
+
+            // Gather the input audio buffers into interleaved samples
+            let samples = &mut buffers.samples[..amount_to_send];
+            for channel in 0..channels {
+                let data_to_send = match &source {
+                    Source::Ports(in_ports) => in_ports[channel].as_slice(ps),
+                    Source::Tone(_) => &buffers.tone[..frames],
+                };
+                if data_to_send.len() != frames {
                     let _ = sender.send(Message::InvalidBufferLengths);
                     return Control::Quit;
                 }
+                if let Some(levels) = input_levels.as_deref() {
+                    levels[channel].add(data_to_send);
+                }
+                // Echo the signal to the monitor ports
+                if let Some(mon_ports) = &mut monitor_ports {
+                    mon_ports[channel]
+                        .as_mut_slice(ps)
+                        .iter_mut()
+                        .zip(data_to_send)
+                        .for_each(|(buffer_val, &data)| *buffer_val = data);
+                }
+                interleave_into(data_to_send, channel, channels, samples);
+            }
 
-                // Check ring buffer space
-                let rb_space = ring_buffer_writer.space();
-                if rb_space < amount_to_send * size_of::<f32>() {
-                    let _ = sender.send(Message::Overrun {
-                        expected: amount_to_send * size_of::<f32>(),
-                        available: rb_space,
+            // Check ring buffer space
+            let rb_space = ring_buffer_writer.space();
+            if rb_space < bytes_to_send {
+                // Discard whole frames of the oldest audio, unless the reader is busy
+                let frame_size = channels * sample_size;
+                let dropped_old = overrun == OverrunPolicy::DropOld
+                    && overrun_reader.try_lock().is_ok_and(|mut reader| {
+                        let discard = (bytes_to_send - rb_space).div_ceil(frame_size) * frame_size;
+                        let discard = discard.min(reader.space() / frame_size * frame_size);
+                        reader.advance(discard);
+                        discard > 0
                     });
-                } else {
-                    // Interleave and write to ring buffer
-                    let mut written = 0;
-                    interleave_channels_buffer
-                        .iter_mut()
-                        // Already checked buffer sizes, so unwrapping is safe
-                        .zip(interleave(data_to_send_l, data_to_send_r).unwrap())
-                        .for_each(|(buffer_val, data)| {
-                            *buffer_val = data;
-                            written += 1;
-                        });
 
-                    ring_buffer_writer.write_buffer(bytemuck::cast_slice(
-                        &interleave_channels_buffer[0..written],
-                    ));
+                let _ = sender.send(Message::Overrun {
+                    expected: bytes_to_send,
+                    available: rb_space,
+                    policy: if dropped_old {
+                        OverrunPolicy::DropOld
+                    } else {
+                        OverrunPolicy::DropNew
+                    },
+                });
+            }
+
+            if ring_buffer_writer.space() >= bytes_to_send {
+                // Encode and write to ring buffer
+                let written = format.encode(
+                    samples.iter().copied(),
+                    dither.as_mut(),
+                    &mut buffers.interleaved,
+                );
+                ring_buffer_writer.write_buffer(&buffers.interleaved[0..written]);
+            }
+
+            // Only wake the network thread once enough periods have been gathered
+            pending_periods += 1;
+            if pending_periods >= coalesce {
+                pending_periods = 0;
+                // A timer-driven send loop does not need waking up
+                if !timer_driven {
+                    let _ = sender.send(Message::Ready);
                 }
+            }
+            Control::Continue
+        },
+        // Grow the scratch buffers when the period changes, outside of processing
+        move |buffers, _, frames| {
+            if frames as usize != buffers.frames {
+                let _ = period_sender.send(Message::PeriodChanged {
+                    frames: frames as usize,
+                });
+            }
+            buffers.resize(frames as usize, channels, sample_size);
+            let period_bytes = frames as usize * channels * sample_size;
+            if period_bytes >= ring_buffer_size {
+                let _ = period_sender.send(Message::RingBufferTooSmall {
+                    period_bytes,
+                    ring_buffer_size,
+                });
+            }
+            Control::Continue
+        },
+    );
 
-                let _ = sender.send(Message::Ready);
-                Control::Continue
-            }),
-        )
-        .map_err(|_| "unable to activate client")?;
+    let warn_interval = options.warn_interval;
+    // Summaries count overruns and xruns in place of the warnings
+    let stats_interval = options.stats_interval;
+    // Coalesced datagrams carry whatever was gathered, up to the MTU
+    let trailer_size = if options.verify {
+        sample_count_size(channels)
+    } else {
+        0
+    };
+    let overhead = options.codec.overhead();
+    let sealed = cipher.overhead();
+    let payload_limit = if options.coalesce > 1 {
+        let frame_size = channels * sample_size;
+        match options.rtp {
+            // RTP packets hold frames of their own sample size
+            Some(encoding) => {
+                (MTU_PAYLOAD_SIZE - RTP_HEADER_SIZE) / (channels * encoding.sample_size())
+                    * frame_size
+            }
+            None => {
+                (MTU_PAYLOAD_SIZE - HEADER_SIZE - trailer_size - overhead - sealed) / frame_size
+                    * frame_size
+            }
+        }
+    } else {
+        options.packet_size
+    };
+    let fec = options.fec.map(fec::Encoder::new);
+    let buffer_size = rtp
+        .as_ref()
+        .map_or(0, |rtp| rtp.packet_size(options.format, payload_limit));
 
-    // Main network send loop
-    let mut buffer = [0; PACKET_SIZE];
-    loop {
-        // Wait for audio thread signal
-        match receiver.recv() {
-            Ok(Message::InvalidBufferLengths) => eprintln!("[ERROR] invalid buffer lengths"),
-            Ok(Message::Overrun {
+    // Served once everything above has been checked, so a failing stream leaves nothing behind
+    for addr in [options.metrics, options.http].into_iter().flatten() {
+        metrics::serve(addr, metrics.clone())?;
+    }
+    if let Some(interval) = options.stats_interval {
+        let frame_size = options.channels * options.format.sample_size();
+        let bytes_per_second = client.sample_rate() as f64 * frame_size as f64;
+        let name = options.name.clone();
+        metrics::report(metrics.clone(), interval, name, "sent", bytes_per_second);
+    }
+    let bitrate = options
+        .adaptive_bitrate
+        .then(|| BitrateControl::new(options.opus_bitrate));
+    let returned_metrics = metrics.clone();
+    let network = move |client: &Client| {
+        connect::keep(client, &connections, || {
+            run(
+                socket,
+                receiver,
+                Network {
+                    options,
+                    sample_size,
+                    ring_buffer_reader,
+                    payload_limit,
+                    buffer: vec![
+                        0;
+                        buffer_size
+                            .max(HEADER_SIZE + payload_limit + overhead + trailer_size)
+                    ]
+                    .into_boxed_slice(),
+                    scratch: vec![0; payload_limit].into_boxed_slice(),
+                    bitrate,
+                    encoder,
+                    sequence: 0,
+                    sent: SampleCount::new(channels),
+                    copies: VecDeque::new(),
+                    fec,
+                    rtp,
+                    paths,
+                    destinations,
+                    path_buffer: Vec::new(),
+                    path_errors: WarningCounter::new("failed sends on extra paths", warn_interval),
+                    copy_buffer: Vec::new(),
+                    spare_copies: Vec::new(),
+                    levels,
+                    last_meter: Instant::now(),
+                    last_ping: Instant::now(),
+                    last_sent: Instant::now(),
+                    overruns: match stats_interval {
+                        Some(_) => WarningCounter::silent("overruns"),
+                        None => WarningCounter::new("overruns", warn_interval),
+                    },
+                    xruns: match stats_interval {
+                        Some(_) => XrunWarnings::silent(),
+                        None => XrunWarnings::new(warn_interval),
+                    },
+                    metrics,
+                    cipher,
+                    client,
+                    latency,
+                },
+            )
+        })
+    };
+    Ok((returned_metrics, process, network))
+}
+
+// Network side of the sender, independent of how datagrams are sent
+struct Network<'a> {
+    options: Options,
+    sample_size: usize,
+    ring_buffer_reader: Arc<Mutex<RingBufferReader>>,
+    payload_limit: usize, // Largest payload of a single datagram
+    buffer: Box<[u8]>,
+    scratch: Box<[u8]>, // Payload before reordering or encoding
+    encoder: Option<Encoder>,
+    bitrate: Option<BitrateControl>, // Steers the encoder with --adaptive-bitrate
+    sequence: u32,
+    sent: SampleCount,                    // Samples per channel sent so far
+    copies: VecDeque<(Instant, Vec<u8>)>, // Redundant copies and parity to send, by due time
+    copy_buffer: Vec<u8>,                 // The copy being sent
+    spare_copies: Vec<Vec<u8>>,           // Buffers of sent copies, reused for new ones
+    fec: Option<fec::Encoder>,            // Builds parity with --fec
+    rtp: Option<rtp::Encoder>,            // Rewrites packets as RTP with --rtp
+    paths: Vec<UdpSocket>,                // Sockets of --extra-peer
+    destinations: Vec<Destination>,       // Receivers of --fanout
+    path_buffer: Vec<u8>,                 // Packet being sent on them, marked as a copy on paths
+    path_errors: WarningCounter,
+    levels: Option<Arc<[InputLevel]>>, // Per channel with --meter
+    last_meter: Instant,
+    last_ping: Instant,
+    last_sent: Instant,
+    overruns: WarningCounter,
+    xruns: XrunWarnings,
+    metrics: Arc<Metrics>,
+    cipher: Cipher,     // Seals what goes out on the extra paths
+    client: &'a Client, // Reports latency changes to JACK
+    latency: Arc<Latency>,
+}
+
+impl Network<'_> {
+    // Prints the aggregated warnings that are due, and any new JACK xruns
+    fn flush_warnings(&mut self) {
+        self.overruns.flush();
+        self.path_errors.flush();
+        self.xruns.check(self.metrics.xruns.load(Ordering::Relaxed));
+    }
+
+    // Moves the bitrate of each --adaptive-bitrate encoder along with the loss its receiver
+    // reported since the last call
+    fn adapt_bitrates(&mut self) {
+        let take = |report: &AtomicU64| LossReport::unpack(report.swap(0, Ordering::Relaxed));
+        if let Some(bitrate) = &mut self.bitrate
+            && let Some(encoder) = &mut self.encoder
+            && let Some(report) = take(&self.metrics.report)
+            && let Some(kbps) = bitrate.update(report.percentage())
+        {
+            match encoder.set_bitrate(kbps) {
+                Ok(()) => info!(
+                    kbps,
+                    loss = format_args!("{:.1}%", report.percentage()),
+                    "opus bitrate changed"
+                ),
+                Err(error) => warning!("{}", error),
+            }
+        }
+        for destination in &mut self.destinations {
+            if let Some(encoder) = &mut destination.encoder
+                && let Some(report) = take(&destination.metrics.report)
+                && let Some(kbps) = destination.bitrate.update(report.percentage())
+            {
+                match encoder.set_bitrate(kbps) {
+                    Ok(()) => info!(
+                        destination = %destination.metrics.addr,
+                        kbps,
+                        loss = format_args!("{:.1}%", report.percentage()),
+                        "opus bitrate changed"
+                    ),
+                    Err(error) => warning!("{}", error),
+                }
+            }
+        }
+    }
+
+    // Reports warnings from the audio thread, fails if it stopped processing
+    fn handle_message(&mut self, message: Message) -> Result<(), &'static str> {
+        match message {
+            // Handled by the send loop, which owns the socket
+            Message::Ready | Message::Repoint(_) => {}
+            Message::InvalidBufferLengths => {
+                return Err("invalid buffer lengths, JACK processing stopped");
+            }
+            Message::PeriodTooLarge { period, capacity } => warning!(
+                "JACK period of {} samples exceeds the buffer capacity of {} samples, \
+                 skipping periods until the buffers are resized",
+                period,
+                capacity
+            ),
+            Message::PeriodChanged { frames } => {
+                info!("JACK period changed to {} frames", frames);
+                // Coalesced inputs wait for a number of periods
+                if self.options.coalesce > 1 {
+                    self.latency.set(0, input_latency(&self.options, frames));
+                    latency::recompute(self.client);
+                }
+            }
+            Message::RingBufferTooSmall {
+                period_bytes,
+                ring_buffer_size,
+            } => error!(
+                "JACK period of {} bytes does not fit the {} byte ring buffer, \
+                 try --ring-buffer-size {}",
+                period_bytes,
+                ring_buffer_size,
+                (2 * period_bytes).next_power_of_two()
+            ),
+            Message::Overrun {
                 expected,
                 available,
-            }) => eprintln!(
-                "[WARNING] overrun, expected to write {} bytes, {} available",
-                expected, available
-            ),
-            // Send when data is available
-            Ok(Message::Ready) | Err(RecvError) => {
-                while ring_buffer_reader.space() >= buffer.len() {
-                    let data_to_send = ring_buffer_reader.read_slice(&mut buffer);
-                    socket
-                        .send(data_to_send)
-                        .map_err(|_| "unable to send data")?;
+                policy,
+            } => {
+                self.metrics.overruns.fetch_add(1, Ordering::Relaxed);
+                if self.overruns.record() {
+                    warning!(
+                        "overrun ({}), expected to write {} bytes, {} available",
+                        policy,
+                        expected,
+                        available
+                    );
                 }
             }
         }
+        Ok(())
+    }
+
+    // Copies the next packet out of the ring buffer if a whole one is available,
+    // or everything gathered so far when coalescing
+    fn next_packet(&mut self) -> Option<&[u8]> {
+        // Copies of earlier packets go out first, so they never arrive after newer packets
+        if !self.copies.is_empty() {
+            let available = self
+                .ring_buffer_reader
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .space();
+            if self.payload_size(available, false) > 0 {
+                return self.next_copy(true);
+            }
+        }
+        self.take_packet(false)
+    }
+
+    // Takes the oldest redundant copy if it is due, or regardless with `flush`
+    fn next_copy(&mut self, flush: bool) -> Option<&[u8]> {
+        let &(due, _) = self.copies.front()?;
+        if !flush && due > Instant::now() {
+            return None;
+        }
+        let (_, copy) = self.copies.pop_front()?;
+        let sent = mem::replace(&mut self.copy_buffer, copy);
+        self.spare_copies.push(sent);
+        if !self.paths.is_empty() || !self.destinations.is_empty() {
+            self.path_buffer.clear();
+            self.path_buffer.extend_from_slice(&self.copy_buffer);
+            self.fan_out(None);
+            self.send_paths();
+        }
+        Some(&self.copy_buffer)
+    }
+
+    // Sends the packet in the path buffer to every --fanout receiver. Those with an encoder of
+    // their own are sent the `pcm` it was made from encoded at their bitrate, copies and
+    // parity, which come without, go out as they are.
+    fn fan_out(&mut self, pcm: Option<usize>) {
+        let shared = |destination: &Destination| destination.encoder.is_none() || pcm.is_none();
+        if self.destinations.iter().any(shared) {
+            let datagram = self.cipher.seal(&self.path_buffer);
+            for destination in self.destinations.iter_mut().filter(|d| shared(d)) {
+                destination.send(datagram);
+            }
+        }
+        let (Some(pcm), Some(header)) = (pcm, Header::read(&self.path_buffer)) else {
+            return;
+        };
+        let pcm = &self.scratch[..pcm];
+        // Sample counts follow the payload unchanged
+        let trailer = &self.path_buffer[HEADER_SIZE + header.length as usize..];
+        for destination in &mut self.destinations {
+            let Some(encoder) = &mut destination.encoder else {
+                continue;
+            };
+            let mut buffer = mem::take(&mut destination.buffer);
+            buffer.resize(HEADER_SIZE + pcm.len() + trailer.len(), 0);
+            let out = &mut buffer[HEADER_SIZE..HEADER_SIZE + pcm.len()];
+            // A frame that fails to encode goes out empty, as on the primary stream
+            let length = encoder
+                .encode(self.options.format, pcm, out)
+                .unwrap_or_else(|error| {
+                    warning!("{}", error);
+                    0
+                });
+            Header {
+                length: length as u16,
+                ..header
+            }
+            .write(&mut buffer);
+            buffer.truncate(HEADER_SIZE + length);
+            buffer.extend_from_slice(trailer);
+            destination.send(self.cipher.seal(&buffer));
+            destination.buffer = buffer;
+        }
+    }
+
+    // Sends the packet in the path buffer on every extra path, marked as a copy so receivers
+    // reached over several paths play whichever arrives first. A path that fails is reported
+    // but does not stop the stream.
+    fn send_paths(&mut self) {
+        if self.paths.is_empty() {
+            return;
+        }
+        let Some(header) = Header::read(&self.path_buffer) else {
+            return;
+        };
+        Header {
+            flags: header.flags | FLAG_COPY,
+            ..header
+        }
+        .write(&mut self.path_buffer);
+        let packet = self.cipher.seal(&self.path_buffer);
+        for path in &self.paths {
+            if let Err(error) = path.send(packet)
+                && self.path_errors.record()
+            {
+                let peer = path.peer_addr().map(|peer| peer.to_string());
+                warning!("unable to send to {}: {}", peer.unwrap_or_default(), error);
+            }
+        }
+    }
+
+    // When the next redundant copy is due
+    fn copy_due(&self) -> Option<Instant> {
+        self.copies.front().map(|&(due, _)| due)
+    }
+
+    // Builds the end-of-stream packet from whatever is left, padded with silence to a
+    // whole packet
+    fn final_packet(&mut self) -> &[u8] {
+        // The final packet is always built
+        self.take_packet(true).unwrap()
+    }
+
+    // Audio bytes the next packet takes out of the `available` ones
+    fn payload_size(&self, available: usize, end: bool) -> usize {
+        let frame_size = self.options.channels * self.sample_size;
+        if end || self.options.coalesce > 1 {
+            available.min(self.payload_limit) / frame_size * frame_size
+        } else if available >= self.payload_limit {
+            self.payload_limit
+        } else {
+            0
+        }
+    }
+
+    fn take_packet(&mut self, end: bool) -> Option<&[u8]> {
+        // Audio bytes taken including padding, and bytes of payload sent for them
+        let (padded_size, wire_size);
+        // Hold the reader only while copying a packet out of it
+        {
+            let mut ring_buffer_reader = self
+                .ring_buffer_reader
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let available = ring_buffer_reader.space();
+            let payload_size = self.payload_size(available, end);
+            padded_size = if end && payload_size > 0 && self.options.coalesce == 1 {
+                self.payload_limit
+            } else {
+                payload_size
+            };
+            if padded_size == 0 && !end {
+                return None;
+            }
+
+            let payload = &mut self.buffer[HEADER_SIZE..HEADER_SIZE + padded_size];
+            wire_size = if self.options.planar {
+                let planar = &mut self.scratch[..padded_size];
+                ring_buffer_reader.read_buffer(&mut planar[..payload_size]);
+                planar[payload_size..].fill(0);
+                payload
+                    .chunks_exact_mut(self.sample_size)
+                    // The payload is a whole number of frames, so unwrapping is safe
+                    .zip(to_planar(planar, self.sample_size, self.options.channels).unwrap())
+                    .for_each(|(buffer_val, data)| buffer_val.copy_from_slice(data));
+                padded_size
+            } else if let Some(encoder) = &mut self.encoder {
+                let pcm = &mut self.scratch[..padded_size];
+                ring_buffer_reader.read_buffer(&mut pcm[..payload_size]);
+                pcm[payload_size..].fill(0);
+                let overhead = self.options.codec.overhead();
+                let out = &mut self.buffer[HEADER_SIZE..HEADER_SIZE + padded_size + overhead];
+                // A frame that fails to encode goes out empty, the receiver plays nothing for it
+                encoder
+                    .encode(self.options.format, pcm, out)
+                    .unwrap_or_else(|error| {
+                        warning!("{}", error);
+                        0
+                    })
+            } else {
+                ring_buffer_reader.read_buffer(&mut payload[..payload_size]);
+                payload[payload_size..].fill(0);
+                padded_size
+            };
+
+            let metrics = &self.metrics;
+            metrics.packets.fetch_add(1, Ordering::Relaxed);
+            metrics
+                .last_packet
+                .store(packet::now_micros(), Ordering::Relaxed);
+            metrics.bytes.fetch_add(wire_size as u64, Ordering::Relaxed);
+            metrics
+                .buffered
+                .store((available - payload_size) as u64, Ordering::Relaxed);
+        }
+
+        // RTP packets carry the samples alone, the payload is set aside while they are written
+        if let Some(rtp) = &mut self.rtp {
+            let payload = &mut self.scratch[..wire_size];
+            payload.copy_from_slice(&self.buffer[HEADER_SIZE..HEADER_SIZE + wire_size]);
+            let length = rtp.write(self.options.format, payload, &mut self.buffer);
+            self.sequence = self.sequence.wrapping_add(1);
+            self.last_sent = Instant::now();
+            if !self.destinations.is_empty() {
+                self.path_buffer.clear();
+                self.path_buffer.extend_from_slice(&self.buffer[..length]);
+                self.fan_out(None);
+            }
+            return Some(&self.buffer[..length]);
+        }
+
+        let mut flags = if end { FLAG_END } else { 0 };
+        let mut length = HEADER_SIZE + wire_size;
+        if self.options.verify {
+            // Counts follow the payload, where receivers not checking them ignore them
+            flags |= FLAG_VERIFY;
+            self.sent.write(&mut self.buffer[length..]);
+            length += self.sent.size();
+            self.sent.add_interleaved(padded_size / self.sample_size);
+        }
+        let header = Header {
+            sequence: self.sequence,
+            timestamp: packet::now_micros(),
+            flags,
+            stream: self.options.stream_id,
+            length: wire_size as u16,
+        };
+        header.write(&mut self.buffer);
+
+        // Parity goes out right after the last packet of its group. The end of the stream is
+        // left out, its group is never complete.
+        if !end
+            && let Some(fec) = &mut self.fec
+            && fec.add(self.sequence, &self.buffer[..length])
+        {
+            let parity_header = Header {
+                flags: FLAG_PARITY,
+                ..header
+            };
+            for index in 0..self.options.fec.map_or(0, |layout| layout.parity) {
+                let mut buffer = self.spare_copies.pop().unwrap_or_default();
+                fec.write_parity(index, parity_header, &mut buffer);
+                let due = Instant::now();
+                let position = self.copies.partition_point(|&(queued, _)| queued <= due);
+                self.copies.insert(position, (due, buffer));
+            }
+        }
+
+        // Queue the redundant copies. The end of the stream is sent once, since a copy arriving
+        // after it would start a new stream.
+        if !end {
+            for copy in 1..self.options.redundancy {
+                let due = Instant::now() + self.options.redundancy_delay * copy;
+                let mut buffer = self.spare_copies.pop().unwrap_or_default();
+                buffer.clear();
+                buffer.extend_from_slice(&self.buffer[..length]);
+                Header {
+                    flags: flags | FLAG_COPY,
+                    ..header
+                }
+                .write(&mut buffer);
+                let index = self.copies.partition_point(|&(queued, _)| queued <= due);
+                self.copies.insert(index, (due, buffer));
+            }
+        }
+        trace!(sequence = self.sequence, length, end, "audio packet");
+        self.sequence = self.sequence.wrapping_add(1);
+        self.last_sent = Instant::now();
+        // Further receivers are sent the end of the stream too, extra paths are not
+        if !self.paths.is_empty() || !self.destinations.is_empty() {
+            self.path_buffer.clear();
+            self.path_buffer.extend_from_slice(&self.buffer[..length]);
+            self.fan_out(self.encoder.is_some().then_some(padded_size));
+            if !end {
+                self.send_paths();
+            }
+        }
+        Some(&self.buffer[..length])
+    }
+
+    // Reports the input levels measured since the last report if one is due
+    fn meter(&mut self) {
+        let Some(levels) = self.levels.as_deref() else {
+            return;
+        };
+        if self.last_meter.elapsed() < METER_INTERVAL {
+            return;
+        }
+        let channels = levels.len();
+        let prefix = port_prefix(self.options.name.as_deref(), "in");
+        let levels: Vec<_> = (0..channels)
+            .zip(levels)
+            .map(|(channel, level)| {
+                let (peak, rms) = level.take();
+                let name = port_name(&prefix, channel, channels);
+                format!("{}: {:.1} dBFS (peak {:.1})", name, rms, peak)
+            })
+            .collect();
+        info!("input levels {}", levels.join(", "));
+        self.last_meter = Instant::now();
+    }
+
+    // Builds a round-trip probe if one is due, telling the receiver the latest round-trip time
+    fn next_ping(&mut self) -> Option<[u8; HEADER_SIZE + PROBE_SIZE]> {
+        if !self.options.ping || self.last_ping.elapsed() < PING_INTERVAL {
+            return None;
+        }
+
+        let mut probe = [0; HEADER_SIZE + PROBE_SIZE];
+        Header {
+            sequence: self.sequence,
+            timestamp: packet::now_micros(),
+            flags: FLAG_PING,
+            stream: self.options.stream_id,
+            length: PROBE_SIZE as u16,
+        }
+        .write(&mut probe);
+        Probe(self.metrics.rtt.load(Ordering::Relaxed)).write(&mut probe[HEADER_SIZE..]);
+        self.last_ping = Instant::now();
+        Some(probe)
+    }
+
+    // Builds a keepalive if no packet has been sent within the keepalive interval
+    fn next_keepalive(&mut self) -> Option<[u8; HEADER_SIZE]> {
+        if self.last_sent.elapsed() < self.options.keepalive? {
+            return None;
+        }
+
+        let mut keepalive = [0; HEADER_SIZE];
+        Header {
+            sequence: self.sequence,
+            timestamp: packet::now_micros(),
+            flags: FLAG_KEEPALIVE,
+            stream: self.options.stream_id,
+            length: 0,
+        }
+        .write(&mut keepalive);
+        self.last_sent = Instant::now();
+        Some(keepalive)
+    }
+}
+
+// Main network send loop
+#[cfg(not(feature = "async"))]
+fn run(
+    socket: UdpSocket,
+    receiver: mpsc::Receiver<Message>,
+    mut network: Network<'_>,
+) -> Result<(), &'static str> {
+    // Listen for receiver feedback on a separate thread
+    let feedback_socket = socket.try_clone().map_err(|_| "unable to clone socket")?;
+    let feedback_cipher = network.cipher.clone();
+    let feedback_metrics = network.metrics.clone();
+    thread::spawn(move || {
+        // Loss reports and pongs carry payloads of the same size
+        let mut buffer = [0; HEADER_SIZE + LOSS_REPORT_SIZE + crypto::OVERHEAD];
+        loop {
+            match feedback_socket.recv(&mut buffer) {
+                Ok(received) => {
+                    if let Some(datagram) = feedback_cipher.open(&mut buffer[..received]) {
+                        handle_feedback(datagram, &feedback_metrics);
+                    }
+                }
+                // Raised while the receiver is not running yet or the network is down
+                Err(error) if transport::is_transient(&error) => thread::sleep(FEEDBACK_PAUSE),
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut cipher = network.cipher.clone();
+    let mut retry = Retry::new("send");
+    let mut next_send = Instant::now();
+    let result: Result<(), &'static str> = try {
+        while !shutdown::requested() {
+            // Wait for audio thread signal, waking up in time for a keepalive or the next timed
+            // send if enabled
+            let until_send = network
+                .options
+                .send_cadence
+                .map(|_| next_send.saturating_duration_since(Instant::now()));
+            let until_copy = network
+                .copy_due()
+                .map(|due| due.saturating_duration_since(Instant::now()));
+            let message = match network
+                .options
+                .keepalive
+                .into_iter()
+                .chain(until_send)
+                .chain(until_copy)
+                .min()
+            {
+                Some(timeout) => receiver.recv_timeout(timeout),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match message {
+                // Send when data is available
+                Ok(Message::Ready) => {
+                    while let Some(packet) = network.next_packet() {
+                        send(&socket, &mut retry, cipher.seal(packet))?;
+                    }
+                }
+                Ok(Message::Repoint(peer)) => {
+                    repoint(SockRef::from(&socket), peer, network.options.ttl)
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => Err("JACK processing stopped")?,
+                Ok(message) => network.handle_message(message)?,
+            }
+
+            // Send whatever is buffered on a steady cadence, independent of the JACK period
+            if let Some(cadence) = network.options.send_cadence
+                && Instant::now() >= next_send
+            {
+                while let Some(packet) = network.next_packet() {
+                    send(&socket, &mut retry, cipher.seal(packet))?;
+                }
+                // Skip ticks missed while stalled rather than catching up in a burst
+                next_send = (next_send + cadence).max(Instant::now());
+            }
+
+            // Send redundant copies once they are due
+            while let Some(copy) = network.next_copy(false) {
+                send(&socket, &mut retry, cipher.seal(copy))?;
+            }
+            network.flush_warnings();
+            network.adapt_bitrates();
+            network.meter();
+
+            // Send a round-trip probe, timed by the pong listener
+            if let Some(probe) = network.next_ping() {
+                send(&socket, &mut retry, cipher.seal(&probe))?;
+            }
+
+            // Keep the link alive during gaps in the audio
+            if let Some(keepalive) = network.next_keepalive() {
+                send(&socket, &mut retry, cipher.seal(&keepalive))?;
+            }
+        }
+    };
+
+    // Send the tail of the stream, ending it explicitly
+    while let Some(packet) = network.next_packet() {
+        let _ = socket.send(cipher.seal(packet));
+    }
+    while let Some(copy) = network.next_copy(true) {
+        let _ = socket.send(cipher.seal(copy));
+    }
+    let _ = socket.send(cipher.seal(network.final_packet()));
+    result
+}
+
+// Main network send loop, driven by a single-threaded tokio runtime
+#[cfg(feature = "async")]
+fn run(
+    socket: UdpSocket,
+    receiver: mpsc::Receiver<Message>,
+    network: Network<'_>,
+) -> Result<(), &'static str> {
+    // Forward audio thread messages so they can be awaited
+    let (forward_sender, forward_receiver) = tokio::sync::mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for message in receiver {
+            if forward_sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|_| "unable to start async runtime")?
+        .block_on(run_async(socket, forward_receiver, network))
+}
+
+#[cfg(feature = "async")]
+async fn run_async(
+    socket: UdpSocket,
+    mut receiver: tokio::sync::mpsc::UnboundedReceiver<Message>,
+    mut network: Network<'_>,
+) -> Result<(), &'static str> {
+    socket
+        .set_nonblocking(true)
+        .map_err(|_| "unable to configure socket")?;
+    let socket =
+        tokio::net::UdpSocket::from_std(socket).map_err(|_| "unable to configure socket")?;
+
+    let mut cipher = network.cipher.clone();
+    let mut retry = Retry::new("send");
+    // Loss reports and pongs carry payloads of the same size
+    let mut feedback_buffer = [0; HEADER_SIZE + LOSS_REPORT_SIZE + crypto::OVERHEAD];
+    let mut ping_ticker = tokio::time::interval(PING_INTERVAL);
+    let mut send_ticker =
+        tokio::time::interval(network.options.send_cadence.unwrap_or(PING_INTERVAL));
+    send_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut keepalive_ticker =
+        tokio::time::interval(network.options.keepalive.unwrap_or(PING_INTERVAL));
+    let mut meter_ticker = tokio::time::interval(METER_INTERVAL);
+    let result: Result<(), &'static str> = try {
+        while !shutdown::requested() {
+            let copy_due = network.copy_due();
+            tokio::select! {
+                // Wait for audio thread signal, send when data is available
+                message = receiver.recv() => match message {
+                    Some(Message::Ready) => {
+                        while let Some(packet) = network.next_packet() {
+                            send_async(&socket, &mut retry, cipher.seal(packet)).await?;
+                        }
+                        network.flush_warnings();
+                        network.adapt_bitrates();
+                    }
+                    Some(Message::Repoint(peer)) => {
+                        repoint(SockRef::from(&socket), peer, network.options.ttl)
+                    }
+                    Some(message) => network.handle_message(message)?,
+                    None => Err("JACK processing stopped")?,
+                },
+                // Send whatever is buffered on a steady cadence, independent of the JACK period
+                _ = send_ticker.tick(), if network.options.send_cadence.is_some() => {
+                    while let Some(packet) = network.next_packet() {
+                        send_async(&socket, &mut retry, cipher.seal(packet)).await?;
+                    }
+                    network.flush_warnings();
+                    network.adapt_bitrates();
+                }
+                // Listen for receiver feedback
+                received = socket.recv(&mut feedback_buffer) => {
+                    if let Ok(received) = received
+                        && let Some(datagram) = cipher.open(&mut feedback_buffer[..received])
+                    {
+                        handle_feedback(datagram, &network.metrics);
+                    }
+                }
+                // Send a round-trip probe
+                _ = ping_ticker.tick(), if network.options.ping => {
+                    if let Some(probe) = network.next_ping() {
+                        send_async(&socket, &mut retry, cipher.seal(&probe)).await?;
+                    }
+                }
+                // Send redundant copies once they are due
+                _ = tokio::time::sleep_until(
+                    copy_due.map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+                ), if copy_due.is_some() => {
+                    while let Some(copy) = network.next_copy(false) {
+                        send_async(&socket, &mut retry, cipher.seal(copy)).await?;
+                    }
+                }
+                // Report the input levels
+                _ = meter_ticker.tick(), if network.options.meter => network.meter(),
+                // Keep the link alive during gaps in the audio
+                _ = keepalive_ticker.tick(), if network.options.keepalive.is_some() => {
+                    if let Some(keepalive) = network.next_keepalive() {
+                        send_async(&socket, &mut retry, cipher.seal(&keepalive)).await?;
+                    }
+                }
+            }
+        }
+    };
+
+    // Send the tail of the stream, ending it explicitly
+    while let Some(packet) = network.next_packet() {
+        let _ = socket.send(cipher.seal(packet)).await;
+    }
+    while let Some(copy) = network.next_copy(true) {
+        let _ = socket.send(cipher.seal(copy)).await;
+    }
+    let _ = socket.send(cipher.seal(network.final_packet())).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_periods_produce_empty_output() {
+        let mut interleaved = [0.0; 6];
+        interleave_into::<f32>(&[], 1, 3, &mut interleaved);
+        assert_eq!(interleaved, [0.0; 6]);
+        assert_eq!(to_planar(&[], 2, 2).unwrap().count(), 0);
+
+        // An empty period leaves the meter silent
+        let level = InputLevel::default();
+        level.add(&[]);
+        let (peak, rms) = level.take();
+        assert_eq!(peak, f32::NEG_INFINITY);
+        assert_eq!(rms, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn buffers_follow_period_changes() {
+        let mut buffers = ProcessBuffers::new(256, 2, 4);
+        assert_eq!(buffers.interleaved.len(), 2048);
+        buffers.oversized = true;
+        buffers.resize(1024, 2, 4);
+        assert_eq!(buffers.frames, 1024);
+        assert_eq!(buffers.interleaved.len(), 8192);
+        assert_eq!(buffers.tone.len(), 1024);
+        assert!(!buffers.oversized);
     }
 }
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by the first SIGINT or SIGTERM, or by `request`
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const NOTICE: &[u8] = b"[INFO] stopping, signal again to quit at once\n";
+
+// Runs as a signal handler, so it only touches the flag and makes async-signal-safe calls
+extern "C" fn handle(_: libc::c_int) {
+    if REQUESTED.swap(true, Ordering::Relaxed) {
+        // SAFETY: _exit is async-signal-safe and skips everything that is not
+        unsafe { libc::_exit(130) };
+    }
+    // SAFETY: NOTICE is valid for its whole length, and write is async-signal-safe
+    unsafe { libc::write(libc::STDERR_FILENO, NOTICE.as_ptr().cast(), NOTICE.len()) };
+}
+
+// Turns SIGINT and SIGTERM into a request to stop, which running senders and receivers follow
+// by ending their streams. A second signal ends the process at once, for when something
+// blocks the way out.
+pub fn install() -> Result<(), &'static str> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: an all-zero sigaction is a valid one without flags or blocked signals, and
+        // handle has the signature sa_sigaction expects without SA_SIGINFO
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err("unable to install signal handlers");
+        }
+    }
+    Ok(())
+}
+
+// Asks every running sender and receiver to stop, as a signal would
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
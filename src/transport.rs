@@ -0,0 +1,659 @@
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    mem,
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+        UdpSocket,
+    },
+    ptr,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::{packet::MAX_DATAGRAM_SIZE, sender};
+
+// Time allowed for establishing a TCP connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Delay between attempts to re-establish a lost TCP connection
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A connection delivering nothing for this long is considered dead, letting a new one in
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Bounds of the pause after a transient socket error, doubling with each further one
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Size of the length prefix framing each packet on a TCP stream
+const LENGTH_SIZE: usize = 2;
+
+// Time packets may wait for a stalled TCP connection before they are dropped
+pub const DEFAULT_QUEUE_DELAY: Duration = Duration::from_millis(100);
+
+// How packets travel between sender and receiver
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,  // One datagram per packet
+    Tcp,  // Length-prefixed packets on a stream, for links that block or throttle UDP
+    Quic, // Unreliable QUIC datagrams, for networks that only pass QUIC
+}
+
+impl FromStr for Transport {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            "quic" => Ok(Transport::Quic),
+            _ => Err("unknown transport, expected udp, tcp or quic"),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Quic => "quic",
+        })
+    }
+}
+
+// Interface carrying multicast traffic, on hosts attached to several networks
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MulticastInterface {
+    Address(Ipv4Addr), // The interface holding this IPv4 address
+    Index(u32),        // Interface index, which names are resolved to
+}
+
+impl MulticastInterface {
+    // Identifies the interface to IPv4 multicast, which goes by address
+    pub fn ipv4(self) -> Result<Ipv4Addr, &'static str> {
+        match self {
+            MulticastInterface::Address(addr) => Ok(addr),
+            MulticastInterface::Index(index) => {
+                interface_ipv4(index).ok_or("multicast interface has no IPv4 address")
+            }
+        }
+    }
+
+    // Identifies the interface to IPv6 multicast, which goes by index
+    pub fn ipv6(self) -> Result<u32, &'static str> {
+        match self {
+            MulticastInterface::Address(_) => {
+                Err("IPv6 multicast needs an interface name or index, not an address")
+            }
+            MulticastInterface::Index(index) => Ok(index),
+        }
+    }
+}
+
+// First IPv4 address of the interface with this index
+fn interface_ipv4(index: u32) -> Option<Ipv4Addr> {
+    let mut addrs = ptr::null_mut();
+    // SAFETY: getifaddrs hands over a list that is only read until it is freed at the end,
+    // and the addresses of AF_INET entries are sockaddr_in
+    unsafe {
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return None;
+        }
+        let mut found = None;
+        let mut entry = addrs;
+        while let Some(ifaddr) = entry.as_ref() {
+            let addr = ifaddr.ifa_addr;
+            if !addr.is_null()
+                && (*addr).sa_family as i32 == libc::AF_INET
+                && libc::if_nametoindex(ifaddr.ifa_name) == index
+            {
+                let addr = &*(addr as *const libc::sockaddr_in);
+                found = Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)));
+                break;
+            }
+            entry = ifaddr.ifa_next;
+        }
+        libc::freeifaddrs(addrs);
+        found
+    }
+}
+
+// The network loops only ever deal with datagrams, so TCP and QUIC are bridged to them through
+// a pair of connected loopback sockets. One end is handed to the network loop, relay threads
+// move packets between the other end and the connection.
+pub fn loopback_pair() -> io::Result<(UdpSocket, UdpSocket)> {
+    let local = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let relay = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    local.connect(relay.local_addr()?)?;
+    relay.connect(local.local_addr()?)?;
+    Ok((local, relay))
+}
+
+// `addr` as a socket bound to `bind` reaches it. Dual-stack IPv6 sockets reach IPv4 unicast
+// addresses in their IPv4-mapped form, which some platforms insist on.
+pub fn reachable(addr: SocketAddr, bind: SocketAddr) -> SocketAddr {
+    match (addr, bind) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) if !v4.ip().is_multicast() => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+// Whether a socket error may go away by itself: the other end not listening yet, as reported
+// by ICMP, a route or interface going away for a moment, full buffers or a firewall rule.
+// Anything else will not get better by trying again.
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    ) || matches!(
+        error.raw_os_error(),
+        Some(
+            libc::ENOBUFS
+                | libc::ENOMEM
+                | libc::EHOSTUNREACH
+                | libc::EHOSTDOWN
+                | libc::ENETUNREACH
+                | libc::ENETDOWN
+                | libc::EADDRNOTAVAIL
+                | libc::EPERM
+        )
+    )
+}
+
+// Carries a network loop through transient socket errors. After one, attempts are skipped
+// for a pause that doubles with every further error, so a missing interface is not hammered.
+// The first error and the recovery are logged, the ones in between are not.
+pub struct Retry {
+    what: Cow<'static, str>,   // Operation retried, e.g. "send"
+    delay: Duration,           // Pause after the next error
+    retry_at: Option<Instant>, // Set while failing
+    failures: u64,
+    since: Instant, // First error of the current outage
+}
+
+impl Retry {
+    pub fn new(what: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            what: what.into(),
+            delay: MIN_RETRY_DELAY,
+            retry_at: None,
+            failures: 0,
+            since: Instant::now(),
+        }
+    }
+
+    // Whether the operation should be attempted now
+    pub fn due(&self) -> bool {
+        self.retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    // Time until the next attempt is due, for loops that would spin on an error otherwise
+    pub fn pause(&self) -> Duration {
+        self.retry_at.map_or(Duration::ZERO, |at| {
+            at.saturating_duration_since(Instant::now())
+        })
+    }
+
+    // Takes the outcome of an attempt. Transient errors turn into None and a pause, the others
+    // are handed back.
+    pub fn check<T>(&mut self, result: io::Result<T>) -> io::Result<Option<T>> {
+        match result {
+            Ok(value) => {
+                if self.retry_at.take().is_some() {
+                    info!(
+                        "able to {} again after {} errors in {:.1}s",
+                        self.what,
+                        self.failures,
+                        self.since.elapsed().as_secs_f64()
+                    );
+                }
+                self.delay = MIN_RETRY_DELAY;
+                self.failures = 0;
+                Ok(Some(value))
+            }
+            Err(error) if is_transient(&error) => {
+                if self.failures == 0 {
+                    warning!("unable to {}: {}, retrying", self.what, error);
+                    self.since = Instant::now();
+                } else {
+                    debug!(
+                        failures = self.failures,
+                        delay_ms = self.delay.as_millis() as u64,
+                        %error,
+                        "unable to {}, backing off",
+                        self.what
+                    );
+                }
+                self.failures += 1;
+                self.retry_at = Some(Instant::now() + self.delay);
+                self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+// Sends the packet stored after the length prefix in `frame`
+fn write_frame(stream: &mut TcpStream, frame: &mut [u8]) -> io::Result<()> {
+    let length = (frame.len() - LENGTH_SIZE) as u16;
+    frame[..LENGTH_SIZE].copy_from_slice(&length.to_be_bytes());
+    stream.write_all(frame)
+}
+
+// Reads one length-prefixed packet into `buffer`, returning its length. A length that does
+// not fit is an error, which ends the connection.
+fn read_frame(stream: &mut TcpStream, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut length = [0; LENGTH_SIZE];
+    stream.read_exact(&mut length)?;
+    let length = u16::from_be_bytes(length) as usize;
+    if length > buffer.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame larger than a datagram",
+        ));
+    }
+    stream.read_exact(&mut buffer[..length])?;
+    Ok(length)
+}
+
+// Packets the sender loop handed over, waiting for the TCP connection to take them
+#[derive(Default)]
+struct Backlog {
+    frames: VecDeque<(Instant, Vec<u8>)>, // Length-prefixed, with the time they were queued
+    dropped: usize,                       // Frames dropped since the connection last took one
+}
+
+impl Backlog {
+    // Drops frames waiting longer than `max_delay`, the audio would arrive too late to play
+    fn prune(&mut self, max_delay: Duration) {
+        while self
+            .frames
+            .front()
+            .is_some_and(|(queued, _)| queued.elapsed() > max_delay)
+        {
+            self.frames.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+// Forwards packets arriving on the stream to the loopback socket until the stream fails,
+// then shuts it down so the writing side notices too
+fn forward_frames(mut stream: TcpStream, relay: &UdpSocket) {
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    while let Ok(length) = read_frame(&mut stream, &mut buffer) {
+        let _ = relay.send(&buffer[..length]);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// Creates a socket bound to `bind`. On IPv6, `v6only` decides whether the socket also carries
+// IPv4 through IPv4-mapped addresses. Platforms disagree on the default (Linux carries IPv4,
+// Windows and the BSDs do not), so without it dual-stack is attempted, where supported.
+// With `reuse` the address may be bound again while in use or lingering.
+pub fn bind_socket(
+    bind: SocketAddr,
+    kind: Type,
+    protocol: Protocol,
+    v6only: Option<bool>,
+    reuse: bool,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(bind), kind, Some(protocol))?;
+    if bind.is_ipv6() {
+        match v6only {
+            Some(v6only) => socket.set_only_v6(v6only)?,
+            // Some platforms, e.g. OpenBSD, never carry IPv4 on IPv6 sockets
+            None => {
+                let _ = socket.set_only_v6(false);
+            }
+        }
+    }
+    if reuse {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&bind.into())?;
+    Ok(socket)
+}
+
+// Binds a UDP socket, see bind_socket for `v6only`. A multicast address joins its group on
+// `interface`, or the default one, and receives on its port, which other receivers on the host
+// may share.
+pub fn bind_udp<T: ToSocketAddrs>(
+    bind: T,
+    v6only: Option<bool>,
+    interface: Option<MulticastInterface>,
+) -> Result<UdpSocket, &'static str> {
+    let bind = bind
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or("unable to bind to address")?;
+    let group = bind.ip().is_multicast().then_some(bind.ip());
+    let local = match group {
+        Some(IpAddr::V4(_)) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), bind.port()),
+        Some(IpAddr::V6(_)) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), bind.port()),
+        None => bind,
+    };
+    let socket = bind_socket(local, Type::DGRAM, Protocol::UDP, v6only, group.is_some())
+        .map_err(|_| "unable to bind to address")?;
+    match group {
+        Some(IpAddr::V4(group)) => {
+            let interface =
+                interface.map_or(Ok(Ipv4Addr::UNSPECIFIED), MulticastInterface::ipv4)?;
+            socket.join_multicast_v4(&group, &interface)
+        }
+        Some(IpAddr::V6(group)) => {
+            let interface = interface.map_or(Ok(0), MulticastInterface::ipv6)?;
+            socket.join_multicast_v6(&group, interface)
+        }
+        None => return Ok(socket.into()),
+    }
+    .map_err(|_| "unable to join multicast group")?;
+    info!("joined multicast group {}", bind.ip());
+    Ok(socket.into())
+}
+
+// Opens a TCP connection from `bind` to `peer`, reusing the address across reconnects
+fn open(
+    bind: SocketAddr,
+    peer: SocketAddr,
+    ttl: Option<u32>,
+    v6only: Option<bool>,
+) -> io::Result<TcpStream> {
+    let socket = bind_socket(bind, Type::STREAM, Protocol::TCP, v6only, true)?;
+    socket.connect_timeout(&peer.into(), CONNECT_TIMEOUT)?;
+    let stream = TcpStream::from(socket);
+    stream.set_nodelay(true)?;
+    if let Some(ttl) = ttl {
+        sender::set_ttl(SockRef::from(&stream), ttl).map_err(io::Error::other)?;
+    }
+    Ok(stream)
+}
+
+// Connects to a receiver over TCP, returning the socket the sender loop uses in place of a
+// UDP socket connected to the receiver. Packets wait at most `max_delay` for a stalled
+// connection, the oldest are dropped beyond that. Lost connections are re-established in the
+// background, dropping the audio sent in the meantime.
+pub fn connect<T: ToSocketAddrs>(
+    bind: T,
+    peer: T,
+    ttl: Option<u32>,
+    v6only: Option<bool>,
+    max_delay: Duration,
+) -> Result<UdpSocket, &'static str> {
+    let resolve = |addr: T| addr.to_socket_addrs().ok()?.next();
+    let bind = resolve(bind).ok_or("unable to bind to address")?;
+    let peer = resolve(peer).ok_or("unable to connect")?;
+    let mut stream = open(bind, peer, ttl, v6only).map_err(|_| "unable to connect")?;
+    let (local, relay) = loopback_pair().map_err(|_| "unable to create relay socket")?;
+    let relay_clone = relay
+        .try_clone()
+        .map_err(|_| "unable to create relay socket")?;
+
+    // Queue everything the sender loop hands over, so a stalled connection holds up nothing
+    // but the writing below
+    let backlog: Arc<(Mutex<Backlog>, Condvar)> = Arc::default();
+    let queue = backlog.clone();
+    thread::spawn(move || {
+        let mut frame = [0; LENGTH_SIZE + MAX_DATAGRAM_SIZE];
+        while let Ok(length) = relay_clone.recv(&mut frame[LENGTH_SIZE..]) {
+            let (backlog, ready) = &*queue;
+            let mut backlog = backlog.lock().unwrap_or_else(PoisonError::into_inner);
+            backlog.prune(max_delay);
+            let frame = frame[..LENGTH_SIZE + length].to_vec();
+            backlog.frames.push_back((Instant::now(), frame));
+            ready.notify_one();
+        }
+    });
+
+    thread::spawn(move || {
+        let (pending, ready) = &*backlog;
+        loop {
+            // Replies from the receiver flow back on their own thread
+            let (Ok(reader), Ok(relay_clone)) = (stream.try_clone(), relay.try_clone()) else {
+                return;
+            };
+            thread::spawn(move || forward_frames(reader, &relay_clone));
+
+            // Send the queued packets still worth sending until the connection fails
+            loop {
+                let (mut frame, dropped) = {
+                    let mut backlog = pending.lock().unwrap_or_else(PoisonError::into_inner);
+                    loop {
+                        backlog.prune(max_delay);
+                        if let Some((_, frame)) = backlog.frames.pop_front() {
+                            break (frame, mem::take(&mut backlog.dropped));
+                        }
+                        backlog = ready.wait(backlog).unwrap_or_else(PoisonError::into_inner);
+                    }
+                };
+                if dropped > 0 {
+                    warning!(
+                        "TCP connection stalled, dropped {} packets queued over {} ms",
+                        dropped,
+                        max_delay.as_millis()
+                    );
+                }
+                if write_frame(&mut stream, &mut frame).is_err() {
+                    break;
+                }
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+            warning!("TCP connection lost, reconnecting");
+
+            stream = loop {
+                thread::sleep(RECONNECT_INTERVAL);
+                if let Ok(stream) = open(bind, peer, ttl, v6only) {
+                    break stream;
+                }
+            };
+            info!("TCP connection re-established");
+
+            // Audio queued up while disconnected is stale by now
+            let mut backlog = pending.lock().unwrap_or_else(PoisonError::into_inner);
+            backlog.frames.clear();
+            backlog.dropped = 0;
+        }
+    });
+    Ok(local)
+}
+
+// Accepts senders over TCP, one connection at a time, returning the socket the receiver
+// loop uses in place of a bound UDP socket. Replies go back on the current connection.
+pub fn listen<T: ToSocketAddrs>(bind: T, v6only: Option<bool>) -> Result<UdpSocket, &'static str> {
+    let bind = bind
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or("unable to bind to address")?;
+    let socket = bind_socket(bind, Type::STREAM, Protocol::TCP, v6only, true)
+        .and_then(|socket| socket.listen(128).map(|()| socket))
+        .map_err(|_| "unable to bind to address")?;
+    let listener = TcpListener::from(socket);
+    let (local, relay) = loopback_pair().map_err(|_| "unable to create relay socket")?;
+    let relay_clone = relay
+        .try_clone()
+        .map_err(|_| "unable to create relay socket")?;
+    let current: Arc<Mutex<Option<TcpStream>>> = Arc::default();
+
+    let reply_stream = current.clone();
+    thread::spawn(move || {
+        let mut frame = [0; LENGTH_SIZE + MAX_DATAGRAM_SIZE];
+        while let Ok(length) = relay.recv(&mut frame[LENGTH_SIZE..]) {
+            let mut stream = reply_stream.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(connection) = stream.as_mut()
+                && write_frame(connection, &mut frame[..LENGTH_SIZE + length]).is_err()
+            {
+                *stream = None;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let peer = stream.peer_addr().map(|peer| peer.to_string());
+            if stream.set_nodelay(true).is_err()
+                || stream.set_read_timeout(Some(IDLE_TIMEOUT)).is_err()
+            {
+                continue;
+            }
+            info!(
+                "TCP connection from {}",
+                peer.as_deref().unwrap_or("unknown peer")
+            );
+            *current.lock().unwrap_or_else(PoisonError::into_inner) = stream.try_clone().ok();
+            forward_frames(stream, &relay_clone);
+            *current.lock().unwrap_or_else(PoisonError::into_inner) = None;
+            warning!("TCP connection closed");
+        }
+    });
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dual_stack_socket_receives_ipv4() {
+        let socket = bind_udp("[::]:0", Some(false), None).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender
+            .send_to(b"ping", (Ipv4Addr::LOCALHOST, port))
+            .unwrap();
+
+        let mut buffer = [0; 4];
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let (length, source) = socket.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"ping");
+        // IPv4 peers show up as IPv4-mapped addresses
+        let SocketAddr::V6(source) = source else {
+            panic!("expected an IPv6 source address");
+        };
+        assert_eq!(source.ip().to_ipv4_mapped(), Some(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn v6only_socket_leaves_ipv4_port_free() {
+        let socket = bind_udp("[::]:0", Some(true), None).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        // Only possible if the IPv6 socket does not take IPv4 traffic on the port
+        assert!(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok());
+
+        let dual_stack = bind_udp("[::]:0", Some(false), None).unwrap();
+        let port = dual_stack.local_addr().unwrap().port();
+        assert!(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_err());
+    }
+
+    #[test]
+    fn dual_stack_sockets_reach_ipv4_mapped() {
+        let v6: SocketAddr = "[::]:0".parse().unwrap();
+        let v4: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let peer: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+        assert_eq!(
+            reachable(peer, v6),
+            "[::ffff:192.0.2.1]:9000".parse().unwrap()
+        );
+        assert_eq!(reachable(peer, v4), peer);
+        let group: SocketAddr = "239.1.1.1:9000".parse().unwrap();
+        assert_eq!(reachable(group, v6), group);
+
+        // A mapped destination works on a dual-stack socket
+        let receiver = bind_udp("127.0.0.1:0", None, None).unwrap();
+        let sender = bind_udp("[::]:0", Some(false), None).unwrap();
+        let addr = receiver.local_addr().unwrap();
+        sender.send_to(b"ping", reachable(addr, v6)).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buffer = [0; 4];
+        assert_eq!(receiver.recv(&mut buffer).unwrap(), 4);
+    }
+
+    #[test]
+    fn transient_errors_back_off() {
+        let mut retry = Retry::new("send");
+        assert!(retry.due());
+        let unreachable = || Err::<(), _>(io::Error::from_raw_os_error(libc::ENETUNREACH));
+        assert!(matches!(retry.check(unreachable()), Ok(None)));
+        assert!(!retry.due());
+        assert!(retry.pause() <= MIN_RETRY_DELAY);
+        retry.check(unreachable()).unwrap();
+        assert!(retry.pause() > MIN_RETRY_DELAY);
+
+        assert!(matches!(retry.check(Ok(1)), Ok(Some(1))));
+        assert!(retry.due());
+        assert_eq!(retry.delay, MIN_RETRY_DELAY);
+
+        let fatal = io::Error::from_raw_os_error(libc::EBADF);
+        assert!(retry.check::<()>(Err(fatal)).is_err());
+    }
+
+    #[test]
+    fn stalled_packets_are_dropped_once_stale() {
+        let mut backlog = Backlog::default();
+        let now = Instant::now();
+        for age in [300, 200, 50, 0] {
+            backlog
+                .frames
+                .push_back((now - Duration::from_millis(age), vec![age as u8]));
+        }
+        backlog.prune(Duration::from_millis(100));
+        assert_eq!(backlog.dropped, 2);
+        assert_eq!(backlog.frames.len(), 2);
+        assert_eq!(backlog.frames[0].1, [50]);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+
+        let mut frame = [0, 0, 1, 2, 3];
+        write_frame(&mut client, &mut frame).unwrap();
+        assert_eq!(read_frame(&mut server, &mut buffer).unwrap(), 3);
+        assert_eq!(buffer[..3], [1, 2, 3]);
+
+        client.write_all(&u16::MAX.to_be_bytes()).unwrap();
+        let error = read_frame(&mut server, &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn multicast_interfaces_resolve_per_family() {
+        // SAFETY: the name is a NUL-terminated string literal
+        let loopback = unsafe { libc::if_nametoindex(c"lo".as_ptr()) };
+        assert_eq!(
+            MulticastInterface::Index(loopback).ipv4(),
+            Ok(Ipv4Addr::LOCALHOST)
+        );
+        assert_eq!(MulticastInterface::Index(loopback).ipv6(), Ok(loopback));
+        assert!(
+            MulticastInterface::Address(Ipv4Addr::LOCALHOST)
+                .ipv6()
+                .is_err()
+        );
+        assert!(MulticastInterface::Index(u32::MAX).ipv4().is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+// Aggregates a repeated warning into one line per interval instead of one line per occurrence
+pub struct WarningCounter {
+    what: &'static str,         // Plural name of the counted event, e.g. "underruns"
+    interval: Option<Duration>, // Aggregation window, None to print every occurrence
+    count: usize,
+    since: Instant,
+}
+
+impl WarningCounter {
+    pub fn new(what: &'static str, interval: Option<Duration>) -> Self {
+        Self {
+            what,
+            interval,
+            count: 0,
+            since: Instant::now(),
+        }
+    }
+
+    // Counts without ever printing, for events a periodic summary reports instead
+    pub fn silent(what: &'static str) -> Self {
+        Self::new(what, Some(Duration::MAX))
+    }
+
+    // Counts an occurrence, returns whether it should be printed on its own
+    pub fn record(&mut self) -> bool {
+        self.count += 1;
+        self.interval.is_none()
+    }
+
+    // Prints the aggregated count once the window has passed
+    pub fn flush(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let elapsed = self.since.elapsed();
+        if elapsed < interval {
+            return;
+        }
+
+        if self.count > 0 {
+            warning!(
+                "{} {} in last {:.1}s",
+                self.count,
+                self.what,
+                elapsed.as_secs_f64()
+            );
+        }
+        self.count = 0;
+        self.since = Instant::now();
+    }
+}
+
+// Warns about the xruns JACK reports, which its notification thread counts in the metrics.
+// An xrun means this machine missed a JACK deadline, so dropouts it causes are not the
+// network's doing.
+pub struct XrunWarnings {
+    seen: u64, // Xruns warned about or counted so far
+    counter: WarningCounter,
+}
+
+impl XrunWarnings {
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self::counting(WarningCounter::new(
+            "JACK xruns (local overload, not the network)",
+            interval,
+        ))
+    }
+
+    // Counts without ever printing, for a periodic summary reporting xruns instead
+    pub fn silent() -> Self {
+        Self::counting(WarningCounter::silent("JACK xruns"))
+    }
+
+    fn counting(counter: WarningCounter) -> Self {
+        Self { seen: 0, counter }
+    }
+
+    // Catches up with the running total of xruns, then prints the aggregated count if due
+    pub fn check(&mut self, xruns: u64) {
+        for _ in self.seen..xruns {
+            if self.counter.record() {
+                warning!(
+                    "JACK xrun, this machine could not keep up (local overload, not the network)"
+                );
+            }
+        }
+        self.seen = xruns;
+        self.counter.flush();
+    }
+}